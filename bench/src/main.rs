@@ -0,0 +1,631 @@
+use anyhow::{anyhow, Context, Result};
+use std::os::unix::process::ExitStatusExt;
+use structopt::StructOpt;
+
+#[derive(StructOpt, Debug)]
+#[structopt(
+    name = "bench",
+    about = "`bench` compares the performance of rcp against cp and rsync.
+
+It generates directory trees, copies each of them with every tool (and every rcp flag set) and reports the walltime,
+CPU time and peak RSS of each run. The copies are compared with the source after each run, a run producing a wrong copy
+is marked as not verified.
+
+Unless --drop-caches is used (and permitted, it requires root) the source is likely in the page cache for all but the
+first run, which favors the later runs."
+)]
+struct Args {
+    /// Directory where the trees are generated and copied to, it must have space for two copies of the largest tree
+    #[structopt(parse(from_os_str))]
+    workdir: std::path::PathBuf,
+
+    /// Tree to benchmark on, can be repeated.
+    ///
+    /// Either one of the presets: wide (1010 small files in 100 directories), deep (1022 small files 8 directory
+    /// levels deep), small-files (5550 1KiB files), large-files (4 files of 64MiB); or a custom tree in the format
+    /// "<name>=<dirwidth>:<numfiles>:<filesize>" with the same meaning as the filegen arguments.
+    ///
+    /// Example: "wide" or "mine=10,10:100:16K"
+    #[structopt(long = "preset", number_of_values = 1)]
+    presets: Vec<Preset>,
+
+    /// Tools to benchmark, a comma separated list of: rcp, cp (as "cp -a"), rsync (as "rsync -a"), rcmp.
+    ///
+    /// rcmp is not a copy: it compares the tree with a copy missing every other top-level entry (and with an extra
+    /// file), so that the trees diverge early. Such a run is verified if rcmp reports the differences.
+    #[structopt(long, default_value = "rcp,cp,rsync")]
+    tools: String,
+
+    /// Flags passed to rcp, can be repeated to benchmark several configurations.
+    ///
+    /// Example: --rcp-flags "" --rcp-flags "--max-workers=4 --reflink=never"
+    #[structopt(long = "rcp-flags", number_of_values = 1, allow_hyphen_values = true)]
+    rcp_flags: Vec<String>,
+
+    /// The rcp binary to benchmark, by default the one next to this binary or the one in PATH
+    #[structopt(long, parse(from_os_str))]
+    rcp: Option<std::path::PathBuf>,
+
+    /// The rcmp binary to benchmark, by default the one next to this binary or the one in PATH
+    #[structopt(long, parse(from_os_str))]
+    rcmp: Option<std::path::PathBuf>,
+
+    /// Number of runs of each configuration
+    #[structopt(long, default_value = "1")]
+    runs: usize,
+
+    /// Drop the page cache before every run, requires root
+    #[structopt(long)]
+    drop_caches: bool,
+
+    /// Format of the report: csv or json
+    #[structopt(long, default_value = "csv")]
+    format: ReportFormat,
+
+    /// Write the report to a file instead of stdout
+    #[structopt(long, parse(from_os_str))]
+    output: Option<std::path::PathBuf>,
+
+    /// Also write the report as a markdown table to a file
+    #[structopt(long, parse(from_os_str))]
+    markdown: Option<std::path::PathBuf>,
+
+    /// Keep the generated trees in the workdir
+    #[structopt(long)]
+    keep_trees: bool,
+}
+
+#[derive(Clone, Debug)]
+struct Preset {
+    name: String,
+    dirwidth: Vec<usize>,
+    numfiles: usize,
+    filesize: u64,
+}
+
+impl std::str::FromStr for Preset {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self> {
+        let preset = |dirwidth: &[usize], numfiles, filesize| Preset {
+            name: s.to_string(),
+            dirwidth: dirwidth.to_vec(),
+            numfiles,
+            filesize,
+        };
+        match s {
+            "wide" => return Ok(preset(&[100], 10, 4 << 10)),
+            "deep" => return Ok(preset(&[2; 8], 2, 4 << 10)),
+            "small-files" => return Ok(preset(&[10, 10], 50, 1 << 10)),
+            "large-files" => return Ok(preset(&[], 4, 64 << 20)),
+            _ => {}
+        }
+        let (name, spec) = s.split_once('=').ok_or_else(|| {
+            anyhow!(
+                "Invalid preset: {}, expected one of: wide, deep, small-files, large-files or \
+                \"<name>=<dirwidth>:<numfiles>:<filesize>\"",
+                s
+            )
+        })?;
+        let parts = spec.split(':').collect::<Vec<_>>();
+        if parts.len() != 3 {
+            return Err(anyhow!(
+                "Invalid custom preset: {}, expected \"<name>=<dirwidth>:<numfiles>:<filesize>\"",
+                s
+            ));
+        }
+        let dirwidth = if parts[0].is_empty() {
+            vec![]
+        } else {
+            parts[0]
+                .split(',')
+                .map(|width| width.parse::<usize>())
+                .collect::<Result<Vec<_>, _>>()
+                .with_context(|| format!("Invalid dirwidth in preset: {}", s))?
+        };
+        let numfiles = parts[1]
+            .parse::<usize>()
+            .with_context(|| format!("Invalid numfiles in preset: {}", s))?;
+        let filesize = parts[2]
+            .parse::<bytesize::ByteSize>()
+            .map_err(|err| anyhow!("Invalid filesize in preset: {}: {}", s, err))?
+            .as_u64();
+        Ok(Preset {
+            name: name.to_string(),
+            dirwidth,
+            numfiles,
+            filesize,
+        })
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum ReportFormat {
+    Csv,
+    Json,
+}
+
+impl std::str::FromStr for ReportFormat {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "csv" => Ok(ReportFormat::Csv),
+            "json" => Ok(ReportFormat::Json),
+            _ => Err(anyhow!(
+                "Invalid report format: {}, expected one of: csv, json",
+                s
+            )),
+        }
+    }
+}
+
+/// A copy (or compare) command being benchmarked
+#[derive(Clone, Debug)]
+enum Tool {
+    Rcp(Vec<String>),
+    Cp,
+    Rsync,
+    Rcmp,
+}
+
+impl Tool {
+    fn name(&self) -> &'static str {
+        match self {
+            Tool::Rcp(_) => "rcp",
+            Tool::Cp => "cp",
+            Tool::Rsync => "rsync",
+            Tool::Rcmp => "rcmp",
+        }
+    }
+
+    fn flags(&self) -> String {
+        match self {
+            Tool::Rcp(flags) => flags.join(" "),
+            Tool::Cp => "-a".to_string(),
+            Tool::Rsync => "-a".to_string(),
+            Tool::Rcmp => "".to_string(),
+        }
+    }
+
+    fn command(
+        &self,
+        binaries: &Binaries,
+        src: &std::path::Path,
+        dst: &std::path::Path,
+    ) -> std::process::Command {
+        match self {
+            Tool::Rcp(flags) => {
+                let mut cmd = std::process::Command::new(&binaries.rcp);
+                cmd.args(flags).arg(src).arg(dst);
+                cmd
+            }
+            Tool::Rcmp => {
+                let mut cmd = std::process::Command::new(&binaries.rcmp);
+                cmd.arg(src).arg(dst);
+                cmd
+            }
+            Tool::Cp => {
+                let mut cmd = std::process::Command::new("cp");
+                cmd.arg("-a").arg(src).arg(dst);
+                cmd
+            }
+            Tool::Rsync => {
+                // the trailing slash copies the contents of 'src' into 'dst', same as the other tools
+                let mut src = src.as_os_str().to_owned();
+                src.push("/");
+                let mut cmd = std::process::Command::new("rsync");
+                cmd.arg("-a").arg(src).arg(dst);
+                cmd
+            }
+        }
+    }
+}
+
+/// Result of a single benchmark run
+struct Record {
+    preset: String,
+    tool: &'static str,
+    flags: String,
+    run: usize,
+    cold_cache: bool,
+    exit_code: Option<i32>,
+    walltime: std::time::Duration,
+    user_time: std::time::Duration,
+    system_time: std::time::Duration,
+    peak_rss: Option<u64>,
+    verified: bool,
+}
+
+const COLUMNS: &[&str] = &[
+    "preset",
+    "tool",
+    "flags",
+    "run",
+    "cold_cache",
+    "exit_code",
+    "walltime_secs",
+    "user_secs",
+    "system_secs",
+    "peak_rss_bytes",
+    "verified",
+];
+
+impl Record {
+    fn values(&self) -> Vec<String> {
+        vec![
+            self.preset.clone(),
+            self.tool.to_string(),
+            self.flags.clone(),
+            self.run.to_string(),
+            self.cold_cache.to_string(),
+            self.exit_code
+                .map_or("".to_string(), |code| code.to_string()),
+            format!("{:.3}", self.walltime.as_secs_f64()),
+            format!("{:.3}", self.user_time.as_secs_f64()),
+            format!("{:.3}", self.system_time.as_secs_f64()),
+            self.peak_rss.map_or("".to_string(), |rss| rss.to_string()),
+            self.verified.to_string(),
+        ]
+    }
+
+    fn to_json(&self) -> serde_json::Value {
+        serde_json::json!({
+            "preset": self.preset,
+            "tool": self.tool,
+            "flags": self.flags,
+            "run": self.run,
+            "cold_cache": self.cold_cache,
+            "exit_code": self.exit_code,
+            "walltime_secs": self.walltime.as_secs_f64(),
+            "user_secs": self.user_time.as_secs_f64(),
+            "system_secs": self.system_time.as_secs_f64(),
+            "peak_rss_bytes": self.peak_rss,
+            "verified": self.verified,
+        })
+    }
+}
+
+fn csv_field(value: &str) -> String {
+    if value.contains([',', '"', '\n']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+fn write_report(
+    records: &[Record],
+    format: ReportFormat,
+    out: &mut dyn std::io::Write,
+) -> Result<()> {
+    match format {
+        ReportFormat::Csv => {
+            writeln!(out, "{}", COLUMNS.join(","))?;
+            for record in records {
+                let values = record.values();
+                let fields = values
+                    .iter()
+                    .map(|value| csv_field(value))
+                    .collect::<Vec<_>>();
+                writeln!(out, "{}", fields.join(","))?;
+            }
+        }
+        ReportFormat::Json => {
+            let records = records.iter().map(Record::to_json).collect::<Vec<_>>();
+            serde_json::to_writer_pretty(&mut *out, &records)?;
+            writeln!(out)?;
+        }
+    }
+    Ok(())
+}
+
+fn write_markdown(records: &[Record], out: &mut dyn std::io::Write) -> Result<()> {
+    writeln!(out, "| {} |", COLUMNS.join(" | "))?;
+    writeln!(out, "|{}", "---|".repeat(COLUMNS.len()))?;
+    for record in records {
+        let values = record.values();
+        let fields = values
+            .iter()
+            .map(|value| value.replace('|', "\\|"))
+            .collect::<Vec<_>>();
+        writeln!(out, "| {} |", fields.join(" | "))?;
+    }
+    Ok(())
+}
+
+/// Flushes the dirty pages and drops the page cache, fails unless running as root
+fn drop_caches() -> Result<()> {
+    // safety: sync has no arguments and cannot fail
+    unsafe { libc::sync() };
+    std::fs::write("/proc/sys/vm/drop_caches", "3").context("cannot drop the page cache")
+}
+
+fn timeval_to_duration(time: libc::timeval) -> std::time::Duration {
+    std::time::Duration::from_secs(time.tv_sec as u64)
+        + std::time::Duration::from_micros(time.tv_usec as u64)
+}
+
+/// Exit status and resource usage of a benchmarked command
+struct RunStats {
+    status: std::process::ExitStatus,
+    walltime: std::time::Duration,
+    user_time: std::time::Duration,
+    system_time: std::time::Duration,
+    peak_rss: Option<u64>,
+}
+
+/// Returns the peak RSS of a running process in bytes
+fn peak_rss(pid: i32) -> Option<u64> {
+    // reported in kB
+    let vmhwm = procfs::process::Process::new(pid)
+        .ok()?
+        .status()
+        .ok()?
+        .vmhwm?;
+    Some(vmhwm * 1024)
+}
+
+/// Runs the command and waits for it to finish
+fn run_command(mut cmd: std::process::Command) -> Result<RunStats> {
+    let start = std::time::Instant::now();
+    let child = cmd
+        .stdout(std::process::Stdio::null())
+        .spawn()
+        .with_context(|| format!("cannot run {:?}", cmd.get_program()))?;
+    let pid = child.id() as libc::pid_t;
+    let done = std::sync::atomic::AtomicBool::new(false);
+    let mut max_rss = None;
+    let res = std::thread::scope(|scope| {
+        let waiter = scope.spawn(|| {
+            let mut status = 0;
+            // safety: rusage is plain data filled in by wait4
+            let mut rusage: libc::rusage = unsafe { std::mem::zeroed() };
+            let res = loop {
+                // std doesn't expose the resource usage of a child, so we reap it ourselves
+                let res = unsafe { libc::wait4(pid, &mut status, 0, &mut rusage) };
+                if res >= 0 {
+                    break Ok(());
+                }
+                let error = std::io::Error::last_os_error();
+                if error.kind() != std::io::ErrorKind::Interrupted {
+                    break Err(error);
+                }
+            };
+            let walltime = start.elapsed();
+            done.store(true, std::sync::atomic::Ordering::Release);
+            res.map(|()| (status, walltime, rusage))
+        });
+        // ru_maxrss includes the memory of this process at the time of the fork, so instead we sample the peak RSS of
+        // the running command, a peak reached in its last few milliseconds may be missed
+        while !done.load(std::sync::atomic::Ordering::Acquire) {
+            if let Some(rss) = peak_rss(pid) {
+                max_rss = max_rss.max(Some(rss));
+            }
+            std::thread::sleep(std::time::Duration::from_millis(10));
+        }
+        waiter.join().unwrap()
+    });
+    let (status, walltime, rusage) =
+        res.with_context(|| format!("failed waiting for {:?}", cmd.get_program()))?;
+    Ok(RunStats {
+        status: std::process::ExitStatus::from_raw(status),
+        walltime,
+        user_time: timeval_to_duration(rusage.ru_utime),
+        system_time: timeval_to_duration(rusage.ru_stime),
+        peak_rss: max_rss,
+    })
+}
+
+/// Compares the copy with the source: file sizes and contents, and that no entries are missing or extra
+async fn verify(src: &std::path::Path, dst: &std::path::Path) -> Result<bool> {
+    let log = common::LogWriter::new(None, Default::default()).await?;
+    let summary = common::cmp(
+        src,
+        dst,
+        &log,
+        &common::CmpSettings {
+            compare: common::parse_compare_settings("f:size,hash", Default::default())?,
+            fail_early: false,
+            exit_early: true,
+        },
+    )
+    .await?;
+    Ok(summary.mismatch.values().all(|results| {
+        results
+            .iter()
+            .all(|(result, &count)| matches!(result, common::CmpResult::Same) || count == 0)
+    }))
+}
+
+/// Paths of the rcp tools being benchmarked
+struct Binaries {
+    rcp: std::path::PathBuf,
+    rcmp: std::path::PathBuf,
+}
+
+fn default_binary(name: &str) -> std::path::PathBuf {
+    if let Ok(exe) = std::env::current_exe() {
+        let binary = exe.with_file_name(name);
+        if binary.exists() {
+            return binary;
+        }
+    }
+    name.into()
+}
+
+/// Copies 'src' to 'dst' and makes them diverge at the top: every other top-level entry of 'dst' is removed and an
+/// extra file is added
+fn diverged_copy(src: &std::path::Path, dst: &std::path::Path) -> Result<()> {
+    let status = std::process::Command::new("cp")
+        .arg("-a")
+        .arg(src)
+        .arg(dst)
+        .status()
+        .context("cannot run cp")?;
+    if !status.success() {
+        return Err(anyhow!("failed copying {:?} to {:?}: {}", src, dst, status));
+    }
+    let mut entries = std::fs::read_dir(dst)
+        .with_context(|| format!("cannot open directory {:?} for reading", dst))?
+        .map(|entry| entry.map(|entry| entry.path()))
+        .collect::<std::io::Result<Vec<_>>>()
+        .with_context(|| format!("failed traversing directory {:?}", dst))?;
+    entries.sort();
+    for path in entries.iter().step_by(2) {
+        if path.is_dir() {
+            std::fs::remove_dir_all(path)
+        } else {
+            std::fs::remove_file(path)
+        }
+        .with_context(|| format!("cannot remove {:?}", path))?;
+    }
+    let extra = dst.join("bench-extra-file");
+    std::fs::write(&extra, "extra").with_context(|| format!("cannot create {:?}", &extra))
+}
+
+async fn bench(args: &Args) -> Result<Vec<Record>> {
+    let presets = if args.presets.is_empty() {
+        ["wide", "deep", "small-files", "large-files"]
+            .iter()
+            .map(|name| name.parse())
+            .collect::<Result<Vec<Preset>>>()?
+    } else {
+        args.presets.clone()
+    };
+    let rcp_flags = if args.rcp_flags.is_empty() {
+        vec![String::new()]
+    } else {
+        args.rcp_flags.clone()
+    };
+    let mut tools = vec![];
+    for tool in args.tools.split(',') {
+        match tool {
+            "rcp" => tools.extend(
+                rcp_flags
+                    .iter()
+                    .map(|flags| Tool::Rcp(flags.split_whitespace().map(str::to_string).collect())),
+            ),
+            "cp" => tools.push(Tool::Cp),
+            "rsync" => tools.push(Tool::Rsync),
+            "rcmp" => tools.push(Tool::Rcmp),
+            _ => {
+                return Err(anyhow!(
+                    "Invalid tool: {}, expected one of: rcp, cp, rsync, rcmp",
+                    tool
+                ))
+            }
+        }
+    }
+    let binaries = Binaries {
+        rcp: args.rcp.clone().unwrap_or_else(|| default_binary("rcp")),
+        rcmp: args.rcmp.clone().unwrap_or_else(|| default_binary("rcmp")),
+    };
+    let mut cold_cache = args.drop_caches;
+    let mut records = vec![];
+    for preset in &presets {
+        let preset_dir = args.workdir.join(&preset.name);
+        let src = preset_dir.join("src");
+        let dst = preset_dir.join("dst");
+        if preset_dir.exists() {
+            tokio::fs::remove_dir_all(&preset_dir)
+                .await
+                .with_context(|| format!("cannot remove {:?}", &preset_dir))?;
+        }
+        tokio::fs::create_dir_all(&src)
+            .await
+            .with_context(|| format!("cannot create {:?}", &src))?;
+        eprintln!("generating {:?}", &preset);
+        common::filegen(
+            &src,
+            &preset.dirwidth,
+            preset.numfiles,
+            preset.filesize as usize,
+            std::cmp::min(preset.filesize as usize, 1 << 20).max(1),
+        )
+        .await?;
+        for tool in &tools {
+            for run in 0..args.runs {
+                if cold_cache {
+                    if let Err(error) = drop_caches() {
+                        eprintln!("{:#}, the remaining runs use a warm page cache", error);
+                        cold_cache = false;
+                    }
+                }
+                if let Tool::Rcmp = tool {
+                    diverged_copy(&src, &dst)?;
+                }
+                let stats = match run_command(tool.command(&binaries, &src, &dst)) {
+                    Ok(stats) => stats,
+                    Err(error) => {
+                        // e.g. rsync is not installed
+                        eprintln!("skipping {}: {:#}", tool.name(), error);
+                        break;
+                    }
+                };
+                let verified = match tool {
+                    // rcmp exits with 1 when it finds differences and with 2 on errors
+                    Tool::Rcmp => stats.status.code() == Some(1),
+                    _ => stats.status.success() && verify(&src, &dst).await?,
+                };
+                let record = Record {
+                    preset: preset.name.clone(),
+                    tool: tool.name(),
+                    flags: tool.flags(),
+                    run,
+                    cold_cache,
+                    exit_code: stats.status.code(),
+                    walltime: stats.walltime,
+                    user_time: stats.user_time,
+                    system_time: stats.system_time,
+                    peak_rss: stats.peak_rss,
+                    verified,
+                };
+                eprintln!(
+                    "{} {} {:?} run {}: {:.2?}{}",
+                    &record.preset,
+                    record.tool,
+                    &record.flags,
+                    run,
+                    record.walltime,
+                    if verified { "" } else { " (NOT VERIFIED)" }
+                );
+                records.push(record);
+                if dst.exists() {
+                    tokio::fs::remove_dir_all(&dst)
+                        .await
+                        .with_context(|| format!("cannot remove {:?}", &dst))?;
+                }
+            }
+        }
+        if !args.keep_trees {
+            tokio::fs::remove_dir_all(&preset_dir)
+                .await
+                .with_context(|| format!("cannot remove {:?}", &preset_dir))?;
+        }
+    }
+    Ok(records)
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let args = Args::from_args();
+    let records = bench(&args).await?;
+    match &args.output {
+        Some(path) => {
+            let mut file = std::io::BufWriter::new(
+                std::fs::File::create(path).with_context(|| format!("cannot create {:?}", path))?,
+            );
+            write_report(&records, args.format, &mut file)?;
+        }
+        None => write_report(&records, args.format, &mut std::io::stdout().lock())?,
+    }
+    if let Some(path) = &args.markdown {
+        let mut file = std::io::BufWriter::new(
+            std::fs::File::create(path).with_context(|| format!("cannot create {:?}", path))?,
+        );
+        write_markdown(&records, &mut file)?;
+    }
+    if records.iter().any(|record| !record.verified) {
+        return Err(anyhow!("some runs didn't produce a correct copy"));
+    }
+    Ok(())
+}
@@ -0,0 +1,39 @@
+#[test]
+fn check_bench_help() {
+    let mut cmd = assert_cmd::Command::cargo_bin("bench").unwrap();
+    cmd.arg("--help").assert();
+}
+
+#[test]
+fn check_bench_report() {
+    let tmp_dir = common::testing::TempDir::new("bench");
+    let markdown = tmp_dir.join("report.md");
+    let output = assert_cmd::Command::cargo_bin("bench")
+        .unwrap()
+        .args([
+            "--tools=cp",
+            "--runs=2",
+            "--preset=tiny=2,2:3:1K",
+            "--format=json",
+        ])
+        .arg("--markdown")
+        .arg(&markdown)
+        .arg(&tmp_dir)
+        .output()
+        .unwrap();
+    assert!(output.status.success(), "{:?}", output);
+    let records: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+    let records = records.as_array().unwrap();
+    assert_eq!(records.len(), 2);
+    for record in records {
+        assert_eq!(record["preset"], "tiny");
+        assert_eq!(record["tool"], "cp");
+        assert_eq!(record["verified"], true);
+    }
+    assert_eq!(
+        std::fs::read_to_string(&markdown).unwrap().lines().count(),
+        4
+    );
+    // the generated trees are removed
+    assert!(!tmp_dir.join("tiny").exists());
+}
@@ -0,0 +1,381 @@
+use anyhow::{anyhow, Context, Result};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt};
+use tracing::{event, instrument, Level};
+
+use crate::throttle;
+
+// Shares the ops and byte-rate throttles between multiple processes on the same host.
+//
+// The first process to use a given socket path becomes the broker: it keeps using its own throttle semaphores and hands
+// out tokens to the other processes (clients) connected over the unix socket. The protocol is line based, each request
+// is answered with a single line:
+// - "ops <n>" - wait for at least one and at most <n> ops tokens, answered with the number of tokens granted
+// - "tput <n>" - same for byte-rate tokens
+// - "release <n>" - return unused ops tokens (no answer)
+// - "stats" - aggregate broker statistics
+
+/// Number of ops tokens a client asks for at once, unused tokens are returned when the client disconnects
+const OPS_BATCH: u64 = 16;
+
+lazy_static! {
+    static ref CLIENT: tokio::sync::Mutex<Option<Client>> = tokio::sync::Mutex::const_new(None);
+    static ref OPS_POOL: tokio::sync::Semaphore = tokio::sync::Semaphore::const_new(0);
+    static ref BROKER_PATH: std::sync::Mutex<Option<std::path::PathBuf>> =
+        std::sync::Mutex::new(None);
+}
+
+static IS_CLIENT: AtomicBool = AtomicBool::new(false);
+static IS_BROKER: AtomicBool = AtomicBool::new(false);
+static BROKER_CLIENTS: AtomicUsize = AtomicUsize::new(0);
+static BROKER_OPS_GRANTED: AtomicU64 = AtomicU64::new(0);
+static BROKER_BYTES_GRANTED: AtomicU64 = AtomicU64::new(0);
+static BROKER_START: std::sync::OnceLock<std::time::Instant> = std::sync::OnceLock::new();
+
+#[derive(Debug, PartialEq, Eq)]
+enum Request {
+    Ops(u64),
+    Tput(u64),
+    Release(u64),
+    Stats,
+}
+
+impl std::str::FromStr for Request {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.split_whitespace();
+        let request = match (parts.next(), parts.next(), parts.next()) {
+            (Some("ops"), Some(n), None) => Request::Ops(n.parse()?),
+            (Some("tput"), Some(n), None) => Request::Tput(n.parse()?),
+            (Some("release"), Some(n), None) => Request::Release(n.parse()?),
+            (Some("stats"), None, None) => Request::Stats,
+            _ => return Err(anyhow!("invalid throttle broker request: {:?}", s)),
+        };
+        Ok(request)
+    }
+}
+
+/// Aggregate statistics of the throttle broker
+#[derive(Debug, Clone, Copy)]
+pub struct BrokerStats {
+    pub clients: usize,
+    pub ops_granted: u64,
+    pub bytes_granted: u64,
+    pub elapsed: std::time::Duration,
+}
+
+impl std::fmt::Display for BrokerStats {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let secs = self.elapsed.as_secs_f64().max(f64::EPSILON);
+        write!(
+            f,
+            "clients={} ops_granted={} bytes_granted={} ops_rate={:.1} bytes_rate={:.1}",
+            self.clients,
+            self.ops_granted,
+            self.bytes_granted,
+            self.ops_granted as f64 / secs,
+            self.bytes_granted as f64 / secs
+        )
+    }
+}
+
+/// Returns the broker statistics if this process is the throttle broker
+pub fn stats() -> Option<BrokerStats> {
+    if !IS_BROKER.load(Ordering::Acquire) {
+        return None;
+    }
+    Some(BrokerStats {
+        clients: BROKER_CLIENTS.load(Ordering::Acquire),
+        ops_granted: BROKER_OPS_GRANTED.load(Ordering::Acquire),
+        bytes_granted: BROKER_BYTES_GRANTED.load(Ordering::Acquire),
+        elapsed: BROKER_START
+            .get()
+            .map_or(Default::default(), |start| start.elapsed()),
+    })
+}
+
+#[instrument(skip(stream))]
+async fn handle_client(stream: tokio::net::UnixStream) -> Result<()> {
+    let (reader, mut writer) = stream.into_split();
+    let mut lines = tokio::io::BufReader::new(reader).lines();
+    while let Some(line) = lines.next_line().await? {
+        let request = line.parse::<Request>()?;
+        let tokens = match request {
+            Request::Ops(tokens) | Request::Tput(tokens) => tokens,
+            Request::Release(tokens) => {
+                throttle::return_tokens(tokens);
+                continue;
+            }
+            Request::Stats => {
+                let stats = stats().unwrap();
+                writer.write_all(format!("{}\n", stats).as_bytes()).await?;
+                continue;
+            }
+        };
+        let grant = async {
+            match request {
+                Request::Ops(_) => throttle::take_tokens(tokens).await,
+                _ => throttle::take_tput_tokens(tokens).await,
+            }
+        };
+        // clients don't send anything while waiting for tokens, so any input here means they went away; waiting for
+        // tokens is cancelled in that case and whatever we acquired so far is returned
+        let granted = tokio::select! {
+            granted = grant => granted,
+            _ = lines.next_line() => {
+                event!(Level::DEBUG, "client disconnected while waiting for tokens");
+                return Ok(());
+            }
+        };
+        match request {
+            Request::Ops(_) => BROKER_OPS_GRANTED.fetch_add(granted, Ordering::AcqRel),
+            _ => BROKER_BYTES_GRANTED.fetch_add(granted, Ordering::AcqRel),
+        };
+        if let Err(error) = writer.write_all(format!("{}\n", granted).as_bytes()).await {
+            if let Request::Ops(_) = request {
+                throttle::return_tokens(granted);
+            }
+            return Err(error.into());
+        }
+    }
+    Ok(())
+}
+
+/// Serves throttle tokens to other processes until the runtime shuts down
+async fn serve(listener: tokio::net::UnixListener) {
+    loop {
+        let stream = match listener.accept().await {
+            Ok((stream, _)) => stream,
+            Err(error) => {
+                event!(Level::WARN, "throttle broker failed to accept: {}", &error);
+                continue;
+            }
+        };
+        tokio::spawn(async move {
+            BROKER_CLIENTS.fetch_add(1, Ordering::AcqRel);
+            if let Err(error) = handle_client(stream).await {
+                event!(Level::WARN, "throttle broker client failed: {:#}", &error);
+            }
+            BROKER_CLIENTS.fetch_sub(1, Ordering::AcqRel);
+        });
+    }
+}
+
+struct Client {
+    lines: tokio::io::Lines<tokio::io::BufReader<tokio::net::unix::OwnedReadHalf>>,
+    writer: tokio::net::unix::OwnedWriteHalf,
+}
+
+impl Client {
+    fn new(stream: tokio::net::UnixStream) -> Self {
+        let (reader, writer) = stream.into_split();
+        Client {
+            lines: tokio::io::BufReader::new(reader).lines(),
+            writer,
+        }
+    }
+
+    async fn request(&mut self, request: &str) -> Result<u64> {
+        self.writer
+            .write_all(format!("{}\n", request).as_bytes())
+            .await?;
+        let response = self
+            .lines
+            .next_line()
+            .await?
+            .ok_or_else(|| anyhow!("connection closed by the throttle broker"))?;
+        Ok(response.parse()?)
+    }
+}
+
+pub fn is_client() -> bool {
+    IS_CLIENT.load(Ordering::Acquire)
+}
+
+/// Connects to the broker or becomes one, returns the client connection if another process is the broker
+async fn connect_or_serve(path: &std::path::Path) -> Result<Option<Client>> {
+    match tokio::net::UnixStream::connect(path).await {
+        Ok(stream) => return Ok(Some(Client::new(stream))),
+        Err(error) if error.kind() == std::io::ErrorKind::ConnectionRefused => {
+            event!(
+                Level::DEBUG,
+                "removing stale throttle broker socket {:?}",
+                path
+            );
+            std::fs::remove_file(path)
+                .with_context(|| format!("cannot remove stale socket {:?}", path))?;
+        }
+        Err(error) if error.kind() == std::io::ErrorKind::NotFound => {}
+        Err(error) => {
+            return Err(error)
+                .with_context(|| format!("cannot connect to the throttle broker at {:?}", path))
+        }
+    }
+    let listener = match tokio::net::UnixListener::bind(path) {
+        Ok(listener) => listener,
+        Err(error) if error.kind() == std::io::ErrorKind::AddrInUse => {
+            // another process became the broker in the meantime
+            let stream = tokio::net::UnixStream::connect(path)
+                .await
+                .with_context(|| format!("cannot connect to the throttle broker at {:?}", path))?;
+            return Ok(Some(Client::new(stream)));
+        }
+        Err(error) => {
+            return Err(error)
+                .with_context(|| format!("cannot bind the throttle broker to {:?}", path))
+        }
+    };
+    BROKER_START.get_or_init(std::time::Instant::now);
+    IS_BROKER.store(true, Ordering::Release);
+    tokio::spawn(serve(listener));
+    Ok(None)
+}
+
+/// Uses the throttle broker at the given socket path, starting it in this process if there's none running
+pub async fn start(path: &std::path::Path) -> Result<()> {
+    *BROKER_PATH.lock().unwrap() = Some(path.to_owned());
+    match connect_or_serve(path).await? {
+        Some(client) => {
+            event!(Level::INFO, "using the throttle broker at {:?}", path);
+            *CLIENT.lock().await = Some(client);
+            IS_CLIENT.store(true, Ordering::Release);
+        }
+        None => event!(Level::INFO, "serving as the throttle broker at {:?}", path),
+    }
+    Ok(())
+}
+
+/// Sends a request to the broker, reconnecting (or taking over as the broker) if the connection was lost. Returns None
+/// if throttling should be done locally from now on.
+async fn request(client: &mut Option<Client>, request: &str) -> Option<u64> {
+    let error = match client.as_mut()?.request(request).await {
+        Ok(granted) => return Some(granted),
+        Err(error) => error,
+    };
+    event!(
+        Level::WARN,
+        "lost connection to the throttle broker: {:#}",
+        &error
+    );
+    *client = None;
+    IS_CLIENT.store(false, Ordering::Release);
+    let path = BROKER_PATH.lock().unwrap().clone()?;
+    match connect_or_serve(&path).await {
+        Ok(Some(mut new_client)) => {
+            let granted = new_client.request(request).await.ok();
+            if granted.is_some() {
+                event!(Level::INFO, "reconnected to the throttle broker");
+                *client = Some(new_client);
+                IS_CLIENT.store(true, Ordering::Release);
+            }
+            granted
+        }
+        Ok(None) => {
+            event!(
+                Level::INFO,
+                "took over as the throttle broker at {:?}",
+                path
+            );
+            None
+        }
+        Err(error) => {
+            event!(
+                Level::WARN,
+                "falling back to local throttling: {:#}",
+                &error
+            );
+            None
+        }
+    }
+}
+
+/// Gets an ops token from the broker, returns false if the token should be taken locally instead
+pub async fn get_token() -> bool {
+    loop {
+        if let Ok(permit) = OPS_POOL.try_acquire() {
+            permit.forget();
+            return true;
+        }
+        let mut client = CLIENT.lock().await;
+        // someone else may have refilled the pool while we were waiting
+        if let Ok(permit) = OPS_POOL.try_acquire() {
+            permit.forget();
+            return true;
+        }
+        match request(&mut client, &format!("ops {}", OPS_BATCH)).await {
+            Some(granted) => OPS_POOL.add_permits(granted as usize),
+            None => return false,
+        }
+    }
+}
+
+/// Gets byte-rate tokens from the broker, returns the number of bytes that should be throttled locally instead
+pub async fn get_tput_tokens(bytes: u64) -> u64 {
+    let mut remaining = bytes;
+    while remaining > 0 {
+        let mut client = CLIENT.lock().await;
+        match request(&mut client, &format!("tput {}", remaining)).await {
+            Some(granted) => remaining = remaining.saturating_sub(granted),
+            None => return remaining,
+        }
+    }
+    0
+}
+
+/// Returns unused tokens to the broker or, if this process is the broker, removes its socket
+pub async fn shutdown() {
+    if IS_BROKER.load(Ordering::Acquire) {
+        if let Some(path) = BROKER_PATH.lock().unwrap().as_ref() {
+            std::fs::remove_file(path).ok();
+        }
+        return;
+    }
+    let mut client = CLIENT.lock().await;
+    if let Some(mut client) = client.take() {
+        IS_CLIENT.store(false, Ordering::Release);
+        let unused = OPS_POOL.available_permits();
+        if unused > 0 {
+            client
+                .writer
+                .write_all(format!("release {}\n", unused).as_bytes())
+                .await
+                .ok();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutils;
+
+    #[test]
+    fn check_request_parsing() {
+        assert_eq!("ops 16".parse::<Request>().unwrap(), Request::Ops(16));
+        assert_eq!("tput 1024".parse::<Request>().unwrap(), Request::Tput(1024));
+        assert_eq!("release 3".parse::<Request>().unwrap(), Request::Release(3));
+        assert_eq!("stats".parse::<Request>().unwrap(), Request::Stats);
+        for request in ["", "ops", "ops x", "ops 1 2", "stats 1", "foo 1"] {
+            assert!(request.parse::<Request>().is_err(), "{:?}", request);
+        }
+    }
+
+    #[tokio::test]
+    async fn check_broker_grants_tokens() -> Result<()> {
+        let tmp_dir = testutils::create_temp_dir().await?;
+        let path = tmp_dir.join("broker.sock");
+        // a stale socket left behind by a broker that exited is replaced
+        drop(std::os::unix::net::UnixListener::bind(&path)?);
+        assert!(connect_or_serve(&path).await?.is_none());
+        assert!(stats().is_some());
+        let mut client = connect_or_serve(&path).await?.unwrap();
+        // no throttling is configured, everything we ask for is granted
+        assert_eq!(client.request("ops 16").await?, 16);
+        assert_eq!(client.request("tput 1000").await?, 1000);
+        client.writer.write_all(b"stats\n").await?;
+        let stats = client.lines.next_line().await?.unwrap();
+        assert!(stats.starts_with("clients=1 "), "{}", stats);
+        assert!(client.request("bogus").await.is_err());
+        Ok(())
+    }
+}
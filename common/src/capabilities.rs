@@ -0,0 +1,328 @@
+/// Optional feature of the tools, identified by the option enabling it
+#[derive(Debug)]
+pub struct Feature {
+    pub name: &'static str,
+    /// Command line option (without the leading dashes) enabling the feature
+    pub flag: &'static str,
+    pub tools: &'static [&'static str],
+}
+
+const ALL_TOOLS: &[&str] = &["rcp", "rrm", "rlink", "rcmp"];
+
+/// Registry of the features reported by --capabilities, add an entry here together with the option enabling it
+pub static FEATURES: &[Feature] = &[
+    Feature {
+        name: "overwrite",
+        flag: "overwrite",
+        tools: &["rcp", "rlink"],
+    },
+    Feature {
+        name: "overwrite-compare",
+        flag: "overwrite-compare",
+        tools: &["rcp", "rlink"],
+    },
+    Feature {
+        name: "update",
+        flag: "update",
+        tools: &["rcp"],
+    },
+    Feature {
+        name: "update-exclusive",
+        flag: "update-exclusive",
+        tools: &["rlink"],
+    },
+    Feature {
+        name: "update-checksum",
+        flag: "update-checksum",
+        tools: &["rlink"],
+    },
+    Feature {
+        name: "inplace",
+        flag: "inplace",
+        tools: &["rcp"],
+    },
+    Feature {
+        name: "atomic-writes",
+        flag: "atomic-writes",
+        tools: &["rcp"],
+    },
+    Feature {
+        name: "whole-dir-atomic",
+        flag: "whole-dir-atomic",
+        tools: &["rcp"],
+    },
+    Feature {
+        name: "backup",
+        flag: "backup",
+        tools: &["rcp"],
+    },
+    Feature {
+        name: "mkpath",
+        flag: "mkpath",
+        tools: &["rcp"],
+    },
+    Feature {
+        name: "metadata-only",
+        flag: "metadata-only",
+        tools: &["rcp"],
+    },
+    Feature {
+        name: "link-dest",
+        flag: "link-dest",
+        tools: &["rcp"],
+    },
+    Feature {
+        name: "compare-dest",
+        flag: "compare-dest",
+        tools: &["rcp"],
+    },
+    Feature {
+        name: "dedup",
+        flag: "dedup",
+        tools: &["rcp"],
+    },
+    Feature {
+        name: "reflink",
+        flag: "reflink",
+        tools: &["rcp"],
+    },
+    Feature {
+        name: "preallocate",
+        flag: "preallocate",
+        tools: &["rcp"],
+    },
+    Feature {
+        name: "preserve-flags",
+        flag: "preserve-flags",
+        tools: &["rcp"],
+    },
+    Feature {
+        name: "chmod",
+        flag: "chmod",
+        tools: &["rcp"],
+    },
+    Feature {
+        name: "chown",
+        flag: "chown",
+        tools: &["rcp"],
+    },
+    Feature {
+        name: "id-mapping",
+        flag: "usermap",
+        tools: &["rcp"],
+    },
+    Feature {
+        name: "dereference-root",
+        flag: "dereference-root",
+        tools: &["rcp"],
+    },
+    Feature {
+        name: "symlink-rewrite",
+        flag: "links",
+        tools: &["rcp"],
+    },
+    Feature {
+        name: "resume",
+        flag: "checkpoint",
+        tools: &["rcp"],
+    },
+    Feature {
+        name: "compare-cache",
+        flag: "compare-cache",
+        tools: &["rcp"],
+    },
+    Feature {
+        name: "itemize-changes",
+        flag: "itemize-changes",
+        tools: &["rcp"],
+    },
+    Feature {
+        name: "list",
+        flag: "list",
+        tools: &["rcp"],
+    },
+    Feature {
+        name: "skip-unreadable",
+        flag: "skip-unreadable",
+        tools: &["rcp"],
+    },
+    Feature {
+        name: "copy-special",
+        flag: "copy-special",
+        tools: &["rcp"],
+    },
+    Feature {
+        name: "copy-limits",
+        flag: "max-files",
+        tools: &["rcp"],
+    },
+    Feature {
+        name: "allow-recursive",
+        flag: "allow-recursive",
+        tools: &["rcp"],
+    },
+    Feature {
+        name: "exclude",
+        flag: "exclude",
+        tools: &["rcp"],
+    },
+    Feature {
+        name: "exclude-from",
+        flag: "exclude-from",
+        tools: &["rcp"],
+    },
+    Feature {
+        name: "gitignore",
+        flag: "filter",
+        tools: &["rcp"],
+    },
+    Feature {
+        name: "size-filter",
+        flag: "min-size",
+        tools: &["rcp", "rrm"],
+    },
+    Feature {
+        name: "type-filter",
+        flag: "type",
+        tools: &["rcp", "rrm"],
+    },
+    Feature {
+        name: "age-filter",
+        flag: "older-than",
+        tools: &["rcp", "rrm"],
+    },
+    Feature {
+        name: "name-filter",
+        flag: "matching",
+        tools: &["rrm"],
+    },
+    Feature {
+        name: "prune-empty-dirs",
+        flag: "prune-empty-dirs",
+        tools: &["rcp", "rrm"],
+    },
+    Feature {
+        name: "metadata-compare",
+        flag: "metadata-compare",
+        tools: &["rcmp"],
+    },
+    Feature {
+        name: "json-log",
+        flag: "log-format",
+        tools: &["rcmp"],
+    },
+    Feature {
+        name: "checksum-algo",
+        flag: "checksum-algo",
+        tools: &["rcp", "rlink", "rcmp"],
+    },
+    Feature {
+        name: "bwlimit",
+        flag: "bwlimit-schedule",
+        tools: &["rcp", "rlink"],
+    },
+    Feature {
+        name: "throttle-rules",
+        flag: "throttle-rule",
+        tools: ALL_TOOLS,
+    },
+    Feature {
+        name: "throttle-broker",
+        flag: "throttle-broker",
+        tools: ALL_TOOLS,
+    },
+    Feature {
+        name: "metrics",
+        flag: "metrics-addr",
+        tools: ALL_TOOLS,
+    },
+    Feature {
+        name: "timeout",
+        flag: "timeout",
+        tools: ALL_TOOLS,
+    },
+    Feature {
+        name: "background",
+        flag: "background",
+        tools: &["rcp"],
+    },
+    Feature {
+        name: "log-file",
+        flag: "log-file",
+        tools: ALL_TOOLS,
+    },
+];
+
+/// Attributes accepted by --preserve-settings, besides an octal mode mask
+pub const PRESERVE_ATTRIBUTES: &[&str] = &["uid", "gid", "time", "atime", "mtime", "btime"];
+
+/// Attributes accepted by --overwrite-compare and --metadata-compare
+pub const COMPARE_ATTRIBUTES: &[&str] = &[
+    "uid", "gid", "mode", "size", "mtime", "mtime_ns", "ctime", "hash",
+];
+
+/// Values accepted by --progress-type
+pub const PROGRESS_TYPES: &[&str] = &["auto", "ProgressBar", "TextUpdates"];
+
+/// Returns true if --capabilities was given, it's handled before parsing the rest of the command line so that the
+/// required arguments can be omitted
+pub fn capabilities_requested() -> bool {
+    std::env::args_os()
+        .skip(1)
+        .any(|arg| arg == "--capabilities")
+}
+
+/// Builds the --capabilities JSON document of the given tool
+pub fn capabilities(tool: &str, version: &str) -> serde_json::Value {
+    serde_json::json!({
+        "tool": tool,
+        "version": version,
+        "features": FEATURES
+            .iter()
+            .filter(|feature| feature.tools.contains(&tool))
+            .map(|feature| serde_json::json!({"name": feature.name, "flag": format!("--{}", feature.flag)}))
+            .collect::<Vec<_>>(),
+        "preserve_attributes": PRESERVE_ATTRIBUTES,
+        "compare_attributes": COMPARE_ATTRIBUTES,
+        "progress_types": PROGRESS_TYPES,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn check_capabilities() -> anyhow::Result<()> {
+        let mut names = std::collections::HashSet::new();
+        for feature in FEATURES {
+            assert!(names.insert(feature.name), "duplicate {:?}", feature);
+            assert!(!feature.tools.is_empty(), "{:?}", feature);
+            assert!(
+                feature.tools.iter().all(|tool| ALL_TOOLS.contains(tool)),
+                "{:?}",
+                feature
+            );
+        }
+        // the reported attributes are the ones the parsers accept
+        for attr in PRESERVE_ATTRIBUTES {
+            crate::parse_preserve_settings(&format!("f:{}", attr))?;
+        }
+        for attr in COMPARE_ATTRIBUTES {
+            crate::parse_metadata_cmp_settings(attr, Default::default())?;
+        }
+        for progress_type in PROGRESS_TYPES {
+            progress_type.parse::<crate::ProgressType>()?;
+        }
+        let doc = capabilities("rrm", "1.2.3");
+        assert_eq!(doc["tool"], "rrm");
+        assert_eq!(doc["version"], "1.2.3");
+        let features = doc["features"].as_array().unwrap();
+        assert!(features
+            .iter()
+            .any(|feature| feature["name"] == "name-filter" && feature["flag"] == "--matching"));
+        assert!(!features.iter().any(|feature| feature["name"] == "dedup"));
+        assert_eq!(doc["compare_attributes"].as_array().unwrap().len(), 8);
+        Ok(())
+    }
+}
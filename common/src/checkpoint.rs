@@ -0,0 +1,134 @@
+use anyhow::{Context, Result};
+use std::os::unix::ffi::OsStrExt;
+use tracing::{event, Level};
+
+use crate::statefile;
+
+const MAGIC: &[u8] = b"RCPCKPT";
+const VERSION: u8 = 1;
+
+/// (source path, destination path) of a completely copied source
+type Key = (std::path::PathBuf, std::path::PathBuf);
+
+/// Sources copied completely by this or a previous run of an interrupted copy, skipped when the copy is restarted.
+///
+/// Top-level sources and directories are recorded once all of their entries were copied without errors.
+#[derive(Default)]
+pub struct Checkpoint {
+    completed: std::sync::Mutex<std::collections::HashSet<Key>>,
+}
+
+impl std::fmt::Debug for Checkpoint {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "Checkpoint({} entries)",
+            self.completed.lock().unwrap().len()
+        )
+    }
+}
+
+impl Checkpoint {
+    /// Loads the checkpoint saved by the previous run, a missing file means nothing was completed yet
+    pub fn load(path: &std::path::Path) -> Result<Self> {
+        let data = match std::fs::read(path) {
+            Ok(data) => data,
+            Err(error) if error.kind() == std::io::ErrorKind::NotFound => {
+                return Ok(Default::default())
+            }
+            Err(error) => {
+                return Err(anyhow::Error::new(error)
+                    .context(format!("failed reading checkpoint {:?}", path)))
+            }
+        };
+        let completed = decode(&data).with_context(|| format!("invalid checkpoint {:?}", path))?;
+        event!(
+            Level::INFO,
+            "loaded {} completed entries from checkpoint {:?}",
+            completed.len(),
+            path
+        );
+        Ok(Checkpoint {
+            completed: std::sync::Mutex::new(completed),
+        })
+    }
+
+    /// Returns true if 'src' was already copied to 'dst' completely
+    pub fn is_completed(&self, src: &std::path::Path, dst: &std::path::Path) -> bool {
+        self.completed
+            .lock()
+            .unwrap()
+            .contains(&(src.to_owned(), dst.to_owned()))
+    }
+
+    /// Records that 'src' was copied to 'dst' completely
+    pub fn record(&self, src: &std::path::Path, dst: &std::path::Path) {
+        self.completed
+            .lock()
+            .unwrap()
+            .insert((src.to_owned(), dst.to_owned()));
+    }
+
+    /// Saves the completed entries of this and the previous runs, replacing the checkpoint file atomically
+    pub fn save(&self, path: &std::path::Path) -> Result<()> {
+        let data = encode(&self.completed.lock().unwrap());
+        statefile::write_atomically(path, &data)
+            .with_context(|| format!("failed writing checkpoint {:?}", path))
+    }
+}
+
+fn encode(entries: &std::collections::HashSet<Key>) -> Vec<u8> {
+    let mut data = statefile::header(MAGIC, VERSION);
+    data.extend_from_slice(&(entries.len() as u64).to_le_bytes());
+    for (src, dst) in entries {
+        statefile::put_bytes(&mut data, src.as_os_str().as_bytes());
+        statefile::put_bytes(&mut data, dst.as_os_str().as_bytes());
+    }
+    statefile::seal(data)
+}
+
+fn decode(data: &[u8]) -> Result<std::collections::HashSet<Key>> {
+    let mut decoder = statefile::Decoder {
+        data: statefile::unseal(MAGIC, VERSION, "checkpoint", data)?,
+    };
+    let count = decoder.u64()?;
+    let mut entries = std::collections::HashSet::new();
+    for _ in 0..count {
+        entries.insert((decoder.path()?, decoder.path()?));
+    }
+    if !decoder.data.is_empty() {
+        return Err(anyhow::anyhow!("unexpected data after the last entry"));
+    }
+    Ok(entries)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutils;
+
+    #[tokio::test]
+    async fn check_save_and_load() -> Result<()> {
+        let tmp_dir = testutils::create_temp_dir().await?;
+        let path = tmp_dir.join("checkpoint");
+        let checkpoint = Checkpoint::load(&path)?;
+        assert!(!checkpoint.is_completed(&tmp_dir.join("a"), &tmp_dir.join("b")));
+        checkpoint.record(&tmp_dir.join("a"), &tmp_dir.join("b"));
+        checkpoint.save(&path)?;
+        let checkpoint = Checkpoint::load(&path)?;
+        assert!(checkpoint.is_completed(&tmp_dir.join("a"), &tmp_dir.join("b")));
+        assert!(!checkpoint.is_completed(&tmp_dir.join("b"), &tmp_dir.join("a")));
+        // entries of the previous runs are kept
+        checkpoint.record(&tmp_dir.join("c"), &tmp_dir.join("d"));
+        checkpoint.save(&path)?;
+        let checkpoint = Checkpoint::load(&path)?;
+        assert!(checkpoint.is_completed(&tmp_dir.join("a"), &tmp_dir.join("b")));
+        assert!(checkpoint.is_completed(&tmp_dir.join("c"), &tmp_dir.join("d")));
+        let mut data = std::fs::read(&path)?;
+        let last = data.len() - 1;
+        data[last] ^= 1;
+        std::fs::write(&path, data)?;
+        assert!(Checkpoint::load(&path).is_err());
+        Ok(())
+    }
+}
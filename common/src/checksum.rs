@@ -0,0 +1,155 @@
+use anyhow::anyhow;
+use sha2::Digest;
+use std::io::Read;
+
+/// Algorithm used wherever file contents are hashed
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Hash)]
+pub enum ChecksumAlgo {
+    /// Fast, non-cryptographic
+    Xxh3,
+    /// Fast, cryptographic
+    #[default]
+    Blake3,
+    /// Slower, for compliance requirements
+    Sha256,
+}
+
+impl std::fmt::Display for ChecksumAlgo {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            ChecksumAlgo::Xxh3 => write!(f, "xxh3"),
+            ChecksumAlgo::Blake3 => write!(f, "blake3"),
+            ChecksumAlgo::Sha256 => write!(f, "sha256"),
+        }
+    }
+}
+
+impl std::str::FromStr for ChecksumAlgo {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "xxh3" => Ok(ChecksumAlgo::Xxh3),
+            "blake3" => Ok(ChecksumAlgo::Blake3),
+            "sha256" => Ok(ChecksumAlgo::Sha256),
+            _ => Err(anyhow!(
+                "Invalid checksum algorithm: {}, expected one of: xxh3, blake3, sha256",
+                s
+            )),
+        }
+    }
+}
+
+/// Checksum of some data, checksums computed using different algorithms are never equal
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct Checksum {
+    pub algo: ChecksumAlgo,
+    pub bytes: Vec<u8>,
+}
+
+impl std::fmt::Display for Checksum {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        for byte in &self.bytes {
+            write!(f, "{:02x}", byte)?;
+        }
+        Ok(())
+    }
+}
+
+/// Streaming hasher for any of the supported algorithms
+pub enum Hasher {
+    Xxh3(Box<xxhash_rust::xxh3::Xxh3>),
+    Blake3(Box<blake3::Hasher>),
+    Sha256(sha2::Sha256),
+}
+
+impl Hasher {
+    pub fn new(algo: ChecksumAlgo) -> Self {
+        match algo {
+            ChecksumAlgo::Xxh3 => Hasher::Xxh3(Box::default()),
+            ChecksumAlgo::Blake3 => Hasher::Blake3(Box::default()),
+            ChecksumAlgo::Sha256 => Hasher::Sha256(sha2::Sha256::new()),
+        }
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        match self {
+            Hasher::Xxh3(hasher) => hasher.update(data),
+            Hasher::Blake3(hasher) => {
+                hasher.update(data);
+            }
+            Hasher::Sha256(hasher) => hasher.update(data),
+        }
+    }
+
+    pub fn finalize(self) -> Checksum {
+        match self {
+            Hasher::Xxh3(hasher) => Checksum {
+                algo: ChecksumAlgo::Xxh3,
+                bytes: hasher.digest128().to_be_bytes().to_vec(),
+            },
+            Hasher::Blake3(hasher) => Checksum {
+                algo: ChecksumAlgo::Blake3,
+                bytes: hasher.finalize().as_bytes().to_vec(),
+            },
+            Hasher::Sha256(hasher) => Checksum {
+                algo: ChecksumAlgo::Sha256,
+                bytes: hasher.finalize().to_vec(),
+            },
+        }
+    }
+}
+
+/// Hashes everything read from the reader
+pub fn hash_reader(algo: ChecksumAlgo, mut reader: impl Read) -> std::io::Result<Checksum> {
+    let mut hasher = Hasher::new(algo);
+    let mut buf = vec![0u8; 1 << 20];
+    loop {
+        match reader.read(&mut buf) {
+            Ok(0) => return Ok(hasher.finalize()),
+            Ok(n) => hasher.update(&buf[..n]),
+            Err(error) if error.kind() == std::io::ErrorKind::Interrupted => continue,
+            Err(error) => return Err(error),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn check_known_checksums() {
+        let checksum = |algo| hash_reader(algo, &b"abc"[..]).unwrap().to_string();
+        assert_eq!(
+            checksum(ChecksumAlgo::Sha256),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+        assert_eq!(
+            checksum(ChecksumAlgo::Blake3),
+            "6437b3ac38465133ffb63b75273a8db548c558465d79db03fd359c6cd5bd9d85"
+        );
+        assert_eq!(checksum(ChecksumAlgo::Xxh3).len(), 32);
+    }
+
+    #[test]
+    fn check_streaming_matches_one_shot() {
+        let data: Vec<u8> = (0..3 * 1024 * 1024 + 7).map(|i| (i % 251) as u8).collect();
+        for algo in [
+            ChecksumAlgo::Xxh3,
+            ChecksumAlgo::Blake3,
+            ChecksumAlgo::Sha256,
+        ] {
+            let mut hasher = Hasher::new(algo);
+            for chunk in data.chunks(4096) {
+                hasher.update(chunk);
+            }
+            assert_eq!(hasher.finalize(), hash_reader(algo, &data[..]).unwrap());
+            assert_eq!(algo.to_string().parse::<ChecksumAlgo>().unwrap(), algo);
+        }
+        assert_ne!(
+            hash_reader(ChecksumAlgo::Xxh3, &data[..]).unwrap(),
+            hash_reader(ChecksumAlgo::Blake3, &data[..]).unwrap()
+        );
+        assert!("md5".parse::<ChecksumAlgo>().is_err());
+    }
+}
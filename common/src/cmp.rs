@@ -17,11 +17,13 @@ pub enum CmpResult {
     DstMissing, // same as above but flipped
 }
 
-#[derive(Copy, Clone, Debug, Enum)]
+#[derive(Copy, Clone, Debug, Enum, PartialEq, Eq)]
 pub enum ObjType {
     File,
     Dir,
     Symlink,
+    /// Special files: sockets, fifos and devices
+    Other,
 }
 
 pub type ObjCmpSettings = EnumMap<ObjType, filecmp::MetadataCmpSettings>;
@@ -35,9 +37,13 @@ pub struct CmpSettings {
 
 pub type Mismatch = EnumMap<ObjType, EnumMap<CmpResult, u64>>;
 
+/// Number of objects (attributed by the source type) that differed on each of the metadata attributes
+pub type AttrMismatch = EnumMap<ObjType, EnumMap<filecmp::MetadataAttr, u64>>;
+
 #[derive(Default)]
 pub struct CmpSummary {
     pub mismatch: Mismatch,
+    pub attr_mismatch: AttrMismatch,
 }
 
 impl std::ops::Add for CmpSummary {
@@ -49,7 +55,16 @@ impl std::ops::Add for CmpSummary {
                 mismatch[obj_type][cmp_res] += count;
             }
         }
-        Self { mismatch }
+        let mut attr_mismatch = self.attr_mismatch;
+        for (obj_type, &attr_map) in &other.attr_mismatch {
+            for (attr, &count) in &attr_map {
+                attr_mismatch[obj_type][attr] += count;
+            }
+        }
+        Self {
+            mismatch,
+            attr_mismatch,
+        }
     }
 }
 
@@ -60,17 +75,69 @@ impl std::fmt::Display for CmpSummary {
                 writeln!(f, "{:?} {:?}: {}", obj_type, cmp_res, count)?;
             }
         }
+        for (obj_type, &attr_map) in &self.attr_mismatch {
+            for (attr, &count) in &attr_map {
+                if count > 0 {
+                    writeln!(f, "{:?} {} mismatch: {}", obj_type, attr, count)?;
+                }
+            }
+        }
         Ok(())
     }
 }
 
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum LogFormat {
+    /// Human readable, multi-line records
+    #[default]
+    Text,
+    /// One record per line: path,type,result,attributes (attributes separated with ';')
+    Csv,
+    /// One JSON object per line with "path", "type", "result" and "attributes" keys
+    JsonLines,
+}
+
+impl std::fmt::Display for LogFormat {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            LogFormat::Text => write!(f, "text"),
+            LogFormat::Csv => write!(f, "csv"),
+            LogFormat::JsonLines => write!(f, "json-lines"),
+        }
+    }
+}
+
+impl std::str::FromStr for LogFormat {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(LogFormat::Text),
+            "csv" => Ok(LogFormat::Csv),
+            "json-lines" => Ok(LogFormat::JsonLines),
+            _ => Err(anyhow!(
+                "Invalid log format: {}, expected one of: text, csv, json-lines",
+                s
+            )),
+        }
+    }
+}
+
+fn csv_field(field: &str) -> String {
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct LogWriter {
     log_opt: Option<std::sync::Arc<tokio::sync::Mutex<tokio::io::BufWriter<tokio::fs::File>>>>,
+    format: LogFormat,
 }
 
 impl LogWriter {
-    pub async fn new(log_path_opt: Option<&std::path::Path>) -> Result<Self> {
+    pub async fn new(log_path_opt: Option<&std::path::Path>, format: LogFormat) -> Result<Self> {
         if let Some(log_path) = log_path_opt {
             let log_file = tokio::fs::OpenOptions::new()
                 .write(true)
@@ -80,12 +147,25 @@ impl LogWriter {
                 .with_context(|| format!("Failed to open log file: {:?}", log_path))?;
             let log =
                 std::sync::Arc::new(tokio::sync::Mutex::new(tokio::io::BufWriter::new(log_file)));
-            Ok(Self { log_opt: Some(log) })
+            let log_writer = Self {
+                log_opt: Some(log),
+                format,
+            };
+            if format == LogFormat::Csv {
+                log_writer.write("path,type,result,attributes\n").await?;
+            }
+            Ok(log_writer)
         } else {
-            Ok(Self { log_opt: None })
+            Ok(Self {
+                log_opt: None,
+                format,
+            })
         }
     }
 
+    /// Logs a mismatch, "rel_path" is the path relative to the compared roots and "attributes" lists the metadata
+    /// attributes that differed (empty unless the result is "Different")
+    #[allow(clippy::too_many_arguments)]
     pub async fn log_mismatch(
         &self,
         cmp_result: CmpResult,
@@ -93,12 +173,43 @@ impl LogWriter {
         src: &std::path::Path,
         dst_obj_type: Option<ObjType>,
         dst: &std::path::Path,
+        rel_path: &std::path::Path,
+        attributes: &[filecmp::MetadataAttr],
     ) -> Result<()> {
-        self.write(&format!(
-            "[{:?}]\n\t[{:?}]\t{:?}\n\t[{:?}]\t{:?}\n",
-            cmp_result, src_obj_type, src, dst_obj_type, dst
-        ))
-        .await
+        if self.log_opt.is_none() {
+            return Ok(());
+        }
+        let rel_path = if rel_path.as_os_str().is_empty() {
+            ".".into()
+        } else {
+            rel_path.to_string_lossy()
+        };
+        // objects missing in src are attributed to the dst type, same as in the summary
+        let obj_type = src_obj_type.or(dst_obj_type).unwrap();
+        let attributes: Vec<String> = attributes.iter().map(|attr| attr.to_string()).collect();
+        let msg = match self.format {
+            LogFormat::Text => format!(
+                "[{:?}]\n\t[{:?}]\t{:?}\n\t[{:?}]\t{:?}\n",
+                cmp_result, src_obj_type, src, dst_obj_type, dst
+            ),
+            LogFormat::Csv => format!(
+                "{},{:?},{:?},{}\n",
+                csv_field(&rel_path),
+                obj_type,
+                cmp_result,
+                csv_field(&attributes.join(";"))
+            ),
+            LogFormat::JsonLines => format!(
+                "{}\n",
+                serde_json::json!({
+                    "path": rel_path,
+                    "type": format!("{:?}", obj_type),
+                    "result": format!("{:?}", cmp_result),
+                    "attributes": attributes,
+                })
+            ),
+        };
+        self.write(&msg).await
     }
 
     async fn write(&self, msg: &str) -> Result<()> {
@@ -120,15 +231,23 @@ impl LogWriter {
     }
 }
 
-fn obj_type(metadata: &std::fs::Metadata) -> ObjType {
-    if metadata.is_file() {
+/// Error of a directory whose entries failed to compare, the failures are logged and counted where they happened
+#[derive(Debug, thiserror::Error)]
+#[error("cmp: {src:?} vs {dst:?} failed!")]
+struct SubtreeFailed {
+    src: std::path::PathBuf,
+    dst: std::path::PathBuf,
+}
+
+fn obj_type(file_type: std::fs::FileType) -> ObjType {
+    if file_type.is_file() {
         ObjType::File
-    } else if metadata.is_dir() {
+    } else if file_type.is_dir() {
         ObjType::Dir
-    } else if metadata.is_symlink() {
+    } else if file_type.is_symlink() {
         ObjType::Symlink
     } else {
-        unreachable!("Unknown object type! {:?}", &metadata);
+        ObjType::Other
     }
 }
 
@@ -136,50 +255,81 @@ fn obj_type(metadata: &std::fs::Metadata) -> ObjType {
 #[async_recursion]
 pub async fn cmp(
     prog_track: &'static progress::Progress,
+    src_root: &std::path::Path,
     src: &std::path::Path,
     dst: &std::path::Path,
     log: &LogWriter,
     settings: &CmpSettings,
 ) -> Result<CmpSummary> {
-    throttle::get_token().await;
+    throttle::get_token_for(&[src, dst]).await;
     let _prog_guard = prog_track.ops.guard();
-    event!(Level::DEBUG, "reading source metadata");
+    event!(Level::DEBUG, "reading source and destination metadata");
+    let (src_metadata, dst_metadata) = tokio::join!(
+        tokio::fs::symlink_metadata(src),
+        tokio::fs::symlink_metadata(dst)
+    );
     // it is impossible for src not exist other than user passing invalid path (which is an error)
-    let src_metadata = tokio::fs::symlink_metadata(src)
-        .await
-        .with_context(|| format!("failed reading metadata from {:?}", &src))?;
+    let src_metadata =
+        src_metadata.with_context(|| format!("failed reading metadata from {:?}", &src))?;
     let mut cmp_summary = CmpSummary::default();
-    let src_obj_type = obj_type(&src_metadata);
+    let src_obj_type = obj_type(src_metadata.file_type());
+    let rel_path = src.strip_prefix(src_root).unwrap_or(src);
     let dst_metadata = {
-        match tokio::fs::symlink_metadata(dst).await {
+        match dst_metadata {
             Ok(metadata) => metadata,
             Err(err) => {
                 if err.kind() == std::io::ErrorKind::NotFound {
+                    // the contents of a missing directory are neither compared nor counted
                     cmp_summary.mismatch[src_obj_type][CmpResult::DstMissing] += 1;
-                    log.log_mismatch(CmpResult::DstMissing, Some(src_obj_type), src, None, dst)
-                        .await?;
+                    prog_track.entries_mismatched.inc();
+                    log.log_mismatch(
+                        CmpResult::DstMissing,
+                        Some(src_obj_type),
+                        src,
+                        None,
+                        dst,
+                        rel_path,
+                        &[],
+                    )
+                    .await?;
                     return Ok(cmp_summary);
                 }
                 return Err(err).context(format!("failed reading metadata from {:?}", &dst));
             }
         }
     };
-    if !is_file_type_same(&src_metadata, &dst_metadata)
-        || !filecmp::metadata_equal(
-            &settings.compare[src_obj_type],
-            &src_metadata,
-            &dst_metadata,
-        )
-    {
+    let mut attributes = vec![];
+    if !is_file_type_same(&src_metadata, &dst_metadata) {
+        attributes.push(filecmp::MetadataAttr::Type);
+    }
+    attributes.extend(filecmp::metadata_diff(
+        &settings.compare[src_obj_type],
+        &src_metadata,
+        &dst_metadata,
+    ));
+    if attributes.is_empty() && settings.compare[src_obj_type].hash && src_metadata.is_file() {
+        // contents are compared only if all the cheaper checks passed
+        let _open_file_guard = throttle::open_file_permit().await;
+        if !filecmp::contents_equal(src, dst, settings.compare[src_obj_type].checksum_algo).await? {
+            attributes.push(filecmp::MetadataAttr::Hash);
+        }
+    }
+    if !attributes.is_empty() {
         // we use the src type for the summary attribution
         cmp_summary.mismatch[src_obj_type][CmpResult::Different] += 1;
-        let dst_obj_type = obj_type(&dst_metadata);
+        prog_track.entries_mismatched.inc();
+        for &attr in &attributes {
+            cmp_summary.attr_mismatch[src_obj_type][attr] += 1;
+        }
+        let dst_obj_type = obj_type(dst_metadata.file_type());
         log.log_mismatch(
             CmpResult::Different,
             Some(src_obj_type),
             src,
             Some(dst_obj_type),
             dst,
+            rel_path,
+            &attributes,
         )
         .await?;
         if settings.exit_early {
@@ -212,8 +362,18 @@ pub async fn cmp(
         let dst_path = dst.join(entry_name);
         let log = log.clone();
         let settings = *settings;
-        let do_cmp =
-            || async move { cmp(prog_track, &entry_path, &dst_path, &log, &settings).await };
+        let src_root = src_root.to_owned();
+        let do_cmp = || async move {
+            cmp(
+                prog_track,
+                &src_root,
+                &entry_path,
+                &dst_path,
+                &log,
+                &settings,
+            )
+            .await
+        };
         join_set.spawn(do_cmp());
     }
     event!(Level::DEBUG, "process contents of 'dst' directory");
@@ -234,17 +394,23 @@ pub async fn cmp(
         }
         event!(Level::DEBUG, "found a new entry in the 'dst' directory");
         let dst_path = dst.join(entry_name);
-        let dst_entry_metadata = tokio::fs::symlink_metadata(&dst_path)
+        // the type is usually known from the directory listing, no need to stat the entry
+        let dst_file_type = dst_entry
+            .file_type()
             .await
             .with_context(|| format!("failed reading metadata from {:?}", &dst_path))?;
-        let dst_obj_type = obj_type(&dst_entry_metadata);
+        let dst_obj_type = obj_type(dst_file_type);
+        // the contents of a directory missing in src are neither compared nor counted
         cmp_summary.mismatch[dst_obj_type][CmpResult::SrcMissing] += 1;
+        prog_track.entries_mismatched.inc();
         log.log_mismatch(
             CmpResult::SrcMissing,
             None,
             &src.join(entry_name),
             Some(dst_obj_type),
             &dst_path,
+            &rel_path.join(entry_name),
+            &[],
         )
         .await?;
     }
@@ -262,12 +428,19 @@ pub async fn cmp(
                 if settings.fail_early {
                     return Err(error);
                 }
+                if !error.is::<SubtreeFailed>() {
+                    prog_track.entries_failed.inc();
+                }
                 success = false;
             }
         }
     }
     if !success {
-        return Err(anyhow!("cmp: {:?} vs {:?} failed!", src, dst));
+        return Err(SubtreeFailed {
+            src: src.to_owned(),
+            dst: dst.to_owned(),
+        }
+        .into());
     }
     Ok(cmp_summary)
 }
@@ -293,7 +466,7 @@ mod cmp_tests {
         let test_path = tmp_dir.as_path();
         copy::copy(
             &PROGRESS,
-            &test_path,
+            test_path,
             &test_path.join("foo"),
             &test_path.join("bar"),
             &copy::CopySettings {
@@ -305,6 +478,7 @@ mod cmp_tests {
                     mtime: true,
                     ..Default::default()
                 },
+                ..Default::default()
             },
             if preserve {
                 &DO_PRESERVE_SETTINGS
@@ -323,9 +497,7 @@ mod cmp_tests {
         Ok(())
     }
 
-    #[tokio::test]
-    #[traced_test]
-    async fn check_basic_cmp() -> Result<()> {
+    async fn setup_mismatched_dirs() -> Result<std::path::PathBuf> {
         let tmp_dir = setup_test_dirs(true).await?;
         // drop 1 file from src
         tokio::fs::remove_file(&tmp_dir.join("foo").join("bar").join("1.txt")).await?;
@@ -333,7 +505,7 @@ mod cmp_tests {
         tokio::time::sleep(std::time::Duration::from_millis(1000)).await;
         // modify 1 file in dst
         truncate_file(
-            &tmp_dir
+            tmp_dir
                 .join("bar")
                 .join("baz")
                 .join("4.txt")
@@ -345,7 +517,11 @@ mod cmp_tests {
         tokio::fs::remove_file(&tmp_dir.join("bar").join("bar").join("2.txt")).await?;
         // create one more file in dst -- this will also modify the mtime of the directory
         tokio::fs::File::create(&tmp_dir.join("bar").join("baz").join("7.txt")).await?;
-        let compare_settings = CmpSettings {
+        Ok(tmp_dir)
+    }
+
+    fn compare_settings() -> CmpSettings {
+        CmpSettings {
             fail_early: false,
             exit_early: false,
             compare: enum_map! {
@@ -362,16 +538,34 @@ mod cmp_tests {
                     mtime: true,
                     ..Default::default()
                 },
+                ObjType::Other => Default::default(),
             },
-        };
+        }
+    }
+
+    async fn cmp_mismatched_dirs(
+        tmp_dir: &std::path::Path,
+        log_format: LogFormat,
+    ) -> Result<CmpSummary> {
+        let log = LogWriter::new(Some(tmp_dir.join("cmp.log").as_path()), log_format).await?;
         let summary = cmp(
             &PROGRESS,
             &tmp_dir.join("foo"),
+            &tmp_dir.join("foo"),
             &tmp_dir.join("bar"),
-            &LogWriter::new(Some(&tmp_dir.join("cmp.log").as_path())).await?,
-            &compare_settings,
+            &log,
+            &compare_settings(),
         )
         .await?;
+        log.flush().await?;
+        Ok(summary)
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn check_basic_cmp() -> Result<()> {
+        let tmp_dir = setup_mismatched_dirs().await?;
+        let summary = cmp_mismatched_dirs(&tmp_dir, LogFormat::Text).await?;
         let mismatch: Mismatch = enum_map! {
             ObjType::File => enum_map! {
                 CmpResult::Different => 1,
@@ -391,8 +585,138 @@ mod cmp_tests {
                 CmpResult::SrcMissing => 0,
                 CmpResult::DstMissing => 0,
             },
+            ObjType::Other => Default::default(),
         };
         assert_eq!(summary.mismatch, mismatch);
+        assert_eq!(
+            summary.attr_mismatch[ObjType::File][filecmp::MetadataAttr::Size],
+            1
+        );
+        assert_eq!(
+            summary.attr_mismatch[ObjType::File][filecmp::MetadataAttr::Mtime],
+            1
+        );
+        assert_eq!(
+            summary.attr_mismatch[ObjType::Dir][filecmp::MetadataAttr::Mtime],
+            2
+        );
+        assert_eq!(
+            summary.attr_mismatch[ObjType::Dir][filecmp::MetadataAttr::Size],
+            0
+        );
         Ok(())
     }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn check_cmp_missing_dirs_and_special_files() -> Result<()> {
+        let tmp_dir = setup_test_dirs(true).await?;
+        tokio::fs::remove_dir_all(tmp_dir.join("bar").join("baz")).await?;
+        tokio::fs::create_dir_all(tmp_dir.join("bar").join("extra").join("sub")).await?;
+        tokio::fs::write(tmp_dir.join("bar").join("extra").join("1.txt"), "1").await?;
+        for dir in ["foo", "bar"] {
+            nix::unistd::mkfifo(
+                &tmp_dir.join(dir).join("pipe"),
+                nix::sys::stat::Mode::from_bits_truncate(0o600),
+            )?;
+        }
+        let log = LogWriter::new(None, LogFormat::Text).await?;
+        let summary = cmp(
+            &PROGRESS,
+            &tmp_dir.join("foo"),
+            &tmp_dir.join("foo"),
+            &tmp_dir.join("bar"),
+            &log,
+            &compare_settings(),
+        )
+        .await?;
+        // the missing directories are counted once, their contents are not
+        assert_eq!(summary.mismatch[ObjType::Dir][CmpResult::DstMissing], 1);
+        assert_eq!(summary.mismatch[ObjType::Dir][CmpResult::SrcMissing], 1);
+        assert_eq!(summary.mismatch[ObjType::File][CmpResult::DstMissing], 0);
+        assert_eq!(summary.mismatch[ObjType::File][CmpResult::SrcMissing], 0);
+        assert_eq!(summary.mismatch[ObjType::Other][CmpResult::Same], 1);
+        assert!(PROGRESS.entries_mismatched.get() >= 2);
+        Ok(())
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn check_json_lines_log() -> Result<()> {
+        let tmp_dir = setup_mismatched_dirs().await?;
+        cmp_mismatched_dirs(&tmp_dir, LogFormat::JsonLines).await?;
+        let log = tokio::fs::read_to_string(tmp_dir.join("cmp.log")).await?;
+        let mut records = std::collections::BTreeMap::new();
+        for line in log.lines() {
+            let record: serde_json::Value = serde_json::from_str(line)?;
+            let record = record.as_object().unwrap();
+            let mut keys: Vec<&str> = record.keys().map(|key| key.as_str()).collect();
+            keys.sort();
+            assert_eq!(keys, vec!["attributes", "path", "result", "type"]);
+            records.insert(
+                record["path"].as_str().unwrap().to_string(),
+                (
+                    record["type"].as_str().unwrap().to_string(),
+                    record["result"].as_str().unwrap().to_string(),
+                    record["attributes"]
+                        .as_array()
+                        .unwrap()
+                        .iter()
+                        .map(|attr| attr.as_str().unwrap().to_string())
+                        .collect::<Vec<_>>(),
+                ),
+            );
+        }
+        let record = |obj_type: &str, result: &str, attributes: &[&str]| {
+            (
+                obj_type.to_string(),
+                result.to_string(),
+                attributes.iter().map(|attr| attr.to_string()).collect(),
+            )
+        };
+        let expected = std::collections::BTreeMap::from([
+            ("bar".to_string(), record("Dir", "Different", &["mtime"])),
+            ("bar/1.txt".to_string(), record("File", "SrcMissing", &[])),
+            ("bar/2.txt".to_string(), record("File", "DstMissing", &[])),
+            ("baz".to_string(), record("Dir", "Different", &["mtime"])),
+            (
+                "baz/4.txt".to_string(),
+                record("File", "Different", &["size", "mtime"]),
+            ),
+            ("baz/7.txt".to_string(), record("File", "SrcMissing", &[])),
+        ]);
+        assert_eq!(records, expected);
+        Ok(())
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn check_csv_log() -> Result<()> {
+        let tmp_dir = setup_mismatched_dirs().await?;
+        cmp_mismatched_dirs(&tmp_dir, LogFormat::Csv).await?;
+        let log = tokio::fs::read_to_string(tmp_dir.join("cmp.log")).await?;
+        let mut lines = log.lines();
+        assert_eq!(lines.next(), Some("path,type,result,attributes"));
+        let mut records: Vec<&str> = lines.collect();
+        records.sort();
+        assert_eq!(
+            records,
+            vec![
+                "bar,Dir,Different,mtime",
+                "bar/1.txt,File,SrcMissing,",
+                "bar/2.txt,File,DstMissing,",
+                "baz,Dir,Different,mtime",
+                "baz/4.txt,File,Different,size;mtime",
+                "baz/7.txt,File,SrcMissing,",
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn check_csv_field_quoting() {
+        assert_eq!(csv_field("a/b.txt"), "a/b.txt");
+        assert_eq!(csv_field("a,b"), "\"a,b\"");
+        assert_eq!(csv_field("a\"b"), "\"a\"\"b\"");
+    }
 }
@@ -0,0 +1,221 @@
+use anyhow::{anyhow, Context, Result};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::MetadataExt;
+use tracing::{event, Level};
+
+use crate::statefile::{self, Decoder};
+
+const MAGIC: &[u8] = b"RCPCMPC";
+const VERSION: u8 = 1;
+
+/// Source file attributes that change whenever the file is modified, renamed or replaced
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+struct FileStamp {
+    dev: u64,
+    ino: u64,
+    size: u64,
+    mtime: (i64, i64),
+    ctime: (i64, i64),
+}
+
+impl FileStamp {
+    fn new(metadata: &std::fs::Metadata) -> Self {
+        FileStamp {
+            dev: metadata.dev(),
+            ino: metadata.ino(),
+            size: metadata.len(),
+            mtime: (metadata.mtime(), metadata.mtime_nsec()),
+            ctime: (metadata.ctime(), metadata.ctime_nsec()),
+        }
+    }
+}
+
+/// (source path, destination path) of a copied file
+type Key = (std::path::PathBuf, std::path::PathBuf);
+
+/// Files copied (or found identical) by the previous run, used to skip reading the destination of source files that
+/// didn't change since.
+///
+/// The cache is per file rather than per directory: a file modified in place doesn't change the mtime of its
+/// directory, so every source file is still checked and only the destination side is skipped. The destination is
+/// assumed not to be modified between the runs.
+#[derive(Default)]
+pub struct CompareCache {
+    previous: std::collections::HashMap<Key, FileStamp>,
+    current: std::sync::Mutex<std::collections::HashMap<Key, FileStamp>>,
+    hits: std::sync::atomic::AtomicUsize,
+}
+
+impl std::fmt::Debug for CompareCache {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        // the entries are not printed, there may be millions of them
+        write!(f, "CompareCache({} entries)", self.previous.len())
+    }
+}
+
+impl CompareCache {
+    /// Loads the cache saved by the previous run, a missing file means an empty cache
+    pub fn load(path: &std::path::Path) -> Result<Self> {
+        let data = match std::fs::read(path) {
+            Ok(data) => data,
+            Err(error) if error.kind() == std::io::ErrorKind::NotFound => {
+                return Ok(Default::default())
+            }
+            Err(error) => {
+                return Err(anyhow::Error::new(error)
+                    .context(format!("failed reading compare cache {:?}", path)))
+            }
+        };
+        let previous =
+            decode(&data).with_context(|| format!("invalid compare cache {:?}", path))?;
+        event!(
+            Level::INFO,
+            "loaded {} entries from compare cache {:?}",
+            previous.len(),
+            path
+        );
+        Ok(CompareCache {
+            previous,
+            ..Default::default()
+        })
+    }
+
+    /// Returns true if the source file didn't change since it was copied to 'dst' by the previous run
+    pub fn is_unchanged(
+        &self,
+        src: &std::path::Path,
+        dst: &std::path::Path,
+        src_metadata: &std::fs::Metadata,
+    ) -> bool {
+        let unchanged = self
+            .previous
+            .get(&(src.to_owned(), dst.to_owned()))
+            .is_some_and(|stamp| *stamp == FileStamp::new(src_metadata));
+        if unchanged {
+            self.hits.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+        }
+        unchanged
+    }
+
+    /// Records that the destination is up to date with the source file, saved for the next run
+    pub fn record(
+        &self,
+        src: &std::path::Path,
+        dst: &std::path::Path,
+        src_metadata: &std::fs::Metadata,
+    ) {
+        self.current.lock().unwrap().insert(
+            (src.to_owned(), dst.to_owned()),
+            FileStamp::new(src_metadata),
+        );
+    }
+
+    /// Number of files skipped thanks to the cache
+    pub fn hits(&self) -> usize {
+        self.hits.load(std::sync::atomic::Ordering::Relaxed)
+    }
+
+    /// Saves the files recorded during this run, replacing the cache file atomically
+    pub fn save(&self, path: &std::path::Path) -> Result<()> {
+        let data = encode(&self.current.lock().unwrap());
+        statefile::write_atomically(path, &data)
+            .with_context(|| format!("failed writing compare cache {:?}", path))
+    }
+}
+
+fn encode(entries: &std::collections::HashMap<Key, FileStamp>) -> Vec<u8> {
+    let mut data = statefile::header(MAGIC, VERSION);
+    data.extend_from_slice(&(entries.len() as u64).to_le_bytes());
+    for ((src, dst), stamp) in entries {
+        statefile::put_bytes(&mut data, src.as_os_str().as_bytes());
+        statefile::put_bytes(&mut data, dst.as_os_str().as_bytes());
+        for value in [stamp.dev, stamp.ino, stamp.size] {
+            data.extend_from_slice(&value.to_le_bytes());
+        }
+        for value in [stamp.mtime.0, stamp.mtime.1, stamp.ctime.0, stamp.ctime.1] {
+            data.extend_from_slice(&value.to_le_bytes());
+        }
+    }
+    statefile::seal(data)
+}
+
+fn decode(data: &[u8]) -> Result<std::collections::HashMap<Key, FileStamp>> {
+    let mut decoder = Decoder {
+        data: statefile::unseal(MAGIC, VERSION, "compare cache", data)?,
+    };
+    let count = decoder.u64()?;
+    let mut entries = std::collections::HashMap::new();
+    for _ in 0..count {
+        let src = decoder.path()?;
+        let dst = decoder.path()?;
+        let stamp = FileStamp {
+            dev: decoder.u64()?,
+            ino: decoder.u64()?,
+            size: decoder.u64()?,
+            mtime: (decoder.i64()?, decoder.i64()?),
+            ctime: (decoder.i64()?, decoder.i64()?),
+        };
+        entries.insert((src, dst), stamp);
+    }
+    if !decoder.data.is_empty() {
+        return Err(anyhow!("unexpected data after the last entry"));
+    }
+    Ok(entries)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutils;
+
+    #[tokio::test]
+    async fn check_save_and_load() -> Result<()> {
+        let tmp_dir = testutils::create_temp_dir().await?;
+        let src = tmp_dir.join("src.txt");
+        let dst = tmp_dir.join("dst.txt");
+        let cache_path = tmp_dir.join("cache");
+        std::fs::write(&src, "data")?;
+        let cache = CompareCache::load(&cache_path)?;
+        assert!(!cache.is_unchanged(&src, &dst, &std::fs::metadata(&src)?));
+        cache.record(&src, &dst, &std::fs::metadata(&src)?);
+        cache.save(&cache_path)?;
+        // no temporary file is left behind
+        assert_eq!(std::fs::read_dir(&tmp_dir)?.count(), 2);
+        let cache = CompareCache::load(&cache_path)?;
+        assert!(cache.is_unchanged(&src, &dst, &std::fs::metadata(&src)?));
+        assert!(!cache.is_unchanged(&dst, &src, &std::fs::metadata(&src)?));
+        assert_eq!(cache.hits(), 1);
+        // modified in place, keeping the size
+        std::fs::write(&src, "DATA")?;
+        assert!(!cache.is_unchanged(&src, &dst, &std::fs::metadata(&src)?));
+        // entries not recorded during the run are dropped
+        cache.save(&cache_path)?;
+        assert!(CompareCache::load(&cache_path)?.previous.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn check_corrupted_cache() {
+        let mut entries = std::collections::HashMap::new();
+        entries.insert(
+            ("src".into(), "dst".into()),
+            FileStamp {
+                dev: 1,
+                ino: 2,
+                size: 3,
+                mtime: (4, 5),
+                ctime: (-6, 7),
+            },
+        );
+        let data = encode(&entries);
+        assert_eq!(decode(&data).unwrap(), entries);
+        assert!(decode(&data[..data.len() - 1]).is_err());
+        let mut corrupted = data.clone();
+        corrupted[MAGIC.len() + 12] ^= 1;
+        assert!(decode(&corrupted).is_err());
+        let mut other_version = data.clone();
+        other_version[MAGIC.len()] = VERSION + 1;
+        assert!(decode(&other_version).is_err());
+        assert!(decode(b"").is_err());
+    }
+}
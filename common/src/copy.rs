@@ -1,11 +1,21 @@
 use anyhow::{anyhow, Context};
 use async_recursion::async_recursion;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::MetadataExt;
 use tracing::{event, instrument, Level};
 
+use crate::checkpoint;
+use crate::checksum;
+use crate::cmp::ObjType;
+use crate::comparecache;
+use crate::datacopy;
 use crate::filecmp;
+use crate::filter;
+use crate::itemize::{self, ItemAction};
 use crate::preserve;
 use crate::progress;
 use crate::rm;
+use crate::statedir;
 use crate::throttle;
 use crate::RmSettings;
 use crate::RmSummary;
@@ -24,506 +34,3029 @@ impl CopyError {
     }
 }
 
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug, Clone, Default)]
 pub struct CopySettings {
     pub dereference: bool,
+    /// Follow the source if it's a symlink, symlinks inside the copied tree are preserved (unless `dereference`)
+    pub dereference_root: bool,
     pub fail_early: bool,
     pub overwrite: bool,
     pub overwrite_compare: filecmp::MetadataCmpSettings,
+    pub reflink: datacopy::Reflink,
+    /// When overwriting a file, write into the existing destination file instead of removing it first
+    pub inplace: bool,
+    /// Only entries matching the filters are copied, directories are created as needed
+    pub filter: filter::FilterSettings,
+    /// Remove directories we created that ended up empty because all their entries were filtered out
+    pub prune_empty_dirs: bool,
+    /// Skip files whose destination is at least as new as the source (by mtime)
+    pub update_only: bool,
+    /// Skip source directories that are destination directories of this copy, used when the destination is inside the
+    /// source (see [`is_dst_inside_src`])
+    pub exclude_dst_dirs: bool,
+    /// Write files to a temporary name and rename them into place once complete
+    pub atomic_writes: bool,
+    /// Only re-apply the metadata of destination entries that are already up to date, data is never overwritten
+    pub metadata_only: Option<MetadataOnly>,
+    /// Path in the reference directory corresponding to the entry being copied, files identical to the reference
+    /// file (see `overwrite_compare`) are hard-linked from it instead of copied
+    pub link_dest: Option<std::path::PathBuf>,
+    /// Path in the reference directory corresponding to the entry being copied, files identical to the reference
+    /// file (see `overwrite_compare`) are skipped, leaving the destination without them
+    pub compare_dest: Option<std::path::PathBuf>,
+    /// Hard-link copied files with identical contents (by size and `overwrite_compare.checksum_algo` hash) to the
+    /// first copy instead of copying them again
+    pub dedup: bool,
+    /// Files copied by the previous run, destination files of unchanged source files are not compared when overwriting
+    pub compare_cache: Option<std::sync::Arc<comparecache::CompareCache>>,
+    /// Rewrite absolute symlink targets pointing inside the source tree, None copies them verbatim
+    pub symlink_rewrite: Option<SymlinkRewrite>,
+    /// Source entries that cannot be read (EACCES) are skipped and counted instead of failing the copy
+    pub skip_unreadable: bool,
+    /// Always overwrite existing destination files, even if they are identical by the `overwrite_compare` criteria
+    pub ignore_times: bool,
+    /// Directories copied completely by a previous run are skipped, new ones are recorded
+    pub checkpoint: Option<std::sync::Arc<checkpoint::Checkpoint>>,
+    /// Receives a change for every destination entry that was created, overwritten, updated or left unchanged
+    pub itemize: Option<itemize::ItemizeSender>,
+    /// Allocate the space of destination files before copying their data
+    pub preallocate: datacopy::Preallocate,
+    /// Keep the overwritten destination entries under a backup name instead of removing them
+    pub backup: Option<Backup>,
+    /// Recreate special files (sockets, fifos and devices) instead of skipping them
+    pub copy_special: bool,
+    /// Limits of the number of files and bytes copied, the copy is aborted once copying a file would exceed them
+    pub limits: Option<std::sync::Arc<CopyLimits>>,
+    /// State directory of the destination, it's not copied when found in the source
+    pub state_dir: Option<statedir::StateDir>,
+    /// State shared by the copies of one invocation, a new copy should start with a fresh one
+    pub copy_state: std::sync::Arc<CopyState>,
 }
 
-#[instrument]
-pub fn is_file_type_same(md1: &std::fs::Metadata, md2: &std::fs::Metadata) -> bool {
-    let ft1 = md1.file_type();
-    let ft2 = md2.file_type();
-    ft1.is_dir() == ft2.is_dir()
-        && ft1.is_file() == ft2.is_file()
-        && ft1.is_symlink() == ft2.is_symlink()
+/// Limits of the total number of files and bytes copied (by all the copies sharing them), a guardrail against copying
+/// far more than intended
+#[derive(Debug, Default)]
+pub struct CopyLimits {
+    max_files: Option<u64>,
+    max_bytes: Option<u64>,
+    files: std::sync::atomic::AtomicU64,
+    bytes: std::sync::atomic::AtomicU64,
+    /// The first limit reached, no new entries are copied afterwards
+    exceeded: std::sync::Mutex<Option<LimitExceeded>>,
 }
 
-#[instrument(skip(prog_track))]
-pub async fn copy_file(
-    prog_track: &'static progress::Progress,
-    src: &std::path::Path,
-    dst: &std::path::Path,
-    settings: &CopySettings,
-    preserve: &preserve::PreserveSettings,
-    is_fresh: bool,
-) -> Result<CopySummary, CopyError> {
-    let _open_file_guard = throttle::open_file_permit().await;
-    event!(
-        Level::DEBUG,
-        "opening 'src' for reading and 'dst' for writing"
-    );
-    let src_metadata = tokio::fs::symlink_metadata(src)
-        .await
-        .with_context(|| format!("failed reading metadata from {:?}", &src))
-        .map_err(|err| CopyError::new(err, Default::default()))?;
-    let mut rm_summary = RmSummary::default();
-    if !is_fresh && dst.exists() {
-        if settings.overwrite {
-            event!(Level::DEBUG, "file exists, check if it's identical");
-            let dst_metadata = tokio::fs::symlink_metadata(dst)
+#[derive(Clone, Debug, thiserror::Error)]
+pub enum LimitExceeded {
+    #[error(
+        "copying {path:?} would exceed the limit of {max} files (--max-files), aborting the copy"
+    )]
+    Files { path: std::path::PathBuf, max: u64 },
+    #[error(
+        "copying {path:?} would exceed the limit of {} (--max-bytes), aborting the copy",
+        progress::format_bytes(*.max)
+    )]
+    Bytes { path: std::path::PathBuf, max: u64 },
+}
+
+impl CopyLimits {
+    pub fn new(max_files: Option<u64>, max_bytes: Option<u64>) -> Self {
+        CopyLimits {
+            max_files,
+            max_bytes,
+            ..Default::default()
+        }
+    }
+
+    /// Fails once one of the limits was reached
+    fn check(&self) -> Result<(), LimitExceeded> {
+        match &*self.exceeded.lock().unwrap() {
+            Some(error) => Err(error.clone()),
+            None => Ok(()),
+        }
+    }
+
+    /// Accounts for a file about to be copied, fails if copying it would exceed one of the limits
+    fn reserve(&self, path: &std::path::Path, size: u64) -> Result<(), LimitExceeded> {
+        use std::sync::atomic::Ordering;
+        self.check()?;
+        let files = self.files.fetch_add(1, Ordering::Relaxed) + 1;
+        let bytes = self.bytes.fetch_add(size, Ordering::Relaxed) + size;
+        let error = match (self.max_files, self.max_bytes) {
+            (Some(max), _) if files > max => LimitExceeded::Files {
+                path: path.to_owned(),
+                max,
+            },
+            (_, Some(max)) if bytes > max => LimitExceeded::Bytes {
+                path: path.to_owned(),
+                max,
+            },
+            _ => return Ok(()),
+        };
+        self.files.fetch_sub(1, Ordering::Relaxed);
+        self.bytes.fetch_sub(size, Ordering::Relaxed);
+        // all the copies report the first limit reached
+        Err(self.exceeded.lock().unwrap().get_or_insert(error).clone())
+    }
+
+    /// Returns a reservation of a file that ended up not being copied, e.g. because it was hard-linked
+    fn release(&self, size: u64) {
+        use std::sync::atomic::Ordering;
+        self.files.fetch_sub(1, Ordering::Relaxed);
+        self.bytes.fetch_sub(size, Ordering::Relaxed);
+    }
+}
+
+/// Returns true if the copy failed because it reached one of the copy limits, see [`CopyLimits`]
+pub fn is_limit_exceeded(error: &anyhow::Error) -> bool {
+    error.downcast_ref::<LimitExceeded>().is_some()
+}
+
+/// Handling of entries missing in the destination when only updating metadata
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum MetadataOnly {
+    /// Missing entries are errors
+    Strict,
+    /// Missing entries are copied
+    Soft,
+}
+
+impl std::fmt::Display for MetadataOnly {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            MetadataOnly::Strict => write!(f, "strict"),
+            MetadataOnly::Soft => write!(f, "soft"),
+        }
+    }
+}
+
+impl std::str::FromStr for MetadataOnly {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "strict" => Ok(MetadataOnly::Strict),
+            "soft" => Ok(MetadataOnly::Soft),
+            _ => Err(anyhow!(
+                "Invalid metadata-only setting: {}, expected one of: strict, soft",
+                s
+            )),
+        }
+    }
+}
+
+/// Handling of absolute symlink targets pointing inside the copied tree
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum LinksMode {
+    /// Copy the targets verbatim
+    #[default]
+    Preserve,
+    /// Replace the targets with equivalent relative paths
+    RewriteRelative,
+    /// Replace the source root prefix of the targets with the destination root
+    RewritePrefix,
+}
+
+impl std::fmt::Display for LinksMode {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            LinksMode::Preserve => write!(f, "preserve"),
+            LinksMode::RewriteRelative => write!(f, "rewrite-relative"),
+            LinksMode::RewritePrefix => write!(f, "rewrite-prefix"),
+        }
+    }
+}
+
+impl std::str::FromStr for LinksMode {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "preserve" => Ok(LinksMode::Preserve),
+            "rewrite-relative" => Ok(LinksMode::RewriteRelative),
+            "rewrite-prefix" => Ok(LinksMode::RewritePrefix),
+            _ => Err(anyhow!(
+                "Invalid links setting: {}, expected one of: preserve, rewrite-relative, rewrite-prefix",
+                s
+            )),
+        }
+    }
+}
+
+/// How the backups of overwritten destination entries are named
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum BackupMode {
+    /// "<name><suffix>", replacing the previous backup
+    #[default]
+    Simple,
+    /// "<name>.~<n>~" with n one higher than the existing numbered backups
+    Numbered,
+}
+
+impl std::fmt::Display for BackupMode {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            BackupMode::Simple => write!(f, "simple"),
+            BackupMode::Numbered => write!(f, "numbered"),
+        }
+    }
+}
+
+impl std::str::FromStr for BackupMode {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "simple" => Ok(BackupMode::Simple),
+            "numbered" => Ok(BackupMode::Numbered),
+            _ => Err(anyhow!(
+                "Invalid backup setting: {}, expected one of: simple, numbered",
+                s
+            )),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Backup {
+    pub mode: BackupMode,
+    /// Suffix of the simple backups
+    pub suffix: String,
+}
+
+/// Returns the path the existing 'dst' is backed up to
+async fn backup_path(dst: &std::path::Path, backup: &Backup) -> anyhow::Result<std::path::PathBuf> {
+    let file_name = dst
+        .file_name()
+        .with_context(|| format!("destination {:?} does not have a basename", dst))?;
+    let mut backup_name = file_name.to_owned();
+    match backup.mode {
+        BackupMode::Simple => backup_name.push(&backup.suffix),
+        BackupMode::Numbered => {
+            let dir = dst.parent().unwrap_or_else(|| std::path::Path::new("."));
+            let mut prefix = file_name.to_owned();
+            prefix.push(".~");
+            let prefix = prefix.as_bytes();
+            let mut entries = tokio::fs::read_dir(dir)
                 .await
-                .with_context(|| format!("failed reading metadata from {:?}", &dst))
-                .map_err(|err| CopyError::new(err, Default::default()))?;
-            if is_file_type_same(&src_metadata, &dst_metadata)
-                && filecmp::metadata_equal(
-                    &settings.overwrite_compare,
-                    &src_metadata,
-                    &dst_metadata,
-                )
+                .with_context(|| format!("cannot open directory {:?} for reading", dir))?;
+            let mut last = 0;
+            while let Some(entry) = entries
+                .next_entry()
+                .await
+                .with_context(|| format!("failed traversing directory {:?}", dir))?
             {
-                event!(Level::DEBUG, "file is identical, skipping");
-                prog_track.files_unchanged.inc();
-                return Ok(CopySummary {
-                    files_unchanged: 1,
-                    ..Default::default()
-                });
+                let name = entry.file_name();
+                let number = name
+                    .as_bytes()
+                    .strip_prefix(prefix)
+                    .and_then(|rest| rest.strip_suffix(b"~"))
+                    .and_then(|number| std::str::from_utf8(number).ok())
+                    .and_then(|number| number.parse::<u64>().ok());
+                if let Some(number) = number {
+                    last = std::cmp::max(last, number);
+                }
             }
-            event!(Level::INFO, "file is different, removing existing file");
-            // note tokio::fs::overwrite cannot handle this path being e.g. a directory
-            rm_summary = rm::rm(
-                prog_track,
-                dst,
-                &RmSettings {
-                    fail_early: settings.fail_early,
-                },
-            )
-            .await
-            .map_err(|err| {
-                let rm_summary = err.summary;
-                let copy_summary = CopySummary {
-                    rm_summary,
-                    ..Default::default()
-                };
-                CopyError::new(anyhow::Error::msg(err), copy_summary)
-            })?;
-        } else {
-            return Err(CopyError::new(
-                anyhow!(
-                    "destination {:?} already exists, did you intend to specify --overwrite?",
-                    dst
-                ),
-                Default::default(),
-            ));
+            backup_name.push(format!(".~{}~", last + 1));
         }
     }
-    event!(Level::DEBUG, "copying data");
-    let mut copy_summary = CopySummary {
-        rm_summary,
-        ..Default::default()
-    };
-    tokio::fs::copy(src, dst)
-        .await
-        .with_context(|| format!("failed copying {:?} to {:?}", &src, &dst))
-        .map_err(|err| CopyError::new(err, copy_summary))?;
-    prog_track.files_copied.inc();
-    prog_track.bytes_copied.add(src_metadata.len());
-    event!(Level::DEBUG, "setting permissions");
-    preserve::set_file_metadata(preserve, &src_metadata, dst)
-        .await
-        .map_err(|err| CopyError::new(err, copy_summary))?;
-    // we mark files as "copied" only after all metadata is set as well
-    copy_summary.bytes_copied += src_metadata.len();
-    copy_summary.files_copied += 1;
-    Ok(copy_summary)
+    Ok(dst.with_file_name(backup_name))
 }
 
-#[derive(Copy, Clone, Debug, Default)]
-pub struct CopySummary {
-    pub bytes_copied: u64,
-    pub files_copied: usize,
-    pub symlinks_created: usize,
-    pub directories_created: usize,
-    pub files_unchanged: usize,
-    pub symlinks_unchanged: usize,
-    pub directories_unchanged: usize,
-    pub rm_summary: RmSummary,
+/// Returns the path the existing 'dst' is backed up to, after removing the previous backup with the same name
+pub(crate) async fn free_backup_path(
+    dst: &std::path::Path,
+    backup: &Backup,
+) -> anyhow::Result<std::path::PathBuf> {
+    let backup_path = backup_path(dst, backup).await?;
+    // rename only replaces files and empty directories, the previous simple backup is removed first
+    match tokio::fs::symlink_metadata(&backup_path).await {
+        Ok(metadata) if metadata.is_dir() => tokio::fs::remove_dir_all(&backup_path).await,
+        Ok(_) => tokio::fs::remove_file(&backup_path).await,
+        Err(error) if error.kind() == std::io::ErrorKind::NotFound => Ok(()),
+        Err(error) => Err(error),
+    }
+    .with_context(|| format!("cannot remove the previous backup {:?}", &backup_path))?;
+    Ok(backup_path)
 }
 
-impl std::ops::Add for CopySummary {
-    type Output = Self;
-    fn add(self, other: Self) -> Self {
-        Self {
-            bytes_copied: self.bytes_copied + other.bytes_copied,
-            files_copied: self.files_copied + other.files_copied,
-            symlinks_created: self.symlinks_created + other.symlinks_created,
-            directories_created: self.directories_created + other.directories_created,
-            files_unchanged: self.files_unchanged + other.files_unchanged,
-            symlinks_unchanged: self.symlinks_unchanged + other.symlinks_unchanged,
-            directories_unchanged: self.directories_unchanged + other.directories_unchanged,
-            rm_summary: self.rm_summary + other.rm_summary,
+/// Moves the existing 'dst' to its backup name, renaming whole directories and never following symlinks.
+///
+/// With 'keep' the backup is a hard-link instead and 'dst' stays in place, so that it can be replaced atomically.
+async fn make_backup(
+    dst: &std::path::Path,
+    backup: &Backup,
+    keep: bool,
+) -> anyhow::Result<std::path::PathBuf> {
+    let backup_path = free_backup_path(dst, backup).await?;
+    if keep {
+        tokio::fs::hard_link(dst, &backup_path).await
+    } else {
+        tokio::fs::rename(dst, &backup_path).await
+    }
+    .with_context(|| format!("cannot back up {:?} as {:?}", dst, &backup_path))?;
+    event!(Level::DEBUG, "backed up {:?} as {:?}", dst, &backup_path);
+    Ok(backup_path)
+}
+
+/// Rewrites symlink targets pointing inside `src_root`, both roots must be absolute paths of the copied source and
+/// destination
+#[derive(Clone, Debug)]
+pub struct SymlinkRewrite {
+    pub mode: LinksMode,
+    pub src_root: std::path::PathBuf,
+    pub dst_root: std::path::PathBuf,
+}
+
+/// Resolves "." and ".." components without touching the filesystem
+fn normalize_lexically(path: &std::path::Path) -> std::path::PathBuf {
+    let mut normalized = std::path::PathBuf::new();
+    for component in path.components() {
+        match component {
+            std::path::Component::CurDir => {}
+            std::path::Component::ParentDir => {
+                normalized.pop();
+            }
+            component => normalized.push(component),
         }
     }
+    normalized
 }
 
-impl std::fmt::Display for CopySummary {
-    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
-        write!(
-            f,
-            "bytes copied: {}\n\
-            files copied: {}\n\
-            symlinks created: {}\n\
-            directories created: {}\n\
-            files unchanged: {}\n\
-            symlinks unchanged: {}\n\
-            directories unchanged: {}\n\
-            {}",
-            bytesize::ByteSize(self.bytes_copied),
-            self.files_copied,
-            self.symlinks_created,
-            self.directories_created,
-            self.files_unchanged,
-            self.symlinks_unchanged,
-            self.directories_unchanged,
-            &self.rm_summary,
-        )
+impl SymlinkRewrite {
+    /// Returns the target of the destination symlink for the source symlink 'src' pointing to 'link'
+    fn target(&self, src: &std::path::Path, link: &std::path::Path) -> std::path::PathBuf {
+        if self.mode == LinksMode::Preserve || link.is_relative() {
+            return link.to_owned();
+        }
+        let src_root = normalize_lexically(&self.src_root);
+        let link_path = normalize_lexically(link);
+        let target = match link_path.strip_prefix(&src_root) {
+            Ok(target) => target,
+            // the target is outside of the copied tree
+            Err(_) => return link.to_owned(),
+        };
+        match self.mode {
+            LinksMode::Preserve => unreachable!(),
+            LinksMode::RewritePrefix => normalize_lexically(&self.dst_root).join(target),
+            LinksMode::RewriteRelative => {
+                // the destination tree mirrors the source, so the relative path is the same in both
+                let link_dir = match std::path::absolute(src)
+                    .ok()
+                    .and_then(|src| normalize_lexically(&src).parent().map(|dir| dir.to_owned()))
+                {
+                    Some(link_dir) => link_dir,
+                    None => return link.to_owned(),
+                };
+                let link_dir = match link_dir.strip_prefix(&src_root) {
+                    Ok(link_dir) => link_dir,
+                    // the copied root itself is the symlink
+                    Err(_) => return link.to_owned(),
+                };
+                let common = link_dir
+                    .components()
+                    .zip(target.components())
+                    .take_while(|(a, b)| a == b)
+                    .count();
+                let mut relative = std::path::PathBuf::new();
+                for _ in common..link_dir.components().count() {
+                    relative.push("..");
+                }
+                for component in target.components().skip(common) {
+                    relative.push(component);
+                }
+                if relative.as_os_str().is_empty() {
+                    relative.push(".");
+                }
+                relative
+            }
+        }
     }
 }
 
-#[instrument(skip(prog_track))]
-#[async_recursion]
-pub async fn copy(
-    prog_track: &'static progress::Progress,
-    cwd: &std::path::Path,
+/// Reads the source symlink target and rewrites it according to the settings
+async fn read_src_link(
     src: &std::path::Path,
-    dst: &std::path::Path,
     settings: &CopySettings,
-    preserve: &preserve::PreserveSettings,
-    mut is_fresh: bool,
-) -> Result<CopySummary, CopyError> {
-    throttle::get_token().await;
-    let _ops_guard = prog_track.ops.guard();
-    event!(Level::DEBUG, "reading source metadata");
-    let src_metadata = tokio::fs::symlink_metadata(src)
+) -> anyhow::Result<std::path::PathBuf> {
+    let link = tokio::fs::read_link(src)
         .await
-        .with_context(|| format!("failed reading metadata from src: {:?}", &src))
-        .map_err(|err| CopyError::new(err, Default::default()))?;
-    if settings.dereference && src_metadata.is_symlink() {
-        let link = tokio::fs::read_link(&src)
+        .with_context(|| format!("failed reading symlink {:?}", &src))?;
+    Ok(match &settings.symlink_rewrite {
+        Some(symlink_rewrite) => symlink_rewrite.target(src, &link),
+        None => link,
+    })
+}
+
+const TMP_FILE_MARKER: &str = ".rcp-tmp.";
+
+/// Name of the directory for the temporary files of atomic writes in the state directory
+const TMP_DIR_NAME: &str = "tmp";
+
+/// Returns true for the names of temporary files created with atomic writes: ".<name>.rcp-tmp.<suffix>"
+fn is_tmp_file_name(name: &std::ffi::OsStr) -> bool {
+    let name = name.to_string_lossy();
+    name.starts_with('.') && name[1..].contains(TMP_FILE_MARKER)
+}
+
+/// Temporary files older than this are removed even if the process that created them seems to be running, e.g.
+/// because its pid was reused or it runs on another host
+const STALE_TMP_FILE_AGE: std::time::Duration = std::time::Duration::from_secs(24 * 60 * 60);
+
+/// Returns the pid of the process that created the temporary file, it's the first part of the suffix
+fn tmp_file_pid(name: &std::ffi::OsStr) -> Option<u32> {
+    let name = name.to_string_lossy();
+    let (_, suffix) = name.rsplit_once(TMP_FILE_MARKER)?;
+    let (pid, _) = suffix.split_once('-')?;
+    u32::from_str_radix(pid, 16).ok()
+}
+
+/// Returns true if the temporary file is not being written by a concurrent copy: the process that created it is gone
+/// or the file wasn't modified for `STALE_TMP_FILE_AGE`
+fn is_stale_tmp_file(name: &std::ffi::OsStr, metadata: &std::fs::Metadata) -> bool {
+    if !tmp_file_pid(name).is_some_and(crate::job::process_exists) {
+        return true;
+    }
+    metadata
+        .modified()
+        .ok()
+        .and_then(|mtime| mtime.elapsed().ok())
+        .is_some_and(|age| age > STALE_TMP_FILE_AGE)
+}
+
+/// Returns the directory for the temporary file of 'dst' in the state directory, None if there is no state directory or
+/// it's on a different filesystem than 'dst' (a temporary file cannot be renamed across filesystems)
+async fn tmp_dir(settings: &CopySettings, dst: &std::path::Path) -> Option<std::path::PathBuf> {
+    let state_dir = settings.state_dir.as_ref()?;
+    let (tmp_dir, tmp_dev) = settings
+        .copy_state
+        .tmp_dir
+        .get_or_init(|| create_tmp_dir(state_dir))
+        .await
+        .as_ref()?;
+    let dst_dir = match dst.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => std::path::Path::new("."),
+    };
+    let dst_metadata = tokio::fs::metadata(dst_dir).await.ok()?;
+    (dst_metadata.dev() == *tmp_dev).then(|| tmp_dir.clone())
+}
+
+/// Creates the directory for the temporary files in the state directory and removes the stale ones left behind by
+/// interrupted copies, returns None if that's not possible (e.g. the destination is a file)
+async fn create_tmp_dir(state_dir: &statedir::StateDir) -> Option<(std::path::PathBuf, u64)> {
+    let result = async {
+        let tmp_dir = state_dir.create_file(TMP_DIR_NAME)?;
+        match tokio::fs::create_dir(&tmp_dir).await {
+            Ok(()) => {}
+            Err(error) if error.kind() == std::io::ErrorKind::AlreadyExists => {}
+            Err(error) => {
+                return Err(anyhow::Error::new(error)
+                    .context(format!("failed creating directory {:?}", &tmp_dir)))
+            }
+        }
+        remove_stale_tmp_files(&tmp_dir).await?;
+        let metadata = tokio::fs::metadata(&tmp_dir)
             .await
-            .with_context(|| format!("failed reading src symlink {:?}", &src))
-            .map_err(|err| CopyError::new(err, Default::default()))?;
-        let abs_link = if link.is_relative() {
-            cwd.join(link)
-        } else {
-            link
-        };
-        let new_cwd = abs_link
-            .parent()
-            .with_context(|| {
-                format!(
-                    "the source symlink {:?} does not have a parent directory",
-                    &src
-                )
-            })
-            .unwrap();
-        return copy(
-            prog_track, new_cwd, &abs_link, dst, settings, preserve, is_fresh,
-        )
-        .await;
+            .with_context(|| format!("failed reading metadata from {:?}", &tmp_dir))?;
+        Ok((tmp_dir, metadata.dev()))
     }
-    if src_metadata.is_file() {
-        return copy_file(prog_track, src, dst, settings, preserve, is_fresh).await;
+    .await;
+    match result {
+        Ok(tmp_dir) => Some(tmp_dir),
+        Err(error) => {
+            event!(
+                Level::INFO,
+                "{:#}, temporary files are created next to the destination files",
+                &error
+            );
+            None
+        }
     }
-    if src_metadata.is_symlink() {
-        let mut rm_summary = RmSummary::default();
-        let link = tokio::fs::read_link(src)
+}
+
+/// Temporary file in the state directory or next to the destination, removed when dropped unless it was renamed into
+/// place
+struct TmpFile {
+    path: std::path::PathBuf,
+    persisted: bool,
+}
+
+impl TmpFile {
+    /// Temporary file for 'dst' in 'tmp_dir', or next to 'dst' if None
+    fn new(dst: &std::path::Path, tmp_dir: Option<&std::path::Path>) -> anyhow::Result<Self> {
+        static COUNTER: std::sync::atomic::AtomicU64 = std::sync::atomic::AtomicU64::new(0);
+        let file_name = dst
+            .file_name()
+            .with_context(|| format!("destination {:?} does not have a basename", dst))?;
+        let suffix = format!(
+            "{:x}-{:x}",
+            std::process::id(),
+            COUNTER.fetch_add(1, std::sync::atomic::Ordering::Relaxed)
+        );
+        let mut tmp_name = std::ffi::OsString::from(".");
+        tmp_name.push(file_name);
+        tmp_name.push(TMP_FILE_MARKER);
+        tmp_name.push(suffix);
+        Ok(TmpFile {
+            path: match tmp_dir {
+                Some(tmp_dir) => tmp_dir.join(tmp_name),
+                None => dst.with_file_name(tmp_name),
+            },
+            persisted: false,
+        })
+    }
+
+    async fn persist(mut self, dst: &std::path::Path) -> anyhow::Result<()> {
+        tokio::fs::rename(&self.path, dst)
             .await
-            .with_context(|| format!("failed reading symlink {:?}", &src))
-            .map_err(|err| CopyError::new(err, Default::default()))?;
-        // try creating a symlink, if dst path exists and overwrite is set - remove and try again
-        if let Err(error) = tokio::fs::symlink(&link, dst).await {
-            if settings.overwrite && error.kind() == std::io::ErrorKind::AlreadyExists {
-                let dst_metadata = tokio::fs::symlink_metadata(dst)
-                    .await
-                    .with_context(|| format!("failed reading metadata from dst: {:?}", &dst))
-                    .map_err(|err| CopyError::new(err, Default::default()))?;
-                if is_file_type_same(&src_metadata, &dst_metadata) {
-                    let dst_link = tokio::fs::read_link(dst)
-                        .await
-                        .with_context(|| format!("failed reading dst symlink: {:?}", &dst))
-                        .map_err(|err| CopyError::new(err, Default::default()))?;
-                    if link == dst_link {
-                        event!(
-                            Level::DEBUG,
-                            "'dst' is a symlink and points to the same location as 'src'"
-                        );
-                        if preserve.symlink.any() {
-                            // do we need to update the metadata for this symlink?
-                            let dst_metadata = tokio::fs::symlink_metadata(dst)
-                                .await
-                                .with_context(|| {
-                                    format!("failed reading metadata from dst: {:?}", &dst)
-                                })
-                                .map_err(|err| CopyError::new(err, Default::default()))?;
-                            if !filecmp::metadata_equal(
-                                &settings.overwrite_compare,
-                                &src_metadata,
-                                &dst_metadata,
-                            ) {
-                                event!(Level::DEBUG, "'dst' metadata is different, updating");
-                                preserve::set_symlink_metadata(preserve, &src_metadata, dst)
-                                    .await
-                                    .map_err(|err| CopyError::new(err, Default::default()))?;
-                                prog_track.symlinks_removed.inc();
-                                prog_track.symlinks_created.inc();
-                                return Ok(CopySummary {
-                                    rm_summary: RmSummary {
-                                        symlinks_removed: 1,
-                                        ..Default::default()
-                                    },
-                                    symlinks_created: 1,
-                                    ..Default::default()
-                                });
-                            }
-                        }
-                        event!(Level::DEBUG, "symlink already exists, skipping");
-                        prog_track.symlinks_unchanged.inc();
-                        return Ok(CopySummary {
-                            symlinks_unchanged: 1,
-                            ..Default::default()
-                        });
+            .with_context(|| format!("failed renaming {:?} to {:?}", &self.path, dst))?;
+        self.persisted = true;
+        Ok(())
+    }
+}
+
+impl Drop for TmpFile {
+    fn drop(&mut self) {
+        if !self.persisted {
+            // the file may not have been created yet
+            std::fs::remove_file(&self.path).ok();
+        }
+    }
+}
+
+/// Removes temporary files left behind in the directory by interrupted copies with atomic writes, the temporary files
+/// of copies still running are kept
+async fn remove_stale_tmp_files(dir: &std::path::Path) -> anyhow::Result<()> {
+    let mut entries = tokio::fs::read_dir(dir)
+        .await
+        .with_context(|| format!("cannot open directory {:?} for reading", dir))?;
+    while let Some(entry) = entries
+        .next_entry()
+        .await
+        .with_context(|| format!("failed traversing directory {:?}", dir))?
+    {
+        if !is_tmp_file_name(&entry.file_name()) {
+            continue;
+        }
+        let metadata = entry
+            .metadata()
+            .await
+            .with_context(|| format!("failed reading metadata from {:?}", entry.path()))?;
+        if !metadata.is_file() || !is_stale_tmp_file(&entry.file_name(), &metadata) {
+            continue;
+        }
+        event!(
+            Level::INFO,
+            "removing stale temporary file {:?}",
+            entry.path()
+        );
+        tokio::fs::remove_file(entry.path())
+            .await
+            .with_context(|| format!("failed removing {:?}", entry.path()))?;
+    }
+    Ok(())
+}
+
+/// Destination file copied with dedup enabled, with its metadata at the time of the copy
+#[derive(Clone, Debug)]
+struct DedupEntry {
+    path: std::path::PathBuf,
+    ino: u64,
+    mtime: (i64, i64),
+}
+
+type DedupSlot = std::sync::Arc<tokio::sync::Mutex<Option<DedupEntry>>>;
+
+/// State collected while copying and shared by all the copies of one invocation, see [`CopySettings::copy_state`]
+#[derive(Debug, Default)]
+pub struct CopyState {
+    /// (dev, ino) of the destination directories, tracked only with exclude_dst_dirs
+    dst_dirs: std::sync::Mutex<std::collections::HashSet<(u64, u64)>>,
+    /// Destination directories that don't accept new entries (not writable or out of quota) and the root cause,
+    /// nothing more is copied under them
+    failed_subtrees: std::sync::Mutex<std::collections::HashMap<std::path::PathBuf, String>>,
+    /// Files copied with dedup enabled keyed by their (size, contents hash), the slot is locked while the first file
+    /// with given contents is being copied so that identical files copied concurrently wait for it
+    dedup_index: std::sync::Mutex<std::collections::HashMap<(u64, checksum::Checksum), DedupSlot>>,
+    /// Directory for the temporary files of atomic writes in the state directory and its device, see [`tmp_dir`]
+    tmp_dir: tokio::sync::OnceCell<Option<(std::path::PathBuf, u64)>>,
+}
+
+/// Hard-links the file copied earlier with the same contents to 'dst', returns false if it was modified since
+async fn dedup_link(entry: &DedupEntry, size: u64, dst: &std::path::Path) -> bool {
+    let unchanged = tokio::fs::symlink_metadata(&entry.path)
+        .await
+        .is_ok_and(|metadata| {
+            metadata.ino() == entry.ino
+                && metadata.len() == size
+                && (metadata.mtime(), metadata.mtime_nsec()) == entry.mtime
+        });
+    if !unchanged {
+        event!(
+            Level::INFO,
+            "{:?} changed since it was copied, not using it for dedup",
+            &entry.path
+        );
+        return false;
+    }
+    match tokio::fs::hard_link(&entry.path, dst).await {
+        Ok(()) => {
+            event!(Level::DEBUG, "deduplicated with {:?}", &entry.path);
+            true
+        }
+        // e.g. the destination spans multiple filesystems or we're overwriting in place
+        Err(error) => {
+            event!(
+                Level::INFO,
+                "cannot hard-link {:?} to {:?}, copying instead: {}",
+                &entry.path,
+                dst,
+                &error
+            );
+            false
+        }
+    }
+}
+
+/// Returns the errno if the error was caused by a directory not accepting new entries: EACCES or EDQUOT
+fn subtree_failure_errno(error: &anyhow::Error) -> Option<i32> {
+    error
+        .chain()
+        .filter_map(|cause| {
+            if let Some(error) = cause.downcast_ref::<std::io::Error>() {
+                error.raw_os_error()
+            } else {
+                cause
+                    .downcast_ref::<nix::errno::Errno>()
+                    .map(|errno| *errno as i32)
+            }
+        })
+        .find(|errno| *errno == libc::EACCES || *errno == libc::EDQUOT)
+}
+
+/// Marks the parent directory of 'dst' as failed if creating 'dst' failed because the directory doesn't accept new
+/// entries. EACCES may also come from reading the source so we check that the directory is not writable.
+fn mark_failed_subtree(state: &CopyState, dst: &std::path::Path, error: &anyhow::Error) {
+    let Some(dst_dir) = dst.parent() else {
+        return;
+    };
+    let Some(errno) = subtree_failure_errno(error) else {
+        return;
+    };
+    if errno == libc::EACCES
+        && nix::unistd::access(
+            dst_dir,
+            nix::unistd::AccessFlags::W_OK | nix::unistd::AccessFlags::X_OK,
+        )
+        .is_ok()
+    {
+        return;
+    }
+    state
+        .failed_subtrees
+        .lock()
+        .unwrap()
+        .entry(dst_dir.to_owned())
+        .or_insert_with(|| std::io::Error::from_raw_os_error(errno).to_string());
+}
+
+/// Returns true if the error was caused by missing permissions (EACCES)
+fn is_permission_denied(error: &anyhow::Error) -> bool {
+    error.chain().any(|cause| {
+        cause
+            .downcast_ref::<std::io::Error>()
+            .is_some_and(|error| error.kind() == std::io::ErrorKind::PermissionDenied)
+    })
+}
+
+/// Returns true if the source entry should be skipped because it couldn't be read due to missing permissions and
+/// `skip_unreadable` is set. The caller must only pass errors coming from reading 'src'.
+fn skip_unreadable(settings: &CopySettings, src: &std::path::Path, error: &anyhow::Error) -> bool {
+    if settings.skip_unreadable && is_permission_denied(error) {
+        event!(
+            Level::INFO,
+            "{:?} is not readable, skipping: {:#}",
+            src,
+            error
+        );
+        return true;
+    }
+    false
+}
+
+/// Summary of a source entry skipped by `skip_unreadable`
+fn unreadable_summary() -> CopySummary {
+    CopySummary {
+        entries_unreadable: 1,
+        ..Default::default()
+    }
+}
+
+/// Checks if 'dst' is under a destination directory that was marked as failed
+fn in_failed_subtree(state: &CopyState, dst: &std::path::Path) -> bool {
+    let failed_subtrees = state.failed_subtrees.lock().unwrap();
+    !failed_subtrees.is_empty()
+        && dst
+            .ancestors()
+            .skip(1)
+            .any(|dir| failed_subtrees.contains_key(dir))
+}
+
+/// Resolves symlinks in the path, trailing components that don't exist yet are appended as they are
+fn canonicalize_partial(path: &std::path::Path) -> anyhow::Result<std::path::PathBuf> {
+    let mut existing = path;
+    let mut missing = vec![];
+    loop {
+        let existing_or_cwd = if existing.as_os_str().is_empty() {
+            std::path::Path::new(".")
+        } else {
+            existing
+        };
+        match std::fs::canonicalize(existing_or_cwd) {
+            Ok(canonical) => {
+                return Ok(missing
+                    .iter()
+                    .rev()
+                    .fold(canonical, |path, component| path.join(component)))
+            }
+            Err(error) if error.kind() == std::io::ErrorKind::NotFound => {
+                match (existing.parent(), existing.file_name()) {
+                    (Some(parent), Some(file_name)) => {
+                        missing.push(file_name);
+                        existing = parent;
                     }
-                    event!(
-                        Level::DEBUG,
-                        "'dst' is a symlink but points to a different path, updating"
-                    );
-                } else {
-                    event!(Level::INFO, "'dst' is not a symlink, updating");
+                    _ => return Err(error).with_context(|| format!("cannot resolve {:?}", path)),
                 }
-                rm_summary = rm::rm(
-                    prog_track,
-                    dst,
-                    &RmSettings {
-                        fail_early: settings.fail_early,
-                    },
-                )
-                .await
-                .map_err(|err| {
-                    let rm_summary = err.summary;
-                    let copy_summary = CopySummary {
-                        rm_summary,
-                        ..Default::default()
-                    };
-                    CopyError::new(err.source, copy_summary)
-                })?;
-                tokio::fs::symlink(&link, dst)
-                    .await
-                    .with_context(|| format!("failed creating symlink {:?}", &dst))
-                    .map_err(|err| {
-                        let copy_summary = CopySummary {
-                            rm_summary,
-                            ..Default::default()
-                        };
-                        CopyError::new(err, copy_summary)
-                    })?;
-            } else {
-                return Err(CopyError::new(
-                    anyhow!("failed creating symlink {:?}", &dst),
-                    Default::default(),
-                ));
             }
+            Err(error) => return Err(error).with_context(|| format!("cannot resolve {:?}", path)),
         }
-        preserve::set_symlink_metadata(preserve, &src_metadata, dst)
+    }
+}
+
+/// Returns true if copying the src directory to dst would copy dst into itself: dst is the same as src or is inside of
+/// it, after resolving symlinks (e.g. dst inside a symlink pointing to a parent of src).
+pub fn is_dst_inside_src(
+    src: &std::path::Path,
+    dst: &std::path::Path,
+    dereference: bool,
+) -> anyhow::Result<bool> {
+    let src_metadata = if dereference {
+        std::fs::metadata(src)
+    } else {
+        std::fs::symlink_metadata(src)
+    };
+    match src_metadata {
+        Ok(src_metadata) if src_metadata.is_dir() => {}
+        // only directories can be copied into themselves, missing sources are reported by the copy
+        _ => return Ok(false),
+    }
+    let src = std::fs::canonicalize(src).with_context(|| format!("cannot resolve {:?}", src))?;
+    Ok(canonicalize_partial(dst)?.starts_with(src))
+}
+
+/// Returns true if src and dst are the same file or directory: either the same inode (e.g. a bind-mount alias) or the
+/// same path after resolving symlinks. The destination itself is never followed as it would be replaced by the copy,
+/// missing paths are never the same.
+pub fn is_same_root(
+    src: &std::path::Path,
+    dst: &std::path::Path,
+    dereference: bool,
+) -> anyhow::Result<bool> {
+    let src_metadata = if dereference {
+        std::fs::metadata(src)
+    } else {
+        std::fs::symlink_metadata(src)
+    };
+    let (Ok(src_metadata), Ok(dst_metadata)) = (src_metadata, std::fs::symlink_metadata(dst))
+    else {
+        return Ok(false);
+    };
+    if src_metadata.dev() == dst_metadata.dev() && src_metadata.ino() == dst_metadata.ino() {
+        return Ok(true);
+    }
+    let resolve = |path: &std::path::Path, follow: bool| match (path.parent(), path.file_name()) {
+        (Some(parent), Some(file_name)) if !follow => {
+            Ok(canonicalize_partial(parent)?.join(file_name))
+        }
+        _ => canonicalize_partial(path),
+    };
+    Ok(resolve(src, dereference)? == resolve(dst, false)?)
+}
+
+#[instrument]
+pub fn is_file_type_same(md1: &std::fs::Metadata, md2: &std::fs::Metadata) -> bool {
+    let ft1 = md1.file_type();
+    let ft2 = md2.file_type();
+    ft1.is_dir() == ft2.is_dir()
+        && ft1.is_file() == ft2.is_file()
+        && ft1.is_symlink() == ft2.is_symlink()
+}
+
+/// Re-applies the source metadata to an existing, up to date destination file or symlink without copying any data.
+///
+/// Returns None if the regular copy should proceed: for directories (their metadata is set once their contents are
+/// processed) and for missing destinations with [`MetadataOnly::Soft`].
+async fn update_metadata_only(
+    prog_track: &'static progress::Progress,
+    src: &std::path::Path,
+    src_metadata: &std::fs::Metadata,
+    dst: &std::path::Path,
+    mode: MetadataOnly,
+    settings: &CopySettings,
+    preserve: &preserve::PreserveSettings,
+) -> Result<Option<CopySummary>, CopyError> {
+    let dst_metadata = match tokio::fs::symlink_metadata(dst).await {
+        Ok(dst_metadata) => dst_metadata,
+        Err(error) if error.kind() == std::io::ErrorKind::NotFound => {
+            if mode == MetadataOnly::Soft {
+                event!(Level::DEBUG, "'dst' is missing, copying it");
+                return Ok(None);
+            }
+            return Err(CopyError::new(
+                anyhow!(
+                    "destination {:?} does not exist, use --metadata-only=soft to copy missing entries",
+                    dst
+                ),
+                Default::default(),
+            ));
+        }
+        Err(error) => {
+            return Err(CopyError::new(
+                anyhow::Error::new(error)
+                    .context(format!("failed reading metadata from dst: {:?}", &dst)),
+                Default::default(),
+            ));
+        }
+    };
+    if !is_file_type_same(src_metadata, &dst_metadata) {
+        return Err(CopyError::new(
+            anyhow!(
+                "destination {:?} is of a different type than the source {:?}, not updating metadata only",
+                dst,
+                src
+            ),
+            Default::default(),
+        ));
+    }
+    if dst_metadata.is_dir() {
+        return Ok(None);
+    }
+    if dst_metadata.is_symlink() {
+        let link = read_src_link(src, settings)
             .await
-            .map_err(|err| {
-                let copy_summary = CopySummary {
-                    rm_summary,
-                    ..Default::default()
-                };
-                CopyError::new(err, copy_summary)
-            })?;
-        prog_track.symlinks_created.inc();
-        return Ok(CopySummary {
-            rm_summary,
-            symlinks_created: 1,
+            .map_err(|err| CopyError::new(err, Default::default()))?;
+        let dst_link = tokio::fs::read_link(dst)
+            .await
+            .with_context(|| format!("failed reading dst symlink: {:?}", &dst))
+            .map_err(|err| CopyError::new(err, Default::default()))?;
+        if link != dst_link {
+            return Err(CopyError::new(
+                anyhow!(
+                    "destination symlink {:?} points to a different path than the source {:?}, not updating metadata only",
+                    dst,
+                    src
+                ),
+                Default::default(),
+            ));
+        }
+        event!(Level::DEBUG, "updating symlink metadata");
+        preserve::set_symlink_metadata(preserve, src_metadata, dst)
+            .await
+            .map_err(|err| CopyError::new(err, Default::default()))?;
+        itemize::report(
+            &settings.itemize,
+            ObjType::Symlink,
+            ItemAction::MetadataUpdated,
+            dst,
+        );
+        prog_track.symlinks_unchanged.inc();
+        return Ok(Some(CopySummary {
+            symlinks_unchanged: 1,
             ..Default::default()
-        });
+        }));
     }
-    if !src_metadata.is_dir() {
+    let quick_check = filecmp::MetadataCmpSettings {
+        size: true,
+        mtime: true,
+        ..Default::default()
+    };
+    if !filecmp::metadata_equal(&quick_check, src_metadata, &dst_metadata) {
         return Err(CopyError::new(
             anyhow!(
-                "copy: {:?} -> {:?} failed, unsupported src file type: {:?}",
-                src,
+                "destination {:?} differs from the source {:?} (size or mtime), its data would need to be copied",
                 dst,
-                src_metadata.file_type()
+                src
             ),
             Default::default(),
         ));
     }
-    event!(Level::DEBUG, "process contents of 'src' directory");
-    let mut entries = tokio::fs::read_dir(src)
+    event!(Level::DEBUG, "updating file metadata");
+    preserve::set_file_metadata(preserve, src_metadata, dst)
         .await
-        .with_context(|| format!("cannot open directory {:?} for reading", src))
         .map_err(|err| CopyError::new(err, Default::default()))?;
-    let mut copy_summary = {
-        if let Err(error) = tokio::fs::create_dir(dst).await {
-            assert!(!is_fresh, "unexpected error creating directory: {:?}", &dst);
-            if settings.overwrite && error.kind() == std::io::ErrorKind::AlreadyExists {
-                // check if the destination is a directory - if so, leave it
-                //
-                // N.B. the permissions may prevent us from writing to it but the alternative is to open up the directory
-                // while we're writing to it which isn't safe
-                let dst_metadata = tokio::fs::metadata(dst)
-                    .await
-                    .with_context(|| format!("failed reading metadata from dst: {:?}", &dst))
-                    .map_err(|err| CopyError::new(err, Default::default()))?;
-                if dst_metadata.is_dir() {
-                    event!(Level::DEBUG, "'dst' is a directory, leaving it as is");
-                    prog_track.directories_unchanged.inc();
-                    CopySummary {
-                        directories_unchanged: 1,
-                        ..Default::default()
-                    }
-                } else {
-                    event!(
-                        Level::INFO,
-                        "'dst' is not a directory, removing and creating a new one"
-                    );
-                    let rm_summary = rm::rm(
-                        prog_track,
-                        dst,
-                        &RmSettings {
-                            fail_early: settings.fail_early,
-                        },
-                    )
+    itemize::report(
+        &settings.itemize,
+        ObjType::File,
+        ItemAction::MetadataUpdated,
+        dst,
+    );
+    prog_track.files_unchanged.inc();
+    Ok(Some(CopySummary {
+        files_unchanged: 1,
+        ..Default::default()
+    }))
+}
+
+/// Checks if the reference file is identical to the source using the `overwrite_compare` criteria
+async fn is_reference_match(
+    src: &std::path::Path,
+    src_metadata: &std::fs::Metadata,
+    reference: &std::path::Path,
+    settings: &CopySettings,
+) -> anyhow::Result<bool> {
+    let reference_metadata = match tokio::fs::symlink_metadata(reference).await {
+        Ok(metadata) => metadata,
+        Err(error) if error.kind() == std::io::ErrorKind::NotFound => return Ok(false),
+        Err(error) => {
+            return Err(anyhow::Error::new(error)
+                .context(format!("failed reading metadata from {:?}", reference)))
+        }
+    };
+    if !reference_metadata.is_file()
+        || !filecmp::metadata_equal(
+            &settings.overwrite_compare,
+            src_metadata,
+            &reference_metadata,
+        )
+    {
+        return Ok(false);
+    }
+    if settings.overwrite_compare.hash {
+        return filecmp::contents_equal(src, reference, settings.overwrite_compare.checksum_algo)
+            .await;
+    }
+    Ok(true)
+}
+
+/// Records in the compare cache (if used) that the destination file is up to date
+fn record_up_to_date(
+    settings: &CopySettings,
+    src: &std::path::Path,
+    dst: &std::path::Path,
+    src_metadata: &std::fs::Metadata,
+) {
+    if let Some(compare_cache) = &settings.compare_cache {
+        compare_cache.record(src, dst, src_metadata);
+    }
+}
+
+/// Accounts for the file in the copy limits, see [`CopyLimits`]
+fn reserve_copy(
+    settings: &CopySettings,
+    src: &std::path::Path,
+    src_metadata: &std::fs::Metadata,
+) -> Result<(), LimitExceeded> {
+    match &settings.limits {
+        Some(limits) => limits.reserve(src, src_metadata.len()),
+        None => Ok(()),
+    }
+}
+
+fn release_copy(settings: &CopySettings, src_metadata: &std::fs::Metadata) {
+    if let Some(limits) = &settings.limits {
+        limits.release(src_metadata.len());
+    }
+}
+
+#[instrument(skip(prog_track))]
+pub async fn copy_file(
+    prog_track: &'static progress::Progress,
+    src: &std::path::Path,
+    dst: &std::path::Path,
+    settings: &CopySettings,
+    preserve: &preserve::PreserveSettings,
+    is_fresh: bool,
+) -> Result<CopySummary, CopyError> {
+    let _open_file_guard = throttle::open_file_permit().await;
+    if in_failed_subtree(&settings.copy_state, dst) {
+        event!(Level::DEBUG, "destination directory failed, skipping");
+        return Ok(CopySummary {
+            entries_skipped: 1,
+            ..Default::default()
+        });
+    }
+    event!(
+        Level::DEBUG,
+        "opening 'src' for reading and 'dst' for writing"
+    );
+    let src_metadata = match tokio::fs::symlink_metadata(src)
+        .await
+        .with_context(|| format!("failed reading metadata from {:?}", &src))
+    {
+        Ok(metadata) => metadata,
+        Err(error) if skip_unreadable(settings, src, &error) => return Ok(unreadable_summary()),
+        Err(error) => return Err(CopyError::new(error, Default::default())),
+    };
+    let in_flight = prog_track.in_flight.file(src, src_metadata.len());
+    // check before touching the destination, an unreadable file must not replace an existing one
+    if settings.skip_unreadable {
+        if let Err(errno) = nix::unistd::access(src, nix::unistd::AccessFlags::R_OK) {
+            let error = anyhow::Error::new(std::io::Error::from(errno))
+                .context(format!("cannot open {:?} for reading", src));
+            if skip_unreadable(settings, src, &error) {
+                return Ok(unreadable_summary());
+            }
+        }
+    }
+    if !is_fresh
+        && settings.overwrite
+        && !settings.ignore_times
+        && settings
+            .compare_cache
+            .as_ref()
+            .is_some_and(|compare_cache| compare_cache.is_unchanged(src, dst, &src_metadata))
+    {
+        event!(
+            Level::DEBUG,
+            "source file unchanged since the previous run, skipping"
+        );
+        record_up_to_date(settings, src, dst, &src_metadata);
+        itemize::report(&settings.itemize, ObjType::File, ItemAction::Unchanged, dst);
+        prog_track.files_unchanged.inc();
+        return Ok(CopySummary {
+            files_unchanged: 1,
+            ..Default::default()
+        });
+    }
+    // decided before touching the destination, a file identical to the reference leaves it as it is
+    if let Some(compare_dest) = &settings.compare_dest {
+        if is_reference_match(src, &src_metadata, compare_dest, settings)
+            .await
+            .map_err(|err| CopyError::new(err, Default::default()))?
+        {
+            event!(Level::DEBUG, "identical to {:?}, skipping", compare_dest);
+            itemize::report(&settings.itemize, ObjType::File, ItemAction::Unchanged, dst);
+            prog_track.files_unchanged.inc();
+            return Ok(CopySummary {
+                files_unchanged: 1,
+                ..Default::default()
+            });
+        }
+    }
+    let mut rm_summary = RmSummary::default();
+    // attributes that made us replace an existing destination
+    let mut overwritten = None;
+    let mut backed_up = 0;
+    let dst_exists = !is_fresh && dst.exists();
+    if dst_exists {
+        if settings.overwrite {
+            event!(Level::DEBUG, "file exists, check if it's identical");
+            let dst_metadata = tokio::fs::symlink_metadata(dst)
+                .await
+                .with_context(|| format!("failed reading metadata from {:?}", &dst))
+                .map_err(|err| CopyError::new(err, Default::default()))?;
+            // e.g. a hard-link or a bind-mount alias, overwriting the file would truncate the source
+            if src_metadata.dev() == dst_metadata.dev() && src_metadata.ino() == dst_metadata.ino()
+            {
+                event!(
+                    Level::WARN,
+                    "source and destination are the same file: {:?} and {:?}, skipping",
+                    src,
+                    dst
+                );
+                return Ok(CopySummary {
+                    files_same: 1,
+                    ..Default::default()
+                });
+            }
+            let mut changed_attrs = if is_file_type_same(&src_metadata, &dst_metadata) {
+                filecmp::metadata_diff(&settings.overwrite_compare, &src_metadata, &dst_metadata)
+            } else {
+                vec![filecmp::MetadataAttr::Type]
+            };
+            if !settings.ignore_times && changed_attrs.is_empty() && settings.overwrite_compare.hash
+            {
+                event!(Level::DEBUG, "metadata is identical, comparing contents");
+                if !filecmp::contents_equal(src, dst, settings.overwrite_compare.checksum_algo)
                     .await
-                    .map_err(|err| {
-                        let rm_summary = err.summary;
-                        let copy_summary = CopySummary {
-                            rm_summary,
-                            ..Default::default()
-                        };
-                        CopyError::new(err.source, copy_summary)
-                    })?;
-                    tokio::fs::create_dir(dst)
-                        .await
-                        .with_context(|| format!("cannot create directory {:?}", dst))
-                        .map_err(|err| {
-                            let copy_summary = CopySummary {
-                                rm_summary,
-                                ..Default::default()
-                            };
-                            CopyError::new(anyhow::Error::msg(err), copy_summary)
-                        })?;
-                    // anythingg copied into dst may assume they don't need to check for conflicts
-                    is_fresh = true;
-                    prog_track.directories_created.inc();
-                    CopySummary {
-                        rm_summary,
-                        directories_created: 1,
-                        ..Default::default()
-                    }
+                    .map_err(|err| CopyError::new(err, Default::default()))?
+                {
+                    changed_attrs.push(filecmp::MetadataAttr::Hash);
                 }
-            } else {
-                event!(Level::ERROR, "{}", &error);
+            }
+            if !settings.ignore_times && changed_attrs.is_empty() {
+                event!(Level::DEBUG, "file is identical, skipping");
+                record_up_to_date(settings, src, dst, &src_metadata);
+                itemize::report(&settings.itemize, ObjType::File, ItemAction::Unchanged, dst);
+                prog_track.files_unchanged.inc();
+                return Ok(CopySummary {
+                    files_unchanged: 1,
+                    ..Default::default()
+                });
+            }
+            if settings.update_only
+                && dst_metadata.is_file()
+                && (dst_metadata.mtime(), dst_metadata.mtime_nsec())
+                    >= (src_metadata.mtime(), src_metadata.mtime_nsec())
+            {
+                event!(
+                    Level::DEBUG,
+                    "destination is newer than the source, skipping"
+                );
+                itemize::report(&settings.itemize, ObjType::File, ItemAction::Unchanged, dst);
+                prog_track.files_unchanged.inc();
+                return Ok(CopySummary {
+                    files_unchanged: 1,
+                    ..Default::default()
+                });
+            }
+            // otherwise removing or rewriting it fails with a bare EPERM
+            if let Some(protection) = preserve::protection_flags(dst) {
                 return Err(CopyError::new(
-                    anyhow!("cannot create directory {:?}", dst),
+                    anyhow!(
+                        "cannot overwrite {:?}, it is {} (see lsattr and chattr)",
+                        dst,
+                        protection
+                    ),
                     Default::default(),
                 ));
             }
-        } else {
-            // new directory created, anythingg copied into dst may assume they don't need to check for conflicts
-            is_fresh = true;
-            prog_track.directories_created.inc();
-            CopySummary {
-                directories_created: 1,
-                ..Default::default()
+            // the limits are checked while the destination is still intact
+            reserve_copy(settings, src, &src_metadata)
+                .map_err(|err| CopyError::new(err.into(), Default::default()))?;
+            overwritten = Some(changed_attrs);
+            if let Some(backup) = &settings.backup {
+                // with atomic writes the backup is a hard-link, the destination is replaced by the rename
+                let keep = settings.atomic_writes && !dst_metadata.is_dir();
+                let backup_path = make_backup(dst, backup, keep)
+                    .await
+                    .map_err(|err| CopyError::new(err, Default::default()))?;
+                event!(
+                    Level::INFO,
+                    "file is different, existing file backed up as {:?}",
+                    backup_path
+                );
+                backed_up = 1;
+            } else if settings.inplace && dst_metadata.is_file() {
+                // the existing file is truncated and rewritten, keeping its inode and any hard-links to it
+                event!(Level::INFO, "file is different, overwriting it in place");
+            } else if settings.atomic_writes && !dst_metadata.is_dir() {
+                // the new file is renamed over the existing one once it's complete
+                event!(Level::INFO, "file is different, replacing it atomically");
+                rm_summary = if dst_metadata.is_symlink() {
+                    prog_track.symlinks_removed.inc();
+                    RmSummary {
+                        symlinks_removed: 1,
+                        ..Default::default()
+                    }
+                } else {
+                    prog_track.files_removed.inc();
+                    RmSummary {
+                        files_removed: 1,
+                        ..Default::default()
+                    }
+                };
+            } else {
+                event!(Level::INFO, "file is different, removing existing file");
+                // note tokio::fs::overwrite cannot handle this path being e.g. a directory
+                rm_summary = rm::rm(
+                    prog_track,
+                    dst,
+                    &RmSettings {
+                        fail_early: settings.fail_early,
+                        filter: Default::default(),
+                        prune_empty_dirs: false,
+                    },
+                )
+                .await
+                .map_err(|err| {
+                    let rm_summary = err.summary;
+                    let copy_summary = CopySummary {
+                        rm_summary,
+                        ..Default::default()
+                    };
+                    CopyError::new(anyhow::Error::msg(err), copy_summary)
+                })?;
             }
+        } else {
+            return Err(CopyError::new(
+                anyhow!(
+                    "destination {:?} already exists, did you intend to specify --overwrite?",
+                    dst
+                ),
+                Default::default(),
+            ));
         }
+    }
+    if !dst_exists {
+        reserve_copy(settings, src, &src_metadata)
+            .map_err(|err| CopyError::new(err.into(), Default::default()))?;
+    }
+    let mut copy_summary = CopySummary {
+        rm_summary,
+        entries_backed_up: backed_up,
+        ..Default::default()
     };
-    let mut join_set = tokio::task::JoinSet::new();
-    let mut success = true;
-    while let Some(entry) = entries
-        .next_entry()
-        .await
-        .with_context(|| format!("failed traversing src directory {:?}", &src))
-        .map_err(|err| CopyError::new(err, copy_summary))?
-    {
-        let cwd_path = src.to_owned();
-        let entry_path = entry.path();
-        let entry_name = entry_path.file_name().unwrap();
-        let dst_path = dst.join(entry_name);
-        let settings = *settings;
-        let preserve = *preserve;
-        let do_copy = || async move {
-            copy(
-                prog_track,
-                &cwd_path,
-                &entry_path,
-                &dst_path,
-                &settings,
-                &preserve,
-                is_fresh,
-            )
+    // with atomic writes everything is written to a temporary file first, it's removed if we fail or get cancelled
+    let tmp_file = if settings.atomic_writes {
+        let tmp_dir = tmp_dir(settings, dst).await;
+        Some(
+            TmpFile::new(dst, tmp_dir.as_deref())
+                .map_err(|err| CopyError::new(err, copy_summary))?,
+        )
+    } else {
+        None
+    };
+    let write_path = tmp_file
+        .as_ref()
+        .map_or(dst, |tmp_file| tmp_file.path.as_path());
+    if let Some(link_dest) = &settings.link_dest {
+        if is_reference_match(src, &src_metadata, link_dest, settings)
             .await
-        };
-        join_set.spawn(do_copy());
-    }
-    while let Some(res) = join_set.join_next().await {
-        match res {
-            Ok(result) => match result {
-                Ok(summary) => copy_summary = copy_summary + summary,
-                Err(error) => {
-                    event!(
-                        Level::ERROR,
-                        "copy: {:?} -> {:?} failed with: {}",
-                        src,
+            .map_err(|err| CopyError::new(err, copy_summary))?
+        {
+            match tokio::fs::hard_link(link_dest, write_path).await {
+                Ok(()) => {
+                    event!(Level::DEBUG, "hard-linked from {:?}", link_dest);
+                    release_copy(settings, &src_metadata);
+                    if let Some(tmp_file) = tmp_file {
+                        tmp_file
+                            .persist(dst)
+                            .await
+                            .map_err(|err| CopyError::new(err, copy_summary))?;
+                    }
+                    record_up_to_date(settings, src, dst, &src_metadata);
+                    itemize::report(
+                        &settings.itemize,
+                        ObjType::File,
+                        ItemAction::HardLinked,
                         dst,
-                        &error
                     );
-                    copy_summary = copy_summary + error.summary;
-                    if settings.fail_early {
-                        return Err(CopyError::new(error.source, copy_summary));
-                    }
-                    success = false;
+                    prog_track.hard_links_created.inc();
+                    copy_summary.files_linked += 1;
+                    return Ok(copy_summary);
                 }
-            },
-            Err(error) => {
-                if settings.fail_early {
-                    return Err(CopyError::new(anyhow::Error::msg(error), copy_summary));
+                // e.g. the reference is on a different filesystem or we're overwriting in place
+                Err(error) => event!(
+                    Level::INFO,
+                    "cannot hard-link {:?} to {:?}, copying instead: {}",
+                    link_dest,
+                    write_path,
+                    &error
+                ),
+            }
+        }
+    }
+    // empty files are not worth linking
+    let mut dedup_slot = if settings.dedup && src_metadata.len() > 0 {
+        let checksum = filecmp::hash_file(src, settings.overwrite_compare.checksum_algo)
+            .await
+            .map_err(|err| CopyError::new(err, copy_summary))?;
+        let slot = settings
+            .copy_state
+            .dedup_index
+            .lock()
+            .unwrap()
+            .entry((src_metadata.len(), checksum))
+            .or_default()
+            .clone();
+        let slot = slot.lock_owned().await;
+        if let Some(entry) = slot.as_ref() {
+            if dedup_link(entry, src_metadata.len(), write_path).await {
+                release_copy(settings, &src_metadata);
+                if let Some(tmp_file) = tmp_file {
+                    tmp_file
+                        .persist(dst)
+                        .await
+                        .map_err(|err| CopyError::new(err, copy_summary))?;
                 }
+                record_up_to_date(settings, src, dst, &src_metadata);
+                itemize::report(
+                    &settings.itemize,
+                    ObjType::File,
+                    ItemAction::HardLinked,
+                    dst,
+                );
+                prog_track.hard_links_created.inc();
+                copy_summary.files_deduplicated += 1;
+                copy_summary.bytes_deduplicated += src_metadata.len();
+                return Ok(copy_summary);
             }
         }
+        Some(slot)
+    } else {
+        None
+    };
+    event!(Level::DEBUG, "copying data");
+    throttle::get_tput_tokens_for(&[src, dst], src_metadata.len()).await;
+    // the file may have changed size since we read its metadata, count what was actually copied
+    let (bytes_copied, copy_method) = {
+        let (src_path, dst_path) = (src.to_owned(), write_path.to_owned());
+        let (reflink, preallocate) = (settings.reflink, settings.preallocate);
+        let file_bytes = in_flight.bytes.clone();
+        tokio::task::spawn_blocking(move || {
+            let bytes_progress = progress::BytesProgress {
+                total: &prog_track.bytes_copied,
+                file: Some(&file_bytes),
+            };
+            datacopy::copy_data(&src_path, &dst_path, reflink, preallocate, &bytes_progress)
+        })
+        .await
+        .map_err(anyhow::Error::msg)
+        .and_then(|res| res)
+        .with_context(|| format!("failed copying {:?} to {:?}", &src, &dst))
+        .map_err(|err| {
+            mark_failed_subtree(&settings.copy_state, dst, &err);
+            CopyError::new(err, copy_summary)
+        })?
+    };
+    if bytes_copied != src_metadata.len() {
+        event!(
+            Level::WARN,
+            "{:?} changed size during the copy: expected {} bytes, copied {}",
+            src,
+            src_metadata.len(),
+            bytes_copied
+        );
     }
-    if !success {
-        return Err(CopyError::new(
-            anyhow!("copy: {:?} -> {:?} failed!", src, dst),
-            copy_summary,
-        ))?;
+    prog_track.files_copied.inc();
+    event!(Level::DEBUG, "setting permissions");
+    preserve::set_file_metadata(preserve, &src_metadata, write_path)
+        .await
+        .map_err(|err| CopyError::new(err, copy_summary))?;
+    if let Some(tmp_file) = tmp_file {
+        tmp_file
+            .persist(dst)
+            .await
+            .map_err(|err| CopyError::new(err, copy_summary))?;
+    }
+    // an immutable file can't be renamed, so the flags are set on the final destination
+    if preserve.flags {
+        preserve::set_flags(src, dst)
+            .await
+            .map_err(|err| CopyError::new(err, copy_summary))?;
+    }
+    if let Some(slot) = dedup_slot.as_mut().filter(|slot| slot.is_none()) {
+        let dst_metadata = tokio::fs::symlink_metadata(dst)
+            .await
+            .with_context(|| format!("failed reading metadata from {:?}", &dst))
+            .map_err(|err| CopyError::new(err, copy_summary))?;
+        **slot = Some(DedupEntry {
+            path: dst.to_owned(),
+            ino: dst_metadata.ino(),
+            mtime: (dst_metadata.mtime(), dst_metadata.mtime_nsec()),
+        });
+    }
+    record_up_to_date(settings, src, dst, &src_metadata);
+    itemize::report(
+        &settings.itemize,
+        ObjType::File,
+        overwritten.map_or(ItemAction::Created, ItemAction::Overwritten),
+        dst,
+    );
+    // we mark files as "copied" only after all metadata is set as well
+    copy_summary.bytes_copied += bytes_copied;
+    copy_summary.files_copied += 1;
+    copy_summary.copy_methods[copy_method] += 1;
+    Ok(copy_summary)
+}
+
+#[derive(Copy, Clone, Debug, Default)]
+pub struct CopySummary {
+    pub bytes_copied: u64,
+    pub files_copied: usize,
+    /// Files hard-linked from the reference directory (`link_dest`) instead of copied
+    pub files_linked: usize,
+    /// Files hard-linked to an identical file copied earlier (`dedup`) instead of copied
+    pub files_deduplicated: usize,
+    /// Size of the deduplicated files, i.e. the space saved by dedup
+    pub bytes_deduplicated: u64,
+    pub symlinks_created: usize,
+    pub directories_created: usize,
+    pub files_unchanged: usize,
+    pub symlinks_unchanged: usize,
+    pub directories_unchanged: usize,
+    /// Entries found while traversing a directory that were removed before we copied them
+    pub entries_vanished: usize,
+    /// Entries skipped because they didn't match the filters
+    pub entries_filtered: usize,
+    /// Entries not copied because their destination directory doesn't accept new entries
+    pub entries_skipped: usize,
+    /// Source entries skipped because we don't have permission to read them (`skip_unreadable`)
+    pub entries_unreadable: usize,
+    /// Files skipped because the destination is the same file as the source (e.g. a hard-link of it)
+    pub files_same: usize,
+    /// Overwritten destination entries kept under a backup name (`backup`) instead of removed
+    pub entries_backed_up: usize,
+    /// Special files (sockets, fifos and devices) recreated in the destination (`copy_special`)
+    pub special_created: usize,
+    /// Special files not copied because `copy_special` is not set
+    pub special_skipped: usize,
+    /// Number of files copied using each of the methods
+    pub copy_methods: enum_map::EnumMap<datacopy::CopyMethod, usize>,
+    pub rm_summary: RmSummary,
+}
+
+impl std::ops::Add for CopySummary {
+    type Output = Self;
+    fn add(self, other: Self) -> Self {
+        Self {
+            bytes_copied: self.bytes_copied + other.bytes_copied,
+            files_copied: self.files_copied + other.files_copied,
+            files_linked: self.files_linked + other.files_linked,
+            files_deduplicated: self.files_deduplicated + other.files_deduplicated,
+            bytes_deduplicated: self.bytes_deduplicated + other.bytes_deduplicated,
+            symlinks_created: self.symlinks_created + other.symlinks_created,
+            directories_created: self.directories_created + other.directories_created,
+            files_unchanged: self.files_unchanged + other.files_unchanged,
+            symlinks_unchanged: self.symlinks_unchanged + other.symlinks_unchanged,
+            directories_unchanged: self.directories_unchanged + other.directories_unchanged,
+            entries_vanished: self.entries_vanished + other.entries_vanished,
+            entries_filtered: self.entries_filtered + other.entries_filtered,
+            entries_skipped: self.entries_skipped + other.entries_skipped,
+            entries_unreadable: self.entries_unreadable + other.entries_unreadable,
+            files_same: self.files_same + other.files_same,
+            entries_backed_up: self.entries_backed_up + other.entries_backed_up,
+            special_created: self.special_created + other.special_created,
+            special_skipped: self.special_skipped + other.special_skipped,
+            copy_methods: enum_map::EnumMap::from_fn(|method| {
+                self.copy_methods[method] + other.copy_methods[method]
+            }),
+            rm_summary: self.rm_summary + other.rm_summary,
+        }
+    }
+}
+
+impl std::fmt::Display for CopySummary {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        writeln!(
+            f,
+            "bytes copied: {}\n\
+            files copied: {}\n\
+            symlinks created: {}\n\
+            directories created: {}\n\
+            files unchanged: {}\n\
+            symlinks unchanged: {}\n\
+            directories unchanged: {}",
+            progress::format_bytes(self.bytes_copied),
+            self.files_copied,
+            self.symlinks_created,
+            self.directories_created,
+            self.files_unchanged,
+            self.symlinks_unchanged,
+            self.directories_unchanged,
+        )?;
+        // the counters of optional features are only printed when they're used, to keep the summary short
+        if self.files_deduplicated > 0 {
+            writeln!(
+                f,
+                "files deduplicated: {} ({} saved)",
+                self.files_deduplicated,
+                progress::format_bytes(self.bytes_deduplicated)
+            )?;
+        }
+        for (name, count) in [
+            ("files hard-linked", self.files_linked),
+            ("entries vanished", self.entries_vanished),
+            ("entries filtered", self.entries_filtered),
+            ("entries skipped", self.entries_skipped),
+            ("entries unreadable", self.entries_unreadable),
+            ("files same as source", self.files_same),
+            ("entries backed up", self.entries_backed_up),
+            ("special files created", self.special_created),
+            ("special files skipped", self.special_skipped),
+            (
+                "files reflinked",
+                self.copy_methods[datacopy::CopyMethod::Reflink],
+            ),
+            (
+                "files copied with copy_file_range",
+                self.copy_methods[datacopy::CopyMethod::CopyFileRange],
+            ),
+            (
+                "files copied with read/write",
+                self.copy_methods[datacopy::CopyMethod::ReadWrite],
+            ),
+        ] {
+            if count > 0 {
+                writeln!(f, "{}: {}", name, count)?;
+            }
+        }
+        write!(f, "{}", &self.rm_summary)
+    }
+}
+
+async fn is_dir_empty(path: &std::path::Path) -> anyhow::Result<bool> {
+    let mut entries = tokio::fs::read_dir(path)
+        .await
+        .with_context(|| format!("cannot open directory {:?} for reading", path))?;
+    Ok(entries
+        .next_entry()
+        .await
+        .with_context(|| format!("failed traversing directory {:?}", path))?
+        .is_none())
+}
+
+/// Checks if a failure to copy was caused by the source getting removed after we found it in its parent directory
+async fn source_vanished(src: &std::path::Path) -> bool {
+    matches!(
+        tokio::fs::symlink_metadata(src).await,
+        Err(error) if error.kind() == std::io::ErrorKind::NotFound
+    )
+}
+
+/// Creates a special file (socket, fifo or device) of the same type as the source, devices can only be created by root
+async fn mknod(dst: &std::path::Path, src_metadata: &std::fs::Metadata) -> anyhow::Result<()> {
+    let dst = dst.to_owned();
+    let kind = nix::sys::stat::SFlag::from_bits_truncate(src_metadata.mode() & libc::S_IFMT);
+    let perm = nix::sys::stat::Mode::from_bits_truncate(src_metadata.mode() & 0o7777);
+    let rdev = src_metadata.rdev();
+    tokio::task::spawn_blocking(move || {
+        nix::sys::stat::mknod(&dst, kind, perm, rdev).map_err(std::io::Error::from)
+    })
+    .await??;
+    Ok(())
+}
+
+#[instrument(skip(prog_track))]
+async fn copy_special(
+    prog_track: &'static progress::Progress,
+    src_metadata: &std::fs::Metadata,
+    dst: &std::path::Path,
+    settings: &CopySettings,
+    preserve: &preserve::PreserveSettings,
+) -> Result<CopySummary, CopyError> {
+    let mut copy_summary = CopySummary::default();
+    let mut overwritten = None;
+    if let Err(error) = mknod(dst, src_metadata).await {
+        let exists = error
+            .downcast_ref::<std::io::Error>()
+            .is_some_and(|error| error.kind() == std::io::ErrorKind::AlreadyExists);
+        if !settings.overwrite || !exists {
+            return Err(CopyError::new(
+                error.context(format!("failed creating special file {:?}", dst)),
+                copy_summary,
+            ));
+        }
+        let dst_metadata = tokio::fs::symlink_metadata(dst)
+            .await
+            .with_context(|| format!("failed reading metadata from dst: {:?}", &dst))
+            .map_err(|err| CopyError::new(err, copy_summary))?;
+        // special files have no contents, they are always recreated
+        overwritten = Some(if is_file_type_same(src_metadata, &dst_metadata) {
+            vec![]
+        } else {
+            vec![filecmp::MetadataAttr::Type]
+        });
+        if let Some(backup) = &settings.backup {
+            make_backup(dst, backup, false)
+                .await
+                .map_err(|err| CopyError::new(err, copy_summary))?;
+            copy_summary.entries_backed_up = 1;
+        } else {
+            copy_summary.rm_summary = rm::rm(
+                prog_track,
+                dst,
+                &RmSettings {
+                    fail_early: settings.fail_early,
+                    filter: Default::default(),
+                    prune_empty_dirs: false,
+                },
+            )
+            .await
+            .map_err(|err| {
+                let copy_summary = CopySummary {
+                    rm_summary: err.summary,
+                    ..Default::default()
+                };
+                CopyError::new(err.source, copy_summary)
+            })?;
+        }
+        mknod(dst, src_metadata)
+            .await
+            .with_context(|| format!("failed creating special file {:?}", dst))
+            .map_err(|err| CopyError::new(err, copy_summary))?;
+    }
+    preserve::set_special_metadata(preserve, src_metadata, dst)
+        .await
+        .map_err(|err| CopyError::new(err, copy_summary))?;
+    itemize::report(
+        &settings.itemize,
+        ObjType::Other,
+        overwritten.map_or(ItemAction::Created, ItemAction::Overwritten),
+        dst,
+    );
+    copy_summary.special_created = 1;
+    Ok(copy_summary)
+}
+
+#[instrument(skip(prog_track))]
+#[async_recursion]
+pub async fn copy(
+    prog_track: &'static progress::Progress,
+    cwd: &std::path::Path,
+    src: &std::path::Path,
+    dst: &std::path::Path,
+    settings: &CopySettings,
+    preserve: &preserve::PreserveSettings,
+    mut is_fresh: bool,
+) -> Result<CopySummary, CopyError> {
+    throttle::get_token_for(&[src, dst]).await;
+    let _ops_guard = prog_track.ops.guard();
+    if let Some(limits) = &settings.limits {
+        limits
+            .check()
+            .map_err(|err| CopyError::new(err.into(), Default::default()))?;
+    }
+    if in_failed_subtree(&settings.copy_state, dst) {
+        event!(Level::DEBUG, "destination directory failed, skipping");
+        return Ok(CopySummary {
+            entries_skipped: 1,
+            ..Default::default()
+        });
+    }
+    event!(Level::DEBUG, "reading source metadata");
+    let src_metadata = match tokio::fs::symlink_metadata(src)
+        .await
+        .with_context(|| format!("failed reading metadata from src: {:?}", &src))
+    {
+        Ok(metadata) => metadata,
+        Err(error) if skip_unreadable(settings, src, &error) => return Ok(unreadable_summary()),
+        Err(error) => return Err(CopyError::new(error, Default::default())),
+    };
+    if (settings.dereference || settings.dereference_root) && src_metadata.is_symlink() {
+        let link = tokio::fs::read_link(&src)
+            .await
+            .with_context(|| format!("failed reading src symlink {:?}", &src))
+            .map_err(|err| CopyError::new(err, Default::default()))?;
+        let abs_link = if link.is_relative() {
+            cwd.join(link)
+        } else {
+            link
+        };
+        let new_cwd = abs_link
+            .parent()
+            .with_context(|| {
+                format!(
+                    "the source symlink {:?} does not have a parent directory",
+                    &src
+                )
+            })
+            .unwrap();
+        return copy(
+            prog_track, new_cwd, &abs_link, dst, settings, preserve, is_fresh,
+        )
+        .await;
+    }
+    if !settings.filter.matches_path(src, &src_metadata) {
+        event!(Level::DEBUG, "entry doesn't match the filters, skipping");
+        return Ok(CopySummary {
+            entries_filtered: 1,
+            ..Default::default()
+        });
+    }
+    if let Some(mode) = settings.metadata_only {
+        if !is_fresh {
+            if let Some(summary) = update_metadata_only(
+                prog_track,
+                src,
+                &src_metadata,
+                dst,
+                mode,
+                settings,
+                preserve,
+            )
+            .await?
+            {
+                return Ok(summary);
+            }
+        }
+    }
+    if src_metadata.is_file() {
+        return copy_file(prog_track, src, dst, settings, preserve, is_fresh).await;
+    }
+    if src_metadata.is_symlink() {
+        let mut rm_summary = RmSummary::default();
+        let mut backed_up = 0;
+        let mut overwritten = None;
+        let link = read_src_link(src, settings)
+            .await
+            .map_err(|err| CopyError::new(err, Default::default()))?;
+        // try creating a symlink, if dst path exists and overwrite is set - remove and try again
+        if let Err(error) = tokio::fs::symlink(&link, dst).await {
+            if settings.overwrite && error.kind() == std::io::ErrorKind::AlreadyExists {
+                let dst_metadata = tokio::fs::symlink_metadata(dst)
+                    .await
+                    .with_context(|| format!("failed reading metadata from dst: {:?}", &dst))
+                    .map_err(|err| CopyError::new(err, Default::default()))?;
+                if is_file_type_same(&src_metadata, &dst_metadata) {
+                    let dst_link = tokio::fs::read_link(dst)
+                        .await
+                        .with_context(|| format!("failed reading dst symlink: {:?}", &dst))
+                        .map_err(|err| CopyError::new(err, Default::default()))?;
+                    if link == dst_link {
+                        event!(
+                            Level::DEBUG,
+                            "'dst' is a symlink and points to the same location as 'src'"
+                        );
+                        if preserve.symlink.any() {
+                            // do we need to update the metadata for this symlink?
+                            let dst_metadata = tokio::fs::symlink_metadata(dst)
+                                .await
+                                .with_context(|| {
+                                    format!("failed reading metadata from dst: {:?}", &dst)
+                                })
+                                .map_err(|err| CopyError::new(err, Default::default()))?;
+                            if !filecmp::metadata_equal(
+                                &settings.overwrite_compare,
+                                &src_metadata,
+                                &dst_metadata,
+                            ) {
+                                event!(Level::DEBUG, "'dst' metadata is different, updating");
+                                preserve::set_symlink_metadata(preserve, &src_metadata, dst)
+                                    .await
+                                    .map_err(|err| CopyError::new(err, Default::default()))?;
+                                itemize::report(
+                                    &settings.itemize,
+                                    ObjType::Symlink,
+                                    ItemAction::MetadataUpdated,
+                                    dst,
+                                );
+                                prog_track.symlinks_removed.inc();
+                                prog_track.symlinks_created.inc();
+                                return Ok(CopySummary {
+                                    rm_summary: RmSummary {
+                                        symlinks_removed: 1,
+                                        ..Default::default()
+                                    },
+                                    symlinks_created: 1,
+                                    ..Default::default()
+                                });
+                            }
+                        }
+                        event!(Level::DEBUG, "symlink already exists, skipping");
+                        itemize::report(
+                            &settings.itemize,
+                            ObjType::Symlink,
+                            ItemAction::Unchanged,
+                            dst,
+                        );
+                        prog_track.symlinks_unchanged.inc();
+                        return Ok(CopySummary {
+                            symlinks_unchanged: 1,
+                            ..Default::default()
+                        });
+                    }
+                    event!(
+                        Level::DEBUG,
+                        "'dst' is a symlink but points to a different path, updating"
+                    );
+                    overwritten = Some(vec![]);
+                } else {
+                    event!(Level::INFO, "'dst' is not a symlink, updating");
+                    overwritten = Some(vec![filecmp::MetadataAttr::Type]);
+                }
+                if let Some(backup) = &settings.backup {
+                    make_backup(dst, backup, false)
+                        .await
+                        .map_err(|err| CopyError::new(err, Default::default()))?;
+                    backed_up = 1;
+                } else {
+                    rm_summary = rm::rm(
+                        prog_track,
+                        dst,
+                        &RmSettings {
+                            fail_early: settings.fail_early,
+                            filter: Default::default(),
+                            prune_empty_dirs: false,
+                        },
+                    )
+                    .await
+                    .map_err(|err| {
+                        let rm_summary = err.summary;
+                        let copy_summary = CopySummary {
+                            rm_summary,
+                            ..Default::default()
+                        };
+                        CopyError::new(err.source, copy_summary)
+                    })?;
+                }
+                tokio::fs::symlink(&link, dst)
+                    .await
+                    .with_context(|| format!("failed creating symlink {:?}", &dst))
+                    .map_err(|err| {
+                        let copy_summary = CopySummary {
+                            rm_summary,
+                            entries_backed_up: backed_up,
+                            ..Default::default()
+                        };
+                        CopyError::new(err, copy_summary)
+                    })?;
+            } else {
+                return Err(CopyError::new(
+                    anyhow!("failed creating symlink {:?}", &dst),
+                    Default::default(),
+                ));
+            }
+        }
+        preserve::set_symlink_metadata(preserve, &src_metadata, dst)
+            .await
+            .map_err(|err| {
+                let copy_summary = CopySummary {
+                    rm_summary,
+                    entries_backed_up: backed_up,
+                    ..Default::default()
+                };
+                CopyError::new(err, copy_summary)
+            })?;
+        itemize::report(
+            &settings.itemize,
+            ObjType::Symlink,
+            overwritten.map_or(ItemAction::Created, ItemAction::Overwritten),
+            dst,
+        );
+        prog_track.symlinks_created.inc();
+        return Ok(CopySummary {
+            rm_summary,
+            entries_backed_up: backed_up,
+            symlinks_created: 1,
+            ..Default::default()
+        });
+    }
+    if !src_metadata.is_dir() {
+        if !settings.copy_special {
+            event!(
+                Level::INFO,
+                "{:?} is a special file ({:?}), skipping",
+                src,
+                src_metadata.file_type()
+            );
+            return Ok(CopySummary {
+                special_skipped: 1,
+                ..Default::default()
+            });
+        }
+        return copy_special(prog_track, &src_metadata, dst, settings, preserve).await;
+    }
+    if settings.exclude_dst_dirs
+        && settings
+            .copy_state
+            .dst_dirs
+            .lock()
+            .unwrap()
+            .contains(&(src_metadata.dev(), src_metadata.ino()))
+    {
+        event!(
+            Level::INFO,
+            "{:?} is a destination directory of this copy, skipping",
+            src
+        );
+        return Ok(Default::default());
+    }
+    if settings
+        .checkpoint
+        .as_ref()
+        .is_some_and(|checkpoint| checkpoint.is_completed(src, dst))
+    {
+        event!(
+            Level::DEBUG,
+            "directory was copied completely by a previous run, skipping"
+        );
+        prog_track.directories_unchanged.inc();
+        return Ok(CopySummary {
+            directories_unchanged: 1,
+            ..Default::default()
+        });
+    }
+    event!(Level::DEBUG, "process contents of 'src' directory");
+    let _in_flight = prog_track.in_flight.dir(src);
+    let mut entries = match tokio::fs::read_dir(src)
+        .await
+        .with_context(|| format!("cannot open directory {:?} for reading", src))
+    {
+        Ok(entries) => entries,
+        Err(error) if skip_unreadable(settings, src, &error) => return Ok(unreadable_summary()),
+        Err(error) => return Err(CopyError::new(error, Default::default())),
+    };
+    let mut copy_summary = {
+        if let Err(error) = tokio::fs::create_dir(dst).await {
+            assert!(!is_fresh, "unexpected error creating directory: {:?}", &dst);
+            if (settings.overwrite || settings.metadata_only.is_some())
+                && error.kind() == std::io::ErrorKind::AlreadyExists
+            {
+                // check if the destination is a directory - if so, leave it
+                //
+                // N.B. the permissions may prevent us from writing to it but the alternative is to open up the directory
+                // while we're writing to it which isn't safe
+                let dst_metadata = tokio::fs::metadata(dst)
+                    .await
+                    .with_context(|| format!("failed reading metadata from dst: {:?}", &dst))
+                    .map_err(|err| CopyError::new(err, Default::default()))?;
+                if dst_metadata.is_dir() {
+                    event!(Level::DEBUG, "'dst' is a directory, leaving it as is");
+                    if settings.atomic_writes {
+                        remove_stale_tmp_files(dst)
+                            .await
+                            .map_err(|err| CopyError::new(err, Default::default()))?;
+                    }
+                    itemize::report(&settings.itemize, ObjType::Dir, ItemAction::Unchanged, dst);
+                    prog_track.directories_unchanged.inc();
+                    CopySummary {
+                        directories_unchanged: 1,
+                        ..Default::default()
+                    }
+                } else {
+                    event!(
+                        Level::INFO,
+                        "'dst' is not a directory, removing and creating a new one"
+                    );
+                    let mut backed_up = 0;
+                    let rm_summary = if let Some(backup) = &settings.backup {
+                        make_backup(dst, backup, false)
+                            .await
+                            .map_err(|err| CopyError::new(err, Default::default()))?;
+                        backed_up = 1;
+                        Default::default()
+                    } else {
+                        rm::rm(
+                            prog_track,
+                            dst,
+                            &RmSettings {
+                                fail_early: settings.fail_early,
+                                filter: Default::default(),
+                                prune_empty_dirs: false,
+                            },
+                        )
+                        .await
+                        .map_err(|err| {
+                            let rm_summary = err.summary;
+                            let copy_summary = CopySummary {
+                                rm_summary,
+                                ..Default::default()
+                            };
+                            CopyError::new(err.source, copy_summary)
+                        })?
+                    };
+                    tokio::fs::create_dir(dst)
+                        .await
+                        .with_context(|| format!("cannot create directory {:?}", dst))
+                        .map_err(|err| {
+                            let copy_summary = CopySummary {
+                                rm_summary,
+                                entries_backed_up: backed_up,
+                                ..Default::default()
+                            };
+                            CopyError::new(anyhow::Error::msg(err), copy_summary)
+                        })?;
+                    // anythingg copied into dst may assume they don't need to check for conflicts
+                    is_fresh = true;
+                    itemize::report(
+                        &settings.itemize,
+                        ObjType::Dir,
+                        ItemAction::Overwritten(vec![filecmp::MetadataAttr::Type]),
+                        dst,
+                    );
+                    prog_track.directories_created.inc();
+                    CopySummary {
+                        rm_summary,
+                        entries_backed_up: backed_up,
+                        directories_created: 1,
+                        ..Default::default()
+                    }
+                }
+            } else {
+                let error =
+                    anyhow::Error::new(error).context(format!("cannot create directory {:?}", dst));
+                mark_failed_subtree(&settings.copy_state, dst, &error);
+                return Err(CopyError::new(error, Default::default()));
+            }
+        } else {
+            // new directory created, anythingg copied into dst may assume they don't need to check for conflicts
+            is_fresh = true;
+            itemize::report(&settings.itemize, ObjType::Dir, ItemAction::Created, dst);
+            prog_track.directories_created.inc();
+            CopySummary {
+                directories_created: 1,
+                ..Default::default()
+            }
+        }
+    };
+    if settings.exclude_dst_dirs {
+        let dst_metadata = tokio::fs::metadata(dst)
+            .await
+            .with_context(|| format!("failed reading metadata from dst: {:?}", &dst))
+            .map_err(|err| CopyError::new(err, copy_summary))?;
+        settings
+            .copy_state
+            .dst_dirs
+            .lock()
+            .unwrap()
+            .insert((dst_metadata.dev(), dst_metadata.ino()));
+    }
+    let entries_filter = settings
+        .filter
+        .enter_dir(src)
+        .await
+        .map_err(|err| CopyError::new(err, copy_summary))?;
+    let mut join_set = tokio::task::JoinSet::new();
+    let mut success = true;
+    while let Some(entry) = entries
+        .next_entry()
+        .await
+        .with_context(|| format!("failed traversing src directory {:?}", &src))
+        .map_err(|err| CopyError::new(err, copy_summary))?
+    {
+        let cwd_path = src.to_owned();
+        let entry_path = entry.path();
+        let entry_name = entry_path.file_name().unwrap();
+        if settings
+            .state_dir
+            .as_ref()
+            .is_some_and(|state_dir| state_dir.is_same(&entry_path))
+        {
+            event!(
+                Level::INFO,
+                "{:?} is the state directory of the destination, skipping",
+                &entry_path
+            );
+            continue;
+        }
+        let dst_path = dst.join(entry_name);
+        let mut settings = settings.clone();
+        settings.dereference_root = false;
+        if let Some(filter) = &entries_filter {
+            settings.filter = filter.clone();
+        }
+        settings.link_dest = settings.link_dest.map(|path| path.join(entry_name));
+        settings.compare_dest = settings.compare_dest.map(|path| path.join(entry_name));
+        let preserve = preserve.clone();
+        let do_copy = || async move {
+            let res = copy(
+                prog_track,
+                &cwd_path,
+                &entry_path,
+                &dst_path,
+                &settings,
+                &preserve,
+                is_fresh,
+            )
+            .await;
+            match res {
+                Err(error) if source_vanished(&entry_path).await => {
+                    event!(
+                        Level::WARN,
+                        "{:?} vanished during the copy: {}",
+                        &entry_path,
+                        &error
+                    );
+                    Ok(error.summary
+                        + CopySummary {
+                            entries_vanished: 1,
+                            ..Default::default()
+                        })
+                }
+                res => res,
+            }
+        };
+        join_set.spawn(do_copy());
+    }
+    let entries_skipped = copy_summary.entries_skipped;
+    let mut subtree_failures = 0;
+    let mut limit_error = None;
+    while let Some(res) = join_set.join_next().await {
+        match res {
+            Ok(result) => match result {
+                Ok(summary) => copy_summary = copy_summary + summary,
+                Err(error) => {
+                    if is_limit_exceeded(&error.source) {
+                        // the other entries fail the same way, the copies in flight are left to finish
+                        copy_summary = copy_summary + error.summary;
+                        limit_error.get_or_insert(error.source);
+                        continue;
+                    }
+                    // failures caused by 'dst' not accepting new entries are reported once, below
+                    if subtree_failure_errno(&error.source).is_some()
+                        && settings
+                            .copy_state
+                            .failed_subtrees
+                            .lock()
+                            .unwrap()
+                            .contains_key(dst)
+                    {
+                        subtree_failures += 1;
+                    } else {
+                        event!(
+                            Level::ERROR,
+                            "copy: {:?} -> {:?} failed with: {}",
+                            src,
+                            dst,
+                            &error
+                        );
+                    }
+                    copy_summary = copy_summary + error.summary;
+                    if settings.fail_early {
+                        return Err(CopyError::new(error.source, copy_summary));
+                    }
+                    success = false;
+                }
+            },
+            Err(error) => {
+                if settings.fail_early {
+                    return Err(CopyError::new(anyhow::Error::msg(error), copy_summary));
+                }
+            }
+        }
+    }
+    if let Some(cause) = settings.copy_state.failed_subtrees.lock().unwrap().get(dst) {
+        event!(
+            Level::ERROR,
+            "copy: cannot create entries in {:?}: {}, {} entries failed and {} were skipped",
+            dst,
+            cause,
+            subtree_failures,
+            copy_summary.entries_skipped - entries_skipped
+        );
+    }
+    if let Some(error) = limit_error {
+        return Err(CopyError::new(error, copy_summary));
+    }
+    if !success {
+        return Err(CopyError::new(
+            anyhow!("copy: {:?} -> {:?} failed!", src, dst),
+            copy_summary,
+        ))?;
+    }
+    // is_fresh is set if we created the 'dst' directory
+    if settings.prune_empty_dirs
+        && is_fresh
+        && copy_summary.entries_filtered > 0
+        && is_dir_empty(dst)
+            .await
+            .map_err(|err| CopyError::new(err, copy_summary))?
+    {
+        event!(
+            Level::DEBUG,
+            "all entries were filtered out, removing the empty 'dst' directory"
+        );
+        tokio::fs::remove_dir(dst)
+            .await
+            .with_context(|| format!("cannot remove empty directory {:?}", dst))
+            .map_err(|err| CopyError::new(err, copy_summary))?;
+        copy_summary.directories_created -= 1;
+        return Ok(copy_summary);
+    }
+    event!(Level::DEBUG, "set 'dst' directory metadata");
+    preserve::set_dir_metadata(preserve, &src_metadata, dst)
+        .await
+        .map_err(|err| CopyError::new(err, copy_summary))?;
+    if preserve.flags {
+        preserve::set_flags(src, dst)
+            .await
+            .map_err(|err| CopyError::new(err, copy_summary))?;
+    }
+    if let Some(checkpoint) = &settings.checkpoint {
+        checkpoint.record(src, dst);
+    }
+    Ok(copy_summary)
+}
+
+#[cfg(test)]
+mod copy_tests {
+    use crate::testutils;
+    use anyhow::Context;
+    use std::os::unix::fs::PermissionsExt;
+    use tracing_test::traced_test;
+
+    use super::*;
+
+    lazy_static! {
+        static ref PROGRESS: progress::Progress = progress::Progress::new();
+        static ref NO_PRESERVE_SETTINGS: preserve::PreserveSettings = preserve::preserve_default();
+        static ref DO_PRESERVE_SETTINGS: preserve::PreserveSettings = preserve::preserve_all();
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn check_basic_copy() -> Result<(), anyhow::Error> {
+        let tmp_dir = testutils::setup_test_dir().await?;
+        let test_path = tmp_dir.as_path();
+        let summary = copy(
+            &PROGRESS,
+            test_path,
+            &test_path.join("foo"),
+            &test_path.join("bar"),
+            &CopySettings {
+                dereference: false,
+                fail_early: false,
+                overwrite: false,
+                overwrite_compare: filecmp::MetadataCmpSettings {
+                    size: true,
+                    mtime: true,
+                    ..Default::default()
+                },
+                ..Default::default()
+            },
+            &NO_PRESERVE_SETTINGS,
+            false,
+        )
+        .await?;
+        assert_eq!(summary.files_copied, 5);
+        assert_eq!(summary.symlinks_created, 2);
+        assert_eq!(summary.directories_created, 3);
+        testutils::check_dirs_identical(
+            &test_path.join("foo"),
+            &test_path.join("bar"),
+            testutils::FileEqualityCheck::Basic,
+        )
+        .await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn check_dir_mtime_preserved() -> Result<(), anyhow::Error> {
+        let tmp_dir = testutils::setup_test_dir().await?;
+        let test_path = tmp_dir.as_path();
+        // writing the entries would update the directory mtime if it was set before them
+        let mtime = std::time::UNIX_EPOCH + std::time::Duration::new(1_000_000_000, 123_456_789);
+        for dir in [
+            test_path.join("foo"),
+            test_path.join("foo").join("bar"),
+            test_path.join("foo").join("baz"),
+        ] {
+            std::fs::File::open(&dir)?.set_times(std::fs::FileTimes::new().set_modified(mtime))?;
+        }
+        copy(
+            &PROGRESS,
+            test_path,
+            &test_path.join("foo"),
+            &test_path.join("bar"),
+            &CopySettings {
+                dereference: false,
+                fail_early: false,
+                overwrite: false,
+                overwrite_compare: Default::default(),
+                ..Default::default()
+            },
+            &DO_PRESERVE_SETTINGS,
+            false,
+        )
+        .await?;
+        for dir in [
+            test_path.join("bar"),
+            test_path.join("bar").join("bar"),
+            test_path.join("bar").join("baz"),
+        ] {
+            assert_eq!(tokio::fs::metadata(&dir).await?.modified()?, mtime);
+        }
+        testutils::check_dirs_identical(
+            &test_path.join("foo"),
+            &test_path.join("bar"),
+            testutils::FileEqualityCheck::Timestamp,
+        )
+        .await?;
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    #[traced_test]
+    async fn check_entries_vanishing_during_copy() -> Result<(), anyhow::Error> {
+        let tmp_dir = testutils::create_temp_dir().await?;
+        let src = tmp_dir.join("src");
+        tokio::fs::create_dir(&src).await?;
+        let num_files = 1000;
+        for i in 0..num_files {
+            tokio::fs::write(src.join(format!("{}.txt", i)), format!("{}", i)).await?;
+        }
+        // remove files concurrently with the copy, the ones removed before we open them are reported as vanished
+        // (unless they were removed even before we found them while traversing the directory)
+        let remover = {
+            let src = src.clone();
+            tokio::spawn(async move {
+                for i in (0..num_files).rev() {
+                    tokio::fs::remove_file(src.join(format!("{}.txt", i))).await?;
+                }
+                Ok::<(), anyhow::Error>(())
+            })
+        };
+        let summary = copy(
+            &PROGRESS,
+            &tmp_dir,
+            &src,
+            &tmp_dir.join("dst"),
+            &CopySettings {
+                dereference: false,
+                fail_early: true,
+                overwrite: false,
+                overwrite_compare: Default::default(),
+                ..Default::default()
+            },
+            &NO_PRESERVE_SETTINGS,
+            false,
+        )
+        .await?;
+        remover.await??;
+        assert!(summary.files_copied + summary.entries_vanished <= num_files);
+        assert_eq!(summary.directories_created, 1);
+        let mut dst_entries = tokio::fs::read_dir(tmp_dir.join("dst")).await?;
+        let mut num_dst_files = 0;
+        while let Some(entry) = dst_entries.next_entry().await? {
+            let name = entry.file_name();
+            let idx = name.to_str().unwrap().strip_suffix(".txt").unwrap();
+            assert_eq!(tokio::fs::read_to_string(entry.path()).await?, idx);
+            num_dst_files += 1;
+        }
+        assert_eq!(num_dst_files, summary.files_copied);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn check_same_root() -> Result<(), anyhow::Error> {
+        let tmp_dir = testutils::setup_test_dir().await?;
+        let foo = tmp_dir.join("foo");
+        tokio::fs::symlink(&foo, tmp_dir.join("alias")).await?;
+        tokio::fs::hard_link(foo.join("0.txt"), tmp_dir.join("0.txt")).await?;
+        assert!(is_same_root(&foo, &foo, false)?);
+        assert!(is_same_root(&foo, &foo.join("bar").join(".."), false)?);
+        assert!(is_same_root(
+            &foo.join("0.txt"),
+            &tmp_dir.join("0.txt"),
+            false
+        )?);
+        assert!(is_same_root(&tmp_dir.join("alias"), &foo, true)?);
+        // the symlink itself is copied, and a symlink destination is replaced, not followed
+        assert!(!is_same_root(&tmp_dir.join("alias"), &foo, false)?);
+        assert!(!is_same_root(&foo, &tmp_dir.join("alias"), true)?);
+        assert!(!is_same_root(&foo, &foo.join("bar"), false)?);
+        assert!(!is_same_root(&foo, &tmp_dir.join("missing"), false)?);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn check_dst_inside_src() -> Result<(), anyhow::Error> {
+        let tmp_dir = testutils::setup_test_dir().await?;
+        let foo = tmp_dir.join("foo");
+        // foo/bar/parent -> foo
+        tokio::fs::symlink(&foo, foo.join("bar").join("parent")).await?;
+        assert!(is_dst_inside_src(&foo, &foo, false)?);
+        assert!(is_dst_inside_src(&foo, &foo.join("bar"), false)?);
+        assert!(is_dst_inside_src(
+            &foo,
+            &foo.join("new").join("dir"),
+            false
+        )?);
+        assert!(is_dst_inside_src(
+            &foo.join("bar"),
+            &foo.join("bar").join("parent").join("bar").join("new"),
+            false
+        )?);
+        assert!(!is_dst_inside_src(&foo, &tmp_dir.join("foo2"), false)?);
+        assert!(!is_dst_inside_src(
+            &foo.join("bar"),
+            &foo.join("baz"),
+            false
+        )?);
+        // files and (not dereferenced) symlinks can't be copied into themselves
+        assert!(!is_dst_inside_src(
+            &foo.join("0.txt"),
+            &foo.join("0.txt"),
+            false
+        )?);
+        assert!(!is_dst_inside_src(
+            &foo.join("bar").join("parent"),
+            &foo.join("new"),
+            false
+        )?);
+        assert!(is_dst_inside_src(
+            &foo.join("bar").join("parent"),
+            &foo.join("new"),
+            true
+        )?);
+        Ok(())
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn check_copy_into_itself() -> Result<(), anyhow::Error> {
+        let tmp_dir = testutils::setup_test_dir().await?;
+        let foo = tmp_dir.join("foo");
+        let dst = foo.join("baz").join("backup");
+        let summary = copy(
+            &PROGRESS,
+            &tmp_dir,
+            &foo,
+            &dst,
+            &CopySettings {
+                dereference: false,
+                fail_early: true,
+                overwrite: false,
+                overwrite_compare: Default::default(),
+                exclude_dst_dirs: true, // <- important!
+                ..Default::default()
+            },
+            &NO_PRESERVE_SETTINGS,
+            false,
+        )
+        .await?;
+        // the backup directory itself is not copied if we come across it while traversing foo
+        assert_eq!(summary.files_copied, 5);
+        assert_eq!(summary.symlinks_created, 2);
+        assert_eq!(summary.directories_created, 3);
+        assert!(!dst.join("baz").join("backup").exists());
+        Ok(())
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn check_filters_and_prune_empty_dirs() -> Result<(), anyhow::Error> {
+        let tmp_dir = testutils::setup_test_dir().await?;
+        let test_path = tmp_dir.as_path();
+        tokio::fs::write(
+            test_path.join("foo").join("bar").join("big.txt"),
+            "0123456789",
+        )
+        .await?;
+        let summary = copy(
+            &PROGRESS,
+            test_path,
+            &test_path.join("foo"),
+            &test_path.join("bar"),
+            &CopySettings {
+                dereference: false,
+                fail_early: false,
+                overwrite: false,
+                overwrite_compare: Default::default(),
+                filter: crate::parse_filter_settings(Some("5B"), None, Some("f"))?,
+                prune_empty_dirs: true,
+                ..Default::default()
+            },
+            &NO_PRESERVE_SETTINGS,
+            false,
+        )
+        .await?;
+        // only bar/big.txt matches, the baz directory (with 4.txt and 2 symlinks) ends up empty and is pruned
+        assert_eq!(summary.files_copied, 1);
+        assert_eq!(summary.symlinks_created, 0);
+        assert_eq!(summary.directories_created, 2);
+        assert_eq!(summary.entries_filtered, 7);
+        assert_eq!(
+            tokio::fs::read_to_string(test_path.join("bar").join("bar").join("big.txt")).await?,
+            "0123456789"
+        );
+        assert!(!test_path.join("bar").join("bar").join("1.txt").exists());
+        assert!(!test_path.join("bar").join("baz").exists());
+        Ok(())
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn no_read_permission() -> Result<(), anyhow::Error> {
+        let tmp_dir = testutils::setup_test_dir().await?;
+        let test_path = tmp_dir.as_path();
+        let filepaths = vec![
+            test_path.join("foo").join("0.txt"),
+            test_path.join("foo").join("baz"),
+        ];
+        for fpath in &filepaths {
+            // change file permissions to not readable
+            tokio::fs::set_permissions(&fpath, std::fs::Permissions::from_mode(0o000)).await?;
+        }
+        match copy(
+            &PROGRESS,
+            test_path,
+            &test_path.join("foo"),
+            &test_path.join("bar"),
+            &CopySettings {
+                dereference: false,
+                fail_early: false,
+                overwrite: false,
+                overwrite_compare: filecmp::MetadataCmpSettings {
+                    size: true,
+                    mtime: true,
+                    ..Default::default()
+                },
+                ..Default::default()
+            },
+            &NO_PRESERVE_SETTINGS,
+            false,
+        )
+        .await
+        {
+            Ok(_) => panic!("Expected the copy to error!"),
+            Err(error) => {
+                event!(Level::INFO, "{}", &error);
+                // foo
+                // |- 0.txt  // <- no read permission
+                // |- bar
+                //    |- 1.txt
+                //    |- 2.txt
+                //    |- 3.txt
+                // |- baz   // <- no read permission
+                //    |- 4.txt
+                //    |- 5.txt -> ../bar/2.txt
+                //    |- 6.txt -> (absolute path) .../foo/bar/3.txt
+                assert_eq!(error.summary.files_copied, 3);
+                assert_eq!(error.summary.symlinks_created, 0);
+                assert_eq!(error.summary.directories_created, 2);
+            }
+        }
+        // make source directory same as what we expect destination to be
+        for fpath in &filepaths {
+            tokio::fs::set_permissions(&fpath, std::fs::Permissions::from_mode(0o700)).await?;
+            if tokio::fs::symlink_metadata(fpath).await?.is_file() {
+                tokio::fs::remove_file(fpath).await?;
+            } else {
+                tokio::fs::remove_dir_all(fpath).await?;
+            }
+        }
+        testutils::check_dirs_identical(
+            &test_path.join("foo"),
+            &test_path.join("bar"),
+            testutils::FileEqualityCheck::Basic,
+        )
+        .await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn skip_unreadable_entries() -> Result<(), anyhow::Error> {
+        let tmp_dir = testutils::setup_test_dir().await?;
+        let test_path = tmp_dir.as_path();
+        let filepaths = vec![
+            test_path.join("foo").join("0.txt"),
+            test_path.join("foo").join("baz"),
+        ];
+        for fpath in &filepaths {
+            tokio::fs::set_permissions(&fpath, std::fs::Permissions::from_mode(0o000)).await?;
+        }
+        let summary = copy(
+            &PROGRESS,
+            test_path,
+            &test_path.join("foo"),
+            &test_path.join("bar"),
+            &CopySettings {
+                dereference: false,
+                fail_early: true,
+                overwrite: false,
+                overwrite_compare: Default::default(),
+                skip_unreadable: true, // <- important!
+                ..Default::default()
+            },
+            &NO_PRESERVE_SETTINGS,
+            false,
+        )
+        .await?;
+        assert_eq!(summary.entries_unreadable, 2);
+        assert_eq!(summary.files_copied, 3);
+        assert_eq!(summary.directories_created, 2);
+        assert!(!test_path.join("bar").join("0.txt").exists());
+        assert!(!test_path.join("bar").join("baz").exists());
+        for fpath in &filepaths {
+            tokio::fs::set_permissions(&fpath, std::fs::Permissions::from_mode(0o700)).await?;
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn check_copy_limits() -> Result<(), anyhow::Error> {
+        let tmp_dir = testutils::setup_test_dir().await?;
+        let test_path = tmp_dir.as_path();
+        let settings = |limits: CopyLimits| CopySettings {
+            dereference: false,
+            fail_early: false,
+            overwrite: false,
+            overwrite_compare: Default::default(),
+            limits: Some(std::sync::Arc::new(limits)),
+            ..Default::default()
+        };
+        for (limits, max_copied) in [
+            (CopyLimits::new(Some(3), None), 3),
+            (CopyLimits::new(None, Some(2)), 2),
+        ] {
+            let dst = test_path.join(format!("bar{}", max_copied));
+            let error = copy(
+                &PROGRESS,
+                test_path,
+                &test_path.join("foo"),
+                &dst,
+                &settings(limits),
+                &NO_PRESERVE_SETTINGS,
+                false,
+            )
+            .await
+            .unwrap_err();
+            assert!(is_limit_exceeded(&error.source), "{:#}", &error.source);
+            assert_eq!(error.summary.files_copied, max_copied);
+            let mut copied = 0;
+            let mut dirs = vec![dst];
+            while let Some(dir) = dirs.pop() {
+                for entry in std::fs::read_dir(dir)? {
+                    let entry = entry?;
+                    if entry.file_type()?.is_dir() {
+                        dirs.push(entry.path());
+                    } else if entry.file_type()?.is_file() {
+                        copied += 1;
+                    }
+                }
+            }
+            assert_eq!(copied, max_copied);
+        }
+        // nothing is aborted if the copy fits within the limits
+        let summary = copy(
+            &PROGRESS,
+            test_path,
+            &test_path.join("foo"),
+            &test_path.join("baz"),
+            &settings(CopyLimits::new(Some(5), Some(5))),
+            &NO_PRESERVE_SETTINGS,
+            false,
+        )
+        .await?;
+        assert_eq!(summary.files_copied, 5);
+        Ok(())
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn check_default_mode() -> Result<(), anyhow::Error> {
+        let tmp_dir = testutils::setup_test_dir().await?;
+        // set file to executable
+        tokio::fs::set_permissions(
+            tmp_dir.join("foo").join("0.txt"),
+            std::fs::Permissions::from_mode(0o700),
+        )
+        .await?;
+        // set file executable AND also set sticky bit, setuid and setgid
+        let exec_sticky_file = tmp_dir.join("foo").join("bar").join("1.txt");
+        tokio::fs::set_permissions(&exec_sticky_file, std::fs::Permissions::from_mode(0o3770))
+            .await?;
+        let test_path = tmp_dir.as_path();
+        let summary = copy(
+            &PROGRESS,
+            test_path,
+            &test_path.join("foo"),
+            &test_path.join("bar"),
+            &CopySettings {
+                dereference: false,
+                fail_early: false,
+                overwrite: false,
+                overwrite_compare: filecmp::MetadataCmpSettings {
+                    size: true,
+                    mtime: true,
+                    ..Default::default()
+                },
+                ..Default::default()
+            },
+            &NO_PRESERVE_SETTINGS,
+            false,
+        )
+        .await?;
+        assert_eq!(summary.files_copied, 5);
+        assert_eq!(summary.symlinks_created, 2);
+        assert_eq!(summary.directories_created, 3);
+        // clear the setuid, setgid and sticky bit for comparison
+        tokio::fs::set_permissions(
+            &exec_sticky_file,
+            std::fs::Permissions::from_mode(
+                std::fs::symlink_metadata(&exec_sticky_file)?
+                    .permissions()
+                    .mode()
+                    & 0o0777,
+            ),
+        )
+        .await?;
+        testutils::check_dirs_identical(
+            &test_path.join("foo"),
+            &test_path.join("bar"),
+            testutils::FileEqualityCheck::Basic,
+        )
+        .await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn no_write_permission() -> Result<(), anyhow::Error> {
+        let tmp_dir = testutils::setup_test_dir().await?;
+        let test_path = tmp_dir.as_path();
+        // directory - readable and non-executable
+        let non_exec_dir = test_path.join("foo").join("bogey");
+        tokio::fs::create_dir(&non_exec_dir).await?;
+        tokio::fs::set_permissions(&non_exec_dir, std::fs::Permissions::from_mode(0o400)).await?;
+        // directory - readable and executable
+        tokio::fs::set_permissions(
+            &test_path.join("foo").join("baz"),
+            std::fs::Permissions::from_mode(0o500),
+        )
+        .await?;
+        // file
+        tokio::fs::set_permissions(
+            &test_path.join("foo").join("baz").join("4.txt"),
+            std::fs::Permissions::from_mode(0o440),
+        )
+        .await?;
+        let summary = copy(
+            &PROGRESS,
+            test_path,
+            &test_path.join("foo"),
+            &test_path.join("bar"),
+            &CopySettings {
+                dereference: false,
+                fail_early: false,
+                overwrite: false,
+                overwrite_compare: filecmp::MetadataCmpSettings {
+                    size: true,
+                    mtime: true,
+                    ..Default::default()
+                },
+                ..Default::default()
+            },
+            &NO_PRESERVE_SETTINGS,
+            false,
+        )
+        .await?;
+        assert_eq!(summary.files_copied, 5);
+        assert_eq!(summary.symlinks_created, 2);
+        assert_eq!(summary.directories_created, 4);
+        testutils::check_dirs_identical(
+            &test_path.join("foo"),
+            &test_path.join("bar"),
+            testutils::FileEqualityCheck::Basic,
+        )
+        .await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn no_write_permission_in_dst() -> Result<(), anyhow::Error> {
+        let tmp_dir = setup_test_dir_and_copy().await?;
+        let test_path = tmp_dir.as_path();
+        for i in 0..20 {
+            tokio::fs::write(
+                test_path
+                    .join("foo")
+                    .join("baz")
+                    .join(format!("new-{}.txt", i)),
+                "new",
+            )
+            .await?;
+        }
+        tokio::fs::create_dir(test_path.join("foo").join("baz").join("qux")).await?;
+        tokio::fs::set_permissions(
+            &test_path.join("bar").join("baz"),
+            std::fs::Permissions::from_mode(0o555),
+        )
+        .await?;
+        let error = copy(
+            &PROGRESS,
+            test_path,
+            &test_path.join("foo"),
+            &test_path.join("bar"),
+            &CopySettings {
+                dereference: false,
+                fail_early: false,
+                overwrite: true,
+                overwrite_compare: filecmp::MetadataCmpSettings {
+                    size: true,
+                    mtime: true,
+                    ..Default::default()
+                },
+                ..Default::default()
+            },
+            &DO_PRESERVE_SETTINGS,
+            false,
+        )
+        .await
+        .expect_err("copying into a read-only directory should fail");
+        tokio::fs::set_permissions(
+            &test_path.join("bar").join("baz"),
+            std::fs::Permissions::from_mode(0o755),
+        )
+        .await?;
+        assert_eq!(error.summary.files_copied, 0);
+        assert!(error.summary.files_unchanged >= 4);
+        assert!(error.summary.entries_skipped > 0);
+        // each new entry either failed or was skipped, the failures are reported once
+        logs_assert(|lines: &[&str]| {
+            let aggregated = lines
+                .iter()
+                .filter(|line| line.contains("cannot create entries in"))
+                .collect::<Vec<_>>();
+            if aggregated.len() != 1 {
+                return Err(format!(
+                    "expected one aggregated error, got: {:?}",
+                    aggregated
+                ));
+            }
+            if lines
+                .iter()
+                .any(|line| line.contains("failed with: failed copying"))
+            {
+                return Err("unexpected error reported for a single file".to_string());
+            }
+            Ok(())
+        });
+        Ok(())
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn dereference() -> Result<(), anyhow::Error> {
+        let tmp_dir = testutils::setup_test_dir().await?;
+        let test_path = tmp_dir.as_path();
+        // make files pointed to by symlinks have different permissions than the symlink itself
+        let src1 = &test_path.join("foo").join("bar").join("2.txt");
+        let src2 = &test_path.join("foo").join("bar").join("3.txt");
+        let test_mode = 0o440;
+        for f in [src1, src2] {
+            tokio::fs::set_permissions(f, std::fs::Permissions::from_mode(test_mode)).await?;
+        }
+        let summary = copy(
+            &PROGRESS,
+            test_path,
+            &test_path.join("foo"),
+            &test_path.join("bar"),
+            &CopySettings {
+                dereference: true, // <- important!
+                fail_early: false,
+                overwrite: false,
+                overwrite_compare: filecmp::MetadataCmpSettings {
+                    size: true,
+                    mtime: true,
+                    ..Default::default()
+                },
+                ..Default::default()
+            },
+            &NO_PRESERVE_SETTINGS,
+            false,
+        )
+        .await?;
+        assert_eq!(summary.files_copied, 7);
+        assert_eq!(summary.symlinks_created, 0);
+        assert_eq!(summary.directories_created, 3);
+        // ...
+        // |- baz
+        //    |- 4.txt
+        //    |- 5.txt -> ../bar/2.txt
+        //    |- 6.txt -> (absolute path) .../foo/bar/3.txt
+        let dst1 = &test_path.join("bar").join("baz").join("5.txt");
+        let dst2 = &test_path.join("bar").join("baz").join("6.txt");
+        for f in [dst1, dst2] {
+            let metadata = tokio::fs::symlink_metadata(f)
+                .await
+                .with_context(|| format!("failed reading metadata from {:?}", &f))?;
+            assert!(metadata.is_file());
+            // check that the permissions are the same as the source file modulo no sticky bit, setuid and setgid
+            assert_eq!(metadata.permissions().mode() & 0o777, test_mode);
+        }
+        Ok(())
+    }
+
+    async fn cp_compare(
+        cp_args: &[&str],
+        rcp_settings: &CopySettings,
+        preserve: bool,
+    ) -> Result<(), anyhow::Error> {
+        let tmp_dir = testutils::setup_test_dir().await?;
+        let test_path = tmp_dir.as_path();
+        // run a cp command to copy the files
+        let cp_output = tokio::process::Command::new("cp")
+            .args(cp_args)
+            .arg(test_path.join("foo"))
+            .arg(test_path.join("bar"))
+            .output()
+            .await?;
+        assert!(cp_output.status.success());
+        // now run rcp
+        let summary = copy(
+            &PROGRESS,
+            test_path,
+            &test_path.join("foo"),
+            &test_path.join("baz"),
+            rcp_settings,
+            if preserve {
+                &DO_PRESERVE_SETTINGS
+            } else {
+                &NO_PRESERVE_SETTINGS
+            },
+            false,
+        )
+        .await?;
+        if rcp_settings.dereference {
+            assert_eq!(summary.files_copied, 7);
+            assert_eq!(summary.symlinks_created, 0);
+        } else {
+            assert_eq!(summary.files_copied, 5);
+            assert_eq!(summary.symlinks_created, 2);
+        }
+        assert_eq!(summary.directories_created, 3);
+        testutils::check_dirs_identical(
+            &test_path.join("bar"),
+            &test_path.join("baz"),
+            if preserve {
+                testutils::FileEqualityCheck::Timestamp
+            } else {
+                testutils::FileEqualityCheck::Basic
+            },
+        )
+        .await?;
+        Ok(())
     }
-    event!(Level::DEBUG, "set 'dst' directory metadata");
-    preserve::set_dir_metadata(preserve, &src_metadata, dst)
-        .await
-        .map_err(|err| CopyError::new(err, copy_summary))?;
-    Ok(copy_summary)
-}
 
-#[cfg(test)]
-mod copy_tests {
-    use crate::testutils;
-    use anyhow::Context;
-    use std::os::unix::fs::PermissionsExt;
-    use tracing_test::traced_test;
+    #[tokio::test]
+    #[traced_test]
+    async fn test_cp_compat() -> Result<(), anyhow::Error> {
+        cp_compare(
+            &["-r"],
+            &CopySettings {
+                dereference: false,
+                fail_early: false,
+                overwrite: false,
+                overwrite_compare: filecmp::MetadataCmpSettings {
+                    size: true,
+                    mtime: true,
+                    ..Default::default()
+                },
+                ..Default::default()
+            },
+            false,
+        )
+        .await?;
+        Ok(())
+    }
 
-    use super::*;
+    #[tokio::test]
+    #[traced_test]
+    async fn test_cp_compat_preserve() -> Result<(), anyhow::Error> {
+        cp_compare(
+            &["-r", "-p"],
+            &CopySettings {
+                dereference: false,
+                fail_early: false,
+                overwrite: false,
+                overwrite_compare: filecmp::MetadataCmpSettings {
+                    size: true,
+                    mtime: true,
+                    ..Default::default()
+                },
+                ..Default::default()
+            },
+            true,
+        )
+        .await?;
+        Ok(())
+    }
 
-    lazy_static! {
-        static ref PROGRESS: progress::Progress = progress::Progress::new();
-        static ref NO_PRESERVE_SETTINGS: preserve::PreserveSettings = preserve::preserve_default();
-        static ref DO_PRESERVE_SETTINGS: preserve::PreserveSettings = preserve::preserve_all();
+    #[tokio::test]
+    #[traced_test]
+    async fn test_cp_compat_dereference() -> Result<(), anyhow::Error> {
+        cp_compare(
+            &["-r", "-L"],
+            &CopySettings {
+                dereference: true,
+                fail_early: false,
+                overwrite: false,
+                overwrite_compare: filecmp::MetadataCmpSettings {
+                    size: true,
+                    mtime: true,
+                    ..Default::default()
+                },
+                ..Default::default()
+            },
+            false,
+        )
+        .await?;
+        Ok(())
     }
 
     #[tokio::test]
     #[traced_test]
-    async fn check_basic_copy() -> Result<(), anyhow::Error> {
+    async fn test_cp_compat_preserve_and_dereference() -> Result<(), anyhow::Error> {
+        cp_compare(
+            &["-r", "-p", "-L"],
+            &CopySettings {
+                dereference: true,
+                fail_early: false,
+                overwrite: false,
+                overwrite_compare: filecmp::MetadataCmpSettings {
+                    size: true,
+                    mtime: true,
+                    ..Default::default()
+                },
+                ..Default::default()
+            },
+            true,
+        )
+        .await?;
+        Ok(())
+    }
+
+    async fn setup_test_dir_and_copy() -> Result<std::path::PathBuf, anyhow::Error> {
         let tmp_dir = testutils::setup_test_dir().await?;
         let test_path = tmp_dir.as_path();
         let summary = copy(
             &PROGRESS,
-            &test_path,
+            test_path,
             &test_path.join("foo"),
             &test_path.join("bar"),
             &CopySettings {
@@ -535,18 +3068,85 @@ mod copy_tests {
                     mtime: true,
                     ..Default::default()
                 },
+                ..Default::default()
             },
-            &NO_PRESERVE_SETTINGS,
+            &DO_PRESERVE_SETTINGS,
             false,
         )
         .await?;
         assert_eq!(summary.files_copied, 5);
         assert_eq!(summary.symlinks_created, 2);
         assert_eq!(summary.directories_created, 3);
+        Ok(tmp_dir)
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn test_cp_overwrite_basic() -> Result<(), anyhow::Error> {
+        let tmp_dir = setup_test_dir_and_copy().await?;
+        let output_path = &tmp_dir.join("bar");
+        {
+            // bar
+            // |- 0.txt
+            // |- bar  <---------------------------------------- REMOVE
+            //    |- 1.txt  <----------------------------------- REMOVE
+            //    |- 2.txt  <----------------------------------- REMOVE
+            //    |- 3.txt  <----------------------------------- REMOVE
+            // |- baz
+            //    |- 4.txt
+            //    |- 5.txt -> ../bar/2.txt <-------------------- REMOVE
+            //    |- 6.txt -> (absolute path) .../foo/bar/3.txt
+            let summary = rm::rm(
+                &PROGRESS,
+                &output_path.join("bar"),
+                &RmSettings {
+                    fail_early: false,
+                    filter: Default::default(),
+                    prune_empty_dirs: false,
+                },
+            )
+            .await?
+                + rm::rm(
+                    &PROGRESS,
+                    &output_path.join("baz").join("5.txt"),
+                    &RmSettings {
+                        fail_early: false,
+                        filter: Default::default(),
+                        prune_empty_dirs: false,
+                    },
+                )
+                .await?;
+            assert_eq!(summary.files_removed, 3);
+            assert_eq!(summary.symlinks_removed, 1);
+            assert_eq!(summary.directories_removed, 1);
+        }
+        let summary = copy(
+            &PROGRESS,
+            &tmp_dir,
+            &tmp_dir.join("foo"),
+            output_path,
+            &CopySettings {
+                dereference: false,
+                fail_early: false,
+                overwrite: true, // <- important!
+                overwrite_compare: filecmp::MetadataCmpSettings {
+                    size: true,
+                    mtime: true,
+                    ..Default::default()
+                },
+                ..Default::default()
+            },
+            &DO_PRESERVE_SETTINGS,
+            false,
+        )
+        .await?;
+        assert_eq!(summary.files_copied, 3);
+        assert_eq!(summary.symlinks_created, 1);
+        assert_eq!(summary.directories_created, 1);
         testutils::check_dirs_identical(
-            &test_path.join("foo"),
-            &test_path.join("bar"),
-            testutils::FileEqualityCheck::Basic,
+            &tmp_dir.join("foo"),
+            output_path,
+            testutils::FileEqualityCheck::Timestamp,
         )
         .await?;
         Ok(())
@@ -554,68 +3154,107 @@ mod copy_tests {
 
     #[tokio::test]
     #[traced_test]
-    async fn no_read_permission() -> Result<(), anyhow::Error> {
-        let tmp_dir = testutils::setup_test_dir().await?;
-        let test_path = tmp_dir.as_path();
-        let filepaths = vec![
-            test_path.join("foo").join("0.txt"),
-            test_path.join("foo").join("baz"),
-        ];
-        for fpath in &filepaths {
-            // change file permissions to not readable
-            tokio::fs::set_permissions(&fpath, std::fs::Permissions::from_mode(0o000)).await?;
-        }
-        match copy(
+    async fn test_cp_overwrite_inplace() -> Result<(), anyhow::Error> {
+        let tmp_dir = setup_test_dir_and_copy().await?;
+        let output_path = &tmp_dir.join("bar");
+        let dst_file = output_path.join("0.txt");
+        tokio::fs::write(&dst_file, "modified contents").await?;
+        let dst_link = tmp_dir.join("0-link.txt");
+        tokio::fs::hard_link(&dst_file, &dst_link).await?;
+        let dst_ino = tokio::fs::metadata(&dst_file).await?.ino();
+        let summary = copy(
             &PROGRESS,
-            &test_path,
-            &test_path.join("foo"),
-            &test_path.join("bar"),
+            &tmp_dir,
+            &tmp_dir.join("foo"),
+            output_path,
             &CopySettings {
                 dereference: false,
                 fail_early: false,
-                overwrite: false,
+                overwrite: true,
                 overwrite_compare: filecmp::MetadataCmpSettings {
                     size: true,
                     mtime: true,
                     ..Default::default()
                 },
+                inplace: true, // <- important!
+                ..Default::default()
             },
-            &NO_PRESERVE_SETTINGS,
+            &DO_PRESERVE_SETTINGS,
             false,
         )
-        .await
-        {
-            Ok(_) => panic!("Expected the copy to error!"),
-            Err(error) => {
-                event!(Level::INFO, "{}", &error);
-                // foo
-                // |- 0.txt  // <- no read permission
-                // |- bar
-                //    |- 1.txt
-                //    |- 2.txt
-                //    |- 3.txt
-                // |- baz   // <- no read permission
-                //    |- 4.txt
-                //    |- 5.txt -> ../bar/2.txt
-                //    |- 6.txt -> (absolute path) .../foo/bar/3.txt
-                assert_eq!(error.summary.files_copied, 3);
-                assert_eq!(error.summary.symlinks_created, 0);
-                assert_eq!(error.summary.directories_created, 2);
-            }
-        }
-        // make source directory same as what we expect destination to be
-        for fpath in &filepaths {
-            tokio::fs::set_permissions(&fpath, std::fs::Permissions::from_mode(0o700)).await?;
-            if tokio::fs::symlink_metadata(fpath).await?.is_file() {
-                tokio::fs::remove_file(fpath).await?;
-            } else {
-                tokio::fs::remove_dir_all(fpath).await?;
-            }
-        }
+        .await?;
+        assert_eq!(summary.files_copied, 1);
+        assert_eq!(summary.rm_summary.files_removed, 0);
+        assert_eq!(tokio::fs::metadata(&dst_file).await?.ino(), dst_ino);
+        // the hard-link shares the new contents
+        let src_contents = tokio::fs::read_to_string(tmp_dir.join("foo").join("0.txt")).await?;
+        assert_eq!(tokio::fs::read_to_string(&dst_link).await?, src_contents);
+        tokio::fs::remove_file(&dst_link).await?;
+        testutils::check_dirs_identical(
+            &tmp_dir.join("foo"),
+            output_path,
+            testutils::FileEqualityCheck::Timestamp,
+        )
+        .await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn test_cp_overwrite_compare_hash() -> Result<(), anyhow::Error> {
+        let tmp_dir = setup_test_dir_and_copy().await?;
+        let output_path = &tmp_dir.join("bar");
+        // same size and mtime, only the contents differ
+        let dst_file = output_path.join("0.txt");
+        let src_mtime = tokio::fs::metadata(tmp_dir.join("foo").join("0.txt"))
+            .await?
+            .modified()?;
+        tokio::fs::write(&dst_file, "x").await?;
+        std::fs::File::options()
+            .write(true)
+            .open(&dst_file)?
+            .set_modified(src_mtime)?;
+        let copy_settings = |hash| CopySettings {
+            dereference: false,
+            fail_early: false,
+            overwrite: true,
+            overwrite_compare: filecmp::MetadataCmpSettings {
+                size: true,
+                mtime: true,
+                hash,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let summary = copy(
+            &PROGRESS,
+            &tmp_dir,
+            &tmp_dir.join("foo"),
+            output_path,
+            &copy_settings(false),
+            &DO_PRESERVE_SETTINGS,
+            false,
+        )
+        .await?;
+        assert_eq!(summary.files_copied, 0);
+        assert_eq!(summary.files_unchanged, 5);
+        assert_eq!(tokio::fs::read_to_string(&dst_file).await?, "x");
+        let summary = copy(
+            &PROGRESS,
+            &tmp_dir,
+            &tmp_dir.join("foo"),
+            output_path,
+            &copy_settings(true), // <- important!
+            &DO_PRESERVE_SETTINGS,
+            false,
+        )
+        .await?;
+        assert_eq!(summary.files_copied, 1);
+        assert_eq!(summary.files_unchanged, 4);
         testutils::check_dirs_identical(
-            &test_path.join("foo"),
-            &test_path.join("bar"),
-            testutils::FileEqualityCheck::Basic,
+            &tmp_dir.join("foo"),
+            output_path,
+            testutils::FileEqualityCheck::Timestamp,
         )
         .await?;
         Ok(())
@@ -623,56 +3262,69 @@ mod copy_tests {
 
     #[tokio::test]
     #[traced_test]
-    async fn check_default_mode() -> Result<(), anyhow::Error> {
-        let tmp_dir = testutils::setup_test_dir().await?;
-        // set file to executable
-        tokio::fs::set_permissions(
-            tmp_dir.join("foo").join("0.txt"),
-            std::fs::Permissions::from_mode(0o700),
-        )
-        .await?;
-        // set file executable AND also set sticky bit, setuid and setgid
-        let exec_sticky_file = tmp_dir.join("foo").join("bar").join("1.txt");
-        tokio::fs::set_permissions(&exec_sticky_file, std::fs::Permissions::from_mode(0o3770))
-            .await?;
-        let test_path = tmp_dir.as_path();
+    async fn test_cp_overwrite_atomic_writes() -> Result<(), anyhow::Error> {
+        let tmp_dir = setup_test_dir_and_copy().await?;
+        let output_path = &tmp_dir.join("bar");
+        let dst_file = output_path.join("0.txt");
+        tokio::fs::write(&dst_file, "modified contents").await?;
+        // a hard-link keeps the replaced file alive, its contents must not change
+        let old_link = tmp_dir.join("0-link.txt");
+        tokio::fs::hard_link(&dst_file, &old_link).await?;
+        // left behind by a process that is gone
+        let mut child = std::process::Command::new("true").spawn()?;
+        child.wait()?;
+        let stale_tmp_file = output_path
+            .join("bar")
+            .join(format!(".1.txt.rcp-tmp.{:x}-0", child.id()));
+        tokio::fs::write(&stale_tmp_file, "partial").await?;
+        // written by a concurrent copy
+        let fresh_tmp_file = output_path
+            .join("bar")
+            .join(format!(".2.txt.rcp-tmp.{:x}-0", std::process::id()));
+        tokio::fs::write(&fresh_tmp_file, "partial").await?;
+        // its writer didn't make progress for too long
+        let old_tmp_file = output_path
+            .join("bar")
+            .join(format!(".3.txt.rcp-tmp.{:x}-0", std::process::id()));
+        tokio::fs::write(&old_tmp_file, "partial").await?;
+        std::fs::File::options()
+            .write(true)
+            .open(&old_tmp_file)?
+            .set_modified(std::time::SystemTime::now() - 2 * STALE_TMP_FILE_AGE)?;
         let summary = copy(
             &PROGRESS,
-            &test_path,
-            &test_path.join("foo"),
-            &test_path.join("bar"),
+            &tmp_dir,
+            &tmp_dir.join("foo"),
+            output_path,
             &CopySettings {
                 dereference: false,
                 fail_early: false,
-                overwrite: false,
+                overwrite: true,
                 overwrite_compare: filecmp::MetadataCmpSettings {
                     size: true,
                     mtime: true,
                     ..Default::default()
                 },
+                atomic_writes: true, // <- important!
+                ..Default::default()
             },
-            &NO_PRESERVE_SETTINGS,
+            &DO_PRESERVE_SETTINGS,
             false,
         )
         .await?;
-        assert_eq!(summary.files_copied, 5);
-        assert_eq!(summary.symlinks_created, 2);
-        assert_eq!(summary.directories_created, 3);
-        // clear the setuid, setgid and sticky bit for comparison
-        tokio::fs::set_permissions(
-            &exec_sticky_file,
-            std::fs::Permissions::from_mode(
-                std::fs::symlink_metadata(&exec_sticky_file)?
-                    .permissions()
-                    .mode()
-                    & 0o0777,
-            ),
-        )
-        .await?;
+        assert_eq!(summary.files_copied, 1);
+        assert_eq!(summary.rm_summary.files_removed, 1);
+        assert_eq!(
+            tokio::fs::read_to_string(&old_link).await?,
+            "modified contents"
+        );
+        assert!(!stale_tmp_file.exists());
+        assert!(!old_tmp_file.exists());
+        assert!(fresh_tmp_file.exists());
         testutils::check_dirs_identical(
-            &test_path.join("foo"),
-            &test_path.join("bar"),
-            testutils::FileEqualityCheck::Basic,
+            &tmp_dir.join("foo"),
+            output_path,
+            testutils::FileEqualityCheck::Timestamp,
         )
         .await?;
         Ok(())
@@ -680,30 +3332,106 @@ mod copy_tests {
 
     #[tokio::test]
     #[traced_test]
-    async fn no_write_permission() -> Result<(), anyhow::Error> {
-        let tmp_dir = testutils::setup_test_dir().await?;
-        let test_path = tmp_dir.as_path();
-        // directory - readable and non-executable
-        let non_exec_dir = test_path.join("foo").join("bogey");
-        tokio::fs::create_dir(&non_exec_dir).await?;
-        tokio::fs::set_permissions(&non_exec_dir, std::fs::Permissions::from_mode(0o400)).await?;
-        // directory - readable and executable
+    async fn test_cp_metadata_only() -> Result<(), anyhow::Error> {
+        let tmp_dir = setup_test_dir_and_copy().await?;
+        let output_path = &tmp_dir.join("bar");
         tokio::fs::set_permissions(
-            &test_path.join("foo").join("baz"),
-            std::fs::Permissions::from_mode(0o500),
+            &tmp_dir.join("foo").join("0.txt"),
+            std::fs::Permissions::from_mode(0o640),
         )
         .await?;
-        // file
         tokio::fs::set_permissions(
-            &test_path.join("foo").join("baz").join("4.txt"),
-            std::fs::Permissions::from_mode(0o440),
+            &tmp_dir.join("foo").join("baz"),
+            std::fs::Permissions::from_mode(0o700),
         )
         .await?;
+        tokio::fs::write(tmp_dir.join("foo").join("new.txt"), "new").await?;
+        let settings = CopySettings {
+            dereference: false,
+            fail_early: false,
+            overwrite: false,
+            overwrite_compare: Default::default(),
+            metadata_only: Some(MetadataOnly::Strict), // <- important!
+            ..Default::default()
+        };
+        // the missing file is an error, the metadata of everything else is updated
+        match copy(
+            &PROGRESS,
+            &tmp_dir,
+            &tmp_dir.join("foo"),
+            output_path,
+            &settings,
+            &DO_PRESERVE_SETTINGS,
+            false,
+        )
+        .await
+        {
+            Ok(_) => panic!("expected the copy to fail"),
+            Err(error) => {
+                assert_eq!(error.summary.files_copied, 0);
+                assert_eq!(error.summary.files_unchanged, 5);
+                assert_eq!(error.summary.symlinks_unchanged, 2);
+                assert_eq!(error.summary.directories_unchanged, 3);
+            }
+        }
+        assert!(!output_path.join("new.txt").exists());
+        let mode = |path: std::path::PathBuf| async move {
+            Ok::<_, anyhow::Error>(tokio::fs::metadata(path).await?.permissions().mode() & 0o7777)
+        };
+        assert_eq!(mode(output_path.join("0.txt")).await?, 0o640);
+        assert_eq!(mode(output_path.join("baz")).await?, 0o700);
         let summary = copy(
             &PROGRESS,
-            &test_path,
-            &test_path.join("foo"),
-            &test_path.join("bar"),
+            &tmp_dir,
+            &tmp_dir.join("foo"),
+            output_path,
+            &CopySettings {
+                metadata_only: Some(MetadataOnly::Soft),
+                ..settings.clone()
+            },
+            &DO_PRESERVE_SETTINGS,
+            false,
+        )
+        .await?;
+        assert_eq!(summary.files_copied, 1);
+        assert_eq!(summary.files_unchanged, 5);
+        testutils::check_dirs_identical(
+            &tmp_dir.join("foo"),
+            output_path,
+            testutils::FileEqualityCheck::Timestamp,
+        )
+        .await?;
+        // files whose data differs are never overwritten
+        tokio::fs::write(output_path.join("0.txt"), "modified contents").await?;
+        assert!(copy(
+            &PROGRESS,
+            &tmp_dir,
+            &tmp_dir.join("foo"),
+            output_path,
+            &settings,
+            &DO_PRESERVE_SETTINGS,
+            false,
+        )
+        .await
+        .is_err());
+        assert_eq!(
+            tokio::fs::read_to_string(output_path.join("0.txt")).await?,
+            "modified contents"
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn test_cp_link_dest() -> Result<(), anyhow::Error> {
+        let tmp_dir = setup_test_dir_and_copy().await?;
+        tokio::fs::write(tmp_dir.join("foo").join("0.txt"), "modified contents").await?;
+        let output_path = &tmp_dir.join("snapshot");
+        let summary = copy(
+            &PROGRESS,
+            &tmp_dir,
+            &tmp_dir.join("foo"),
+            output_path,
             &CopySettings {
                 dereference: false,
                 fail_early: false,
@@ -713,18 +3441,32 @@ mod copy_tests {
                     mtime: true,
                     ..Default::default()
                 },
+                link_dest: Some(tmp_dir.join("bar")), // <- important!
+                ..Default::default()
             },
-            &NO_PRESERVE_SETTINGS,
+            &DO_PRESERVE_SETTINGS,
             false,
         )
         .await?;
-        assert_eq!(summary.files_copied, 5);
+        assert_eq!(summary.files_copied, 1);
+        assert_eq!(summary.files_linked, 4);
         assert_eq!(summary.symlinks_created, 2);
-        assert_eq!(summary.directories_created, 4);
+        assert_eq!(summary.directories_created, 3);
+        let ino = |path: std::path::PathBuf| async move {
+            Ok::<_, anyhow::Error>(tokio::fs::metadata(path).await?.ino())
+        };
+        assert_eq!(
+            ino(output_path.join("baz").join("4.txt")).await?,
+            ino(tmp_dir.join("bar").join("baz").join("4.txt")).await?
+        );
+        assert_ne!(
+            ino(output_path.join("0.txt")).await?,
+            ino(tmp_dir.join("bar").join("0.txt")).await?
+        );
         testutils::check_dirs_identical(
-            &test_path.join("foo"),
-            &test_path.join("bar"),
-            testutils::FileEqualityCheck::Basic,
+            &tmp_dir.join("foo"),
+            output_path,
+            testutils::FileEqualityCheck::Timestamp,
         )
         .await?;
         Ok(())
@@ -732,144 +3474,425 @@ mod copy_tests {
 
     #[tokio::test]
     #[traced_test]
-    async fn dereference() -> Result<(), anyhow::Error> {
+    async fn test_cp_compare_dest() -> Result<(), anyhow::Error> {
+        let tmp_dir = setup_test_dir_and_copy().await?;
+        tokio::fs::write(tmp_dir.join("foo").join("0.txt"), "modified contents").await?;
+        let output_path = &tmp_dir.join("changes");
+        let settings = CopySettings {
+            dereference: false,
+            fail_early: false,
+            overwrite: false,
+            overwrite_compare: filecmp::MetadataCmpSettings {
+                size: true,
+                mtime: true,
+                ..Default::default()
+            },
+            compare_dest: Some(tmp_dir.join("bar")), // <- important!
+            ..Default::default()
+        };
+        let summary = copy(
+            &PROGRESS,
+            &tmp_dir,
+            &tmp_dir.join("foo"),
+            output_path,
+            &settings,
+            &DO_PRESERVE_SETTINGS,
+            false,
+        )
+        .await?;
+        assert_eq!(summary.files_copied, 1);
+        assert_eq!(summary.files_unchanged, 4);
+        assert_eq!(summary.symlinks_created, 2);
+        assert_eq!(summary.directories_created, 3);
+        assert_eq!(
+            tokio::fs::read_to_string(output_path.join("0.txt")).await?,
+            "modified contents"
+        );
+        assert!(!output_path.join("bar").join("1.txt").exists());
+        assert!(!output_path.join("baz").join("4.txt").exists());
+        // an existing destination of a file identical to the reference is neither removed nor replaced
+        tokio::fs::write(output_path.join("bar").join("1.txt"), "stale contents").await?;
+        let summary = copy(
+            &PROGRESS,
+            &tmp_dir,
+            &tmp_dir.join("foo"),
+            output_path,
+            &CopySettings {
+                overwrite: true,
+                ..settings
+            },
+            &DO_PRESERVE_SETTINGS,
+            false,
+        )
+        .await?;
+        assert_eq!(summary.files_unchanged, 5);
+        assert_eq!(summary.rm_summary.files_removed, 0);
+        assert_eq!(
+            tokio::fs::read_to_string(output_path.join("bar").join("1.txt")).await?,
+            "stale contents"
+        );
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    #[traced_test]
+    async fn test_cp_checkpoint() -> Result<(), anyhow::Error> {
         let tmp_dir = testutils::setup_test_dir().await?;
         let test_path = tmp_dir.as_path();
-        // make files pointed to by symlinks have different permissions than the symlink itself
-        let src1 = &test_path.join("foo").join("bar").join("2.txt");
-        let src2 = &test_path.join("foo").join("bar").join("3.txt");
-        let test_mode = 0o440;
-        for f in vec![src1, src2] {
-            tokio::fs::set_permissions(f, std::fs::Permissions::from_mode(test_mode)).await?;
-        }
+        let output_path = &test_path.join("bar");
+        let checkpoint = std::sync::Arc::new(checkpoint::Checkpoint::default());
+        // pretend a previous run copied foo/bar completely
+        checkpoint.record(&test_path.join("foo").join("bar"), &output_path.join("bar"));
         let summary = copy(
             &PROGRESS,
-            &test_path,
+            test_path,
             &test_path.join("foo"),
-            &test_path.join("bar"),
+            output_path,
             &CopySettings {
-                dereference: true, // <- important!
+                dereference: false,
                 fail_early: false,
                 overwrite: false,
-                overwrite_compare: filecmp::MetadataCmpSettings {
-                    size: true,
-                    mtime: true,
-                    ..Default::default()
-                },
+                overwrite_compare: Default::default(),
+                checkpoint: Some(checkpoint.clone()), // <- important!
+                ..Default::default()
             },
             &NO_PRESERVE_SETTINGS,
             false,
         )
         .await?;
-        assert_eq!(summary.files_copied, 7);
-        assert_eq!(summary.symlinks_created, 0);
-        assert_eq!(summary.directories_created, 3);
-        // ...
-        // |- baz
-        //    |- 4.txt
-        //    |- 5.txt -> ../bar/2.txt
-        //    |- 6.txt -> (absolute path) .../foo/bar/3.txt
-        let dst1 = &test_path.join("bar").join("baz").join("5.txt");
-        let dst2 = &test_path.join("bar").join("baz").join("6.txt");
-        for f in vec![dst1, dst2] {
-            let metadata = tokio::fs::symlink_metadata(f)
-                .await
-                .with_context(|| format!("failed reading metadata from {:?}", &f))?;
-            assert!(metadata.is_file());
-            // check that the permissions are the same as the source file modulo no sticky bit, setuid and setgid
-            assert_eq!(metadata.permissions().mode() & 0o777, test_mode);
-        }
+        assert_eq!(summary.files_copied, 2);
+        assert_eq!(summary.symlinks_created, 2);
+        assert_eq!(summary.directories_created, 2);
+        assert_eq!(summary.directories_unchanged, 1);
+        assert!(!output_path.join("bar").exists());
+        assert!(checkpoint.is_completed(&test_path.join("foo"), output_path));
+        assert!(
+            checkpoint.is_completed(&test_path.join("foo").join("baz"), &output_path.join("baz"))
+        );
         Ok(())
     }
 
-    async fn cp_compare(
-        cp_args: &[&str],
-        rcp_settings: &CopySettings,
-        preserve: bool,
-    ) -> Result<(), anyhow::Error> {
+    #[tokio::test(flavor = "multi_thread")]
+    #[traced_test]
+    async fn test_cp_dedup() -> Result<(), anyhow::Error> {
         let tmp_dir = testutils::setup_test_dir().await?;
         let test_path = tmp_dir.as_path();
-        // run a cp command to copy the files
-        let cp_output = tokio::process::Command::new("cp")
-            .args(cp_args)
-            .arg(test_path.join("foo"))
-            .arg(test_path.join("bar"))
-            .output()
-            .await?;
-        assert!(cp_output.status.success());
-        // now run rcp
+        // same contents as bar/1.txt
+        tokio::fs::write(test_path.join("foo").join("bar").join("dup.txt"), "1").await?;
+        tokio::fs::write(test_path.join("foo").join("baz").join("dup.txt"), "1").await?;
+        tokio::fs::write(test_path.join("foo").join("empty1.txt"), "").await?;
+        tokio::fs::write(test_path.join("foo").join("empty2.txt"), "").await?;
+        let output_path = &test_path.join("bar");
         let summary = copy(
             &PROGRESS,
-            &test_path,
+            test_path,
             &test_path.join("foo"),
-            &test_path.join("baz"),
-            rcp_settings,
-            if preserve {
-                &DO_PRESERVE_SETTINGS
-            } else {
-                &NO_PRESERVE_SETTINGS
+            output_path,
+            &CopySettings {
+                dereference: false,
+                fail_early: false,
+                overwrite: false,
+                overwrite_compare: Default::default(),
+                dedup: true, // <- important!
+                ..Default::default()
             },
+            &NO_PRESERVE_SETTINGS,
             false,
         )
         .await?;
-        if rcp_settings.dereference {
-            assert_eq!(summary.files_copied, 7);
-            assert_eq!(summary.symlinks_created, 0);
-        } else {
-            assert_eq!(summary.files_copied, 5);
-            assert_eq!(summary.symlinks_created, 2);
-        }
-        assert_eq!(summary.directories_created, 3);
+        assert_eq!(summary.files_copied, 7);
+        assert_eq!(summary.files_deduplicated, 2);
+        assert_eq!(summary.bytes_deduplicated, 2);
+        let ino = |path: std::path::PathBuf| async move {
+            Ok::<_, anyhow::Error>(tokio::fs::metadata(path).await?.ino())
+        };
+        let first = ino(output_path.join("bar").join("1.txt")).await?;
+        assert_eq!(ino(output_path.join("bar").join("dup.txt")).await?, first);
+        assert_eq!(ino(output_path.join("baz").join("dup.txt")).await?, first);
+        assert_ne!(ino(output_path.join("bar").join("2.txt")).await?, first);
+        assert_ne!(
+            ino(output_path.join("empty1.txt")).await?,
+            ino(output_path.join("empty2.txt")).await?
+        );
         testutils::check_dirs_identical(
-            &test_path.join("bar"),
-            &test_path.join("baz"),
-            if preserve {
-                testutils::FileEqualityCheck::Timestamp
-            } else {
-                testutils::FileEqualityCheck::Basic
-            },
+            &test_path.join("foo"),
+            output_path,
+            testutils::FileEqualityCheck::Basic,
+        )
+        .await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn test_cp_compare_cache() -> Result<(), anyhow::Error> {
+        let tmp_dir = setup_test_dir_and_copy().await?;
+        let output_path = &tmp_dir.join("bar");
+        let cache_path = tmp_dir.join("compare-cache");
+        let copy_with_cache = || async {
+            let compare_cache = std::sync::Arc::new(comparecache::CompareCache::load(&cache_path)?);
+            let summary = copy(
+                &PROGRESS,
+                &tmp_dir,
+                &tmp_dir.join("foo"),
+                output_path,
+                &CopySettings {
+                    dereference: false,
+                    fail_early: false,
+                    overwrite: true,
+                    overwrite_compare: filecmp::MetadataCmpSettings {
+                        size: true,
+                        mtime: true,
+                        ..Default::default()
+                    },
+                    compare_cache: Some(compare_cache.clone()), // <- important!
+                    ..Default::default()
+                },
+                &DO_PRESERVE_SETTINGS,
+                false,
+            )
+            .await?;
+            compare_cache.save(&cache_path)?;
+            Ok::<_, anyhow::Error>((summary, compare_cache.hits()))
+        };
+        // the first run fills the cache, the destination is checked
+        let (summary, hits) = copy_with_cache().await?;
+        assert_eq!(summary.files_unchanged, 5);
+        assert_eq!(hits, 0);
+        let (summary, hits) = copy_with_cache().await?;
+        assert_eq!(summary.files_unchanged, 5);
+        assert_eq!(hits, 5);
+        // modify a file in place keeping its size and rename another one
+        let dir_mtime = || std::fs::metadata(tmp_dir.join("foo")).and_then(|m| m.modified());
+        let mtime_before = dir_mtime()?;
+        tokio::fs::write(tmp_dir.join("foo").join("0.txt"), "x").await?;
+        // the directory doesn't change, so a cached directory listing wouldn't notice the modification
+        assert_eq!(dir_mtime()?, mtime_before);
+        tokio::fs::rename(
+            tmp_dir.join("foo").join("bar").join("1.txt"),
+            tmp_dir.join("foo").join("bar").join("1b.txt"),
         )
         .await?;
+        let (summary, hits) = copy_with_cache().await?;
+        assert_eq!(summary.files_copied, 2);
+        assert_eq!(summary.files_unchanged, 3);
+        assert_eq!(hits, 3);
+        assert_eq!(
+            tokio::fs::read_to_string(output_path.join("0.txt")).await?,
+            "x"
+        );
+        assert!(output_path.join("bar").join("1b.txt").exists());
+        // a corrupted cache is rejected
+        std::fs::write(&cache_path, "garbage")?;
+        assert!(comparecache::CompareCache::load(&cache_path).is_err());
         Ok(())
     }
 
+    #[test]
+    fn check_symlink_rewrite_targets() {
+        let rewrite = |mode, src: &str, link: &str| {
+            SymlinkRewrite {
+                mode,
+                src_root: "/src/data/".into(),
+                dst_root: "/dst/./data".into(),
+            }
+            .target(std::path::Path::new(src), std::path::Path::new(link))
+        };
+        let relative = LinksMode::RewriteRelative;
+        let prefix = LinksMode::RewritePrefix;
+        assert_eq!(
+            rewrite(relative, "/src/data/current", "/src/data/v2"),
+            std::path::Path::new("v2")
+        );
+        assert_eq!(
+            rewrite(relative, "/src/data/a/b/link", "/src/data/c/../v2/file"),
+            std::path::Path::new("../../v2/file")
+        );
+        assert_eq!(
+            rewrite(relative, "/src/data/a/link", "/src/data/a"),
+            std::path::Path::new(".")
+        );
+        assert_eq!(
+            rewrite(prefix, "/src/data/a/link", "/src/data/v2/file"),
+            std::path::Path::new("/dst/data/v2/file")
+        );
+        // out of tree and relative targets are not changed
+        for mode in [LinksMode::Preserve, relative, prefix] {
+            assert_eq!(
+                rewrite(mode, "/src/data/link", "/src/database"),
+                std::path::Path::new("/src/database")
+            );
+            assert_eq!(
+                rewrite(mode, "/src/data/link", "/src/data/../other"),
+                std::path::Path::new("/src/data/../other")
+            );
+            assert_eq!(
+                rewrite(mode, "/src/data/link", "../data/v2"),
+                std::path::Path::new("../data/v2")
+            );
+        }
+        assert_eq!(
+            rewrite(LinksMode::Preserve, "/src/data/link", "/src/data/v2"),
+            std::path::Path::new("/src/data/v2")
+        );
+        assert!("rewrite-absolute".parse::<LinksMode>().is_err());
+    }
+
     #[tokio::test]
     #[traced_test]
-    async fn test_cp_compat() -> Result<(), anyhow::Error> {
-        cp_compare(
-            &["-r"],
+    async fn test_cp_rewrite_symlinks() -> Result<(), anyhow::Error> {
+        let tmp_dir = testutils::setup_test_dir().await?;
+        let src_root = tmp_dir.join("foo");
+        // baz/6.txt is an absolute link to foo/bar/3.txt, add one pointing outside of the tree
+        tokio::fs::symlink(tmp_dir.join("outside"), src_root.join("outside-link")).await?;
+        for mode in [LinksMode::RewriteRelative, LinksMode::RewritePrefix] {
+            let dst_root = tmp_dir.join(format!("dst-{}", mode));
+            let expected = match mode {
+                LinksMode::RewriteRelative => std::path::PathBuf::from("../bar/3.txt"),
+                _ => dst_root.join("bar").join("3.txt"),
+            };
+            let summary = copy(
+                &PROGRESS,
+                &tmp_dir,
+                &src_root,
+                &dst_root,
+                &CopySettings {
+                    dereference: false,
+                    fail_early: false,
+                    overwrite: false,
+                    overwrite_compare: Default::default(),
+                    symlink_rewrite: Some(SymlinkRewrite {
+                        mode,
+                        src_root: src_root.clone(),
+                        dst_root: dst_root.clone(),
+                    }), // <- important!
+                    ..Default::default()
+                },
+                &NO_PRESERVE_SETTINGS,
+                false,
+            )
+            .await?;
+            assert_eq!(summary.symlinks_created, 3);
+            assert_eq!(
+                tokio::fs::read_link(dst_root.join("baz").join("6.txt")).await?,
+                expected
+            );
+            assert_eq!(
+                tokio::fs::read_to_string(dst_root.join("baz").join("6.txt")).await?,
+                "3"
+            );
+            // already relative and out of tree links are copied verbatim
+            assert_eq!(
+                tokio::fs::read_link(dst_root.join("baz").join("5.txt")).await?,
+                std::path::Path::new("../bar/2.txt")
+            );
+            assert_eq!(
+                tokio::fs::read_link(dst_root.join("outside-link")).await?,
+                tmp_dir.join("outside")
+            );
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn check_tmp_file_names() {
+        let tmp_file = TmpFile::new(std::path::Path::new("/foo/bar.txt"), None).unwrap();
+        let name = tmp_file.path.file_name().unwrap();
+        assert!(name.to_str().unwrap().starts_with(".bar.txt.rcp-tmp."));
+        assert!(is_tmp_file_name(name));
+        assert_eq!(tmp_file.path.parent(), Some(std::path::Path::new("/foo")));
+        assert!(!is_tmp_file_name(std::ffi::OsStr::new("bar.txt")));
+        assert!(!is_tmp_file_name(std::ffi::OsStr::new(".rcp-tmp.1")));
+        assert!(!is_tmp_file_name(std::ffi::OsStr::new("bar.txt.rcp-tmp.1")));
+        assert_eq!(tmp_file_pid(name), Some(std::process::id()));
+        assert_eq!(
+            tmp_file_pid(std::ffi::OsStr::new(".bar.txt.rcp-tmp.1")),
+            None
+        );
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn test_cp_overwrite_update_only() -> Result<(), anyhow::Error> {
+        let tmp_dir = setup_test_dir_and_copy().await?;
+        let output_path = &tmp_dir.join("bar");
+        // destination modified after the copy is newer and must be kept
+        tokio::fs::write(output_path.join("0.txt"), "newer destination").await?;
+        // source modified after the copy is newer and must be copied
+        tokio::fs::write(
+            tmp_dir.join("foo").join("bar").join("1.txt"),
+            "newer source",
+        )
+        .await?;
+        let summary = copy(
+            &PROGRESS,
+            &tmp_dir,
+            &tmp_dir.join("foo"),
+            output_path,
             &CopySettings {
                 dereference: false,
                 fail_early: false,
-                overwrite: false,
+                overwrite: true,
                 overwrite_compare: filecmp::MetadataCmpSettings {
                     size: true,
                     mtime: true,
                     ..Default::default()
                 },
+                update_only: true, // <- important!
+                ..Default::default()
             },
+            &DO_PRESERVE_SETTINGS,
             false,
         )
         .await?;
+        assert_eq!(summary.files_copied, 1);
+        assert_eq!(summary.files_unchanged, 4);
+        assert_eq!(summary.rm_summary.files_removed, 1);
+        assert_eq!(
+            tokio::fs::read_to_string(output_path.join("0.txt")).await?,
+            "newer destination"
+        );
+        assert_eq!(
+            tokio::fs::read_to_string(output_path.join("bar").join("1.txt")).await?,
+            "newer source"
+        );
         Ok(())
     }
 
     #[tokio::test]
     #[traced_test]
-    async fn test_cp_compat_preserve() -> Result<(), anyhow::Error> {
-        cp_compare(
-            &["-r", "-p"],
+    async fn test_cp_overwrite_ignore_times() -> Result<(), anyhow::Error> {
+        let tmp_dir = setup_test_dir_and_copy().await?;
+        let output_path = &tmp_dir.join("bar");
+        let summary = copy(
+            &PROGRESS,
+            &tmp_dir,
+            &tmp_dir.join("foo"),
+            output_path,
             &CopySettings {
                 dereference: false,
                 fail_early: false,
-                overwrite: false,
+                overwrite: true,
                 overwrite_compare: filecmp::MetadataCmpSettings {
                     size: true,
                     mtime: true,
                     ..Default::default()
                 },
+                ignore_times: true, // <- important!
+                ..Default::default()
             },
-            true,
+            &DO_PRESERVE_SETTINGS,
+            false,
+        )
+        .await?;
+        // identical files are copied again
+        assert_eq!(summary.files_copied, 5);
+        assert_eq!(summary.files_unchanged, 0);
+        assert_eq!(summary.rm_summary.files_removed, 5);
+        testutils::check_dirs_identical(
+            &tmp_dir.join("foo"),
+            output_path,
+            testutils::FileEqualityCheck::Timestamp,
         )
         .await?;
         Ok(())
@@ -877,134 +3900,304 @@ mod copy_tests {
 
     #[tokio::test]
     #[traced_test]
-    async fn test_cp_compat_dereference() -> Result<(), anyhow::Error> {
-        cp_compare(
-            &["-r", "-L"],
+    async fn test_cp_itemize_changes() -> Result<(), anyhow::Error> {
+        let tmp_dir = setup_test_dir_and_copy().await?;
+        let output_path = &tmp_dir.join("bar");
+        tokio::fs::write(tmp_dir.join("foo").join("0.txt"), "modified contents").await?;
+        tokio::fs::remove_file(output_path.join("baz").join("4.txt")).await?;
+        let (sender, mut receiver) = tokio::sync::mpsc::unbounded_channel();
+        copy(
+            &PROGRESS,
+            &tmp_dir,
+            &tmp_dir.join("foo"),
+            output_path,
             &CopySettings {
-                dereference: true,
+                dereference: false,
                 fail_early: false,
-                overwrite: false,
+                overwrite: true,
                 overwrite_compare: filecmp::MetadataCmpSettings {
                     size: true,
-                    mtime: true,
                     ..Default::default()
                 },
+                itemize: Some(sender), // <- important!
+                ..Default::default()
             },
+            &DO_PRESERVE_SETTINGS,
             false,
         )
         .await?;
+        let mut changes = std::collections::HashMap::new();
+        while let Ok(change) = receiver.try_recv() {
+            let path = change.path.strip_prefix(output_path)?.to_owned();
+            assert!(changes.insert(path, change).is_none());
+        }
+        assert_eq!(changes.len(), 10);
+        let change = &changes[std::path::Path::new("0.txt")];
+        assert_eq!(change.obj_type, ObjType::File);
+        assert_eq!(
+            change.action,
+            ItemAction::Overwritten(vec![filecmp::MetadataAttr::Size])
+        );
+        let change = &changes[&std::path::Path::new("baz").join("4.txt")];
+        assert_eq!(change.action, ItemAction::Created);
+        let change = &changes[&std::path::Path::new("baz").join("5.txt")];
+        assert_eq!(change.obj_type, ObjType::Symlink);
+        assert_eq!(change.action, ItemAction::Unchanged);
+        let change = &changes[std::path::Path::new("")];
+        assert_eq!(change.obj_type, ObjType::Dir);
+        assert_eq!(change.action, ItemAction::Unchanged);
         Ok(())
     }
 
     #[tokio::test]
     #[traced_test]
-    async fn test_cp_compat_preserve_and_dereference() -> Result<(), anyhow::Error> {
-        cp_compare(
-            &["-r", "-p", "-L"],
+    async fn test_cp_overwrite_same_file() -> Result<(), anyhow::Error> {
+        let tmp_dir = setup_test_dir_and_copy().await?;
+        let output_path = &tmp_dir.join("bar");
+        // hard-link a source file in place of its copy
+        tokio::fs::remove_file(output_path.join("0.txt")).await?;
+        tokio::fs::hard_link(tmp_dir.join("foo").join("0.txt"), output_path.join("0.txt")).await?;
+        let summary = copy(
+            &PROGRESS,
+            &tmp_dir,
+            &tmp_dir.join("foo"),
+            output_path,
             &CopySettings {
-                dereference: true,
+                dereference: false,
                 fail_early: false,
-                overwrite: false,
-                overwrite_compare: filecmp::MetadataCmpSettings {
-                    size: true,
-                    mtime: true,
-                    ..Default::default()
-                },
+                overwrite: true,
+                overwrite_compare: Default::default(),
+                ignore_times: true, // <- important!
+                ..Default::default()
             },
-            true,
+            &DO_PRESERVE_SETTINGS,
+            false,
         )
         .await?;
+        assert_eq!(summary.files_same, 1);
+        assert_eq!(summary.files_copied, 4);
+        assert_eq!(summary.rm_summary.files_removed, 4);
+        assert_eq!(
+            tokio::fs::read_to_string(tmp_dir.join("foo").join("0.txt")).await?,
+            "0"
+        );
         Ok(())
     }
 
-    async fn setup_test_dir_and_copy() -> Result<std::path::PathBuf, anyhow::Error> {
+    #[tokio::test]
+    #[traced_test]
+    async fn test_cp_special_files() -> Result<(), anyhow::Error> {
+        use std::os::unix::fs::FileTypeExt;
         let tmp_dir = testutils::setup_test_dir().await?;
-        let test_path = tmp_dir.as_path();
+        let src = tmp_dir.join("foo");
+        nix::unistd::mkfifo(
+            &src.join("pipe"),
+            nix::sys::stat::Mode::from_bits_truncate(0o640),
+        )?;
+        let _listener = std::os::unix::net::UnixListener::bind(src.join("agent.sock"))?;
+        let settings = |copy_special, overwrite| CopySettings {
+            dereference: false,
+            fail_early: false,
+            overwrite,
+            overwrite_compare: Default::default(),
+            copy_special,
+            ..Default::default()
+        };
+        let dst = tmp_dir.join("bar");
+        // skipped by default, the rest of the tree is copied
         let summary = copy(
             &PROGRESS,
-            &test_path,
-            &test_path.join("foo"),
-            &test_path.join("bar"),
-            &CopySettings {
-                dereference: false,
-                fail_early: false,
-                overwrite: false,
-                overwrite_compare: filecmp::MetadataCmpSettings {
-                    size: true,
-                    mtime: true,
-                    ..Default::default()
-                },
-            },
+            &tmp_dir,
+            &src,
+            &dst,
+            &settings(false, false),
             &DO_PRESERVE_SETTINGS,
             false,
         )
         .await?;
+        assert_eq!(summary.special_skipped, 2);
+        assert_eq!(summary.special_created, 0);
         assert_eq!(summary.files_copied, 5);
-        assert_eq!(summary.symlinks_created, 2);
-        assert_eq!(summary.directories_created, 3);
-        Ok(tmp_dir)
+        assert!(!dst.join("pipe").exists());
+        let summary = copy(
+            &PROGRESS,
+            &tmp_dir,
+            &src,
+            &dst,
+            &settings(true, true),
+            &DO_PRESERVE_SETTINGS,
+            false,
+        )
+        .await?;
+        assert_eq!(summary.special_created, 2);
+        assert_eq!(summary.special_skipped, 0);
+        let pipe_metadata = std::fs::symlink_metadata(dst.join("pipe"))?;
+        assert!(pipe_metadata.file_type().is_fifo());
+        assert_eq!(pipe_metadata.permissions().mode() & 0o7777, 0o640);
+        assert!(std::fs::symlink_metadata(dst.join("agent.sock"))?
+            .file_type()
+            .is_socket());
+        // existing special files are replaced
+        let summary = copy(
+            &PROGRESS,
+            &tmp_dir,
+            &src,
+            &dst,
+            &settings(true, true),
+            &DO_PRESERVE_SETTINGS,
+            false,
+        )
+        .await?;
+        assert_eq!(summary.special_created, 2);
+        assert_eq!(summary.rm_summary.files_removed, 2);
+        Ok(())
     }
 
     #[tokio::test]
     #[traced_test]
-    async fn test_cp_overwrite_basic() -> Result<(), anyhow::Error> {
+    async fn test_cp_backup() -> Result<(), anyhow::Error> {
         let tmp_dir = setup_test_dir_and_copy().await?;
         let output_path = &tmp_dir.join("bar");
-        {
-            // bar
-            // |- 0.txt
-            // |- bar  <---------------------------------------- REMOVE
-            //    |- 1.txt  <----------------------------------- REMOVE
-            //    |- 2.txt  <----------------------------------- REMOVE
-            //    |- 3.txt  <----------------------------------- REMOVE
-            // |- baz
-            //    |- 4.txt
-            //    |- 5.txt -> ../bar/2.txt <-------------------- REMOVE
-            //    |- 6.txt -> (absolute path) .../foo/bar/3.txt
-            let summary = rm::rm(
-                &PROGRESS,
-                &output_path.join("bar"),
-                &RmSettings { fail_early: false },
-            )
-            .await?
-                + rm::rm(
+        // foo/baz/4.txt replaces a directory and foo/baz/5.txt (a symlink) replaces a file
+        tokio::fs::remove_file(output_path.join("baz").join("4.txt")).await?;
+        tokio::fs::create_dir(output_path.join("baz").join("4.txt")).await?;
+        tokio::fs::write(output_path.join("baz").join("4.txt").join("a.txt"), "a").await?;
+        tokio::fs::remove_file(output_path.join("baz").join("5.txt")).await?;
+        tokio::fs::write(output_path.join("baz").join("5.txt"), "5").await?;
+        let copy_with = |mode, atomic_writes| {
+            let tmp_dir = tmp_dir.clone();
+            async move {
+                copy(
                     &PROGRESS,
-                    &output_path.join("baz").join("5.txt"),
-                    &RmSettings { fail_early: false },
+                    &tmp_dir,
+                    &tmp_dir.join("foo"),
+                    &tmp_dir.join("bar"),
+                    &CopySettings {
+                        dereference: false,
+                        fail_early: false,
+                        overwrite: true,
+                        overwrite_compare: filecmp::MetadataCmpSettings {
+                            size: true,
+                            mtime: true,
+                            ..Default::default()
+                        },
+                        atomic_writes,
+                        backup: Some(Backup {
+                            mode,
+                            suffix: ".bak".to_string(),
+                        }), // <- important!
+                        ..Default::default()
+                    },
+                    &DO_PRESERVE_SETTINGS,
+                    false,
                 )
-                .await?;
-            assert_eq!(summary.files_removed, 3);
-            assert_eq!(summary.symlinks_removed, 1);
-            assert_eq!(summary.directories_removed, 1);
+                .await
+            }
+        };
+        let summary = copy_with(BackupMode::Simple, false).await?;
+        assert_eq!(summary.entries_backed_up, 2);
+        assert_eq!(summary.rm_summary.files_removed, 0);
+        assert_eq!(summary.rm_summary.directories_removed, 0);
+        let baz = output_path.join("baz");
+        assert_eq!(
+            tokio::fs::read_to_string(baz.join("4.txt.bak").join("a.txt")).await?,
+            "a"
+        );
+        assert_eq!(tokio::fs::read_to_string(baz.join("4.txt")).await?, "4");
+        assert_eq!(tokio::fs::read_to_string(baz.join("5.txt.bak")).await?, "5");
+        assert!(tokio::fs::symlink_metadata(baz.join("5.txt"))
+            .await?
+            .is_symlink());
+        // numbered backups never replace each other
+        for i in 1..=2 {
+            tokio::fs::write(output_path.join("0.txt"), format!("old{}", i)).await?;
+            let summary = copy_with(BackupMode::Numbered, i == 2).await?;
+            assert_eq!(summary.entries_backed_up, 1);
+            assert_eq!(summary.files_copied, 1);
         }
-        let summary = copy(
+        assert_eq!(
+            tokio::fs::read_to_string(output_path.join("0.txt.~1~")).await?,
+            "old1"
+        );
+        assert_eq!(
+            tokio::fs::read_to_string(output_path.join("0.txt.~2~")).await?,
+            "old2"
+        );
+        assert_eq!(
+            tokio::fs::read_to_string(output_path.join("0.txt")).await?,
+            "0"
+        );
+        Ok(())
+    }
+
+    fn chattr(args: &str, paths: &[std::path::PathBuf]) -> bool {
+        std::process::Command::new("chattr")
+            .arg(args)
+            .args(paths)
+            .status()
+            .is_ok_and(|status| status.success())
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn test_cp_preserve_flags() -> Result<(), anyhow::Error> {
+        let tmp_dir = testutils::setup_test_dir().await?;
+        let src_file = tmp_dir.join("foo").join("0.txt");
+        let src_dir = tmp_dir.join("foo").join("baz");
+        // setting the flags requires root and a filesystem supporting them
+        if !chattr("+i", std::slice::from_ref(&src_file)) {
+            return Ok(());
+        }
+        assert!(chattr("+a", std::slice::from_ref(&src_dir)));
+        let output_path = tmp_dir.join("bar");
+        let mut preserve = preserve::preserve_all();
+        preserve.flags = true;
+        let settings = CopySettings {
+            dereference: false,
+            fail_early: false,
+            overwrite: true,
+            overwrite_compare: Default::default(),
+            ignore_times: true, // <- important!
+            ..Default::default()
+        };
+        let result = copy(
             &PROGRESS,
             &tmp_dir,
             &tmp_dir.join("foo"),
             &output_path,
-            &CopySettings {
-                dereference: false,
-                fail_early: false,
-                overwrite: true, // <- important!
-                overwrite_compare: filecmp::MetadataCmpSettings {
-                    size: true,
-                    mtime: true,
-                    ..Default::default()
-                },
-            },
-            &DO_PRESERVE_SETTINGS,
+            &settings,
+            &preserve,
             false,
         )
-        .await?;
-        assert_eq!(summary.files_copied, 3);
-        assert_eq!(summary.symlinks_created, 1);
-        assert_eq!(summary.directories_created, 1);
-        testutils::check_dirs_identical(
-            &tmp_dir.join("foo"),
-            &output_path,
-            testutils::FileEqualityCheck::Timestamp,
+        .await;
+        let dst_file = output_path.join("0.txt");
+        let dst_dir = output_path.join("baz");
+        let dst_flags = (
+            preserve::get_flags(&dst_file),
+            preserve::get_flags(&dst_dir),
+        );
+        // overwriting the immutable copy is refused with a clear error
+        let overwrite_result = copy_file(
+            &PROGRESS,
+            &src_file,
+            &dst_file,
+            &settings,
+            &preserve::preserve_all(),
+            false,
         )
-        .await?;
+        .await;
+        // clear the flags before checking anything so the test directory can be removed
+        chattr("-ia", &[src_file, src_dir, dst_file, dst_dir]);
+        let summary = result?;
+        assert_eq!(summary.files_copied, 5);
+        assert_ne!(dst_flags.0? & preserve::FS_IMMUTABLE_FL, 0);
+        assert_ne!(dst_flags.1? & preserve::FS_APPEND_FL, 0);
+        let error = overwrite_result.expect_err("overwriting an immutable file should fail");
+        assert!(
+            format!("{:#}", error.source).contains("is immutable"),
+            "{:#}",
+            error.source
+        );
         Ok(())
     }
 
@@ -1027,13 +4220,21 @@ mod copy_tests {
             let summary = rm::rm(
                 &PROGRESS,
                 &output_path.join("bar").join("1.txt"),
-                &RmSettings { fail_early: false },
+                &RmSettings {
+                    fail_early: false,
+                    filter: Default::default(),
+                    prune_empty_dirs: false,
+                },
             )
             .await?
                 + rm::rm(
                     &PROGRESS,
                     &output_path.join("baz"),
-                    &RmSettings { fail_early: false },
+                    &RmSettings {
+                        fail_early: false,
+                        filter: Default::default(),
+                        prune_empty_dirs: false,
+                    },
                 )
                 .await?;
             assert_eq!(summary.files_removed, 2);
@@ -1050,7 +4251,7 @@ mod copy_tests {
             &PROGRESS,
             &tmp_dir,
             &tmp_dir.join("foo"),
-            &output_path,
+            output_path,
             &CopySettings {
                 dereference: false,
                 fail_early: false,
@@ -1060,6 +4261,7 @@ mod copy_tests {
                     mtime: true,
                     ..Default::default()
                 },
+                ..Default::default()
             },
             &DO_PRESERVE_SETTINGS,
             false,
@@ -1073,7 +4275,7 @@ mod copy_tests {
         assert_eq!(summary.directories_created, 1);
         testutils::check_dirs_identical(
             &tmp_dir.join("foo"),
-            &output_path,
+            output_path,
             testutils::FileEqualityCheck::Timestamp,
         )
         .await?;
@@ -1095,13 +4297,21 @@ mod copy_tests {
             let summary = rm::rm(
                 &PROGRESS,
                 &output_path.join("baz").join("4.txt"),
-                &RmSettings { fail_early: false },
+                &RmSettings {
+                    fail_early: false,
+                    filter: Default::default(),
+                    prune_empty_dirs: false,
+                },
             )
             .await?
                 + rm::rm(
                     &PROGRESS,
                     &output_path.join("baz").join("5.txt"),
-                    &RmSettings { fail_early: false },
+                    &RmSettings {
+                        fail_early: false,
+                        filter: Default::default(),
+                        prune_empty_dirs: false,
+                    },
                 )
                 .await?;
             assert_eq!(summary.files_removed, 1);
@@ -1118,7 +4328,7 @@ mod copy_tests {
             &PROGRESS,
             &tmp_dir,
             &tmp_dir.join("foo"),
-            &output_path,
+            output_path,
             &CopySettings {
                 dereference: false,
                 fail_early: false,
@@ -1128,6 +4338,7 @@ mod copy_tests {
                     mtime: true,
                     ..Default::default()
                 },
+                ..Default::default()
             },
             &DO_PRESERVE_SETTINGS,
             false,
@@ -1141,7 +4352,7 @@ mod copy_tests {
         assert_eq!(summary.directories_created, 0);
         testutils::check_dirs_identical(
             &tmp_dir.join("foo"),
-            &output_path,
+            output_path,
             testutils::FileEqualityCheck::Timestamp,
         )
         .await?;
@@ -1166,13 +4377,21 @@ mod copy_tests {
             let summary = rm::rm(
                 &PROGRESS,
                 &output_path.join("bar"),
-                &RmSettings { fail_early: false },
+                &RmSettings {
+                    fail_early: false,
+                    filter: Default::default(),
+                    prune_empty_dirs: false,
+                },
             )
             .await?
                 + rm::rm(
                     &PROGRESS,
                     &output_path.join("baz").join("5.txt"),
-                    &RmSettings { fail_early: false },
+                    &RmSettings {
+                        fail_early: false,
+                        filter: Default::default(),
+                        prune_empty_dirs: false,
+                    },
                 )
                 .await?;
             assert_eq!(summary.files_removed, 3);
@@ -1189,7 +4408,7 @@ mod copy_tests {
             &PROGRESS,
             &tmp_dir,
             &tmp_dir.join("foo"),
-            &output_path,
+            output_path,
             &CopySettings {
                 dereference: false,
                 fail_early: false,
@@ -1199,6 +4418,7 @@ mod copy_tests {
                     mtime: true,
                     ..Default::default()
                 },
+                ..Default::default()
             },
             &DO_PRESERVE_SETTINGS,
             false,
@@ -1215,7 +4435,7 @@ mod copy_tests {
         assert_eq!(summary.directories_unchanged, 2);
         testutils::check_dirs_identical(
             &tmp_dir.join("foo"),
-            &output_path,
+            output_path,
             testutils::FileEqualityCheck::Timestamp,
         )
         .await?;
@@ -1229,7 +4449,7 @@ mod copy_tests {
         let test_path = tmp_dir.as_path();
         let summary = copy(
             &PROGRESS,
-            &test_path,
+            test_path,
             &test_path.join("foo"),
             &test_path.join("bar"),
             &CopySettings {
@@ -1241,6 +4461,7 @@ mod copy_tests {
                     mtime: true,
                     ..Default::default()
                 },
+                ..Default::default()
             },
             &NO_PRESERVE_SETTINGS, // we want timestamps to differ!
             false,
@@ -1273,7 +4494,7 @@ mod copy_tests {
             &PROGRESS,
             &tmp_dir,
             &tmp_dir.join("foo"),
-            &output_path,
+            output_path,
             &CopySettings {
                 dereference: false,
                 fail_early: false,
@@ -1283,6 +4504,7 @@ mod copy_tests {
                     mtime: true,
                     ..Default::default()
                 },
+                ..Default::default()
             },
             &DO_PRESERVE_SETTINGS,
             false,
@@ -1325,6 +4547,7 @@ mod copy_tests {
                     mtime: true,
                     ..Default::default()
                 },
+                ..Default::default()
             },
             &DO_PRESERVE_SETTINGS,
             false,
@@ -1335,7 +4558,7 @@ mod copy_tests {
         assert_eq!(summary.directories_created, 5);
         // check_dirs_identical doesn't handle dereference so let's do it manually
         tokio::process::Command::new("cp")
-            .args(&["-r", "-L"])
+            .args(["-r", "-L"])
             .arg(tmp_dir.join("foo"))
             .arg(tmp_dir.join("bar-cp"))
             .output()
@@ -1348,4 +4571,75 @@ mod copy_tests {
         .await?;
         Ok(())
     }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn test_cp_dereference_root() -> Result<(), anyhow::Error> {
+        let tmp_dir = testutils::setup_test_dir().await?;
+        tokio::fs::symlink("foo", &tmp_dir.join("dir-link")).await?;
+        tokio::fs::symlink("foo/0.txt", &tmp_dir.join("file-link")).await?;
+        tokio::fs::symlink("missing", &tmp_dir.join("dangling-link")).await?;
+        let settings = |dereference, dereference_root| CopySettings {
+            dereference,
+            dereference_root,
+            fail_early: false,
+            overwrite: false,
+            overwrite_compare: Default::default(),
+            ..Default::default()
+        };
+        // (dereference, dereference_root) -> (files copied, symlinks created, directories created)
+        let cases = [
+            ("dir-link", (false, false), Some((0, 1, 0))),
+            ("dir-link", (false, true), Some((5, 2, 3))),
+            ("dir-link", (true, false), Some((7, 0, 3))),
+            ("file-link", (false, false), Some((0, 1, 0))),
+            ("file-link", (false, true), Some((1, 0, 0))),
+            ("file-link", (true, false), Some((1, 0, 0))),
+            ("dangling-link", (false, false), Some((0, 1, 0))),
+            ("dangling-link", (false, true), None),
+            ("dangling-link", (true, false), None),
+        ];
+        for (idx, (src, (dereference, dereference_root), expected)) in cases.into_iter().enumerate()
+        {
+            let dst = tmp_dir.join(format!("dst{}", idx));
+            let res = copy(
+                &PROGRESS,
+                &tmp_dir,
+                &tmp_dir.join(src),
+                &dst,
+                &settings(dereference, dereference_root),
+                &NO_PRESERVE_SETTINGS,
+                false,
+            )
+            .await;
+            match (res, expected) {
+                (Ok(summary), Some(expected)) => {
+                    assert_eq!(
+                        (
+                            summary.files_copied,
+                            summary.symlinks_created,
+                            summary.directories_created
+                        ),
+                        expected,
+                        "{} {:?}",
+                        src,
+                        (dereference, dereference_root)
+                    );
+                }
+                // a dangling symlink can't be followed, nothing is created
+                (Err(error), None) => {
+                    assert_eq!(error.summary.files_copied, 0);
+                    assert!(!dst.exists() && !dst.is_symlink());
+                }
+                (res, _) => panic!("{} {:?}: {:?}", src, (dereference, dereference_root), res),
+            }
+        }
+        // only the root is followed, the symlinks inside the tree are preserved
+        assert_eq!(
+            tokio::fs::read_link(tmp_dir.join("dst1").join("baz").join("5.txt")).await?,
+            std::path::PathBuf::from("../bar/2.txt")
+        );
+        assert_eq!(tokio::fs::read_to_string(tmp_dir.join("dst4")).await?, "0");
+        Ok(())
+    }
 }
@@ -0,0 +1,401 @@
+use anyhow::{anyhow, Context, Result};
+use enum_map::Enum;
+use std::io::{Read, Write};
+use std::os::fd::AsRawFd;
+use std::os::unix::fs::MetadataExt;
+use tracing::{event, Level};
+
+use crate::progress;
+
+/// Maximum size of a single copy_file_range call, keeps the byte progress of large files updating smoothly
+const COPY_FILE_RANGE_CHUNK: usize = 1 << 27;
+
+/// Controls the use of reflinks (copy-on-write clones) when copying file data
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum Reflink {
+    /// Try to reflink and fall back to regular copying if not supported
+    #[default]
+    Auto,
+    /// Fail copying files that cannot be reflinked
+    Always,
+    /// Never try to reflink
+    Never,
+}
+
+impl std::fmt::Display for Reflink {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Reflink::Auto => write!(f, "auto"),
+            Reflink::Always => write!(f, "always"),
+            Reflink::Never => write!(f, "never"),
+        }
+    }
+}
+
+impl std::str::FromStr for Reflink {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "auto" => Ok(Reflink::Auto),
+            "always" => Ok(Reflink::Always),
+            "never" => Ok(Reflink::Never),
+            _ => Err(anyhow!(
+                "Invalid reflink setting: {}, expected one of: auto, always, never",
+                s
+            )),
+        }
+    }
+}
+
+/// Controls preallocating the space of destination files before their data is copied
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum Preallocate {
+    /// Preallocate large files on filesystems supporting fallocate
+    #[default]
+    Auto,
+    /// Preallocate every file, falling back to posix_fallocate if fallocate is not supported
+    Always,
+    /// Never preallocate
+    Never,
+}
+
+impl std::fmt::Display for Preallocate {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Preallocate::Auto => write!(f, "auto"),
+            Preallocate::Always => write!(f, "always"),
+            Preallocate::Never => write!(f, "never"),
+        }
+    }
+}
+
+impl std::str::FromStr for Preallocate {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "auto" => Ok(Preallocate::Auto),
+            "always" => Ok(Preallocate::Always),
+            "never" => Ok(Preallocate::Never),
+            _ => Err(anyhow!(
+                "Invalid preallocate setting: {}, expected one of: auto, always, never",
+                s
+            )),
+        }
+    }
+}
+
+/// Files smaller than this are not preallocated with `Preallocate::Auto`
+const PREALLOCATE_AUTO_MIN_SIZE: u64 = 64 << 20;
+
+lazy_static! {
+    // devices (st_dev) on which fallocate is not supported, so that we only try once per filesystem
+    static ref FALLOCATE_UNSUPPORTED: std::sync::Mutex<std::collections::HashSet<u64>> =
+        Default::default();
+}
+
+/// Allocates 'size' bytes for the destination file, returns true if the space was preallocated.
+///
+/// Failing to preallocate is only a warning, except for running out of space which fails the copy right away instead of
+/// once most of the data was written.
+fn preallocate(
+    dst: &std::fs::File,
+    size: u64,
+    setting: Preallocate,
+    auto_min_size: u64,
+) -> Result<bool> {
+    if size == 0
+        || setting == Preallocate::Never
+        || (setting == Preallocate::Auto && size < auto_min_size)
+    {
+        return Ok(false);
+    }
+    let dev = dst.metadata()?.dev();
+    if setting == Preallocate::Auto && FALLOCATE_UNSUPPORTED.lock().unwrap().contains(&dev) {
+        return Ok(false);
+    }
+    let mut result = nix::fcntl::fallocate(
+        dst.as_raw_fd(),
+        nix::fcntl::FallocateFlags::empty(),
+        0,
+        size as libc::off_t,
+    );
+    if result == Err(nix::errno::Errno::EOPNOTSUPP) {
+        FALLOCATE_UNSUPPORTED.lock().unwrap().insert(dev);
+        if setting == Preallocate::Auto {
+            event!(Level::DEBUG, "fallocate not supported on device {}", dev);
+            return Ok(false);
+        }
+        // emulated by writing zeros, slow but it still reserves the space
+        result = nix::fcntl::posix_fallocate(dst.as_raw_fd(), 0, size as libc::off_t);
+    }
+    match result {
+        Ok(()) => Ok(true),
+        Err(nix::errno::Errno::ENOSPC) => Err(anyhow!(
+            "not enough space left on the device for {} bytes",
+            size
+        )),
+        Err(error) => {
+            event!(Level::WARN, "cannot preallocate {} bytes: {}", size, &error);
+            Ok(false)
+        }
+    }
+}
+
+/// The method used to copy the data of a file, in the order they are tried
+#[derive(Copy, Clone, Debug, Enum, PartialEq, Eq)]
+pub enum CopyMethod {
+    Reflink,
+    CopyFileRange,
+    ReadWrite,
+}
+
+fn reflink(src: &std::fs::File, dst: &std::fs::File) -> std::io::Result<()> {
+    // safety: both file descriptors are valid for the duration of the call
+    let res = unsafe { libc::ioctl(dst.as_raw_fd(), libc::FICLONE as _, src.as_raw_fd()) };
+    if res < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Copies using copy_file_range, returns None if it's not supported between the two files
+fn copy_file_range(
+    src: &std::fs::File,
+    dst: &std::fs::File,
+    bytes_progress: &progress::BytesProgress,
+) -> Result<Option<u64>> {
+    let mut copied = 0;
+    loop {
+        match nix::fcntl::copy_file_range(src, None, dst, None, COPY_FILE_RANGE_CHUNK) {
+            Ok(0) => return Ok(Some(copied)),
+            Ok(n) => {
+                copied += n as u64;
+                bytes_progress.add(n as u64);
+            }
+            Err(
+                nix::errno::Errno::EXDEV
+                | nix::errno::Errno::ENOSYS
+                | nix::errno::Errno::EOPNOTSUPP
+                | nix::errno::Errno::EINVAL,
+            ) if copied == 0 => {
+                return Ok(None);
+            }
+            Err(error) => return Err(error).context("copy_file_range failed"),
+        }
+    }
+}
+
+fn read_write(
+    src: &mut std::fs::File,
+    dst: &mut std::fs::File,
+    bytes_progress: &progress::BytesProgress,
+) -> Result<u64> {
+    let mut buf = vec![0u8; 1 << 20];
+    let mut copied = 0;
+    loop {
+        let n = match src.read(&mut buf) {
+            Ok(0) => return Ok(copied),
+            Ok(n) => n,
+            Err(error) if error.kind() == std::io::ErrorKind::Interrupted => continue,
+            Err(error) => return Err(error).context("failed reading source file"),
+        };
+        dst.write_all(&buf[..n])
+            .context("failed writing destination file")?;
+        copied += n as u64;
+        bytes_progress.add(n as u64);
+    }
+}
+
+/// Copies file data from src to dst (created or truncated) returning the number of bytes copied and the method used.
+///
+/// The bytes are also added to 'bytes_progress' as they are copied, not only once the whole file is done.
+pub fn copy_data(
+    src: &std::path::Path,
+    dst: &std::path::Path,
+    reflink_setting: Reflink,
+    preallocate_setting: Preallocate,
+    bytes_progress: &progress::BytesProgress,
+) -> Result<(u64, CopyMethod)> {
+    let mut src_file =
+        std::fs::File::open(src).with_context(|| format!("cannot open {:?} for reading", src))?;
+    let mut dst_file = std::fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(dst)
+        .with_context(|| format!("cannot open {:?} for writing", dst))?;
+    if reflink_setting != Reflink::Never {
+        match reflink(&src_file, &dst_file) {
+            Ok(()) => {
+                let size = src_file.metadata()?.len();
+                bytes_progress.add(size);
+                event!(Level::DEBUG, "reflinked {:?} to {:?}", src, dst);
+                return Ok((size, CopyMethod::Reflink));
+            }
+            Err(error) => {
+                if reflink_setting == Reflink::Always {
+                    // don't leave an empty file behind
+                    drop(dst_file);
+                    std::fs::remove_file(dst).ok();
+                    return Err(error)
+                        .with_context(|| format!("cannot reflink {:?} to {:?}", src, dst));
+                }
+                event!(Level::DEBUG, "reflink not possible: {}", &error);
+            }
+        }
+    }
+    let size = src_file.metadata()?.len();
+    let preallocated = preallocate(
+        &dst_file,
+        size,
+        preallocate_setting,
+        PREALLOCATE_AUTO_MIN_SIZE,
+    )
+    .with_context(|| format!("cannot preallocate {:?}", dst))?;
+    let (copied, method) =
+        if let Some(copied) = copy_file_range(&src_file, &dst_file, bytes_progress)? {
+            event!(
+                Level::DEBUG,
+                "copied {:?} to {:?} using copy_file_range",
+                src,
+                dst
+            );
+            (copied, CopyMethod::CopyFileRange)
+        } else {
+            let copied = read_write(&mut src_file, &mut dst_file, bytes_progress)?;
+            event!(
+                Level::DEBUG,
+                "copied {:?} to {:?} using read/write",
+                src,
+                dst
+            );
+            (copied, CopyMethod::ReadWrite)
+        };
+    // the source may have shrunk since we preallocated its size
+    if preallocated && copied < size {
+        dst_file
+            .set_len(copied)
+            .with_context(|| format!("cannot truncate {:?}", dst))?;
+    }
+    Ok((copied, method))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutils;
+
+    #[tokio::test]
+    async fn check_copy_fallback_chain() -> Result<()> {
+        let tmp_dir = testutils::create_temp_dir().await?;
+        let src = tmp_dir.join("src.txt");
+        let data: Vec<u8> = (0..3 * 1024 * 1024).map(|i| (i % 251) as u8).collect();
+        std::fs::write(&src, &data)?;
+        let bytes_progress = progress::TlsCounter::new();
+        let file_bytes = std::sync::atomic::AtomicU64::new(0);
+        let (copied, method) = copy_data(
+            &src,
+            &tmp_dir.join("auto.txt"),
+            Reflink::Auto,
+            Preallocate::Auto,
+            &progress::BytesProgress {
+                total: &bytes_progress,
+                file: Some(&file_bytes),
+            },
+        )?;
+        assert_eq!(copied, data.len() as u64);
+        assert_eq!(bytes_progress.get(), copied);
+        assert_eq!(file_bytes.into_inner(), copied);
+        assert_eq!(std::fs::read(tmp_dir.join("auto.txt"))?, data);
+        let (copied, method_never) = copy_data(
+            &src,
+            &tmp_dir.join("never.txt"),
+            Reflink::Never,
+            Preallocate::Never,
+            &(&bytes_progress).into(),
+        )?;
+        assert_eq!(copied, data.len() as u64);
+        assert_eq!(bytes_progress.get(), 2 * copied);
+        assert_ne!(method_never, CopyMethod::Reflink);
+        assert_eq!(std::fs::read(tmp_dir.join("never.txt"))?, data);
+        // "always" must either reflink or fail, depending on the filesystem
+        match copy_data(
+            &src,
+            &tmp_dir.join("always.txt"),
+            Reflink::Always,
+            Preallocate::Auto,
+            &(&bytes_progress).into(),
+        ) {
+            Ok((_, method_always)) => {
+                assert_eq!(method_always, CopyMethod::Reflink);
+                assert_eq!(method, CopyMethod::Reflink);
+                assert_eq!(std::fs::read(tmp_dir.join("always.txt"))?, data);
+            }
+            Err(_) => assert_ne!(method, CopyMethod::Reflink),
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn check_preallocate() -> Result<()> {
+        let tmp_dir = testutils::create_temp_dir().await?;
+        let file = std::fs::File::create(tmp_dir.join("dst.txt"))?;
+        // small files are only preallocated with "always", unless the threshold is lowered
+        assert!(!preallocate(
+            &file,
+            4096,
+            Preallocate::Auto,
+            PREALLOCATE_AUTO_MIN_SIZE
+        )?);
+        assert!(!preallocate(&file, 4096, Preallocate::Never, 0)?);
+        assert!(!preallocate(&file, 0, Preallocate::Always, 0)?);
+        let dev = file.metadata()?.dev();
+        let supported = preallocate(&file, 4096, Preallocate::Auto, 1)?;
+        assert_eq!(
+            supported,
+            !FALLOCATE_UNSUPPORTED.lock().unwrap().contains(&dev)
+        );
+        if supported {
+            assert_eq!(file.metadata()?.len(), 4096);
+            assert!(file.metadata()?.blocks() * 512 >= 4096);
+        }
+        assert!(preallocate(
+            &file,
+            8192,
+            Preallocate::Always,
+            PREALLOCATE_AUTO_MIN_SIZE
+        )?);
+        assert_eq!(file.metadata()?.len(), 8192);
+        // copying over the preallocated space leaves the file with the size of the source
+        let src = tmp_dir.join("src.txt");
+        std::fs::write(&src, "data")?;
+        let bytes_progress = progress::TlsCounter::new();
+        copy_data(
+            &src,
+            &tmp_dir.join("dst.txt"),
+            Reflink::Never,
+            Preallocate::Always,
+            &(&bytes_progress).into(),
+        )?;
+        assert_eq!(std::fs::read(tmp_dir.join("dst.txt"))?, b"data");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn check_read_write_copy() -> Result<()> {
+        let tmp_dir = testutils::create_temp_dir().await?;
+        let src = tmp_dir.join("src.txt");
+        let data: Vec<u8> = (0..(1 << 20) + 17).map(|i| (i % 13) as u8).collect();
+        std::fs::write(&src, &data)?;
+        let mut src_file = std::fs::File::open(&src)?;
+        let mut dst_file = std::fs::File::create(tmp_dir.join("dst.txt"))?;
+        let bytes_progress = progress::TlsCounter::new();
+        assert_eq!(
+            read_write(&mut src_file, &mut dst_file, &(&bytes_progress).into())?,
+            data.len() as u64
+        );
+        assert_eq!(bytes_progress.get(), data.len() as u64);
+        assert_eq!(std::fs::read(tmp_dir.join("dst.txt"))?, data);
+        Ok(())
+    }
+}
@@ -0,0 +1,153 @@
+use anyhow::{Context, Result};
+use std::io::Read;
+
+use crate::checksum;
+
+/// Weak checksum of a block (as in rsync), it can be updated in constant time when the block slides by one byte
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct RollingChecksum {
+    a: u32,
+    b: u32,
+    len: u32,
+}
+
+impl RollingChecksum {
+    pub fn new(data: &[u8]) -> Self {
+        let mut checksum = RollingChecksum {
+            len: data.len() as u32,
+            ..Default::default()
+        };
+        for (idx, &byte) in data.iter().enumerate() {
+            checksum.a = checksum.a.wrapping_add(byte as u32);
+            checksum.b = checksum
+                .b
+                .wrapping_add((data.len() - idx) as u32 * byte as u32);
+        }
+        checksum
+    }
+
+    /// Slides the block by one byte: removes the first byte of the block and appends the next one
+    pub fn roll(&mut self, removed: u8, added: u8) {
+        self.a = self
+            .a
+            .wrapping_sub(removed as u32)
+            .wrapping_add(added as u32);
+        self.b = self
+            .b
+            .wrapping_sub(self.len.wrapping_mul(removed as u32))
+            .wrapping_add(self.a);
+    }
+
+    pub fn value(&self) -> u32 {
+        (self.a & 0xffff) | (self.b << 16)
+    }
+}
+
+/// Checksums of a block of a file existing on the destination, the sender looks for blocks with the same contents to
+/// avoid transferring them
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct BlockChecksum {
+    pub offset: u64,
+    /// Size of the block, only the last block of a file can be shorter than the block size
+    pub len: usize,
+    pub weak: u32,
+    pub strong: checksum::Checksum,
+}
+
+/// Block size used for a file of the given size: about its square root (like rsync) within [2KiB, 128KiB], so that the
+/// number of blocks and the size of each of them grow together
+pub fn block_size(file_size: u64) -> usize {
+    ((file_size as f64).sqrt() as usize).clamp(2 << 10, 128 << 10) & !7
+}
+
+/// Reads the file and computes the checksums of each of its blocks
+pub fn block_checksums(
+    path: &std::path::Path,
+    block_size: usize,
+    algo: checksum::ChecksumAlgo,
+) -> Result<Vec<BlockChecksum>> {
+    let mut file =
+        std::fs::File::open(path).with_context(|| format!("cannot open {:?} for reading", path))?;
+    let mut checksums = vec![];
+    let mut buf = vec![0u8; block_size];
+    let mut offset = 0;
+    loop {
+        // fill the whole block, reads may return less than requested
+        let mut len = 0;
+        while len < block_size {
+            match file.read(&mut buf[len..]) {
+                Ok(0) => break,
+                Ok(n) => len += n,
+                Err(error) if error.kind() == std::io::ErrorKind::Interrupted => continue,
+                Err(error) => {
+                    return Err(error).with_context(|| format!("failed reading {:?}", path))
+                }
+            }
+        }
+        if len == 0 {
+            return Ok(checksums);
+        }
+        let block = &buf[..len];
+        let mut hasher = checksum::Hasher::new(algo);
+        hasher.update(block);
+        checksums.push(BlockChecksum {
+            offset,
+            len,
+            weak: RollingChecksum::new(block).value(),
+            strong: hasher.finalize(),
+        });
+        offset += len as u64;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutils;
+
+    #[test]
+    fn check_rolling_checksum() {
+        let data: Vec<u8> = (0..4096).map(|i| ((i * 7) % 256) as u8).collect();
+        let window = 700;
+        let mut rolling = RollingChecksum::new(&data[..window]);
+        for start in 1..data.len() - window {
+            rolling.roll(data[start - 1], data[start + window - 1]);
+            assert_eq!(
+                rolling,
+                RollingChecksum::new(&data[start..start + window]),
+                "offset {}",
+                start
+            );
+        }
+        assert_ne!(
+            RollingChecksum::new(b"abcd").value(),
+            RollingChecksum::new(b"abdc").value()
+        );
+    }
+
+    #[tokio::test]
+    async fn check_block_checksums() -> Result<()> {
+        assert_eq!(block_size(0), 2 << 10);
+        assert_eq!(block_size(1 << 30), 32 << 10);
+        assert_eq!(block_size(1 << 40), 128 << 10);
+        let tmp_dir = testutils::create_temp_dir().await?;
+        let path = tmp_dir.join("file");
+        let data: Vec<u8> = (0..10_000).map(|i| (i % 251) as u8).collect();
+        std::fs::write(&path, &data)?;
+        let checksums = block_checksums(&path, 4096, checksum::ChecksumAlgo::Blake3)?;
+        assert_eq!(checksums.len(), 3);
+        assert_eq!(checksums[2].offset, 8192);
+        assert_eq!(checksums[2].len, 10_000 - 8192);
+        for block in &checksums {
+            let contents = &data[block.offset as usize..block.offset as usize + block.len];
+            assert_eq!(block.weak, RollingChecksum::new(contents).value());
+            assert_eq!(
+                block.strong,
+                checksum::hash_reader(checksum::ChecksumAlgo::Blake3, contents)?
+            );
+        }
+        std::fs::write(&path, "")?;
+        assert!(block_checksums(&path, 4096, checksum::ChecksumAlgo::Xxh3)?.is_empty());
+        Ok(())
+    }
+}
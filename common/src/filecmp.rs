@@ -1,6 +1,10 @@
+use anyhow::{Context, Result};
+use enum_map::Enum;
 use std::os::unix::fs::MetadataExt;
 use tracing::instrument;
 
+use crate::checksum;
+
 #[derive(Copy, Clone, Debug, Default)]
 pub struct MetadataCmpSettings {
     pub uid: bool,
@@ -8,49 +12,126 @@ pub struct MetadataCmpSettings {
     pub mode: bool,
     pub size: bool,
     pub mtime: bool,
+    /// Compare mtime including the nanoseconds, even if one of the filesystems doesn't store them
+    pub mtime_ns: bool,
     pub ctime: bool,
+    /// Compare file contents hashes, checked only for files whose other attributes are equal
+    pub hash: bool,
+    pub checksum_algo: checksum::ChecksumAlgo,
+}
+
+/// Metadata attributes that can differ between two objects.
+#[derive(Copy, Clone, Debug, Enum, PartialEq, Eq)]
+pub enum MetadataAttr {
+    Type,
+    Uid,
+    Gid,
+    Mode,
+    Size,
+    Mtime,
+    Ctime,
+    Hash,
+}
+
+impl std::fmt::Display for MetadataAttr {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        // same names as used by --metadata-compare
+        let name = match self {
+            MetadataAttr::Type => "type",
+            MetadataAttr::Uid => "uid",
+            MetadataAttr::Gid => "gid",
+            MetadataAttr::Mode => "mode",
+            MetadataAttr::Size => "size",
+            MetadataAttr::Mtime => "mtime",
+            MetadataAttr::Ctime => "ctime",
+            MetadataAttr::Hash => "hash",
+        };
+        write!(f, "{}", name)
+    }
 }
 
+fn time_equal(sec1: i64, nsec1: i64, sec2: i64, nsec2: i64) -> bool {
+    // some filesystems do not support nanosecond precision, so we only compare nanoseconds if both files have them
+    sec1 == sec2 && (nsec1 == 0 || nsec2 == 0 || nsec1 == nsec2)
+}
+
+/// Returns the list of attributes (out of those enabled in settings) that differ. File type and contents are not checked
+/// here, see [`contents_equal`].
 #[instrument]
-pub fn metadata_equal(
+pub fn metadata_diff(
     settings: &MetadataCmpSettings,
     metadata1: &std::fs::Metadata,
     metadata2: &std::fs::Metadata,
-) -> bool {
+) -> Vec<MetadataAttr> {
+    let mut diff = vec![];
     if settings.uid && metadata1.uid() != metadata2.uid() {
-        return false;
+        diff.push(MetadataAttr::Uid);
     }
     if settings.gid && metadata1.gid() != metadata2.gid() {
-        return false;
+        diff.push(MetadataAttr::Gid);
     }
     if settings.mode && metadata1.mode() != metadata2.mode() {
-        return false;
+        diff.push(MetadataAttr::Mode);
     }
     if settings.size && metadata1.size() != metadata2.size() {
-        return false;
+        diff.push(MetadataAttr::Size);
     }
-    if settings.mtime {
-        if metadata1.mtime() != metadata2.mtime() {
-            return false;
-        }
-        // some filesystems do not support nanosecond precision, so we only compare nanoseconds if both files have them
-        if metadata1.mtime_nsec() != 0
-            && metadata2.mtime_nsec() != 0
-            && metadata1.mtime_nsec() != metadata2.mtime_nsec()
-        {
-            return false;
-        }
+    if (settings.mtime
+        && !time_equal(
+            metadata1.mtime(),
+            metadata1.mtime_nsec(),
+            metadata2.mtime(),
+            metadata2.mtime_nsec(),
+        ))
+        || (settings.mtime_ns
+            && (metadata1.mtime(), metadata1.mtime_nsec())
+                != (metadata2.mtime(), metadata2.mtime_nsec()))
+    {
+        diff.push(MetadataAttr::Mtime);
     }
-    if settings.ctime {
-        if metadata1.ctime() != metadata2.ctime() {
-            return false;
-        }
-        if metadata1.ctime_nsec() != 0
-            && metadata2.ctime_nsec() != 0
-            && metadata1.ctime_nsec() != metadata2.ctime_nsec()
-        {
-            return false;
-        }
+    if settings.ctime
+        && !time_equal(
+            metadata1.ctime(),
+            metadata1.ctime_nsec(),
+            metadata2.ctime(),
+            metadata2.ctime_nsec(),
+        )
+    {
+        diff.push(MetadataAttr::Ctime);
     }
-    true
+    diff
+}
+
+#[instrument]
+pub fn metadata_equal(
+    settings: &MetadataCmpSettings,
+    metadata1: &std::fs::Metadata,
+    metadata2: &std::fs::Metadata,
+) -> bool {
+    metadata_diff(settings, metadata1, metadata2).is_empty()
+}
+
+/// Computes the hash of the file contents
+pub async fn hash_file(
+    path: &std::path::Path,
+    algo: checksum::ChecksumAlgo,
+) -> Result<checksum::Checksum> {
+    let path = path.to_owned();
+    tokio::task::spawn_blocking(move || {
+        let file = std::fs::File::open(&path)
+            .with_context(|| format!("cannot open {:?} for reading", &path))?;
+        checksum::hash_reader(algo, file).with_context(|| format!("failed reading {:?}", &path))
+    })
+    .await?
+}
+
+/// Returns true if both files have the same contents hash
+#[instrument]
+pub async fn contents_equal(
+    path1: &std::path::Path,
+    path2: &std::path::Path,
+    algo: checksum::ChecksumAlgo,
+) -> Result<bool> {
+    let (hash1, hash2) = tokio::try_join!(hash_file(path1, algo), hash_file(path2, algo))?;
+    Ok(hash1 == hash2)
 }
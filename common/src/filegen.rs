@@ -0,0 +1,65 @@
+use anyhow::{Context, Result};
+use async_recursion::async_recursion;
+use rand::Rng;
+use tokio::io::AsyncWriteExt;
+
+async fn write_file(path: std::path::PathBuf, mut filesize: usize, bufsize: usize) -> Result<()> {
+    let mut bytes = vec![0u8; bufsize];
+    let mut file = tokio::fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(&path)
+        .await
+        .context(format!("Error opening {:?}", &path))?;
+    while filesize > 0 {
+        {
+            // make sure rng falls out of scope before await
+            let mut rng = rand::thread_rng();
+            rng.fill(&mut bytes[..]);
+        }
+        let writesize = std::cmp::min(filesize, bufsize);
+        file.write_all(&bytes[..writesize])
+            .await
+            .context(format!("Error writing to {:?}", &path))?;
+        filesize -= writesize;
+    }
+    Ok(())
+}
+
+/// Generates a directory tree with random file contents under 'root' (which must exist).
+///
+/// Every directory gets 'numfiles' files of 'filesize' bytes and the i-th level of sub-directories has 'dirwidth[i]'
+/// directories in each parent, e.g. [3, 2] generates 3 directories with 2 sub-directories each.
+#[async_recursion]
+pub async fn filegen(
+    root: &std::path::Path,
+    dirwidth: &[usize],
+    numfiles: usize,
+    filesize: usize,
+    writebuf: usize,
+) -> Result<()> {
+    let numdirs = *dirwidth.first().unwrap_or(&0);
+    let mut join_set = tokio::task::JoinSet::new();
+    // generate directories and recurse into them
+    for i in 0..numdirs {
+        let path = root.join(format!("dir{}", i));
+        let dirwidth = dirwidth[1..].to_owned();
+        let recurse = || async move {
+            tokio::fs::create_dir(&path)
+                .await
+                .map_err(anyhow::Error::msg)?;
+            filegen(&path, &dirwidth, numfiles, filesize, writebuf).await
+        };
+        join_set.spawn(recurse());
+    }
+    // generate files
+    for i in 0..numfiles {
+        let path = root.join(format!("file{}", i));
+        join_set.spawn(write_file(path.clone(), filesize, writebuf));
+    }
+    while let Some(res) = join_set.join_next().await {
+        res??
+    }
+    Ok(())
+}
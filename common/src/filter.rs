@@ -0,0 +1,416 @@
+use anyhow::{anyhow, Context, Result};
+use std::os::unix::fs::MetadataExt;
+
+use crate::ignore::IgnoreRules;
+
+/// Object types selected by the --type option
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct TypeFilter {
+    pub file: bool,
+    pub symlink: bool,
+    pub dir: bool,
+}
+
+impl std::str::FromStr for TypeFilter {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut types = TypeFilter {
+            file: false,
+            symlink: false,
+            dir: false,
+        };
+        for obj_type in s.split(',') {
+            match obj_type {
+                "f" | "file" => types.file = true,
+                "l" | "link" | "symlink" => types.symlink = true,
+                "d" | "dir" | "directory" => types.dir = true,
+                _ => return Err(anyhow!("Unknown object type: {}", obj_type)),
+            }
+        }
+        Ok(types)
+    }
+}
+
+/// Timestamp used by the --older-than/--newer-than filters
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum TimeAttr {
+    #[default]
+    Mtime,
+    Atime,
+    Ctime,
+}
+
+/// Compares the entry timestamp against the cutoff, used to select entries older or newer than it
+#[derive(Copy, Clone, Debug)]
+pub struct AgeFilter {
+    pub cutoff: std::time::SystemTime,
+    pub attr: TimeAttr,
+}
+
+impl AgeFilter {
+    /// Returns true if the timestamp is before the cutoff, false if it's at or after it
+    fn is_older(&self, metadata: &std::fs::Metadata) -> bool {
+        let (secs, nsecs) = match self.attr {
+            TimeAttr::Mtime => (metadata.mtime(), metadata.mtime_nsec()),
+            TimeAttr::Atime => (metadata.atime(), metadata.atime_nsec()),
+            TimeAttr::Ctime => (metadata.ctime(), metadata.ctime_nsec()),
+        };
+        let time = if secs >= 0 {
+            std::time::UNIX_EPOCH + std::time::Duration::new(secs as u64, nsecs as u32)
+        } else {
+            std::time::UNIX_EPOCH - std::time::Duration::from_secs(secs.unsigned_abs())
+        };
+        time < self.cutoff
+    }
+}
+
+/// Include or exclude rule (--include/--exclude) matched against the entry name
+#[derive(Clone, Debug)]
+pub struct NameRule {
+    pub include: bool,
+    pub pattern: glob::Pattern,
+    /// The pattern ended with a slash and matches directories only
+    pub dir_only: bool,
+}
+
+impl NameRule {
+    pub fn new(pattern: &str, include: bool) -> Result<Self> {
+        let (pattern, dir_only) = match pattern.strip_suffix('/') {
+            Some(pattern) => (pattern, true),
+            None => (pattern, false),
+        };
+        Ok(NameRule {
+            include,
+            pattern: glob::Pattern::new(pattern)
+                .with_context(|| format!("invalid pattern: {:?}", pattern))?,
+            dir_only,
+        })
+    }
+
+    fn matches(&self, name: &std::ffi::OsStr, metadata: &std::fs::Metadata) -> bool {
+        (!self.dir_only || metadata.is_dir()) && self.pattern.matches(&name.to_string_lossy())
+    }
+}
+
+/// Reads the rules of an --include-from/--exclude-from file: one pattern per line, empty lines and lines starting with
+/// '#' are ignored
+pub fn read_name_rules(path: &std::path::Path, include: bool) -> Result<Vec<NameRule>> {
+    let contents = std::fs::read_to_string(path)
+        .with_context(|| format!("cannot read patterns from {:?}", path))?;
+    contents
+        .lines()
+        .enumerate()
+        .filter(|(_, line)| !line.trim().is_empty() && !line.starts_with('#'))
+        .map(|(idx, line)| {
+            NameRule::new(line, include).with_context(|| format!("{:?}, line {}", path, idx + 1))
+        })
+        .collect()
+}
+
+/// Selects which entries are processed while traversing the source tree.
+///
+/// The include/exclude rules are checked first, in order, and the first one matching the entry name decides: excluded
+/// directories are skipped with all their contents. Otherwise directories are always traversed (to find the matching
+/// entries inside them), the other filters apply to files and symlinks only. All the filters must match for an entry
+/// to be selected.
+#[derive(Clone, Debug, Default)]
+pub struct FilterSettings {
+    pub min_size: Option<u64>,
+    pub max_size: Option<u64>,
+    /// None means all object types are selected
+    pub types: Option<TypeFilter>,
+    /// Files only, symlinks are selected by their own timestamps
+    pub older_than: Option<AgeFilter>,
+    /// Selects entries with the timestamp at or after the cutoff, same as `older_than` otherwise
+    pub newer_than: Option<AgeFilter>,
+    /// Glob pattern matched against the entry name
+    pub matching: Option<glob::Pattern>,
+    /// Include/exclude rules in the order they were given, entries not matching any of them are included
+    pub rules: Vec<NameRule>,
+    /// Skip the entries ignored by the .gitignore/.rcpignore files found while traversing the source
+    pub ignore_files: bool,
+    /// Rules of the ignore files found in the directories leading to the entries, set while traversing
+    pub ignore_rules: Option<std::sync::Arc<IgnoreRules>>,
+}
+
+impl FilterSettings {
+    /// Returns false if the entry with the given name and metadata should be skipped
+    pub fn matches(&self, name: &std::ffi::OsStr, metadata: &std::fs::Metadata) -> bool {
+        if self
+            .rules
+            .iter()
+            .find(|rule| rule.matches(name, metadata))
+            .is_some_and(|rule| !rule.include)
+        {
+            return false;
+        }
+        if metadata.is_dir() {
+            return true;
+        }
+        if self
+            .older_than
+            .is_some_and(|older_than| !older_than.is_older(metadata))
+        {
+            return false;
+        }
+        if self
+            .newer_than
+            .is_some_and(|newer_than| newer_than.is_older(metadata))
+        {
+            return false;
+        }
+        if self
+            .matching
+            .as_ref()
+            .is_some_and(|pattern| !pattern.matches(&name.to_string_lossy()))
+        {
+            return false;
+        }
+        if let Some(types) = self.types {
+            if (metadata.is_file() && !types.file) || (metadata.is_symlink() && !types.symlink) {
+                return false;
+            }
+        }
+        if metadata.is_file() {
+            if self
+                .min_size
+                .is_some_and(|min_size| metadata.len() < min_size)
+            {
+                return false;
+            }
+            if self
+                .max_size
+                .is_some_and(|max_size| metadata.len() > max_size)
+            {
+                return false;
+            }
+        }
+        true
+    }
+
+    /// Same as `matches`, also checking the rules of the ignore files found while traversing
+    pub fn matches_path(&self, path: &std::path::Path, metadata: &std::fs::Metadata) -> bool {
+        if self
+            .ignore_rules
+            .as_ref()
+            .is_some_and(|rules| rules.is_ignored(path, metadata.is_dir()))
+        {
+            return false;
+        }
+        self.matches(path.file_name().unwrap_or(path.as_os_str()), metadata)
+    }
+
+    /// Returns the filter for the entries of the given directory, with the rules of its ignore files added
+    pub async fn enter_dir(&self, dir: &std::path::Path) -> Result<Option<FilterSettings>> {
+        if !self.ignore_files {
+            return Ok(None);
+        }
+        Ok(Some(FilterSettings {
+            ignore_rules: IgnoreRules::load(self.ignore_rules.clone(), dir).await?,
+            ..self.clone()
+        }))
+    }
+
+    /// Returns true if directories are selected, used by rrm to decide if they should be removed
+    pub fn matches_dirs(&self) -> bool {
+        self.types.is_none_or(|types| types.dir)
+    }
+
+    /// Returns true if entries are selected by their name or age, rrm then keeps directories unless pruning them
+    pub fn selects_by_name_or_age(&self) -> bool {
+        self.older_than.is_some() || self.newer_than.is_some() || self.matching.is_some()
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.min_size.is_some()
+            || self.max_size.is_some()
+            || self.types.is_some()
+            || self.selects_by_name_or_age()
+            || !self.rules.is_empty()
+            || self.ignore_files
+    }
+}
+
+fn parse_size(size: &str) -> Result<u64> {
+    Ok(size
+        .parse::<bytesize::ByteSize>()
+        .map_err(anyhow::Error::msg)
+        .with_context(|| format!("invalid size: {:?}", size))?
+        .as_u64())
+}
+
+/// Parses the cutoff time of the --older-than/--newer-than options: a duration relative to the current time (e.g.
+/// "30d"), an RFC3339 timestamp (e.g. "2024-01-01T00:00:00Z", UTC if the offset is omitted) or seconds since the epoch
+/// (e.g. "@1704067200")
+fn parse_cutoff(time: &str) -> Result<std::time::SystemTime> {
+    if let Some(secs) = time.strip_prefix('@') {
+        let secs = secs
+            .parse::<i64>()
+            .with_context(|| format!("invalid epoch time: {:?}", time))?;
+        let offset = std::time::Duration::from_secs(secs.unsigned_abs());
+        return if secs >= 0 {
+            std::time::UNIX_EPOCH.checked_add(offset)
+        } else {
+            std::time::UNIX_EPOCH.checked_sub(offset)
+        }
+        .with_context(|| format!("epoch time {:?} is out of range", time));
+    }
+    if let Ok(timestamp) = humantime::parse_rfc3339_weak(time) {
+        return Ok(timestamp);
+    }
+    let age = humantime::parse_duration(time).with_context(|| {
+        format!(
+            "invalid time: {:?}, expected a duration (e.g. 30d), an RFC3339 timestamp or @<seconds since epoch>",
+            time
+        )
+    })?;
+    std::time::SystemTime::now()
+        .checked_sub(age)
+        .with_context(|| format!("duration {:?} is too large", time))
+}
+
+/// Builds the age filter out of the --older-than/--newer-than option
+pub fn parse_age_filter(time: &str, attr: TimeAttr) -> Result<AgeFilter> {
+    Ok(AgeFilter {
+        cutoff: parse_cutoff(time)?,
+        attr,
+    })
+}
+
+/// Parses the --matching glob pattern
+pub fn parse_name_pattern(pattern: &str) -> Result<glob::Pattern> {
+    glob::Pattern::new(pattern).with_context(|| format!("invalid --matching: {:?}", pattern))
+}
+
+/// Builds the filter settings out of the --min-size, --max-size and --type options
+pub fn parse_filter_settings(
+    min_size: Option<&str>,
+    max_size: Option<&str>,
+    types: Option<&str>,
+) -> Result<FilterSettings> {
+    let settings = FilterSettings {
+        min_size: min_size.map(parse_size).transpose()?,
+        max_size: max_size.map(parse_size).transpose()?,
+        types: types.map(str::parse).transpose()?,
+        ..Default::default()
+    };
+    if let (Some(min_size), Some(max_size)) = (settings.min_size, settings.max_size) {
+        if min_size > max_size {
+            return Err(anyhow!(
+                "--min-size ({}) is larger than --max-size ({})",
+                min_size,
+                max_size
+            ));
+        }
+    }
+    Ok(settings)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutils;
+
+    #[tokio::test]
+    async fn check_filter_matches() -> Result<()> {
+        let tmp_dir = testutils::create_temp_dir().await?;
+        let small = tmp_dir.join("small.txt");
+        let large = tmp_dir.join("large.txt");
+        let link = tmp_dir.join("link");
+        std::fs::write(&small, "x")?;
+        std::fs::write(&large, vec![0u8; 2048])?;
+        std::os::unix::fs::symlink(&small, &link)?;
+        let metadata = |path: &std::path::Path| std::fs::symlink_metadata(path).unwrap();
+        let name = |path: &std::path::Path| path.file_name().unwrap().to_owned();
+        let filter = parse_filter_settings(Some("1KiB"), Some("1MiB"), Some("f"))?;
+        assert!(!filter.matches(&name(&small), &metadata(&small)));
+        assert!(filter.matches(&name(&large), &metadata(&large)));
+        assert!(!filter.matches(&name(&link), &metadata(&link)));
+        assert!(filter.matches(&name(&tmp_dir), &metadata(&tmp_dir)));
+        assert!(!filter.matches_dirs());
+        let filter = parse_filter_settings(None, Some("1KiB"), Some("f,l,d"))?;
+        assert!(filter.matches(&name(&small), &metadata(&small)));
+        assert!(!filter.matches(&name(&large), &metadata(&large)));
+        assert!(filter.matches(&name(&link), &metadata(&link)));
+        assert!(filter.matches_dirs());
+        let filter = FilterSettings::default();
+        assert!(!filter.is_active());
+        assert!(filter.matches(&name(&large), &metadata(&large)));
+        let filter = FilterSettings {
+            matching: Some(parse_name_pattern("s*.txt")?),
+            ..Default::default()
+        };
+        assert!(filter.matches(&name(&small), &metadata(&small)));
+        assert!(!filter.matches(&name(&large), &metadata(&large)));
+        assert!(!filter.matches(&name(&link), &metadata(&link)));
+        assert!(filter.matches(&name(&tmp_dir), &metadata(&tmp_dir)));
+        let filter = FilterSettings {
+            older_than: Some(parse_age_filter("1h", TimeAttr::Mtime)?),
+            ..Default::default()
+        };
+        assert!(!filter.matches(&name(&small), &metadata(&small)));
+        std::fs::File::open(&small)?
+            .set_times(std::fs::FileTimes::new().set_modified(
+                std::time::SystemTime::now() - std::time::Duration::from_secs(7200),
+            ))?;
+        assert!(filter.matches(&name(&small), &metadata(&small)));
+        assert!(filter.is_active());
+        let filter = FilterSettings {
+            newer_than: Some(parse_age_filter("1h", TimeAttr::Mtime)?),
+            ..Default::default()
+        };
+        assert!(!filter.matches(&name(&small), &metadata(&small)));
+        assert!(filter.matches(&name(&large), &metadata(&large)));
+        assert!(filter.matches(&name(&tmp_dir), &metadata(&tmp_dir)));
+        // the first matching rule decides
+        let filter = FilterSettings {
+            rules: vec![
+                NameRule::new("small*", true)?,
+                NameRule::new("*.txt", false)?,
+                NameRule::new("*/", false)?,
+            ],
+            ..Default::default()
+        };
+        assert!(filter.is_active());
+        assert!(filter.matches(&name(&small), &metadata(&small)));
+        assert!(!filter.matches(&name(&large), &metadata(&large)));
+        assert!(filter.matches(&name(&link), &metadata(&link)));
+        assert!(!filter.matches(&name(&tmp_dir), &metadata(&tmp_dir)));
+        let rules_file = tmp_dir.join("rules");
+        std::fs::write(&rules_file, "# comment\n\n*.txt\nlog/\n")?;
+        let rules = read_name_rules(&rules_file, false)?;
+        assert_eq!(rules.len(), 2);
+        assert!(!rules[0].include && !rules[0].dir_only);
+        assert!(rules[1].dir_only);
+        std::fs::write(&rules_file, "[a-\n")?;
+        assert!(read_name_rules(&rules_file, false).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn check_parse_cutoff() -> Result<()> {
+        let epoch = |secs| std::time::UNIX_EPOCH + std::time::Duration::from_secs(secs);
+        assert_eq!(parse_cutoff("@1704067200")?, epoch(1704067200));
+        assert_eq!(parse_cutoff("2024-01-01T00:00:00Z")?, epoch(1704067200));
+        assert_eq!(parse_cutoff("2024-01-01 00:00:00")?, epoch(1704067200));
+        assert_eq!(
+            parse_cutoff("@-1")?,
+            std::time::UNIX_EPOCH - std::time::Duration::from_secs(1)
+        );
+        let cutoff = parse_cutoff("1h")?;
+        let expected = std::time::SystemTime::now() - std::time::Duration::from_secs(3600);
+        assert!(expected.duration_since(cutoff)? < std::time::Duration::from_secs(60));
+        assert!(parse_cutoff("@now").is_err());
+        assert!(parse_cutoff("2024-13-01T00:00:00Z").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn check_invalid_filters() {
+        assert!(parse_filter_settings(Some("10MiB"), Some("1MiB"), None).is_err());
+        assert!(parse_filter_settings(Some("lots"), None, None).is_err());
+        assert!(parse_filter_settings(None, None, Some("f,x")).is_err());
+        assert!(parse_age_filter("old", TimeAttr::Mtime).is_err());
+        assert!(parse_name_pattern("[a-").is_err());
+    }
+}
@@ -0,0 +1,172 @@
+use anyhow::{Context, Result};
+use tracing::{event, Level};
+
+/// Files with gitignore-style rules, applying to the directory they're in and everything below it
+pub const IGNORE_FILE_NAMES: &[&str] = &[".gitignore", ".rcpignore"];
+
+#[derive(Debug)]
+struct IgnoreRule {
+    pattern: glob::Pattern,
+    /// Entries matching a negated rule ("!pattern") are not ignored, even if an earlier rule ignores them
+    negated: bool,
+    dir_only: bool,
+    /// Patterns with a slash are matched against the path relative to the directory of the ignore file, others
+    /// against the entry name
+    anchored: bool,
+}
+
+impl IgnoreRule {
+    fn parse(line: &str) -> Result<Option<Self>> {
+        let line = line.trim_end();
+        if line.is_empty() || line.starts_with('#') {
+            return Ok(None);
+        }
+        let (line, negated) = match line.strip_prefix('!') {
+            Some(line) => (line, true),
+            None => (line.strip_prefix('\\').unwrap_or(line), false),
+        };
+        let (line, dir_only) = match line.strip_suffix('/') {
+            Some(line) => (line, true),
+            None => (line, false),
+        };
+        let (line, anchored) = match line.strip_prefix('/') {
+            Some(line) => (line, true),
+            None => (line, line.contains('/')),
+        };
+        let pattern =
+            glob::Pattern::new(line).with_context(|| format!("invalid pattern: {:?}", line))?;
+        Ok(Some(IgnoreRule {
+            pattern,
+            negated,
+            dir_only,
+            anchored,
+        }))
+    }
+
+    fn matches(&self, rel_path: &std::path::Path, is_dir: bool) -> bool {
+        if self.dir_only && !is_dir {
+            return false;
+        }
+        if self.anchored {
+            self.pattern.matches_path_with(
+                rel_path,
+                glob::MatchOptions {
+                    require_literal_separator: true,
+                    ..Default::default()
+                },
+            )
+        } else {
+            rel_path
+                .file_name()
+                .is_some_and(|name| self.pattern.matches(&name.to_string_lossy()))
+        }
+    }
+}
+
+/// Rules of the ignore files found in a directory, linked to the rules of its parent directories
+#[derive(Debug)]
+pub struct IgnoreRules {
+    dir: std::path::PathBuf,
+    rules: Vec<IgnoreRule>,
+    parent: Option<std::sync::Arc<IgnoreRules>>,
+}
+
+impl IgnoreRules {
+    /// Reads the ignore files of the given directory, returns the rules of the parent directories if there are none
+    pub async fn load(
+        parent: Option<std::sync::Arc<IgnoreRules>>,
+        dir: &std::path::Path,
+    ) -> Result<Option<std::sync::Arc<IgnoreRules>>> {
+        let mut rules = vec![];
+        for name in IGNORE_FILE_NAMES {
+            let path = dir.join(name);
+            let contents = match tokio::fs::read_to_string(&path).await {
+                Ok(contents) => contents,
+                Err(error) if error.kind() == std::io::ErrorKind::NotFound => continue,
+                Err(error) => {
+                    return Err(error)
+                        .with_context(|| format!("cannot read ignore file {:?}", &path))
+                }
+            };
+            for (idx, line) in contents.lines().enumerate() {
+                match IgnoreRule::parse(line) {
+                    Ok(rule) => rules.extend(rule),
+                    // like git, a broken rule doesn't prevent using the others
+                    Err(error) => event!(
+                        Level::WARN,
+                        "{:?}, line {}: {:#}, ignoring it",
+                        &path,
+                        idx + 1,
+                        &error
+                    ),
+                }
+            }
+        }
+        if rules.is_empty() {
+            return Ok(parent);
+        }
+        Ok(Some(std::sync::Arc::new(IgnoreRules {
+            dir: dir.to_owned(),
+            rules,
+            parent,
+        })))
+    }
+
+    /// Returns true if the entry is ignored: the last matching rule of the deepest ignore file with a matching rule
+    /// decides
+    pub fn is_ignored(&self, path: &std::path::Path, is_dir: bool) -> bool {
+        let mut level = Some(self);
+        while let Some(rules) = level {
+            if let Ok(rel_path) = path.strip_prefix(&rules.dir) {
+                if let Some(rule) = rules
+                    .rules
+                    .iter()
+                    .rev()
+                    .find(|rule| rule.matches(rel_path, is_dir))
+                {
+                    return !rule.negated;
+                }
+            }
+            level = rules.parent.as_deref();
+        }
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutils;
+
+    #[tokio::test]
+    async fn check_ignore_rules() -> Result<()> {
+        let tmp_dir = testutils::create_temp_dir().await?;
+        let sub = tmp_dir.join("sub");
+        std::fs::create_dir(&sub)?;
+        std::fs::write(
+            tmp_dir.join(".gitignore"),
+            "# build output\n*.o\n/target/\ndocs/*.html\n!keep.o\n[a-\n",
+        )?;
+        std::fs::write(sub.join(".rcpignore"), "data\n!*.o\n")?;
+        let root = IgnoreRules::load(None, &tmp_dir).await?.unwrap();
+        assert!(root.is_ignored(&tmp_dir.join("main.o"), false));
+        assert!(!root.is_ignored(&tmp_dir.join("keep.o"), false));
+        assert!(root.is_ignored(&tmp_dir.join("target"), true));
+        assert!(!root.is_ignored(&tmp_dir.join("target"), false));
+        assert!(!root.is_ignored(&sub.join("target"), true));
+        assert!(root.is_ignored(&tmp_dir.join("docs").join("index.html"), false));
+        assert!(!root.is_ignored(&tmp_dir.join("docs").join("api").join("index.html"), false));
+        let rules = IgnoreRules::load(Some(root.clone()), &sub).await?.unwrap();
+        assert!(rules.is_ignored(&sub.join("data"), true));
+        assert!(!rules.is_ignored(&tmp_dir.join("data"), true));
+        // the deeper ignore file takes precedence
+        assert!(!rules.is_ignored(&sub.join("main.o"), false));
+        // directories without ignore files use the rules of their parents
+        let empty = sub.join("empty");
+        std::fs::create_dir(&empty)?;
+        let rules = IgnoreRules::load(Some(rules), &empty).await?.unwrap();
+        assert!(rules.is_ignored(&empty.join("data"), false));
+        assert!(IgnoreRules::load(None, &empty).await?.is_none());
+        Ok(())
+    }
+}
@@ -0,0 +1,44 @@
+use crate::cmp::ObjType;
+use crate::filecmp;
+
+/// What the copy did with a destination entry
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ItemAction {
+    Created,
+    /// The existing destination was replaced, with the compared attributes that differed (empty if the overwrite was
+    /// forced, e.g. by `ignore_times`, or the comparison doesn't apply, e.g. symlinks pointing to different paths)
+    Overwritten(Vec<filecmp::MetadataAttr>),
+    Unchanged,
+    /// Only the metadata of the existing destination was updated
+    MetadataUpdated,
+    /// Hard-linked to a reference or a previously copied identical file instead of copied
+    HardLinked,
+}
+
+#[derive(Clone, Debug)]
+pub struct ItemizedChange {
+    pub obj_type: ObjType,
+    pub action: ItemAction,
+    /// Destination path
+    pub path: std::path::PathBuf,
+}
+
+/// Receives a change for every destination entry the copy handled, the receiving end decides how to report them
+pub type ItemizeSender = tokio::sync::mpsc::UnboundedSender<ItemizedChange>;
+
+/// Reports a change if itemizing is enabled
+pub(crate) fn report(
+    sender: &Option<ItemizeSender>,
+    obj_type: ObjType,
+    action: ItemAction,
+    path: &std::path::Path,
+) {
+    if let Some(sender) = sender {
+        // the receiver going away only means nobody is interested anymore
+        let _ = sender.send(ItemizedChange {
+            obj_type,
+            action,
+            path: path.to_owned(),
+        });
+    }
+}
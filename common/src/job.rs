@@ -0,0 +1,364 @@
+use anyhow::{anyhow, Context, Result};
+use tracing::{event, Level};
+
+use crate::progress;
+
+const STATE_FILE_NAME: &str = "state.json";
+const LOG_FILE_NAME: &str = "output.log";
+
+/// State of a job recorded in its job directory, updated periodically while it runs
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct JobState {
+    pub pid: u32,
+    /// Exit code, set once the job finished
+    pub exit_code: Option<i32>,
+    /// Latest progress snapshot, the same as the text progress updates
+    pub progress: String,
+    /// Summary (or error) printed at the end of the job
+    pub summary: Option<String>,
+    /// Time of the last update, in seconds since the epoch
+    pub updated: u64,
+}
+
+impl JobState {
+    fn to_json(&self) -> serde_json::Value {
+        serde_json::json!({
+            "pid": self.pid,
+            "exit_code": self.exit_code,
+            "progress": self.progress,
+            "summary": self.summary,
+            "updated": self.updated,
+        })
+    }
+
+    fn from_json(value: &serde_json::Value) -> Result<Self> {
+        let pid = value["pid"]
+            .as_u64()
+            .context("the job state doesn't have a pid")?;
+        Ok(JobState {
+            pid: pid as u32,
+            exit_code: value["exit_code"].as_i64().map(|code| code as i32),
+            progress: value["progress"].as_str().unwrap_or_default().to_string(),
+            summary: value["summary"].as_str().map(str::to_string),
+            updated: value["updated"].as_u64().unwrap_or_default(),
+        })
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.exit_code.is_some()
+    }
+
+    /// Returns true if the job process is still running
+    pub fn is_alive(&self) -> bool {
+        process_exists(self.pid)
+    }
+}
+
+/// Returns true if a process with the pid exists
+pub(crate) fn process_exists(pid: u32) -> bool {
+    // signal 0 only checks that the process exists, EPERM means it exists but belongs to someone else
+    let res = unsafe { libc::kill(pid as libc::pid_t, 0) };
+    res == 0 || std::io::Error::last_os_error().raw_os_error() == Some(libc::EPERM)
+}
+
+impl std::fmt::Display for JobState {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match (self.exit_code, &self.summary) {
+            (Some(exit_code), summary) => {
+                writeln!(f, "job {} finished with exit code {}", self.pid, exit_code)?;
+                if let Some(summary) = summary {
+                    write!(f, "{}", summary)?;
+                }
+                Ok(())
+            }
+            (None, _) => {
+                let age = std::time::SystemTime::now()
+                    .duration_since(std::time::UNIX_EPOCH)
+                    .map_or(0, |now| now.as_secs().saturating_sub(self.updated));
+                writeln!(
+                    f,
+                    "job {} is {}, last update {}s ago",
+                    self.pid,
+                    if self.is_alive() {
+                        "running"
+                    } else {
+                        "not running anymore and didn't record a result"
+                    },
+                    age
+                )?;
+                write!(f, "{}", self.progress)
+            }
+        }
+    }
+}
+
+/// Directory where a job records its state, so that it can be queried while it runs in the background and after it
+/// finished
+#[derive(Clone, Debug)]
+pub struct JobDir {
+    path: std::path::PathBuf,
+}
+
+impl JobDir {
+    pub fn new(path: &std::path::Path) -> Self {
+        JobDir {
+            path: path.to_owned(),
+        }
+    }
+
+    pub fn path(&self) -> &std::path::Path {
+        &self.path
+    }
+
+    /// File the output of a job started in the background goes to
+    pub fn log_path(&self) -> std::path::PathBuf {
+        self.path.join(LOG_FILE_NAME)
+    }
+
+    pub fn create(&self) -> Result<()> {
+        std::fs::create_dir_all(&self.path)
+            .with_context(|| format!("failed creating job directory {:?}", &self.path))
+    }
+
+    /// Writes the state atomically, readers never observe a partially written state
+    pub fn write_state(&self, state: &JobState) -> Result<()> {
+        let (tmp_path, path) = self.write_tmp_state(state)?;
+        std::fs::rename(&tmp_path, &path)
+            .with_context(|| format!("failed renaming {:?} to {:?}", &tmp_path, &path))
+    }
+
+    /// Writes the state unless the job already recorded one, returns false if it did
+    fn write_state_if_missing(&self, state: &JobState) -> Result<bool> {
+        let (tmp_path, path) = self.write_tmp_state(state)?;
+        // linking fails if the destination exists, unlike renaming
+        let res = std::fs::hard_link(&tmp_path, &path);
+        std::fs::remove_file(&tmp_path)
+            .with_context(|| format!("failed removing {:?}", &tmp_path))?;
+        match res {
+            Ok(()) => Ok(true),
+            Err(error) if error.kind() == std::io::ErrorKind::AlreadyExists => Ok(false),
+            Err(error) => {
+                Err(error).with_context(|| format!("failed linking {:?} to {:?}", &tmp_path, &path))
+            }
+        }
+    }
+
+    fn write_tmp_state(
+        &self,
+        state: &JobState,
+    ) -> Result<(std::path::PathBuf, std::path::PathBuf)> {
+        let state = JobState {
+            updated: std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .map_or(0, |now| now.as_secs()),
+            ..state.clone()
+        };
+        let path = self.path.join(STATE_FILE_NAME);
+        let tmp_path = self
+            .path
+            .join(format!(".{}.{}", STATE_FILE_NAME, std::process::id()));
+        std::fs::write(&tmp_path, format!("{:#}\n", state.to_json()))
+            .with_context(|| format!("failed writing job state {:?}", &tmp_path))?;
+        Ok((tmp_path, path))
+    }
+
+    pub fn read_state(&self) -> Result<JobState> {
+        let path = self.path.join(STATE_FILE_NAME);
+        let contents = std::fs::read_to_string(&path)
+            .with_context(|| format!("cannot read job state {:?}", &path))?;
+        let value: serde_json::Value = serde_json::from_str(&contents)
+            .with_context(|| format!("invalid job state {:?}", &path))?;
+        JobState::from_json(&value).with_context(|| format!("invalid job state {:?}", &path))
+    }
+
+    /// Starts the current executable with the given arguments in a new session, detached from the terminal, with its
+    /// output going to the job log file. Returns the pid of the started process.
+    pub fn spawn_detached(&self, args: &[std::ffi::OsString]) -> Result<u32> {
+        use std::os::unix::process::CommandExt;
+        self.create()?;
+        // the state of a previous job would be reported until the new one records its own
+        let state_path = self.path.join(STATE_FILE_NAME);
+        match std::fs::remove_file(&state_path) {
+            Err(error) if error.kind() != std::io::ErrorKind::NotFound => {
+                return Err(error).with_context(|| format!("failed removing {:?}", &state_path))
+            }
+            _ => {}
+        }
+        let log_path = self.log_path();
+        let log = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&log_path)
+            .with_context(|| format!("cannot open {:?}", &log_path))?;
+        let exe = std::env::current_exe().context("cannot find the current executable")?;
+        let mut cmd = std::process::Command::new(exe);
+        cmd.args(args)
+            .stdin(std::process::Stdio::null())
+            .stdout(log.try_clone()?)
+            .stderr(log);
+        // safety: setsid is async-signal-safe
+        unsafe {
+            cmd.pre_exec(|| {
+                if libc::setsid() < 0 {
+                    return Err(std::io::Error::last_os_error());
+                }
+                Ok(())
+            });
+        }
+        let child = cmd
+            .spawn()
+            .context("failed starting the job in the background")?;
+        let state = JobState {
+            pid: child.id(),
+            ..Default::default()
+        };
+        // makes the job visible to --status right away, the job itself may have recorded its state already
+        self.write_state_if_missing(&state)?;
+        Ok(child.id())
+    }
+
+    /// Waits until the job finishes and returns its final state
+    pub fn wait(&self) -> Result<JobState> {
+        loop {
+            let state = self.read_state()?;
+            if state.is_finished() {
+                return Ok(state);
+            }
+            if !state.is_alive() {
+                // the final state may have been written after we read it
+                let state = self.read_state()?;
+                if state.is_finished() {
+                    return Ok(state);
+                }
+                return Err(anyhow!(
+                    "job {} is not running anymore and didn't record a result",
+                    state.pid
+                ));
+            }
+            std::thread::sleep(std::time::Duration::from_millis(200));
+        }
+    }
+}
+
+/// Records the progress of the running job in its job directory every second and the result once it finishes
+pub(crate) struct JobTracker {
+    job_dir: JobDir,
+    lock_cvar: std::sync::Arc<(std::sync::Mutex<bool>, std::sync::Condvar)>,
+    thread: Option<std::thread::JoinHandle<()>>,
+}
+
+impl JobTracker {
+    pub fn new(
+        job_dir: JobDir,
+        prog: &'static progress::Progress,
+        units: progress::ByteUnits,
+    ) -> Result<Self> {
+        job_dir.create()?;
+        job_dir.write_state(&JobState {
+            pid: std::process::id(),
+            ..Default::default()
+        })?;
+        let lock_cvar =
+            std::sync::Arc::new((std::sync::Mutex::new(false), std::sync::Condvar::new()));
+        let thread = {
+            let job_dir = job_dir.clone();
+            let lock_cvar = lock_cvar.clone();
+            std::thread::spawn(move || {
+                let (lock, cvar) = &*lock_cvar;
+                let mut prog_printer = progress::ProgressPrinter::new(prog, units);
+                let mut is_done = lock.lock().unwrap();
+                loop {
+                    let result = cvar
+                        .wait_timeout(is_done, std::time::Duration::from_secs(1))
+                        .unwrap();
+                    is_done = result.0;
+                    if *is_done {
+                        break;
+                    }
+                    let state = JobState {
+                        pid: std::process::id(),
+                        progress: prog_printer.print().unwrap(),
+                        ..Default::default()
+                    };
+                    if let Err(error) = job_dir.write_state(&state) {
+                        event!(Level::WARN, "{:#}", &error);
+                    }
+                }
+            })
+        };
+        Ok(JobTracker {
+            job_dir,
+            lock_cvar,
+            thread: Some(thread),
+        })
+    }
+
+    /// Stops the progress updates and records the exit code and the summary
+    pub fn finish(mut self, exit_code: crate::ExitCode, summary: String) {
+        self.stop();
+        let state = JobState {
+            pid: std::process::id(),
+            exit_code: Some(exit_code.code()),
+            summary: Some(summary),
+            ..Default::default()
+        };
+        if let Err(error) = self.job_dir.write_state(&state) {
+            event!(Level::ERROR, "{:#}", &error);
+        }
+    }
+
+    fn stop(&mut self) {
+        let (lock, cvar) = &*self.lock_cvar;
+        *lock.lock().unwrap() = true;
+        cvar.notify_one();
+        if let Some(thread) = self.thread.take() {
+            thread.join().unwrap();
+        }
+    }
+}
+
+impl Drop for JobTracker {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutils;
+
+    #[tokio::test]
+    async fn check_job_state() -> Result<()> {
+        let tmp_dir = testutils::create_temp_dir().await?;
+        let job_dir = JobDir::new(&tmp_dir.join("job"));
+        job_dir.create()?;
+        assert!(job_dir.read_state().is_err());
+        let mut state = JobState {
+            pid: std::process::id(),
+            progress: "files: 1".to_string(),
+            ..Default::default()
+        };
+        job_dir.write_state(&state)?;
+        let read = job_dir.read_state()?;
+        assert!(read.is_alive());
+        assert!(!read.is_finished());
+        assert_eq!(read.progress, "files: 1");
+        assert!(read.to_string().contains("is running"));
+        state.exit_code = Some(1);
+        state.summary = Some("files copied: 1".to_string());
+        job_dir.write_state(&state)?;
+        let read = job_dir.wait()?;
+        assert_eq!(read.exit_code, Some(1));
+        assert_eq!(
+            read.to_string(),
+            format!(
+                "job {} finished with exit code 1\nfiles copied: 1",
+                std::process::id()
+            )
+        );
+        // only the state file is left
+        assert_eq!(std::fs::read_dir(job_dir.path())?.count(), 1);
+        Ok(())
+    }
+}
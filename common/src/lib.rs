@@ -3,38 +3,96 @@ extern crate lazy_static;
 
 use anyhow::anyhow;
 use anyhow::Context;
-use cmp::ObjType;
 use std::fmt;
 use std::io::IsTerminal;
 use tracing::{event, instrument, Level};
 use tracing_subscriber::fmt::format::FmtSpan;
 use tracing_subscriber::prelude::*;
 
+mod broker;
+mod capabilities;
+mod checkpoint;
+mod checksum;
 mod cmp;
+mod comparecache;
 mod copy;
+mod datacopy;
+mod delta;
 mod filecmp;
+mod filegen;
+mod filter;
+mod ignore;
+mod itemize;
+mod job;
 mod link;
+mod list;
+mod logfile;
+#[cfg(feature = "metrics")]
+mod metrics;
 mod preserve;
 mod progress;
 mod rm;
+mod staging;
+mod statedir;
+mod statefile;
+#[cfg(feature = "testing")]
+pub mod testing;
 mod testutils;
 mod throttle;
 
+pub use capabilities::{capabilities, capabilities_requested};
+pub use checkpoint::Checkpoint;
+pub use checksum::{Checksum, ChecksumAlgo, Hasher};
 pub use cmp::CmpResult;
 pub use cmp::CmpSettings;
 pub use cmp::CmpSummary;
+pub use cmp::LogFormat;
 pub use cmp::LogWriter;
 pub use cmp::ObjCmpSettings;
+pub use cmp::ObjType;
+pub use comparecache::CompareCache;
+pub use copy::is_dst_inside_src;
+pub use copy::is_limit_exceeded;
+pub use copy::is_same_root;
+pub use copy::Backup;
+pub use copy::BackupMode;
 pub use copy::CopyError;
+pub use copy::CopyLimits;
 pub use copy::CopySettings;
+pub use copy::CopyState;
 pub use copy::CopySummary;
+pub use copy::LinksMode;
+pub use copy::MetadataOnly;
+pub use copy::SymlinkRewrite;
+pub use datacopy::CopyMethod;
+pub use datacopy::Preallocate;
+pub use datacopy::Reflink;
+// building blocks of delta transfers, not used by the tools yet
+#[doc(hidden)]
+pub use delta::{block_checksums, block_size, BlockChecksum, RollingChecksum};
+pub use filecmp::MetadataAttr;
+pub use filegen::filegen;
+pub use filter::{
+    parse_age_filter, parse_filter_settings, parse_name_pattern, read_name_rules, AgeFilter,
+    FilterSettings, NameRule, TimeAttr,
+};
+pub use itemize::{ItemAction, ItemizeSender, ItemizedChange};
+pub use job::{JobDir, JobState};
 pub use link::LinkError;
 pub use link::LinkSettings;
 pub use link::LinkSummary;
-pub use preserve::{preserve_all, preserve_default, PreserveSettings};
+pub use list::ListSettings;
+pub use logfile::LogFileSettings;
+pub use preserve::{
+    preserve_all, preserve_default, Chmod, IdMapping, PreserveSettings, UnmappedIds,
+};
+pub use progress::format_bytes;
+pub use progress::ByteUnits;
 pub use rm::RmError;
 pub use rm::RmSettings;
 pub use rm::RmSummary;
+pub use staging::Staging;
+pub use statedir::{StateDir, StateEntry, STATE_DIR_NAME};
 
 lazy_static! {
     static ref PROGRESS: progress::Progress = progress::Progress::new();
@@ -43,6 +101,7 @@ lazy_static! {
 
 struct ProgressTracker {
     lock_cvar: std::sync::Arc<(std::sync::Mutex<bool>, std::sync::Condvar)>,
+    interactive: bool,
     pbar_thread: Option<std::thread::JoinHandle<()>>,
 }
 
@@ -87,16 +146,118 @@ impl std::str::FromStr for ProgressType {
     }
 }
 
+/// Outcomes of the tools, several of them share an exit code (see [`ExitCode::code`]):
+/// - 0: success
+/// - 1: completed with errors, some entries failed but the operation finished
+/// - 2: fatal error, the operation couldn't start (e.g. invalid arguments) or was aborted
+/// - 3: interrupted, the operation was cancelled by a signal or on timeout
+///
+/// `rcmp` also returns 1 if differences were found.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ExitCode {
+    /// All operations succeeded
+    Success,
+    /// Some operations failed, the operation finished
+    PartialFailure,
+    /// Invalid arguments or settings, nothing was done
+    UsageError,
+    /// Failed connecting to a remote host (reserved for remote operations)
+    ConnectionError,
+    /// Nothing could be done or the operation was aborted (e.g. with --fail-early)
+    Failure,
+    /// The operation didn't finish within the time limit (--timeout) and was cancelled
+    Timeout,
+    /// The operation was cancelled by SIGINT
+    Interrupted,
+    /// The operation was cancelled by SIGTERM
+    Terminated,
+}
+
+impl ExitCode {
+    /// Returns `Failure` if nothing succeeded (the operation couldn't start) and `PartialFailure` otherwise
+    pub fn from_errors(any_succeeded: bool) -> Self {
+        if any_succeeded {
+            ExitCode::PartialFailure
+        } else {
+            ExitCode::Failure
+        }
+    }
+
+    /// Finds the exit code attached to the error, errors without one are treated as `Failure`
+    pub fn from_error(error: &anyhow::Error) -> Self {
+        error
+            .downcast_ref::<ExitError>()
+            .map_or(ExitCode::Failure, |error| error.exit_code)
+    }
+
+    /// Returns true if the operation was cancelled before it finished: on timeout or by a signal
+    pub fn is_cancelled(self) -> bool {
+        matches!(
+            self,
+            ExitCode::Timeout | ExitCode::Interrupted | ExitCode::Terminated
+        )
+    }
+
+    /// The exit code of the process
+    pub fn code(self) -> i32 {
+        match self {
+            ExitCode::Success => 0,
+            ExitCode::PartialFailure => 1,
+            ExitCode::UsageError | ExitCode::ConnectionError | ExitCode::Failure => 2,
+            ExitCode::Timeout | ExitCode::Interrupted | ExitCode::Terminated => 3,
+        }
+    }
+
+    pub fn exit(self) -> ! {
+        std::process::exit(self.code())
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+#[error("{error:#}")]
+pub struct ExitError {
+    pub error: anyhow::Error,
+    pub exit_code: ExitCode,
+}
+
+impl ExitError {
+    pub fn new(error: anyhow::Error, exit_code: ExitCode) -> Self {
+        ExitError { error, exit_code }
+    }
+}
+
 #[derive(Debug)]
 pub struct ProgressSettings {
     pub progress_type: ProgressType,
     pub progress_delay: Option<String>,
 }
 
+/// Logging, throttling and monitoring options of the tools, see [`run`]
+#[derive(Debug, Default)]
+pub struct RunSettings {
+    /// Write the log messages to a rotated file instead of stdout
+    pub log_file: Option<LogFileSettings>,
+    /// Units of the byte counts in the progress and the summary
+    pub units: ByteUnits,
+    /// Bandwidth limits changing with the time of day (--bwlimit-schedule)
+    pub bwlimit_schedule: Option<String>,
+    /// Throttling rules applying to some of the paths (--throttle-rule)
+    pub throttle_rules: Vec<String>,
+    /// Socket of the broker sharing the throttling limits between processes
+    pub throttle_broker: Option<String>,
+    /// Address to serve the Prometheus metrics on
+    pub metrics_addr: Option<String>,
+    /// Limit of the wall-clock time of the whole operation
+    pub timeout: Option<String>,
+    /// Directory to record the state of the job in, see [`JobDir`]
+    pub job_dir: Option<std::path::PathBuf>,
+}
+
 fn progress_bar(
     lock: &std::sync::Mutex<bool>,
     cvar: &std::sync::Condvar,
     delay_opt: &Option<std::time::Duration>,
+    units: progress::ByteUnits,
 ) {
     let delay = delay_opt.unwrap_or(std::time::Duration::from_millis(200));
     PBAR.set_style(
@@ -104,11 +265,16 @@ fn progress_bar(
             .unwrap()
             .tick_strings(&["⠋", "⠙", "⠹", "⠸", "⠼", "⠴", "⠦", "⠧", "⠇", "⠏"]),
     );
-    let mut prog_printer = progress::ProgressPrinter::new(&PROGRESS);
+    let mut prog_printer = progress::ProgressPrinter::new(&PROGRESS, units);
     let mut is_done = lock.lock().unwrap();
     loop {
         PBAR.set_position(PBAR.position() + 1); // do we need to update?
-        PBAR.set_message(prog_printer.print().unwrap());
+        let message = prog_printer.print().unwrap();
+        // the width is checked every time so we adjust as soon as the terminal is resized
+        PBAR.set_message(match terminal_width() {
+            Some(width) => progress::fit_to_width(&message, width, 2),
+            None => message,
+        });
         let result = cvar.wait_timeout(is_done, delay).unwrap();
         is_done = result.0;
         if *is_done {
@@ -122,9 +288,10 @@ fn text_updates(
     lock: &std::sync::Mutex<bool>,
     cvar: &std::sync::Condvar,
     delay_opt: &Option<std::time::Duration>,
+    units: progress::ByteUnits,
 ) {
     let delay = delay_opt.unwrap_or(std::time::Duration::from_secs(10));
-    let mut prog_printer = progress::ProgressPrinter::new(&PROGRESS);
+    let mut prog_printer = progress::ProgressPrinter::new(&PROGRESS, units);
     let mut is_done = lock.lock().unwrap();
     loop {
         eprintln!("--{}", prog_printer.print().unwrap());
@@ -137,28 +304,50 @@ fn text_updates(
 }
 
 impl ProgressTracker {
-    pub fn new(progress_type: ProgressType, delay_opt: Option<std::time::Duration>) -> Self {
+    pub fn new(
+        progress_type: ProgressType,
+        delay_opt: Option<std::time::Duration>,
+        units: progress::ByteUnits,
+    ) -> Self {
         let lock_cvar =
             std::sync::Arc::new((std::sync::Mutex::new(false), std::sync::Condvar::new()));
         let lock_cvar_clone = lock_cvar.clone();
+        let interactive = match progress_type {
+            ProgressType::Auto => std::io::stderr().is_terminal(),
+            ProgressType::ProgressBar => true,
+            ProgressType::TextUpdates => false,
+        };
         let pbar_thread = std::thread::spawn(move || {
             let (lock, cvar) = &*lock_cvar_clone;
-            let interactive = match progress_type {
-                ProgressType::Auto => std::io::stderr().is_terminal(),
-                ProgressType::ProgressBar => true,
-                ProgressType::TextUpdates => false,
-            };
             if interactive {
-                progress_bar(lock, cvar, &delay_opt);
+                progress_bar(lock, cvar, &delay_opt, units);
             } else {
-                text_updates(lock, cvar, &delay_opt);
+                text_updates(lock, cvar, &delay_opt, units);
             }
         });
         Self {
             lock_cvar,
+            interactive,
             pbar_thread: Some(pbar_thread),
         }
     }
+
+    /// Redraws the progress bar as soon as the terminal is resized (SIGWINCH), until the runtime shuts down
+    async fn redraw_on_resize(
+        lock_cvar: std::sync::Arc<(std::sync::Mutex<bool>, std::sync::Condvar)>,
+    ) {
+        let mut resized =
+            match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::window_change()) {
+                Ok(resized) => resized,
+                Err(error) => {
+                    event!(Level::WARN, "cannot watch for terminal resizes: {}", &error);
+                    return;
+                }
+            };
+        while resized.recv().await.is_some() {
+            lock_cvar.1.notify_one();
+        }
+    }
 }
 
 impl Drop for ProgressTracker {
@@ -176,8 +365,12 @@ impl Drop for ProgressTracker {
 
 pub fn parse_metadata_cmp_settings(
     settings: &str,
+    checksum_algo: ChecksumAlgo,
 ) -> Result<filecmp::MetadataCmpSettings, anyhow::Error> {
-    let mut metadata_cmp_settings = filecmp::MetadataCmpSettings::default();
+    let mut metadata_cmp_settings = filecmp::MetadataCmpSettings {
+        checksum_algo,
+        ..Default::default()
+    };
     for setting in settings.split(',') {
         match setting {
             "uid" => metadata_cmp_settings.uid = true,
@@ -185,7 +378,9 @@ pub fn parse_metadata_cmp_settings(
             "mode" => metadata_cmp_settings.mode = true,
             "size" => metadata_cmp_settings.size = true,
             "mtime" => metadata_cmp_settings.mtime = true,
+            "mtime_ns" => metadata_cmp_settings.mtime_ns = true,
             "ctime" => metadata_cmp_settings.ctime = true,
+            "hash" => metadata_cmp_settings.hash = true,
             _ => {
                 return Err(anyhow!("Unknown metadata comparison setting: {}", setting));
             }
@@ -203,7 +398,13 @@ fn parse_type_settings(
         match setting {
             "uid" => user_and_time.uid = true,
             "gid" => user_and_time.gid = true,
-            "time" => user_and_time.time = true,
+            "time" => {
+                user_and_time.atime = true;
+                user_and_time.mtime = true;
+            }
+            "atime" => user_and_time.atime = true,
+            "mtime" => user_and_time.mtime = true,
+            "btime" => user_and_time.btime = true,
             _ => {
                 if let Ok(mask) = u32::from_str_radix(setting, 8) {
                     mode_mask = Some(mask);
@@ -257,14 +458,63 @@ pub fn parse_preserve_settings(
     Ok(preserve_settings)
 }
 
-pub fn parse_compare_settings(settings: &str) -> Result<ObjCmpSettings, anyhow::Error> {
+fn resolve_user(name: &str) -> Result<u32, anyhow::Error> {
+    nix::unistd::User::from_name(name)
+        .with_context(|| format!("failed looking up user: {}", name))?
+        .map(|user| user.uid.as_raw())
+        .ok_or_else(|| anyhow!("Unknown user: {}", name))
+}
+
+fn resolve_group(name: &str) -> Result<u32, anyhow::Error> {
+    nix::unistd::Group::from_name(name)
+        .with_context(|| format!("failed looking up group: {}", name))?
+        .map(|group| group.gid.as_raw())
+        .ok_or_else(|| anyhow!("Unknown group: {}", name))
+}
+
+/// Parses the --usermap option, names are resolved using the local user database
+pub fn parse_user_map(map: &str) -> Result<std::collections::HashMap<u32, u32>, anyhow::Error> {
+    preserve::parse_id_map(map, resolve_user)
+}
+
+/// Parses the --groupmap option, names are resolved using the local group database
+pub fn parse_group_map(map: &str) -> Result<std::collections::HashMap<u32, u32>, anyhow::Error> {
+    preserve::parse_id_map(map, resolve_group)
+}
+
+/// Parses the --chown option: "<user>:<group>", "<user>:" or ":<group>" where the user and group are either numeric
+/// ids or names resolved using the local databases. Returns the uid and gid to apply, None for the omitted parts.
+pub fn parse_chown(chown: &str) -> Result<(Option<u32>, Option<u32>), anyhow::Error> {
+    let (user, group) = chown
+        .split_once(':')
+        .ok_or_else(|| anyhow!("Invalid owner: {}, expected <user>:<group>", chown))?;
+    if user.is_empty() && group.is_empty() {
+        return Err(anyhow!("Invalid owner: {}, expected <user>:<group>", chown));
+    }
+    let resolve = |id: &str, resolve_name: fn(&str) -> Result<u32, anyhow::Error>| {
+        if id.is_empty() {
+            return Ok(None);
+        }
+        match id.parse::<u32>() {
+            Ok(id) => Ok(Some(id)),
+            Err(_) => resolve_name(id).map(Some),
+        }
+    };
+    Ok((resolve(user, resolve_user)?, resolve(group, resolve_group)?))
+}
+
+pub fn parse_compare_settings(
+    settings: &str,
+    checksum_algo: ChecksumAlgo,
+) -> Result<ObjCmpSettings, anyhow::Error> {
     let mut cmp_settings = ObjCmpSettings::default();
     for type_settings in settings.split(' ') {
         if let Some((obj_type, obj_settings)) = type_settings.split_once(':') {
-            let obj_cmp_settings = parse_metadata_cmp_settings(obj_settings).context(format!(
-                "parsing preserve settings: {}, type: {}",
-                obj_settings, obj_type
-            ))?;
+            let obj_cmp_settings = parse_metadata_cmp_settings(obj_settings, checksum_algo)
+                .context(format!(
+                    "parsing preserve settings: {}, type: {}",
+                    obj_settings, obj_type
+                ))?;
             let obj_type = match obj_type {
                 "f" | "file" => ObjType::File,
                 "d" | "dir" | "directory" => ObjType::Dir,
@@ -287,7 +537,7 @@ pub async fn cmp(
     log: &cmp::LogWriter,
     settings: &cmp::CmpSettings,
 ) -> Result<CmpSummary, anyhow::Error> {
-    cmp::cmp(&PROGRESS, src, dst, log, settings).await
+    cmp::cmp(&PROGRESS, src, src, dst, log, settings).await
 }
 
 pub async fn copy(
@@ -298,6 +548,11 @@ pub async fn copy(
 ) -> Result<CopySummary, CopyError> {
     let cwd = std::env::current_dir()
         .map_err(|err| CopyError::new(anyhow::Error::msg(err), CopySummary::default()))?;
+    // relative symlink targets are relative to the directory of the symlink
+    let cwd = match src.parent() {
+        Some(parent) => cwd.join(parent),
+        None => cwd,
+    };
     copy::copy(&PROGRESS, &cwd, src, dst, settings, preserve, false).await
 }
 
@@ -305,6 +560,12 @@ pub async fn rm(path: &std::path::Path, settings: &rm::RmSettings) -> Result<RmS
     rm::rm(&PROGRESS, path, settings).await
 }
 
+/// Prints the entries that would be copied from 'src' to stdout, with paths starting with the name of 'src'
+pub async fn list(src: &std::path::Path, settings: &list::ListSettings) -> anyhow::Result<()> {
+    let rel_path = src.file_name().map_or(src, std::path::Path::new);
+    list::list(src, rel_path, settings, &mut std::io::stdout()).await
+}
+
 pub async fn link(
     src: &std::path::Path,
     dst: &std::path::Path,
@@ -335,10 +596,19 @@ fn print_runtime_stats() -> Result<(), anyhow::Error> {
     let ticks_to_duration = |ticks: u64| {
         std::time::Duration::from_secs_f64(ticks as f64 / clock_ticks_per_second as f64)
     };
+    // reported in kB
     let vmhwm = process.status()?.vmhwm.unwrap_or(0);
     println!("walltime : {:.2?}", &PROGRESS.get_duration(),);
     println!("cpu time : {:.2?} | k: {:.2?} | u: {:.2?}", ticks_to_duration(stat.utime + stat.stime), ticks_to_duration(stat.stime), ticks_to_duration(stat.utime));
-    println!("peak RSS : {:.2?}", bytesize::ByteSize(vmhwm));
+    println!("peak RSS : {}", progress::format_bytes(vmhwm * 1024));
+    if let Some(tokens) = throttle::tput_tokens_consumed() {
+        let (_, tput_wait) = throttle::wait_times();
+        println!(
+            "bwlimit  : {} throttled | waited: {:.2?}",
+            progress::format_bytes(tokens),
+            tput_wait
+        );
+    }
     Ok(())
 }
 
@@ -356,21 +626,133 @@ fn get_max_open_files() -> Result<u64, std::io::Error> {
     }
 }
 
-struct ProgWriter {}
+/// Returns the width of the terminal attached to stderr (where the progress bar is drawn)
+fn terminal_width() -> Option<usize> {
+    let mut winsize: libc::winsize = unsafe { std::mem::zeroed() };
+    // safety: TIOCGWINSZ only writes into the winsize struct
+    let result = unsafe { libc::ioctl(libc::STDERR_FILENO, libc::TIOCGWINSZ, &mut winsize) };
+    if result == 0 && winsize.ws_col > 0 {
+        Some(winsize.ws_col as usize)
+    } else {
+        None
+    }
+}
+
+/// Buffers a log message and prints it all at once while the progress bar is cleared, so that the progress bar is
+/// never drawn in the middle of a message (one writer is created for every tracing event)
+struct ProgWriter {
+    buf: Vec<u8>,
+}
 
 impl ProgWriter {
     fn new() -> Self {
-        Self {}
+        Self { buf: Vec::new() }
     }
 }
 
 impl std::io::Write for ProgWriter {
     fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
-        PBAR.suspend(|| std::io::stdout().write(buf))
+        self.buf.extend_from_slice(buf);
+        Ok(buf.len())
     }
 
     fn flush(&mut self) -> std::io::Result<()> {
-        std::io::stdout().flush()
+        if self.buf.is_empty() {
+            return Ok(());
+        }
+        let buf = std::mem::take(&mut self.buf);
+        PBAR.suspend(|| {
+            let mut stdout = std::io::stdout().lock();
+            stdout.write_all(&buf)?;
+            stdout.flush()
+        })
+    }
+}
+
+impl Drop for ProgWriter {
+    fn drop(&mut self) {
+        use std::io::Write;
+        self.flush().ok();
+    }
+}
+
+/// Reports errors in the tool settings detected before the operation starts
+fn settings_error(quiet: bool, error: anyhow::Error) -> ExitError {
+    if !quiet {
+        println!("{:#}", error);
+    }
+    ExitError::new(error, ExitCode::UsageError)
+}
+
+/// Samples the runtime resource usage shown in the progress updates, logged with -vv
+async fn monitor_resources() {
+    let mut interval = tokio::time::interval(std::time::Duration::from_secs(1));
+    for sample in 0u64.. {
+        interval.tick().await;
+        // a task that yields is polled again after the tasks already queued on its worker
+        let start = std::time::Instant::now();
+        tokio::task::yield_now().await;
+        let scheduling_delay = start.elapsed();
+        PROGRESS.resources.scheduling_delay_us.store(
+            scheduling_delay.as_micros() as u64,
+            std::sync::atomic::Ordering::Relaxed,
+        );
+        if sample % 10 == 0 {
+            event!(
+                Level::DEBUG,
+                "open files: {}, workers: {}, scheduling delay: {:?}",
+                PROGRESS.resources.open_files(),
+                PROGRESS
+                    .resources
+                    .workers
+                    .load(std::sync::atomic::Ordering::Relaxed),
+                scheduling_delay
+            );
+        }
+    }
+}
+
+/// Waits for SIGINT or SIGTERM and returns the error the operation is cancelled with
+async fn termination_signal() -> ExitError {
+    use tokio::signal::unix::{signal, SignalKind};
+    let (mut sigint, mut sigterm) = match (
+        signal(SignalKind::interrupt()),
+        signal(SignalKind::terminate()),
+    ) {
+        (Ok(sigint), Ok(sigterm)) => (sigint, sigterm),
+        (Err(error), _) | (_, Err(error)) => {
+            event!(
+                Level::WARN,
+                "cannot handle SIGINT and SIGTERM, a partial summary won't be printed: {}",
+                &error
+            );
+            return std::future::pending().await;
+        }
+    };
+    let (signal_name, exit_code) = tokio::select! {
+        _ = sigint.recv() => ("SIGINT", ExitCode::Interrupted),
+        _ = sigterm.recv() => ("SIGTERM", ExitCode::Terminated),
+    };
+    ExitError::new(
+        anyhow!("operation interrupted by {}", signal_name),
+        exit_code,
+    )
+}
+
+/// Prints the directories and files being copied to stderr on SIGUSR1, until the runtime shuts down
+async fn dump_in_flight_on_signal(units: ByteUnits) {
+    let mut dump =
+        match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::user_defined1()) {
+            Ok(dump) => dump,
+            Err(error) => {
+                event!(Level::WARN, "cannot handle SIGUSR1: {}", &error);
+                return;
+            }
+        };
+    while dump.recv().await.is_some() {
+        let dump = PROGRESS.in_flight.dump(units);
+        // like the log messages, the dump is printed while the progress bar is cleared
+        PBAR.suspend(|| eprintln!("{}", dump));
     }
 }
 
@@ -385,40 +767,76 @@ pub fn run<Fut, Summary, Error>(
     max_blocking_threads: usize,
     max_open_files: Option<usize>,
     ops_throttle: usize,
+    settings: RunSettings,
     func: impl FnOnce() -> Fut,
 ) -> Result<Summary, anyhow::Error>
 where
     Summary: std::fmt::Display,
-    Error: std::fmt::Display + std::fmt::Debug,
+    Error: Into<anyhow::Error>,
     Fut: std::future::Future<Output = Result<Summary, Error>>,
 {
+    let RunSettings {
+        log_file,
+        units,
+        bwlimit_schedule,
+        throttle_rules,
+        throttle_broker,
+        metrics_addr,
+        timeout,
+        job_dir,
+    } = settings;
+    progress::set_byte_units(units);
     if !quiet {
-        let fmt_layer = tracing_subscriber::fmt::layer()
-            .with_target(true)
-            .with_line_number(true)
-            .with_span_events(if verbose > 2 {
+        let env_filter = || {
+            tracing_subscriber::EnvFilter::try_new(match verbose {
+                0 => "error",
+                1 => "info",
+                2 => "debug",
+                _ => "trace",
+            })
+            .unwrap()
+        };
+        let span_events = || {
+            if verbose > 2 {
                 FmtSpan::NEW | FmtSpan::CLOSE
             } else {
                 FmtSpan::NONE
-            })
-            .pretty()
-            .with_writer(ProgWriter::new)
-            .with_filter(
-                tracing_subscriber::EnvFilter::try_new(match verbose {
-                    0 => "error",
-                    1 => "info",
-                    2 => "debug",
-                    _ => "trace",
-                })
-                .unwrap(),
-            );
+            }
+        };
+        // log messages go either to stdout or to the log file (if specified)
+        let (fmt_layer, file_layer) = match log_file {
+            Some(log_file_settings) => {
+                let log_file = logfile::RotatingFile::new(&log_file_settings)
+                    .map_err(|err| settings_error(quiet, err))?;
+                let file_layer = tracing_subscriber::fmt::layer()
+                    .with_target(true)
+                    .with_line_number(true)
+                    .with_span_events(span_events())
+                    .with_ansi(false)
+                    .with_writer(std::sync::Mutex::new(log_file))
+                    .with_filter(env_filter());
+                (None, Some(file_layer))
+            }
+            None => {
+                let fmt_layer = tracing_subscriber::fmt::layer()
+                    .with_target(true)
+                    .with_line_number(true)
+                    .with_span_events(span_events())
+                    .pretty()
+                    .with_writer(ProgWriter::new)
+                    .with_filter(env_filter());
+                (Some(fmt_layer), None)
+            }
+        };
 
         let is_console_enabled = match std::env::var("RCP_TOKIO_TRACING_CONSOLE_ENABLED") {
             Ok(val) => matches!(val.to_lowercase().as_str(), "true" | "1"),
             Err(_) => false,
         };
 
-        let subscriber = tracing_subscriber::registry().with(fmt_layer);
+        let subscriber = tracing_subscriber::registry()
+            .with(fmt_layer)
+            .with(file_layer);
 
         if is_console_enabled {
             let console_port: u16 =
@@ -469,35 +887,147 @@ where
     } else {
         event!(Level::INFO, "Not applying any limit to max open files!",);
     }
+    let timeout = timeout
+        .map(|timeout| {
+            humantime::parse_duration(&timeout)
+                .with_context(|| format!("invalid --timeout: {:?}", timeout))
+        })
+        .transpose()
+        .map_err(|err| settings_error(quiet, err))?;
     let runtime = builder.build()?;
+    PROGRESS.resources.workers.store(
+        runtime.metrics().num_workers(),
+        std::sync::atomic::Ordering::Relaxed,
+    );
+    runtime.spawn(monitor_resources());
     if ops_throttle > 0 {
-        let mut replenish = ops_throttle;
-        let mut interval = std::time::Duration::from_secs(1);
-        while replenish > 100 && interval > std::time::Duration::from_millis(1) {
-            replenish /= 10;
-            interval /= 10;
-        }
+        let (replenish, interval) = throttle::ops_replenish(ops_throttle);
         throttle::set_init_tokens(replenish);
         runtime.spawn(throttle::start_replenish_thread(replenish, interval));
     }
+    if let Some(bwlimit_schedule) = bwlimit_schedule {
+        let schedule = bwlimit_schedule
+            .parse::<throttle::BwSchedule>()
+            .with_context(|| format!("invalid --bwlimit-schedule: {:?}", bwlimit_schedule))
+            .map_err(|err| settings_error(quiet, err))?;
+        throttle::set_tput_schedule(&schedule);
+        runtime.spawn(throttle::start_tput_replenish_thread(schedule));
+    }
+    if !throttle_rules.is_empty() {
+        let rules = throttle_rules
+            .iter()
+            .map(|rule| {
+                rule.parse::<throttle::ThrottleRule>()
+                    .with_context(|| format!("invalid --throttle-rule: {:?}", rule))
+            })
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|err| settings_error(quiet, err))?;
+        throttle::set_throttle_rules(rules);
+        runtime.spawn(throttle::start_throttle_rules_replenish_threads());
+    }
+    if let Some(throttle_broker) = throttle_broker {
+        if let Err(error) = runtime.block_on(broker::start(std::path::Path::new(&throttle_broker)))
+        {
+            event!(
+                Level::WARN,
+                "cannot use the throttle broker, throttling locally: {:#}",
+                &error
+            );
+        }
+    }
+    if let Some(metrics_addr) = metrics_addr {
+        #[cfg(feature = "metrics")]
+        {
+            let listener = runtime
+                .block_on(metrics::bind(&metrics_addr))
+                .map_err(|err| settings_error(quiet, err))?;
+            runtime.spawn(metrics::serve(listener, &PROGRESS));
+        }
+        #[cfg(not(feature = "metrics"))]
+        return Err(settings_error(
+            quiet,
+            anyhow!(
+                "cannot serve metrics on {:?}, built without the \"metrics\" feature",
+                metrics_addr
+            ),
+        )
+        .into());
+    }
+    runtime.spawn(dump_in_flight_on_signal(units));
+    let job = job_dir
+        .map(|job_dir| job::JobTracker::new(JobDir::new(&job_dir), &PROGRESS, units))
+        .transpose()
+        .map_err(|err| settings_error(quiet, err))?;
     let res = {
         let _progress = progress.map(|settings| {
             let delay = settings.progress_delay.map(|delay_str| {
                 humantime::parse_duration(&delay_str)
                     .expect("Couldn't parse duration out of --progress-delay")
             });
-            ProgressTracker::new(settings.progress_type, delay)
+            let tracker = ProgressTracker::new(settings.progress_type, delay, units);
+            if tracker.interactive {
+                runtime.spawn(ProgressTracker::redraw_on_resize(tracker.lock_cvar.clone()));
+            }
+            tracker
+        });
+        let res = runtime.block_on(async {
+            let operation = async {
+                match timeout {
+                    Some(timeout) => match tokio::time::timeout(timeout, func()).await {
+                        Ok(res) => res.map_err(Into::into),
+                        Err(_) => Err(ExitError::new(
+                            anyhow!(
+                                "operation timed out after {}",
+                                humantime::format_duration(timeout)
+                            ),
+                            ExitCode::Timeout,
+                        )
+                        .into()),
+                    },
+                    None => func().await.map_err(Into::into),
+                }
+            };
+            // the operation is cancelled when its future is dropped, the files being written are left as they are (or
+            // removed, with atomic writes)
+            tokio::select! {
+                res = operation => res,
+                error = termination_signal() => Err(error.into()),
+            }
         });
-        runtime.block_on(func())
+        runtime.block_on(broker::shutdown());
+        res
     };
-    if let Err(error) = res {
-        if !quiet {
-            println!("{:#}", error);
-            print_runtime_stats()?;
+    let summary = match res {
+        Ok(summary) => summary,
+        Err(error) => {
+            if ExitCode::from_error(&error).is_cancelled() {
+                // give the cancelled tasks a moment to wind down (e.g. remove temporary files), blocking tasks copying
+                // file data are not waited for
+                runtime.shutdown_timeout(std::time::Duration::from_secs(1));
+            }
+            if let Some(job) = job {
+                job.finish(
+                    ExitCode::from_error(&error),
+                    format!(
+                        "{:#}\n\npartial summary:\n{}",
+                        error,
+                        PROGRESS.totals(units)
+                    ),
+                );
+            }
+            if !quiet {
+                println!("{:#}", error);
+                if ExitCode::from_error(&error).is_cancelled() && (print_summary || verbose > 0) {
+                    println!("\npartial summary:\n{}", PROGRESS.totals(units));
+                }
+                print_runtime_stats()?;
+            }
+            return Err(error);
         }
-        return Err(anyhow!("{}", error));
+    };
+    if let Some(job) = job {
+        job.finish(ExitCode::Success, summary.to_string());
     }
-    let summary = res.unwrap();
     if print_summary || verbose > 0 {
         println!("{}", &summary);
         print_runtime_stats()?;
@@ -30,7 +30,7 @@ impl LinkError {
     }
 }
 
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug, Clone)]
 pub struct LinkSettings {
     pub copy_settings: CopySettings,
     pub update_compare: filecmp::MetadataCmpSettings,
@@ -107,6 +107,8 @@ async fn hard_link_helper(
                 dst,
                 &rm::RmSettings {
                     fail_early: settings.copy_settings.fail_early,
+                    filter: Default::default(),
+                    prune_empty_dirs: false,
                 },
             )
             .await
@@ -137,7 +139,7 @@ pub async fn link(
     settings: &LinkSettings,
     mut is_fresh: bool,
 ) -> Result<LinkSummary, LinkError> {
-    throttle::get_token().await;
+    throttle::get_token_for(&[src, dst]).await;
     let _prog_guard = prog_track.ops.guard();
     event!(Level::DEBUG, "reading source metadata");
     let src_metadata = tokio::fs::symlink_metadata(src)
@@ -205,7 +207,16 @@ pub async fn link(
         }
         if update_metadata.is_file() {
             // check if the file is unchanged and if so hard-link, otherwise copy from the updated one
-            if filecmp::metadata_equal(&settings.update_compare, &src_metadata, update_metadata) {
+            let mut is_unchanged =
+                filecmp::metadata_equal(&settings.update_compare, &src_metadata, update_metadata);
+            if is_unchanged && settings.update_compare.hash {
+                event!(Level::DEBUG, "metadata is unchanged, comparing contents");
+                is_unchanged =
+                    filecmp::contents_equal(src, update, settings.update_compare.checksum_algo)
+                        .await
+                        .map_err(|err| LinkError::new(err, Default::default()))?;
+            }
+            if is_unchanged {
                 event!(Level::DEBUG, "no change, hard link 'src'");
                 return hard_link_helper(prog_track, src, &src_metadata, dst, settings).await;
             } else {
@@ -343,6 +354,8 @@ pub async fn link(
                         dst,
                         &rm::RmSettings {
                             fail_early: settings.copy_settings.fail_early,
+                            filter: Default::default(),
+                            prune_empty_dirs: false,
                         },
                     )
                     .await
@@ -413,7 +426,7 @@ pub async fn link(
         processed_files.insert(entry_name.to_owned());
         let dst_path = dst.join(entry_name);
         let update_path = update.as_ref().map(|s| s.join(entry_name));
-        let settings = *settings;
+        let settings = settings.clone();
         let do_link = || async move {
             link(
                 prog_track,
@@ -453,7 +466,7 @@ pub async fn link(
             event!(Level::DEBUG, "found a new entry in the 'update' directory");
             let dst_path = dst.join(entry_name);
             let update_path = update.join(entry_name);
-            let settings = *settings;
+            let settings = settings.clone();
             let do_copy = || async move {
                 let copy_summary = copy::copy(
                     prog_track,
@@ -544,6 +557,7 @@ mod link_tests {
                     mtime: true,
                     ..Default::default()
                 },
+                ..Default::default()
             },
             update_compare: filecmp::MetadataCmpSettings {
                 size: true,
@@ -561,7 +575,7 @@ mod link_tests {
         let test_path = tmp_dir.as_path();
         let summary = link(
             &PROGRESS,
-            &test_path,
+            test_path,
             &test_path.join("foo"),
             &test_path.join("bar"),
             &None,
@@ -589,7 +603,7 @@ mod link_tests {
         let test_path = tmp_dir.as_path();
         let summary = link(
             &PROGRESS,
-            &test_path,
+            test_path,
             &test_path.join("foo"),
             &test_path.join("bar"),
             &Some(test_path.join("foo")),
@@ -618,7 +632,7 @@ mod link_tests {
         let test_path = tmp_dir.as_path();
         let summary = link(
             &PROGRESS,
-            &test_path,
+            test_path,
             &test_path.join("baz"), // empty source
             &test_path.join("bar"),
             &Some(test_path.join("foo")),
@@ -639,7 +653,7 @@ mod link_tests {
         Ok(())
     }
 
-    pub async fn setup_update_dir(tmp_dir: &std::path::PathBuf) -> Result<(), anyhow::Error> {
+    pub async fn setup_update_dir(tmp_dir: &std::path::Path) -> Result<(), anyhow::Error> {
         // update
         // |- 0.txt
         // |- bar
@@ -662,6 +676,58 @@ mod link_tests {
         Ok(())
     }
 
+    #[tokio::test]
+    #[traced_test]
+    async fn test_link_update_checksum() -> Result<(), anyhow::Error> {
+        let tmp_dir = testutils::setup_test_dir().await?;
+        let test_path = tmp_dir.as_path();
+        // same size and mtime as foo/0.txt, but different contents
+        let update = test_path.join("update");
+        tokio::fs::create_dir(&update).await?;
+        tokio::fs::write(update.join("0.txt"), "X").await?;
+        let mtime = std::fs::metadata(test_path.join("foo").join("0.txt"))?.modified()?;
+        std::fs::File::options()
+            .write(true)
+            .open(update.join("0.txt"))?
+            .set_times(std::fs::FileTimes::new().set_modified(mtime))?;
+        let mut settings = common_settings(false, false);
+        settings.update_exclusive = true;
+        let summary = link(
+            &PROGRESS,
+            test_path,
+            &test_path.join("foo"),
+            &test_path.join("bar"),
+            &Some(update.clone()),
+            &settings,
+            false,
+        )
+        .await?;
+        // the metadata quick-check alone produces a wrong hard-link
+        assert_eq!(summary.hard_links_created, 1);
+        assert_eq!(
+            tokio::fs::read_to_string(test_path.join("bar").join("0.txt")).await?,
+            "0"
+        );
+        settings.update_compare.hash = true;
+        let summary = link(
+            &PROGRESS,
+            test_path,
+            &test_path.join("foo"),
+            &test_path.join("baz"),
+            &Some(update),
+            &settings,
+            false,
+        )
+        .await?;
+        assert_eq!(summary.hard_links_created, 0);
+        assert_eq!(summary.copy_summary.files_copied, 1);
+        assert_eq!(
+            tokio::fs::read_to_string(test_path.join("baz").join("0.txt")).await?,
+            "X"
+        );
+        Ok(())
+    }
+
     #[tokio::test]
     #[traced_test]
     async fn test_link_update() -> Result<(), anyhow::Error> {
@@ -670,7 +736,7 @@ mod link_tests {
         let test_path = tmp_dir.as_path();
         let summary = link(
             &PROGRESS,
-            &test_path,
+            test_path,
             &test_path.join("foo"),
             &test_path.join("bar"),
             &Some(test_path.join("update")),
@@ -709,7 +775,7 @@ mod link_tests {
         settings.update_exclusive = true;
         let summary = link(
             &PROGRESS,
-            &test_path,
+            test_path,
             &test_path.join("foo"),
             &test_path.join("bar"),
             &Some(test_path.join("update")),
@@ -741,7 +807,7 @@ mod link_tests {
         let test_path = tmp_dir.as_path();
         let summary = link(
             &PROGRESS,
-            &test_path,
+            test_path,
             &test_path.join("foo"),
             &test_path.join("bar"),
             &None,
@@ -774,13 +840,21 @@ mod link_tests {
             let summary = rm::rm(
                 &PROGRESS,
                 &output_path.join("bar"),
-                &rm::RmSettings { fail_early: false },
+                &rm::RmSettings {
+                    fail_early: false,
+                    filter: Default::default(),
+                    prune_empty_dirs: false,
+                },
             )
             .await?
                 + rm::rm(
                     &PROGRESS,
                     &output_path.join("baz").join("5.txt"),
-                    &rm::RmSettings { fail_early: false },
+                    &rm::RmSettings {
+                        fail_early: false,
+                        filter: Default::default(),
+                        prune_empty_dirs: false,
+                    },
                 )
                 .await?;
             assert_eq!(summary.files_removed, 3);
@@ -791,7 +865,7 @@ mod link_tests {
             &PROGRESS,
             &tmp_dir,
             &tmp_dir.join("foo"),
-            &output_path,
+            output_path,
             &None,
             &common_settings(false, true), // overwrite!
             false,
@@ -802,7 +876,7 @@ mod link_tests {
         assert_eq!(summary.copy_summary.directories_created, 1);
         testutils::check_dirs_identical(
             &tmp_dir.join("foo"),
-            &output_path,
+            output_path,
             testutils::FileEqualityCheck::Timestamp,
         )
         .await?;
@@ -828,13 +902,21 @@ mod link_tests {
             let summary = rm::rm(
                 &PROGRESS,
                 &output_path.join("bar"),
-                &rm::RmSettings { fail_early: false },
+                &rm::RmSettings {
+                    fail_early: false,
+                    filter: Default::default(),
+                    prune_empty_dirs: false,
+                },
             )
             .await?
                 + rm::rm(
                     &PROGRESS,
                     &output_path.join("baz").join("5.txt"),
-                    &rm::RmSettings { fail_early: false },
+                    &rm::RmSettings {
+                        fail_early: false,
+                        filter: Default::default(),
+                        prune_empty_dirs: false,
+                    },
                 )
                 .await?;
             assert_eq!(summary.files_removed, 3);
@@ -851,7 +933,7 @@ mod link_tests {
             &PROGRESS,
             &tmp_dir,
             &tmp_dir.join("foo"),
-            &output_path,
+            output_path,
             &Some(tmp_dir.join("update")),
             &common_settings(false, true), // overwrite!
             false,
@@ -896,25 +978,41 @@ mod link_tests {
             let summary = rm::rm(
                 &PROGRESS,
                 &bar_path.join("1.txt"),
-                &rm::RmSettings { fail_early: false },
+                &rm::RmSettings {
+                    fail_early: false,
+                    filter: Default::default(),
+                    prune_empty_dirs: false,
+                },
             )
             .await?
                 + rm::rm(
                     &PROGRESS,
                     &bar_path.join("2.txt"),
-                    &rm::RmSettings { fail_early: false },
+                    &rm::RmSettings {
+                        fail_early: false,
+                        filter: Default::default(),
+                        prune_empty_dirs: false,
+                    },
                 )
                 .await?
                 + rm::rm(
                     &PROGRESS,
                     &bar_path.join("3.txt"),
-                    &rm::RmSettings { fail_early: false },
+                    &rm::RmSettings {
+                        fail_early: false,
+                        filter: Default::default(),
+                        prune_empty_dirs: false,
+                    },
                 )
                 .await?
                 + rm::rm(
                     &PROGRESS,
                     &output_path.join("baz"),
-                    &rm::RmSettings { fail_early: false },
+                    &rm::RmSettings {
+                        fail_early: false,
+                        filter: Default::default(),
+                        prune_empty_dirs: false,
+                    },
                 )
                 .await?;
             assert_eq!(summary.files_removed, 4);
@@ -938,7 +1036,7 @@ mod link_tests {
             &PROGRESS,
             &tmp_dir,
             &tmp_dir.join("foo"),
-            &output_path,
+            output_path,
             &None,
             &common_settings(false, true), // overwrite!
             false,
@@ -975,25 +1073,41 @@ mod link_tests {
             let summary = rm::rm(
                 &PROGRESS,
                 &bar_path.join("1.txt"),
-                &rm::RmSettings { fail_early: false },
+                &rm::RmSettings {
+                    fail_early: false,
+                    filter: Default::default(),
+                    prune_empty_dirs: false,
+                },
             )
             .await?
                 + rm::rm(
                     &PROGRESS,
                     &bar_path.join("2.txt"),
-                    &rm::RmSettings { fail_early: false },
+                    &rm::RmSettings {
+                        fail_early: false,
+                        filter: Default::default(),
+                        prune_empty_dirs: false,
+                    },
                 )
                 .await?
                 + rm::rm(
                     &PROGRESS,
                     &bar_path.join("3.txt"),
-                    &rm::RmSettings { fail_early: false },
+                    &rm::RmSettings {
+                        fail_early: false,
+                        filter: Default::default(),
+                        prune_empty_dirs: false,
+                    },
                 )
                 .await?
                 + rm::rm(
                     &PROGRESS,
                     &output_path.join("baz"),
-                    &rm::RmSettings { fail_early: false },
+                    &rm::RmSettings {
+                        fail_early: false,
+                        filter: Default::default(),
+                        prune_empty_dirs: false,
+                    },
                 )
                 .await?;
             assert_eq!(summary.files_removed, 4);
@@ -1027,7 +1141,7 @@ mod link_tests {
             &PROGRESS,
             &tmp_dir,
             &tmp_dir.join("foo"),
-            &output_path,
+            output_path,
             &None,
             &common_settings(false, true), // overwrite!
             false,
@@ -0,0 +1,148 @@
+use anyhow::{anyhow, Context};
+use async_recursion::async_recursion;
+use tracing::{event, instrument, Level};
+
+use crate::filter;
+use crate::statedir;
+
+#[derive(Debug, Clone)]
+pub struct ListSettings {
+    pub dereference: bool,
+    pub filter: filter::FilterSettings,
+    /// State directory of the destination, it's not listed when found in the source
+    pub state_dir: Option<statedir::StateDir>,
+}
+
+/// Writes a line with the type, size and path of the entry, sizes of directories are not shown
+fn write_entry(
+    out: &mut (dyn std::io::Write + Send),
+    path: &std::path::Path,
+    metadata: &std::fs::Metadata,
+) -> anyhow::Result<()> {
+    let (obj_type, size) = if metadata.is_dir() {
+        ("d", "-".to_string())
+    } else if metadata.is_symlink() {
+        ("l", metadata.len().to_string())
+    } else if metadata.is_file() {
+        ("f", metadata.len().to_string())
+    } else {
+        ("?", metadata.len().to_string())
+    };
+    writeln!(out, "{}\t{}\t{}", obj_type, size, path.display()).context("failed writing listing")
+}
+
+/// Lists the entries that would be copied from 'src', with paths relative to the directory 'src' would be copied into.
+///
+/// Directories are listed in order, entries that fail to be read are logged and the listing continues.
+#[instrument(skip(out))]
+#[async_recursion]
+pub async fn list(
+    src: &std::path::Path,
+    rel_path: &std::path::Path,
+    settings: &ListSettings,
+    out: &mut (dyn std::io::Write + Send),
+) -> anyhow::Result<()> {
+    let metadata = if settings.dereference {
+        tokio::fs::metadata(src).await
+    } else {
+        tokio::fs::symlink_metadata(src).await
+    }
+    .with_context(|| format!("failed reading metadata from {:?}", &src))?;
+    if !settings.filter.matches_path(src, &metadata) {
+        return Ok(());
+    }
+    if !metadata.is_dir() {
+        write_entry(out, rel_path, &metadata)?;
+        return Ok(());
+    }
+    write_entry(out, rel_path, &metadata)?;
+    let mut entries = tokio::fs::read_dir(src)
+        .await
+        .with_context(|| format!("cannot open directory {:?} for reading", src))?;
+    let mut names = vec![];
+    while let Some(entry) = entries
+        .next_entry()
+        .await
+        .with_context(|| format!("failed traversing src directory {:?}", &src))?
+    {
+        names.push(entry.file_name());
+    }
+    names.sort();
+    let entries_settings = settings
+        .filter
+        .enter_dir(src)
+        .await?
+        .map(|filter| ListSettings {
+            dereference: settings.dereference,
+            filter,
+            state_dir: settings.state_dir.clone(),
+        });
+    let settings = entries_settings.as_ref().unwrap_or(settings);
+    let mut success = true;
+    for name in names {
+        let entry_path = src.join(&name);
+        if settings
+            .state_dir
+            .as_ref()
+            .is_some_and(|state_dir| state_dir.is_same(&entry_path))
+        {
+            continue;
+        }
+        if let Err(error) = list(&entry_path, &rel_path.join(&name), settings, out).await {
+            event!(Level::ERROR, "{:#}", &error);
+            success = false;
+        }
+    }
+    if !success {
+        return Err(anyhow!("failed listing some entries of {:?}", src));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutils;
+    use tracing_test::traced_test;
+
+    #[tokio::test]
+    #[traced_test]
+    async fn check_list() -> anyhow::Result<()> {
+        let tmp_dir = testutils::setup_test_dir().await?;
+        let mut out = vec![];
+        let settings = ListSettings {
+            dereference: false,
+            filter: filter::parse_filter_settings(None, None, Some("f"))?,
+            state_dir: None,
+        };
+        list(
+            &tmp_dir.join("foo"),
+            std::path::Path::new("foo"),
+            &settings,
+            &mut out,
+        )
+        .await?;
+        // foo
+        // |- 0.txt
+        // |- bar
+        //    |- 1.txt
+        //    |- 2.txt
+        //    |- 3.txt
+        // |- baz
+        //    |- 4.txt
+        //    |- 5.txt -> ../bar/2.txt
+        //    |- 6.txt -> (absolute path) .../foo/bar/3.txt
+        assert_eq!(
+            String::from_utf8(out)?,
+            "d\t-\tfoo\n\
+            f\t1\tfoo/0.txt\n\
+            d\t-\tfoo/bar\n\
+            f\t1\tfoo/bar/1.txt\n\
+            f\t1\tfoo/bar/2.txt\n\
+            f\t1\tfoo/bar/3.txt\n\
+            d\t-\tfoo/baz\n\
+            f\t1\tfoo/baz/4.txt\n"
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,143 @@
+use anyhow::{Context, Result};
+use std::io::Write;
+
+#[derive(Debug, Clone)]
+pub struct LogFileSettings {
+    pub path: std::path::PathBuf,
+    /// Rotate the log file once it grows beyond this size, e.g. "100MiB"
+    pub max_size: Option<String>,
+    /// Number of rotated files to keep: <path>.1 is the most recent, <path>.<max_files> the oldest
+    pub max_files: usize,
+}
+
+/// Log file writer rotating the file once it reaches the maximum size.
+#[derive(Debug)]
+pub struct RotatingFile {
+    path: std::path::PathBuf,
+    max_size: Option<u64>,
+    max_files: usize,
+    file: std::fs::File,
+    size: u64,
+}
+
+fn rotated_path(path: &std::path::Path, idx: usize) -> std::path::PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(format!(".{}", idx));
+    std::path::PathBuf::from(name)
+}
+
+impl RotatingFile {
+    pub fn new(settings: &LogFileSettings) -> Result<Self> {
+        let max_size = match &settings.max_size {
+            Some(max_size) => Some(
+                max_size
+                    .parse::<bytesize::ByteSize>()
+                    .map_err(anyhow::Error::msg)
+                    .with_context(|| format!("invalid log file size: {:?}", max_size))?
+                    .as_u64(),
+            ),
+            None => None,
+        };
+        let file = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&settings.path)
+            .with_context(|| format!("failed to open log file: {:?}", &settings.path))?;
+        let size = file.metadata()?.len();
+        Ok(Self {
+            path: settings.path.clone(),
+            max_size,
+            max_files: settings.max_files,
+            file,
+            size,
+        })
+    }
+
+    fn rotate(&mut self) -> std::io::Result<()> {
+        self.file.flush()?;
+        if self.max_files > 0 {
+            for idx in (1..self.max_files).rev() {
+                let from = rotated_path(&self.path, idx);
+                if from.exists() {
+                    std::fs::rename(&from, rotated_path(&self.path, idx + 1))?;
+                }
+            }
+            std::fs::rename(&self.path, rotated_path(&self.path, 1))?;
+        }
+        self.file = std::fs::OpenOptions::new()
+            .create(true)
+            .write(true)
+            .truncate(true)
+            .open(&self.path)?;
+        self.size = 0;
+        Ok(())
+    }
+}
+
+impl Write for RotatingFile {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        if let Some(max_size) = self.max_size {
+            if self.size > 0 && self.size + buf.len() as u64 > max_size {
+                self.rotate()?;
+            }
+        }
+        // write the whole buffer so that a single log line never gets split between files
+        self.file.write_all(buf)?;
+        self.size += buf.len() as u64;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.file.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutils;
+
+    #[tokio::test]
+    async fn check_rotation() -> Result<()> {
+        let tmp_dir = testutils::create_temp_dir().await?;
+        let log_path = tmp_dir.join("rcp.log");
+        let mut log = RotatingFile::new(&LogFileSettings {
+            path: log_path.clone(),
+            max_size: Some("10B".to_string()),
+            max_files: 2,
+        })?;
+        for line in ["aaaaaa\n", "bbbbbb\n", "cccccc\n", "dddddd\n"] {
+            log.write_all(line.as_bytes())?;
+        }
+        log.flush()?;
+        assert_eq!(std::fs::read_to_string(&log_path)?, "dddddd\n");
+        assert_eq!(
+            std::fs::read_to_string(rotated_path(&log_path, 1))?,
+            "cccccc\n"
+        );
+        assert_eq!(
+            std::fs::read_to_string(rotated_path(&log_path, 2))?,
+            "bbbbbb\n"
+        );
+        assert!(!rotated_path(&log_path, 3).exists());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn check_no_rotation() -> Result<()> {
+        let tmp_dir = testutils::create_temp_dir().await?;
+        let log_path = tmp_dir.join("rcp.log");
+        let mut log = RotatingFile::new(&LogFileSettings {
+            path: log_path.clone(),
+            max_size: None,
+            max_files: 2,
+        })?;
+        for line in ["aaaaaa\n", "bbbbbb\n"] {
+            log.write_all(line.as_bytes())?;
+        }
+        log.flush()?;
+        assert_eq!(std::fs::read_to_string(&log_path)?, "aaaaaa\nbbbbbb\n");
+        assert!(!rotated_path(&log_path, 1).exists());
+        Ok(())
+    }
+}
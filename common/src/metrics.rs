@@ -0,0 +1,299 @@
+use anyhow::{Context, Result};
+use std::fmt::Write;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tracing::{event, instrument, Level};
+
+use crate::broker;
+use crate::progress;
+use crate::throttle;
+
+/// Keeps track of the values from the previous scrape to compute the current throughput.
+struct MetricsState {
+    last_bytes: u64,
+    last_update: std::time::Instant,
+}
+
+fn write_metric(
+    out: &mut String,
+    name: &str,
+    metric_type: &str,
+    help: &str,
+    value: impl std::fmt::Display,
+) {
+    // writing to a String cannot fail
+    writeln!(out, "# HELP {} {}", name, help).unwrap();
+    writeln!(out, "# TYPE {} {}", name, metric_type).unwrap();
+    writeln!(out, "{} {}", name, value).unwrap();
+}
+
+fn render(progress: &progress::Progress, state: &mut MetricsState) -> String {
+    let time_now = std::time::Instant::now();
+    let bytes = progress.bytes_copied.get();
+    let curr_duration_secs = (time_now - state.last_update).as_secs_f64();
+    let current_bytes_rate = if curr_duration_secs > 0.0 {
+        bytes.saturating_sub(state.last_bytes) as f64 / curr_duration_secs
+    } else {
+        0.0
+    };
+    state.last_bytes = bytes;
+    state.last_update = time_now;
+    let ops = progress.ops.get();
+    let mut out = String::new();
+    let counters = [
+        ("rcp_bytes_copied_total", "Bytes copied", bytes),
+        (
+            "rcp_ops_finished_total",
+            "Operations finished",
+            ops.finished,
+        ),
+        (
+            "rcp_files_copied_total",
+            "Files copied",
+            progress.files_copied.get(),
+        ),
+        (
+            "rcp_symlinks_created_total",
+            "Symlinks created",
+            progress.symlinks_created.get(),
+        ),
+        (
+            "rcp_directories_created_total",
+            "Directories created",
+            progress.directories_created.get(),
+        ),
+        (
+            "rcp_hard_links_created_total",
+            "Hard-links created",
+            progress.hard_links_created.get(),
+        ),
+        (
+            "rcp_files_unchanged_total",
+            "Files left unchanged",
+            progress.files_unchanged.get(),
+        ),
+        (
+            "rcp_symlinks_unchanged_total",
+            "Symlinks left unchanged",
+            progress.symlinks_unchanged.get(),
+        ),
+        (
+            "rcp_directories_unchanged_total",
+            "Directories left unchanged",
+            progress.directories_unchanged.get(),
+        ),
+        (
+            "rcp_hard_links_unchanged_total",
+            "Hard-links left unchanged",
+            progress.hard_links_unchanged.get(),
+        ),
+        (
+            "rcp_files_removed_total",
+            "Files removed",
+            progress.files_removed.get(),
+        ),
+        (
+            "rcp_symlinks_removed_total",
+            "Symlinks removed",
+            progress.symlinks_removed.get(),
+        ),
+        (
+            "rcp_directories_removed_total",
+            "Directories removed",
+            progress.directories_removed.get(),
+        ),
+    ];
+    for (name, help, value) in counters {
+        write_metric(&mut out, name, "counter", help, value);
+    }
+    write_metric(
+        &mut out,
+        "rcp_ops_pending",
+        "gauge",
+        "Operations in progress",
+        ops.started - ops.finished,
+    );
+    write_metric(
+        &mut out,
+        "rcp_throughput_bytes_per_second",
+        "gauge",
+        "Bytes copied per second since the previous scrape",
+        current_bytes_rate,
+    );
+    write_metric(
+        &mut out,
+        "rcp_elapsed_seconds",
+        "gauge",
+        "Time since the start of the operation",
+        progress.get_duration().as_secs_f64(),
+    );
+    if let Some((open_files, max_open_files)) = throttle::open_files_in_use() {
+        write_metric(
+            &mut out,
+            "rcp_open_files",
+            "gauge",
+            "Open file permits currently in use",
+            open_files,
+        );
+        write_metric(
+            &mut out,
+            "rcp_open_files_limit",
+            "gauge",
+            "Maximum number of open files",
+            max_open_files,
+        );
+    }
+    let (ops_wait, tput_wait) = throttle::wait_times();
+    write_metric(
+        &mut out,
+        "rcp_throttle_ops_wait_seconds_total",
+        "counter",
+        "Time spent waiting for ops tokens (--ops-throttle)",
+        ops_wait.as_secs_f64(),
+    );
+    write_metric(
+        &mut out,
+        "rcp_throttle_bytes_wait_seconds_total",
+        "counter",
+        "Time spent waiting for byte-rate tokens (--bwlimit-schedule)",
+        tput_wait.as_secs_f64(),
+    );
+    write_metric(
+        &mut out,
+        "rcp_workers",
+        "gauge",
+        "Runtime worker threads",
+        progress
+            .resources
+            .workers
+            .load(std::sync::atomic::Ordering::Relaxed),
+    );
+    write_metric(
+        &mut out,
+        "rcp_scheduling_delay_seconds",
+        "gauge",
+        "How long a ready task waits to be polled, grows when all the workers are busy",
+        progress
+            .resources
+            .scheduling_delay_us
+            .load(std::sync::atomic::Ordering::Relaxed) as f64
+            / 1e6,
+    );
+    if let Some(stats) = broker::stats() {
+        write_metric(
+            &mut out,
+            "rcp_broker_clients",
+            "gauge",
+            "Processes connected to the throttle broker",
+            stats.clients,
+        );
+        write_metric(
+            &mut out,
+            "rcp_broker_ops_granted_total",
+            "counter",
+            "Ops tokens granted to throttle broker clients",
+            stats.ops_granted,
+        );
+        write_metric(
+            &mut out,
+            "rcp_broker_bytes_granted_total",
+            "counter",
+            "Byte-rate tokens granted to throttle broker clients",
+            stats.bytes_granted,
+        );
+    }
+    out
+}
+
+async fn respond(stream: &mut tokio::net::TcpStream, body: &str) -> Result<()> {
+    // we serve the same response for any request, just consume the request headers
+    let mut request = Vec::new();
+    let mut buf = [0u8; 1024];
+    while !request.windows(4).any(|w| w == b"\r\n\r\n") && request.len() < 64 * 1024 {
+        let n = stream.read(&mut buf).await?;
+        if n == 0 {
+            break;
+        }
+        request.extend_from_slice(&buf[..n]);
+    }
+    let response = format!(
+        "HTTP/1.1 200 OK\r\n\
+        Content-Type: text/plain; version=0.0.4\r\n\
+        Content-Length: {}\r\n\
+        Connection: close\r\n\
+        \r\n\
+        {}",
+        body.len(),
+        body
+    );
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await?;
+    Ok(())
+}
+
+pub async fn bind(addr: &str) -> Result<tokio::net::TcpListener> {
+    tokio::net::TcpListener::bind(addr)
+        .await
+        .with_context(|| format!("failed to bind metrics endpoint to {:?}", addr))
+}
+
+/// Serves Prometheus text format metrics until the runtime shuts down.
+#[instrument(skip(progress))]
+pub async fn serve(listener: tokio::net::TcpListener, progress: &'static progress::Progress) {
+    let state = std::sync::Arc::new(tokio::sync::Mutex::new(MetricsState {
+        last_bytes: progress.bytes_copied.get(),
+        last_update: std::time::Instant::now(),
+    }));
+    loop {
+        let (mut stream, peer) = match listener.accept().await {
+            Ok(conn) => conn,
+            Err(error) => {
+                event!(Level::WARN, "metrics endpoint failed to accept: {}", &error);
+                continue;
+            }
+        };
+        let state = state.clone();
+        tokio::spawn(async move {
+            let body = render(progress, &mut *state.lock().await);
+            if let Err(error) = respond(&mut stream, &body).await {
+                event!(
+                    Level::DEBUG,
+                    "failed serving metrics to {:?}: {}",
+                    peer,
+                    &error
+                );
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    lazy_static! {
+        static ref PROGRESS: progress::Progress = progress::Progress::new();
+    }
+
+    #[tokio::test]
+    async fn check_metrics_endpoint() -> Result<()> {
+        PROGRESS.bytes_copied.add(1234);
+        PROGRESS.files_copied.inc();
+        let listener = bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        let server = tokio::spawn(serve(listener, &PROGRESS));
+        let mut stream = tokio::net::TcpStream::connect(addr).await?;
+        stream
+            .write_all(b"GET /metrics HTTP/1.1\r\nHost: localhost\r\n\r\n")
+            .await?;
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await?;
+        server.abort();
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.contains("\nrcp_bytes_copied_total 1234\n"));
+        assert!(response.contains("\nrcp_files_copied_total 1\n"));
+        assert!(response.contains("# TYPE rcp_ops_pending gauge\n"));
+        assert!(response.contains("# TYPE rcp_throttle_ops_wait_seconds_total counter\n"));
+        assert!(response.contains("# TYPE rcp_scheduling_delay_seconds gauge\n"));
+        Ok(())
+    }
+}
@@ -7,12 +7,15 @@ use tracing::{event, instrument, Level};
 pub struct UserAndTimeSettings {
     pub uid: bool,
     pub gid: bool,
-    pub time: bool,
+    pub atime: bool,
+    pub mtime: bool,
+    /// Birth (creation) time, Linux doesn't allow setting it so this is currently a no-op (logged once)
+    pub btime: bool,
 }
 
 impl UserAndTimeSettings {
     pub fn any(&self) -> bool {
-        self.uid || self.gid || self.time
+        self.uid || self.gid || self.atime || self.mtime || self.btime
     }
 }
 
@@ -59,35 +62,374 @@ impl SymlinkSettings {
     }
 }
 
-#[derive(Copy, Clone, Debug, Default)]
+/// What to do with a uid/gid that has no entry in a non-empty user/group map
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum UnmappedIds {
+    /// Apply the source id as is
+    #[default]
+    KeepNumeric,
+    /// Leave the destination owner/group unchanged
+    SkipChown,
+    /// Fail setting metadata of the object
+    Error,
+}
+
+impl std::str::FromStr for UnmappedIds {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "keep-numeric" => Ok(UnmappedIds::KeepNumeric),
+            "skip-chown" => Ok(UnmappedIds::SkipChown),
+            "error" => Ok(UnmappedIds::Error),
+            _ => Err(anyhow::anyhow!(
+                "Invalid unmapped ids policy: {}, expected one of: keep-numeric, skip-chown, error",
+                s
+            )),
+        }
+    }
+}
+
+/// Maps source uids/gids to the ones applied to the destination, empty maps preserve ids as they are
+#[derive(Clone, Debug, Default)]
+pub struct IdMapping {
+    pub users: std::collections::HashMap<u32, u32>,
+    pub groups: std::collections::HashMap<u32, u32>,
+    pub unmapped: UnmappedIds,
+    /// Owner applied to every destination object regardless of the source and of the preserve settings (`--chown`)
+    pub owner: Option<u32>,
+    /// Group applied to every destination object regardless of the source and of the preserve settings (`--chown`)
+    pub group: Option<u32>,
+}
+
+fn map_id(
+    map: &std::collections::HashMap<u32, u32>,
+    unmapped: UnmappedIds,
+    id: u32,
+    kind: &str,
+) -> Result<Option<u32>> {
+    if map.is_empty() {
+        return Ok(Some(id));
+    }
+    match (map.get(&id), unmapped) {
+        (Some(&mapped_id), _) => Ok(Some(mapped_id)),
+        (None, UnmappedIds::KeepNumeric) => Ok(Some(id)),
+        (None, UnmappedIds::SkipChown) => Ok(None),
+        (None, UnmappedIds::Error) => Err(anyhow::anyhow!("no mapping for {} {}", kind, id)),
+    }
+}
+
+impl IdMapping {
+    /// Returns the uid to set on the destination, None if the owner should be left unchanged
+    pub fn map_uid(&self, uid: u32) -> Result<Option<u32>> {
+        map_id(&self.users, self.unmapped, uid, "uid")
+    }
+
+    /// Returns the gid to set on the destination, None if the group should be left unchanged
+    pub fn map_gid(&self, gid: u32) -> Result<Option<u32>> {
+        map_id(&self.groups, self.unmapped, gid, "gid")
+    }
+}
+
+/// Parses comma separated "<src>:<dst>" pairs where each side is either a numeric id or a name resolved using
+/// "resolve_name"
+pub fn parse_id_map(
+    map: &str,
+    resolve_name: impl Fn(&str) -> Result<u32>,
+) -> Result<std::collections::HashMap<u32, u32>> {
+    let resolve = |id: &str| -> Result<u32> {
+        match id.parse::<u32>() {
+            Ok(id) => Ok(id),
+            Err(_) => resolve_name(id),
+        }
+    };
+    let mut id_map = std::collections::HashMap::new();
+    for pair in map.split(',') {
+        let (src, dst) = pair
+            .split_once(':')
+            .ok_or_else(|| anyhow::anyhow!("Invalid id mapping: {}, expected <src>:<dst>", pair))?;
+        if id_map.insert(resolve(src)?, resolve(dst)?).is_some() {
+            return Err(anyhow::anyhow!("Duplicate id mapping for: {}", src));
+        }
+    }
+    Ok(id_map)
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum ChmodAction {
+    /// Octal mode replacing the current one
+    Set(ModeMask),
+    /// Symbolic change, e.g. "go-w": the "who" mask, the operator ('+', '-' or '='), the permission bits and whether
+    /// execute bits are added only to directories and files already executable by someone ('X')
+    Symbolic {
+        who: ModeMask,
+        op: char,
+        bits: ModeMask,
+        conditional_x: bool,
+    },
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+struct ChmodClause {
+    files: bool,
+    dirs: bool,
+    action: ChmodAction,
+}
+
+/// Mode changes applied to the destination after the preserved mode, e.g. "Dg+s,Fo-rwx" or "F644,D755".
+///
+/// Clauses are comma separated and applied in order, each may be limited to files ("F") or directories ("D"). Without
+/// a "who" part symbolic clauses apply to everyone ("a"), the umask is not used.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Chmod {
+    clauses: Vec<ChmodClause>,
+}
+
+fn parse_symbolic_mode(clause: &str) -> Result<Vec<ChmodAction>> {
+    let invalid = || anyhow::anyhow!("Invalid mode change: {}", clause);
+    let op_start = clause.find(['+', '-', '=']).ok_or_else(invalid)?;
+    let mut who = 0;
+    for c in clause[..op_start].chars() {
+        who |= match c {
+            'u' => 0o4700,
+            'g' => 0o2070,
+            'o' => 0o1007,
+            'a' => 0o7777,
+            _ => return Err(invalid()),
+        };
+    }
+    if who == 0 {
+        who = 0o7777;
+    }
+    let mut actions = vec![];
+    let mut rest = &clause[op_start..];
+    while let Some(op) = rest.chars().next() {
+        let perms_end = rest[1..]
+            .find(['+', '-', '='])
+            .map_or(rest.len(), |pos| pos + 1);
+        let mut bits = 0;
+        let mut conditional_x = false;
+        for c in rest[1..perms_end].chars() {
+            bits |= match c {
+                'r' => 0o444,
+                'w' => 0o222,
+                'x' => 0o111,
+                'X' => {
+                    conditional_x = true;
+                    0
+                }
+                's' => 0o6000,
+                't' => 0o1000,
+                _ => return Err(invalid()),
+            };
+        }
+        actions.push(ChmodAction::Symbolic {
+            who,
+            op,
+            bits,
+            conditional_x,
+        });
+        rest = &rest[perms_end..];
+    }
+    Ok(actions)
+}
+
+impl std::str::FromStr for Chmod {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut chmod = Chmod::default();
+        for clause in s.split(',') {
+            let (files, dirs, mode) = match clause.strip_prefix('F') {
+                Some(mode) => (true, false, mode),
+                None => match clause.strip_prefix('D') {
+                    Some(mode) => (false, true, mode),
+                    None => (true, true, clause),
+                },
+            };
+            let actions = if !mode.is_empty() && mode.chars().all(|c| c.is_digit(8)) {
+                let mode = ModeMask::from_str_radix(mode, 8)
+                    .ok()
+                    .filter(|mode| *mode <= 0o7777)
+                    .ok_or_else(|| anyhow::anyhow!("Invalid mode: {}", mode))?;
+                vec![ChmodAction::Set(mode)]
+            } else {
+                parse_symbolic_mode(mode)?
+            };
+            chmod
+                .clauses
+                .extend(actions.into_iter().map(|action| ChmodClause {
+                    files,
+                    dirs,
+                    action,
+                }));
+        }
+        Ok(chmod)
+    }
+}
+
+impl Chmod {
+    /// Returns the permission bits (0o7777) of a file or directory with the mode changes applied
+    pub fn apply(&self, mode: ModeMask, is_dir: bool) -> ModeMask {
+        let mut mode = mode & 0o7777;
+        for clause in &self.clauses {
+            let applies = if is_dir { clause.dirs } else { clause.files };
+            if !applies {
+                continue;
+            }
+            mode = match clause.action {
+                ChmodAction::Set(new_mode) => new_mode,
+                ChmodAction::Symbolic {
+                    who,
+                    op,
+                    mut bits,
+                    conditional_x,
+                } => {
+                    if conditional_x && (is_dir || mode & 0o111 != 0) {
+                        bits |= 0o111;
+                    }
+                    let bits = bits & who;
+                    match op {
+                        '+' => mode | bits,
+                        '-' => mode & !bits,
+                        _ => (mode & !who) | bits,
+                    }
+                }
+            };
+        }
+        mode
+    }
+}
+
+#[derive(Clone, Debug, Default)]
 pub struct PreserveSettings {
     pub file: FileSettings,
     pub dir: DirSettings,
     pub symlink: SymlinkSettings,
+    pub id_mapping: std::sync::Arc<IdMapping>,
+    /// Mode changes applied on top of the preserved mode (`--chmod`)
+    pub chmod: std::sync::Arc<Chmod>,
+    /// Reapply the immutable and append-only flags of files and directories, see `set_flags`
+    pub flags: bool,
+}
+
+// inode flags from linux/fs.h, the same on all architectures
+pub const FS_IMMUTABLE_FL: libc::c_int = 0x00000010;
+pub const FS_APPEND_FL: libc::c_int = 0x00000020;
+/// Flags reapplied with `PreserveSettings::flags`, other flags (e.g. compression) are properties of the filesystem
+const PRESERVED_FLAGS: libc::c_int = FS_IMMUTABLE_FL | FS_APPEND_FL;
+
+fn open_for_flags(path: &std::path::Path) -> std::io::Result<std::fs::File> {
+    use std::os::unix::fs::OpenOptionsExt;
+    // O_NONBLOCK so we don't hang on a fifo, flags can only be read through a file descriptor
+    std::fs::OpenOptions::new()
+        .read(true)
+        .custom_flags(libc::O_NONBLOCK | libc::O_NOFOLLOW)
+        .open(path)
+}
+
+fn ioctl_flags(
+    file: &std::fs::File,
+    request: libc::Ioctl,
+    flags: &mut libc::c_int,
+) -> std::io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+    // safety: the file descriptor is valid for the duration of the call and the kernel reads / writes an int
+    let res = unsafe { libc::ioctl(file.as_raw_fd(), request, flags as *mut libc::c_int) };
+    if res < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+fn is_flags_unsupported(error: &std::io::Error) -> bool {
+    matches!(
+        error.raw_os_error(),
+        Some(libc::ENOTTY) | Some(libc::EOPNOTSUPP) | Some(libc::EINVAL)
+    )
+}
+
+/// Reads the inode flags (`FS_IOC_GETFLAGS`, as shown by `lsattr`) of a file or directory, returns 0 if the
+/// filesystem doesn't support them
+pub fn get_flags(path: &std::path::Path) -> Result<libc::c_int> {
+    let file = open_for_flags(path)
+        .with_context(|| format!("cannot open {:?} to read its flags", path))?;
+    let mut flags = 0;
+    match ioctl_flags(&file, libc::FS_IOC_GETFLAGS, &mut flags) {
+        Ok(()) => Ok(flags),
+        Err(error) if is_flags_unsupported(&error) => Ok(0),
+        Err(error) => {
+            Err(anyhow::Error::new(error).context(format!("cannot read {:?} flags", path)))
+        }
+    }
+}
+
+/// Returns a description of the flags that prevent modifying or removing the path, if any
+pub(crate) fn protection_flags(path: &std::path::Path) -> Option<&'static str> {
+    // failing to read the flags is not interesting here, the caller will report the actual error
+    let flags = get_flags(path).ok()?;
+    if flags & FS_IMMUTABLE_FL != 0 {
+        Some("immutable")
+    } else if flags & FS_APPEND_FL != 0 {
+        Some("append-only")
+    } else {
+        None
+    }
+}
+
+/// Sets the immutable and append-only flags of 'src' on 'dst', must be called after all data and metadata was
+/// written as the flags prevent further modification
+pub async fn set_flags(src: &std::path::Path, dst: &std::path::Path) -> Result<()> {
+    let src = src.to_owned();
+    let dst = dst.to_owned();
+    tokio::task::spawn_blocking(move || -> Result<()> {
+        let src_flags = get_flags(&src)? & PRESERVED_FLAGS;
+        if src_flags == 0 {
+            return Ok(());
+        }
+        event!(Level::DEBUG, "setting flags {:#x}", src_flags);
+        let file = open_for_flags(&dst)
+            .with_context(|| format!("cannot open {:?} to set its flags", &dst))?;
+        let mut flags = 0;
+        ioctl_flags(&file, libc::FS_IOC_GETFLAGS, &mut flags)
+            .with_context(|| format!("cannot read {:?} flags", &dst))?;
+        flags |= src_flags;
+        ioctl_flags(&file, libc::FS_IOC_SETFLAGS, &mut flags)
+            .with_context(|| format!("cannot set {:?} flags to {:#x}", &dst, flags))?;
+        Ok(())
+    })
+    .await?
 }
 
 #[instrument]
 async fn set_owner_and_time(
     settings: &UserAndTimeSettings,
+    id_mapping: &std::sync::Arc<IdMapping>,
     path: &std::path::Path,
     metadata: &std::fs::Metadata,
 ) -> Result<()> {
     let settings = settings.to_owned();
+    let id_mapping = id_mapping.clone();
     let dst = path.to_owned();
     let metadata = metadata.to_owned();
     tokio::task::spawn_blocking(move || -> Result<()> {
-        if settings.uid || settings.gid {
+        if settings.uid || settings.gid || id_mapping.owner.is_some() || id_mapping.group.is_some()
+        {
             // set user and group
             event!(Level::DEBUG, "setting uid ang gid");
-            let uid = if settings.uid {
-                Some(metadata.uid().into())
-            } else {
-                None
+            let uid = match id_mapping.owner {
+                Some(owner) => Some(owner.into()),
+                None if settings.uid => id_mapping
+                    .map_uid(metadata.uid())
+                    .with_context(|| format!("cannot set {:?} owner", &dst))?
+                    .map(Into::into),
+                None => None,
             };
-            let gid = if settings.gid {
-                Some(metadata.gid().into())
-            } else {
-                None
+            let gid = match id_mapping.group {
+                Some(group) => Some(group.into()),
+                None if settings.gid => id_mapping
+                    .map_gid(metadata.gid())
+                    .with_context(|| format!("cannot set {:?} group", &dst))?
+                    .map(Into::into),
+                None => None,
             };
             nix::unistd::fchownat(
                 None,
@@ -101,14 +443,30 @@ async fn set_owner_and_time(
                     "cannot set {:?} owner to {:?} and/or group id to {:?}",
                     &dst, &uid, &gid
                 )
-            })
-            .map_err(anyhow::Error::from)?;
+            })?;
+        }
+        if settings.btime {
+            static BTIME_UNSUPPORTED: std::sync::Once = std::sync::Once::new();
+            BTIME_UNSUPPORTED.call_once(|| {
+                event!(
+                    Level::INFO,
+                    "the birth time can't be set on this platform, it's not preserved"
+                )
+            });
         }
         // set timestamps last - modifying other file metadata can change them
-        if settings.time {
+        if settings.atime || settings.mtime {
             event!(Level::DEBUG, "setting timestamps");
-            let atime = nix::sys::time::TimeSpec::new(metadata.atime(), metadata.atime_nsec());
-            let mtime = nix::sys::time::TimeSpec::new(metadata.mtime(), metadata.mtime_nsec());
+            let atime = if settings.atime {
+                nix::sys::time::TimeSpec::new(metadata.atime(), metadata.atime_nsec())
+            } else {
+                nix::sys::time::TimeSpec::UTIME_OMIT
+            };
+            let mtime = if settings.mtime {
+                nix::sys::time::TimeSpec::new(metadata.mtime(), metadata.mtime_nsec())
+            } else {
+                nix::sys::time::TimeSpec::UTIME_OMIT
+            };
             nix::sys::stat::utimensat(
                 None,
                 &dst,
@@ -134,13 +492,21 @@ pub async fn set_file_metadata(
     } else {
         std::fs::Permissions::from_mode(metadata.permissions().mode() & settings.file.mode_mask)
     };
+    let permissions =
+        std::fs::Permissions::from_mode(settings.chmod.apply(permissions.mode(), false));
     let file = tokio::fs::File::open(path).await?;
     file.set_permissions(permissions.clone())
         .await
         .with_context(|| format!("cannot set {:?} permissions to {:?}", &path, &permissions))?;
     // close the file we don't accidentally race and have permissions applied after the timestamps, which would modify them!
     drop(file);
-    set_owner_and_time(&settings.file.user_and_time, path, metadata).await?;
+    set_owner_and_time(
+        &settings.file.user_and_time,
+        &settings.id_mapping,
+        path,
+        metadata,
+    )
+    .await?;
     Ok(())
 }
 
@@ -155,10 +521,45 @@ pub async fn set_dir_metadata(
     } else {
         std::fs::Permissions::from_mode(metadata.permissions().mode() & settings.dir.mode_mask)
     };
+    let permissions =
+        std::fs::Permissions::from_mode(settings.chmod.apply(permissions.mode(), true));
+    tokio::fs::set_permissions(path, permissions.clone())
+        .await
+        .with_context(|| format!("cannot set {:?} permissions to {:?}", &path, &permissions))?;
+    set_owner_and_time(
+        &settings.dir.user_and_time,
+        &settings.id_mapping,
+        path,
+        metadata,
+    )
+    .await?;
+    Ok(())
+}
+
+/// Sets the metadata of a special file (socket, fifo or device) using the file settings, it's never opened
+pub async fn set_special_metadata(
+    settings: &PreserveSettings,
+    metadata: &std::fs::Metadata,
+    path: &std::path::Path,
+) -> Result<()> {
+    let permissions = if settings.file.mode_mask == 0o7777 {
+        // special case for default preserve
+        metadata.permissions()
+    } else {
+        std::fs::Permissions::from_mode(metadata.permissions().mode() & settings.file.mode_mask)
+    };
+    let permissions =
+        std::fs::Permissions::from_mode(settings.chmod.apply(permissions.mode(), false));
     tokio::fs::set_permissions(path, permissions.clone())
         .await
         .with_context(|| format!("cannot set {:?} permissions to {:?}", &path, &permissions))?;
-    set_owner_and_time(&settings.dir.user_and_time, path, metadata).await?;
+    set_owner_and_time(
+        &settings.file.user_and_time,
+        &settings.id_mapping,
+        path,
+        metadata,
+    )
+    .await?;
     Ok(())
 }
 
@@ -168,7 +569,13 @@ pub async fn set_symlink_metadata(
     path: &std::path::Path,
 ) -> Result<()> {
     // we don't set permissions for symlinks, only owner and time
-    set_owner_and_time(&settings.file.user_and_time, path, metadata).await?;
+    set_owner_and_time(
+        &settings.file.user_and_time,
+        &settings.id_mapping,
+        path,
+        metadata,
+    )
+    .await?;
     Ok(())
 }
 
@@ -176,7 +583,9 @@ pub fn preserve_all() -> PreserveSettings {
     let user_and_time = UserAndTimeSettings {
         uid: true,
         gid: true,
-        time: true,
+        atime: true,
+        mtime: true,
+        btime: false,
     };
 
     PreserveSettings {
@@ -189,9 +598,188 @@ pub fn preserve_all() -> PreserveSettings {
             mode_mask: 0o7777,
         },
         symlink: SymlinkSettings { user_and_time },
+        id_mapping: Default::default(),
+        chmod: Default::default(),
+        flags: false,
     }
 }
 
 pub fn preserve_default() -> PreserveSettings {
     PreserveSettings::default()
 }
+
+#[cfg(test)]
+mod preserve_tests {
+    use super::*;
+
+    fn resolve_name(name: &str) -> Result<u32> {
+        match name {
+            "alice" => Ok(1000),
+            "bob" => Ok(2000),
+            _ => Err(anyhow::anyhow!("unknown name: {}", name)),
+        }
+    }
+
+    #[test]
+    fn check_parse_id_map() -> Result<()> {
+        let id_map = parse_id_map("1000:1001,bob:alice,5:bob", resolve_name)?;
+        assert_eq!(
+            id_map,
+            std::collections::HashMap::from([(1000, 1001), (2000, 1000), (5, 2000)])
+        );
+        assert!(parse_id_map("1000", resolve_name).is_err());
+        assert!(parse_id_map("carol:1000", resolve_name).is_err());
+        assert!(parse_id_map("1:2,1:3", resolve_name).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn check_unmapped_ids() -> Result<()> {
+        let mut id_mapping = IdMapping {
+            users: std::collections::HashMap::from([(1000, 2000)]),
+            ..Default::default()
+        };
+        // the group map is empty so gids are always preserved as they are
+        assert_eq!(id_mapping.map_gid(7)?, Some(7));
+        assert_eq!(id_mapping.map_uid(1000)?, Some(2000));
+        assert_eq!(id_mapping.map_uid(1001)?, Some(1001));
+        id_mapping.unmapped = UnmappedIds::SkipChown;
+        assert_eq!(id_mapping.map_uid(1000)?, Some(2000));
+        assert_eq!(id_mapping.map_uid(1001)?, None);
+        id_mapping.unmapped = UnmappedIds::Error;
+        assert_eq!(id_mapping.map_uid(1000)?, Some(2000));
+        assert!(id_mapping.map_uid(1001).is_err());
+        assert_eq!(id_mapping.map_gid(7)?, Some(7));
+        Ok(())
+    }
+
+    #[test]
+    fn check_parse_time_settings() -> Result<()> {
+        let settings = crate::parse_preserve_settings("f:uid,mtime d:time l:atime,btime")?;
+        let file = settings.file.user_and_time;
+        assert!(file.uid && file.mtime && !file.atime && !file.gid);
+        let dir = settings.dir.user_and_time;
+        assert!(dir.atime && dir.mtime && !dir.btime);
+        let symlink = settings.symlink.user_and_time;
+        assert!(symlink.atime && symlink.btime && !symlink.mtime);
+        Ok(())
+    }
+
+    #[test]
+    fn check_chmod() -> Result<()> {
+        let chmod: Chmod = "Dg+s,Fo-rwx".parse()?;
+        assert_eq!(chmod.apply(0o755, true), 0o2755);
+        assert_eq!(chmod.apply(0o755, false), 0o750);
+        let chmod: Chmod = "F644,D755".parse()?;
+        assert_eq!(chmod.apply(0o100700, false), 0o644);
+        assert_eq!(chmod.apply(0o040700, true), 0o755);
+        let chmod: Chmod = "u=rwX,go=rX".parse()?;
+        assert_eq!(chmod.apply(0o600, false), 0o644);
+        assert_eq!(chmod.apply(0o700, false), 0o755);
+        assert_eq!(chmod.apply(0o700, true), 0o755);
+        let chmod: Chmod = "go-w+r,+t".parse()?;
+        assert_eq!(chmod.apply(0o622, true), 0o1644);
+        for chmod in ["", "F", "8644", "77777", "u+q", "z+r", "rw"] {
+            assert!(
+                chmod.parse::<Chmod>().is_err(),
+                "{:?} should not parse",
+                chmod
+            );
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn check_parse_chown() -> Result<()> {
+        assert_eq!(crate::parse_chown("1000:7")?, (Some(1000), Some(7)));
+        assert_eq!(crate::parse_chown("root:")?, (Some(0), None));
+        assert_eq!(crate::parse_chown(":7")?, (None, Some(7)));
+        assert!(crate::parse_chown(":").is_err());
+        assert!(crate::parse_chown("1000").is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn check_chown_override() -> Result<()> {
+        let tmp_dir = crate::testutils::setup_test_dir().await?;
+        let src = tmp_dir.join("foo").join("0.txt");
+        let dst = tmp_dir.join("foo").join("bar").join("1.txt");
+        // only root can give files away, other users can still apply their own ids
+        let (owner, group) = if nix::unistd::geteuid().is_root() {
+            (12345, 23456)
+        } else {
+            (
+                nix::unistd::geteuid().as_raw(),
+                nix::unistd::getegid().as_raw(),
+            )
+        };
+        // the override applies even though the owner is not preserved
+        let settings = PreserveSettings {
+            id_mapping: std::sync::Arc::new(IdMapping {
+                owner: Some(owner),
+                group: Some(group),
+                ..Default::default()
+            }),
+            ..preserve_default()
+        };
+        set_file_metadata(&settings, &std::fs::metadata(&src)?, &dst).await?;
+        let dst_metadata = std::fs::metadata(&dst)?;
+        assert_eq!(dst_metadata.uid(), owner);
+        assert_eq!(dst_metadata.gid(), group);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn check_preserve_mtime_only() -> Result<()> {
+        let tmp_dir = crate::testutils::setup_test_dir().await?;
+        let src = tmp_dir.join("foo").join("0.txt");
+        let dst = tmp_dir.join("foo").join("bar").join("1.txt");
+        let old_time = std::time::UNIX_EPOCH + std::time::Duration::from_secs(1_000_000_000);
+        std::fs::File::open(&src)?.set_times(
+            std::fs::FileTimes::new()
+                .set_accessed(old_time)
+                .set_modified(old_time),
+        )?;
+        let dst_atime = std::fs::metadata(&dst)?.accessed()?;
+        let settings = crate::parse_preserve_settings("f:mtime")?;
+        set_file_metadata(&settings, &std::fs::metadata(&src)?, &dst).await?;
+        let dst_metadata = std::fs::metadata(&dst)?;
+        assert_eq!(dst_metadata.modified()?, old_time);
+        assert_eq!(dst_metadata.accessed()?, dst_atime);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn check_preserve_nanosecond_times() -> Result<()> {
+        let tmp_dir = crate::testutils::setup_test_dir().await?;
+        let src = tmp_dir.join("foo").join("0.txt");
+        let dst = tmp_dir.join("foo").join("bar").join("1.txt");
+        let atime = std::time::UNIX_EPOCH + std::time::Duration::new(1_000_000_000, 123_456_789);
+        let mtime = std::time::UNIX_EPOCH + std::time::Duration::new(1_000_000_000, 987_654_321);
+        std::fs::File::open(&src)?.set_times(
+            std::fs::FileTimes::new()
+                .set_accessed(atime)
+                .set_modified(mtime),
+        )?;
+        let cmp_settings = crate::parse_metadata_cmp_settings("mtime_ns", Default::default())?;
+        let src_metadata = std::fs::metadata(&src)?;
+        assert!(!crate::filecmp::metadata_equal(
+            &cmp_settings,
+            &src_metadata,
+            &std::fs::metadata(&dst)?
+        ));
+        // btime is requested as well, it's not supported but shouldn't fail the rest
+        let settings = crate::parse_preserve_settings("f:time,btime")?;
+        set_file_metadata(&settings, &src_metadata, &dst).await?;
+        let dst_metadata = std::fs::metadata(&dst)?;
+        assert_eq!(dst_metadata.accessed()?, atime);
+        assert_eq!(dst_metadata.modified()?, mtime);
+        assert_eq!(dst_metadata.mtime_nsec(), 987_654_321);
+        assert!(crate::filecmp::metadata_equal(
+            &cmp_settings,
+            &src_metadata,
+            &dst_metadata
+        ));
+        Ok(())
+    }
+}
@@ -1,5 +1,7 @@
 use tracing::{event, instrument, Level};
 
+use crate::throttle;
+
 #[derive(Debug)]
 pub struct TlsCounter {
     // mutex is used primarily from one thread, so it's not a bottleneck
@@ -34,6 +36,27 @@ impl Default for TlsCounter {
     }
 }
 
+/// Adds the copied bytes to the totals and, if set, to the progress of the file being copied
+pub struct BytesProgress<'a> {
+    pub total: &'a TlsCounter,
+    pub file: Option<&'a std::sync::atomic::AtomicU64>,
+}
+
+impl BytesProgress<'_> {
+    pub fn add(&self, value: u64) {
+        self.total.add(value);
+        if let Some(file) = self.file {
+            file.fetch_add(value, std::sync::atomic::Ordering::Relaxed);
+        }
+    }
+}
+
+impl<'a> From<&'a TlsCounter> for BytesProgress<'a> {
+    fn from(total: &'a TlsCounter) -> Self {
+        BytesProgress { total, file: None }
+    }
+}
+
 #[derive(Debug)]
 pub struct ProgressCounter {
     started: TlsCounter,
@@ -76,7 +99,7 @@ impl ProgressCounter {
         }
     }
 
-    pub fn guard(&self) -> ProgressGuard {
+    pub fn guard(&self) -> ProgressGuard<'_> {
         ProgressGuard::new(self)
     }
 
@@ -99,6 +122,132 @@ impl ProgressCounter {
     }
 }
 
+#[derive(Debug)]
+struct InFlightEntry {
+    path: std::path::PathBuf,
+    /// Size of the file being copied, None for directories
+    size: Option<u64>,
+    bytes: std::sync::Arc<std::sync::atomic::AtomicU64>,
+    started: std::time::Instant,
+}
+
+/// Directories and files being copied, dumped on SIGUSR1 to diagnose copies that seem stuck
+#[derive(Debug, Default)]
+pub struct InFlight {
+    next_id: std::sync::atomic::AtomicU64,
+    entries: std::sync::Mutex<std::collections::HashMap<u64, InFlightEntry>>,
+}
+
+/// Removes the entry from the in-flight set when dropped
+pub struct InFlightGuard<'a> {
+    in_flight: &'a InFlight,
+    id: u64,
+    /// Bytes of the file copied so far
+    pub bytes: std::sync::Arc<std::sync::atomic::AtomicU64>,
+}
+
+impl Drop for InFlightGuard<'_> {
+    fn drop(&mut self) {
+        self.in_flight.entries.lock().unwrap().remove(&self.id);
+    }
+}
+
+impl InFlight {
+    fn add(&self, path: &std::path::Path, size: Option<u64>) -> InFlightGuard<'_> {
+        let id = self
+            .next_id
+            .fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+        let bytes = std::sync::Arc::new(std::sync::atomic::AtomicU64::new(0));
+        self.entries.lock().unwrap().insert(
+            id,
+            InFlightEntry {
+                path: path.to_owned(),
+                size,
+                bytes: bytes.clone(),
+                started: std::time::Instant::now(),
+            },
+        );
+        InFlightGuard {
+            in_flight: self,
+            id,
+            bytes,
+        }
+    }
+
+    /// Tracks a directory until all of its entries are processed
+    pub fn dir(&self, path: &std::path::Path) -> InFlightGuard<'_> {
+        self.add(path, None)
+    }
+
+    /// Tracks a file until its data and metadata are copied
+    pub fn file(&self, path: &std::path::Path, size: u64) -> InFlightGuard<'_> {
+        self.add(path, Some(size))
+    }
+
+    /// Lists the in-flight entries, the ones in flight for the longest time first
+    pub fn dump(&self, units: ByteUnits) -> String {
+        let entries = self.entries.lock().unwrap();
+        let mut entries: Vec<_> = entries.values().collect();
+        entries.sort_by_key(|entry| entry.started);
+        let dirs = entries.iter().filter(|entry| entry.size.is_none()).count();
+        let mut dump = format!(
+            "in flight: {} directories, {} files",
+            dirs,
+            entries.len() - dirs
+        );
+        for entry in entries {
+            let elapsed = entry.started.elapsed().as_secs_f64();
+            match entry.size {
+                None => dump += &format!("\ndir  {:>9.1}s {:?}", elapsed, entry.path),
+                Some(size) => {
+                    dump += &format!(
+                        "\nfile {:>9.1}s {:?} {} / {}",
+                        elapsed,
+                        entry.path,
+                        units.format(entry.bytes.load(std::sync::atomic::Ordering::Relaxed)),
+                        units.format(size)
+                    )
+                }
+            }
+        }
+        dump
+    }
+}
+
+/// Runtime resource usage sampled while the operation runs, see `run`
+#[derive(Debug, Default)]
+pub struct ResourceUsage {
+    pub workers: std::sync::atomic::AtomicUsize,
+    /// How long a task waits to be polled again after yielding, grows when all the workers are busy
+    pub scheduling_delay_us: std::sync::atomic::AtomicU64,
+}
+
+impl ResourceUsage {
+    /// Open file permits in use out of --max-open-files
+    pub fn open_files(&self) -> String {
+        match throttle::open_files_in_use() {
+            Some((open_files, max_open_files)) => format!("{}/{}", open_files, max_open_files),
+            None => "unlimited".to_string(),
+        }
+    }
+}
+
+impl std::fmt::Display for ResourceUsage {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "open files:  {:>10}\n\
+            workers:     {:>10}\n\
+            sched delay: {:>10.2} ms",
+            self.open_files(),
+            self.workers.load(std::sync::atomic::Ordering::Relaxed),
+            self.scheduling_delay_us
+                .load(std::sync::atomic::Ordering::Relaxed) as f64
+                / 1000.0,
+        )
+    }
+}
+
 pub struct Progress {
     pub ops: ProgressCounter,
     pub bytes_copied: TlsCounter,
@@ -113,6 +262,12 @@ pub struct Progress {
     pub files_removed: TlsCounter,
     pub symlinks_removed: TlsCounter,
     pub directories_removed: TlsCounter,
+    /// Entries found different or missing on one side by rcmp
+    pub entries_mismatched: TlsCounter,
+    /// Entries rcmp failed to compare
+    pub entries_failed: TlsCounter,
+    pub resources: ResourceUsage,
+    pub in_flight: InFlight,
     start_time: std::time::Instant,
 }
 
@@ -132,6 +287,10 @@ impl Progress {
             files_removed: Default::default(),
             symlinks_removed: Default::default(),
             directories_removed: Default::default(),
+            entries_mismatched: Default::default(),
+            entries_failed: Default::default(),
+            resources: Default::default(),
+            in_flight: Default::default(),
             start_time: std::time::Instant::now(),
         }
     }
@@ -139,19 +298,119 @@ impl Progress {
     pub fn get_duration(&self) -> std::time::Duration {
         self.start_time.elapsed()
     }
+
+    /// Everything done so far, printed when the operation is cancelled before producing its summary
+    pub fn totals(&self, units: ByteUnits) -> String {
+        format!(
+            "bytes copied: {}\n\
+            files copied: {}\n\
+            symlinks created: {}\n\
+            directories created: {}\n\
+            hard-links created: {}\n\
+            files unchanged: {}\n\
+            symlinks unchanged: {}\n\
+            directories unchanged: {}\n\
+            hard-links unchanged: {}\n\
+            files removed: {}\n\
+            symlinks removed: {}\n\
+            directories removed: {}",
+            units.format(self.bytes_copied.get()),
+            self.files_copied.get(),
+            self.symlinks_created.get(),
+            self.directories_created.get(),
+            self.hard_links_created.get(),
+            self.files_unchanged.get(),
+            self.symlinks_unchanged.get(),
+            self.directories_unchanged.get(),
+            self.hard_links_unchanged.get(),
+            self.files_removed.get(),
+            self.symlinks_removed.get(),
+            self.directories_removed.get(),
+        )
+    }
+}
+
+/// Units used to render byte counts in the progress and the summary
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum ByteUnits {
+    /// Powers of 1024: KiB, MiB, GiB, ...
+    #[default]
+    Iec,
+    /// Powers of 1000: KB, MB, GB, ...
+    Si,
+}
+
+impl std::fmt::Display for ByteUnits {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            ByteUnits::Iec => write!(f, "iec"),
+            ByteUnits::Si => write!(f, "si"),
+        }
+    }
+}
+
+impl std::str::FromStr for ByteUnits {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "iec" => Ok(ByteUnits::Iec),
+            "si" => Ok(ByteUnits::Si),
+            _ => Err(anyhow::anyhow!(
+                "Invalid units: {}, expected one of: iec, si",
+                s
+            )),
+        }
+    }
+}
+
+impl ByteUnits {
+    pub fn format(self, bytes: u64) -> String {
+        let (base, prefixes) = match self {
+            ByteUnits::Iec => (1024.0, ["KiB", "MiB", "GiB", "TiB", "PiB", "EiB"]),
+            ByteUnits::Si => (1000.0, ["KB", "MB", "GB", "TB", "PB", "EB"]),
+        };
+        let mut value = bytes as f64;
+        if value < base {
+            return format!("{} B", bytes);
+        }
+        let mut prefix = prefixes[0];
+        value /= base;
+        for next_prefix in &prefixes[1..] {
+            if value < base {
+                break;
+            }
+            value /= base;
+            prefix = next_prefix;
+        }
+        format!("{:.1} {}", value, prefix)
+    }
+}
+
+// set once by `run`, before any output is produced
+static BYTE_UNITS: std::sync::OnceLock<ByteUnits> = std::sync::OnceLock::new();
+
+pub fn set_byte_units(units: ByteUnits) {
+    BYTE_UNITS.set(units).ok();
+}
+
+/// Formats the byte count using the units selected for this process
+pub fn format_bytes(bytes: u64) -> String {
+    BYTE_UNITS.get().copied().unwrap_or_default().format(bytes)
 }
 
 pub struct ProgressPrinter<'a> {
     progress: &'a Progress,
+    units: ByteUnits,
     last_ops: u64,
     last_bytes: u64,
     last_update: std::time::Instant,
 }
 
 impl<'a> ProgressPrinter<'a> {
-    pub fn new(progress: &'a Progress) -> Self {
+    pub fn new(progress: &'a Progress, units: ByteUnits) -> Self {
         Self {
             progress,
+            units,
             last_ops: progress.ops.get().finished,
             last_bytes: progress.bytes_copied.get(),
             last_update: std::time::Instant::now(),
@@ -199,14 +458,21 @@ impl<'a> ProgressPrinter<'a> {
             REMOVED:\n\
             files:       {:>10}\n\
             symlinks:    {:>10}\n\
-            directories: {:>10}",
+            directories: {:>10}\n\
+            -----------------------\n\
+            COMPARED:\n\
+            mismatched:  {:>10}\n\
+            failed:      {:>10}\n\
+            -----------------------\n\
+            RESOURCES:\n\
+            {}",
             ops.started - ops.finished, // pending
             avarage_ops_rate,
             current_ops_rate,
             // copy
-            bytesize::ByteSize(avarage_bytes_rate as u64),
-            bytesize::ByteSize(current_bytes_rate as u64),
-            bytesize::ByteSize(self.progress.bytes_copied.get()),
+            self.units.format(avarage_bytes_rate as u64),
+            self.units.format(current_bytes_rate as u64),
+            self.units.format(self.progress.bytes_copied.get()),
             self.progress.files_copied.get(),
             self.progress.symlinks_created.get(),
             self.progress.directories_created.get(),
@@ -220,15 +486,64 @@ impl<'a> ProgressPrinter<'a> {
             self.progress.files_removed.get(),
             self.progress.symlinks_removed.get(),
             self.progress.directories_removed.get(),
+            // compare
+            self.progress.entries_mismatched.get(),
+            self.progress.entries_failed.get(),
+            &self.progress.resources,
         ))
     }
 }
 
+/// Truncates every line of the message so that it fits the given width, `prefix_width` is taken by the spinner in
+/// front of the first line. Lines wrapped by the terminal would not be cleared properly by the progress bar.
+pub fn fit_to_width(message: &str, width: usize, prefix_width: usize) -> String {
+    message
+        .lines()
+        .enumerate()
+        .map(|(idx, line)| {
+            let width = if idx == 0 {
+                width.saturating_sub(prefix_width)
+            } else {
+                width
+            };
+            line.chars().take(width).collect::<String>()
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
     use anyhow::Result;
 
+    #[test]
+    fn check_byte_units() -> Result<()> {
+        assert_eq!(ByteUnits::Iec.format(1023), "1023 B");
+        assert_eq!(ByteUnits::Iec.format(1536), "1.5 KiB");
+        assert_eq!(ByteUnits::Iec.format(5 * 1024 * 1024 * 1024), "5.0 GiB");
+        assert_eq!(ByteUnits::Si.format(999), "999 B");
+        assert_eq!(ByteUnits::Si.format(1536), "1.5 KB");
+        assert_eq!(ByteUnits::Si.format(5_000_000_000), "5.0 GB");
+        assert_eq!("si".parse::<ByteUnits>()?, ByteUnits::Si);
+        assert!("bytes".parse::<ByteUnits>().is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn check_resource_usage() {
+        let resources = ResourceUsage::default();
+        resources
+            .workers
+            .store(8, std::sync::atomic::Ordering::Relaxed);
+        resources
+            .scheduling_delay_us
+            .store(1500, std::sync::atomic::Ordering::Relaxed);
+        let text = resources.to_string();
+        assert!(text.contains("workers:              8"), "{}", text);
+        assert!(text.contains("sched delay:       1.50 ms"), "{}", text);
+    }
+
     #[test]
     fn basic_counting() -> Result<()> {
         let tls_counter = TlsCounter::new();
@@ -262,4 +577,12 @@ mod tests {
         let _guard = tls_progress.guard();
         Ok(())
     }
+
+    #[test]
+    fn fit_to_width_truncates_lines() {
+        let message = "0123456789\nabc\n\nabcdefghij";
+        assert_eq!(fit_to_width(message, 100, 2), message);
+        assert_eq!(fit_to_width(message, 6, 2), "0123\nabc\n\nabcdef");
+        assert_eq!(fit_to_width(message, 1, 2), "\na\n\na");
+    }
 }
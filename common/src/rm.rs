@@ -3,6 +3,7 @@ use async_recursion::async_recursion;
 use std::os::unix::fs::PermissionsExt;
 use tracing::{event, instrument, Level};
 
+use crate::filter;
 use crate::progress;
 use crate::throttle;
 
@@ -23,6 +24,9 @@ impl RmError {
 #[derive(Debug, Clone)]
 pub struct RmSettings {
     pub fail_early: bool,
+    pub filter: filter::FilterSettings,
+    /// Remove directories left empty even if they don't match the filters
+    pub prune_empty_dirs: bool,
 }
 
 #[derive(Copy, Clone, Debug, Default)]
@@ -30,6 +34,8 @@ pub struct RmSummary {
     pub files_removed: usize,
     pub symlinks_removed: usize,
     pub directories_removed: usize,
+    /// Entries not removed because they didn't match the filters
+    pub entries_filtered: usize,
 }
 
 impl std::ops::Add for RmSummary {
@@ -39,6 +45,7 @@ impl std::ops::Add for RmSummary {
             files_removed: self.files_removed + other.files_removed,
             symlinks_removed: self.symlinks_removed + other.symlinks_removed,
             directories_removed: self.directories_removed + other.directories_removed,
+            entries_filtered: self.entries_filtered + other.entries_filtered,
         }
     }
 }
@@ -51,7 +58,11 @@ impl std::fmt::Display for RmSummary {
             symlinks removed: {}\n\
             directories removed: {}",
             self.files_removed, self.symlinks_removed, self.directories_removed
-        )
+        )?;
+        if self.entries_filtered > 0 {
+            write!(f, "\nentries kept by filters: {}", self.entries_filtered)?;
+        }
+        Ok(())
     }
 }
 
@@ -62,13 +73,23 @@ pub async fn rm(
     path: &std::path::Path,
     settings: &RmSettings,
 ) -> Result<RmSummary, RmError> {
-    throttle::get_token().await;
+    throttle::get_token_for(&[path]).await;
     let _ops_guard = prog_track.ops.guard();
     event!(Level::DEBUG, "read path metadata");
     let src_metadata = tokio::fs::symlink_metadata(path)
         .await
         .with_context(|| format!("failed reading metadata from {:?}", &path))
         .map_err(|err| RmError::new(anyhow::Error::msg(err), Default::default()))?;
+    if !settings
+        .filter
+        .matches(path.file_name().unwrap_or(path.as_os_str()), &src_metadata)
+    {
+        event!(Level::DEBUG, "entry doesn't match the filters, skipping");
+        return Ok(RmSummary {
+            entries_filtered: 1,
+            ..Default::default()
+        });
+    }
     if !src_metadata.is_dir() {
         event!(Level::DEBUG, "not a directory, just remove");
         tokio::fs::remove_file(path)
@@ -140,6 +161,17 @@ pub async fn rm(
     if !success {
         return Err(RmError::new(anyhow!("rm: {:?} failed!", &path), rm_summary));
     }
+    // directories selected by the name or age filters are only removed when pruning, otherwise e.g. removing old files
+    // would also remove all the (now) empty directories regardless of their own age or name
+    let remove_dir = settings.prune_empty_dirs
+        || (settings.filter.matches_dirs() && !settings.filter.selects_by_name_or_age());
+    if rm_summary.entries_filtered > 0 || !remove_dir {
+        event!(
+            Level::DEBUG,
+            "directory is not selected by the filters or still has entries that were filtered, keeping it"
+        );
+        return Ok(rm_summary);
+    }
     event!(Level::DEBUG, "finally remove the empty directory");
     tokio::fs::remove_dir(path)
         .await
@@ -178,7 +210,11 @@ mod tests {
         let summary = rm(
             &PROGRESS,
             &test_path.join("foo"),
-            &RmSettings { fail_early: false },
+            &RmSettings {
+                fail_early: false,
+                filter: Default::default(),
+                prune_empty_dirs: false,
+            },
         )
         .await?;
         assert!(!test_path.join("foo").exists());
@@ -187,4 +223,108 @@ mod tests {
         assert_eq!(summary.directories_removed, 3);
         Ok(())
     }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn check_rm_with_filters() -> Result<(), anyhow::Error> {
+        let tmp_dir = testutils::setup_test_dir().await?;
+        let test_path = tmp_dir.as_path();
+        tokio::fs::write(
+            test_path.join("foo").join("baz").join("big.txt"),
+            "0123456789",
+        )
+        .await?;
+        // remove small files and directories, symlinks are kept so baz cannot be removed
+        let summary = rm(
+            &PROGRESS,
+            &test_path.join("foo"),
+            &RmSettings {
+                fail_early: false,
+                filter: crate::parse_filter_settings(None, Some("5B"), Some("f,d"))?,
+                prune_empty_dirs: false,
+            },
+        )
+        .await?;
+        assert_eq!(summary.files_removed, 5);
+        assert_eq!(summary.symlinks_removed, 0);
+        assert_eq!(summary.directories_removed, 1);
+        assert_eq!(summary.entries_filtered, 3);
+        assert!(!test_path.join("foo").join("bar").exists());
+        assert!(test_path.join("foo").join("baz").join("big.txt").exists());
+        assert!(test_path.join("foo").join("baz").join("5.txt").is_symlink());
+        Ok(())
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn check_rm_older_than() -> Result<(), anyhow::Error> {
+        let tmp_dir = testutils::setup_test_dir().await?;
+        let foo = tmp_dir.join("foo");
+        let old_time = std::time::SystemTime::now() - std::time::Duration::from_secs(2 * 86400);
+        for file in ["0.txt", "bar/1.txt", "bar/2.txt", "bar/3.txt"] {
+            std::fs::File::open(foo.join(file))?
+                .set_times(std::fs::FileTimes::new().set_modified(old_time))?;
+        }
+        let settings = |prune_empty_dirs| -> Result<RmSettings, anyhow::Error> {
+            let filter = filter::FilterSettings {
+                older_than: Some(crate::parse_age_filter("1d", filter::TimeAttr::Mtime)?),
+                ..Default::default()
+            };
+            Ok(RmSettings {
+                fail_early: false,
+                filter,
+                prune_empty_dirs,
+            })
+        };
+        // newer files are retained and the emptied directory is kept
+        let summary = rm(&PROGRESS, &foo, &settings(false)?).await?;
+        assert_eq!(summary.files_removed, 4);
+        assert_eq!(summary.symlinks_removed, 0);
+        assert_eq!(summary.directories_removed, 0);
+        assert_eq!(summary.entries_filtered, 3);
+        assert!(!foo.join("0.txt").exists());
+        assert!(foo.join("bar").is_dir());
+        assert!(foo.join("baz").join("4.txt").exists());
+        assert!(foo.join("baz").join("5.txt").is_symlink());
+        // pruning removes the empty directory only, baz still has entries
+        let summary = rm(&PROGRESS, &foo, &settings(true)?).await?;
+        assert_eq!(summary.files_removed, 0);
+        assert_eq!(summary.directories_removed, 1);
+        assert_eq!(summary.entries_filtered, 3);
+        assert!(!foo.join("bar").exists());
+        assert!(foo.join("baz").join("4.txt").exists());
+        Ok(())
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn check_rm_matching() -> Result<(), anyhow::Error> {
+        let tmp_dir = testutils::setup_test_dir().await?;
+        let foo = tmp_dir.join("foo");
+        // the filters combine: only regular files matching the pattern are removed
+        let filter = filter::FilterSettings {
+            matching: Some(crate::parse_name_pattern("[0-4].txt")?),
+            types: Some("f".parse()?),
+            ..Default::default()
+        };
+        let summary = rm(
+            &PROGRESS,
+            &foo,
+            &RmSettings {
+                fail_early: false,
+                filter,
+                prune_empty_dirs: true,
+            },
+        )
+        .await?;
+        assert_eq!(summary.files_removed, 5);
+        assert_eq!(summary.symlinks_removed, 0);
+        assert_eq!(summary.directories_removed, 1);
+        assert_eq!(summary.entries_filtered, 2);
+        assert!(!foo.join("bar").exists());
+        assert!(foo.join("baz").join("5.txt").is_symlink());
+        assert!(foo.join("baz").join("6.txt").is_symlink());
+        assert!(!foo.join("baz").join("4.txt").exists());
+        Ok(())
+    }
 }
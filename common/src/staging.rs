@@ -0,0 +1,288 @@
+use anyhow::{anyhow, Context, Result};
+use std::os::unix::fs::MetadataExt;
+use tracing::{event, Level};
+
+use crate::copy;
+
+const STAGING_MARKER: &str = ".rcp-staging.";
+
+/// Sibling of the destination the whole copy is written to before being renamed into place, so that the destination
+/// is never observed partially copied
+#[derive(Debug)]
+pub struct Staging {
+    path: std::path::PathBuf,
+    dst: std::path::PathBuf,
+    /// Keep the staging directory when the copy fails, e.g. for debugging
+    keep: bool,
+}
+
+impl Staging {
+    /// Staging path of the given destination: `<dst>.rcp-staging.<random>`, in the same directory so that it's on the
+    /// same filesystem
+    pub fn new(dst: &std::path::Path, keep: bool) -> Result<Self> {
+        let file_name = dst
+            .file_name()
+            .with_context(|| format!("destination {:?} does not have a basename", dst))?;
+        let mut staging_name = file_name.to_owned();
+        staging_name.push(STAGING_MARKER);
+        staging_name.push(format!("{:08x}", rand::random::<u32>()));
+        let staging = Staging {
+            path: dst.with_file_name(staging_name),
+            dst: dst.to_owned(),
+            keep,
+        };
+        staging.check_same_filesystem()?;
+        Ok(staging)
+    }
+
+    pub fn path(&self) -> &std::path::Path {
+        &self.path
+    }
+
+    /// Rename is atomic only within a filesystem, an existing destination must not be a mount point
+    fn check_same_filesystem(&self) -> Result<()> {
+        let dst_metadata = match std::fs::symlink_metadata(&self.dst) {
+            Ok(metadata) => metadata,
+            Err(error) if error.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+            Err(error) => {
+                return Err(error)
+                    .with_context(|| format!("failed reading metadata from {:?}", &self.dst))
+            }
+        };
+        let parent = match self.dst.parent() {
+            Some(parent) if !parent.as_os_str().is_empty() => parent,
+            _ => std::path::Path::new("."),
+        };
+        let parent_metadata = std::fs::metadata(parent)
+            .with_context(|| format!("failed reading metadata from {:?}", parent))?;
+        if dst_metadata.dev() != parent_metadata.dev() {
+            return Err(anyhow!(
+                "{:?} is on a different filesystem than its parent directory (e.g. a mount point), it can't be \
+                replaced atomically",
+                &self.dst
+            ));
+        }
+        Ok(())
+    }
+
+    /// Renames the staging directory to the destination.
+    ///
+    /// An existing destination is only replaced with 'overwrite': it's exchanged atomically with the staging
+    /// directory and then moved to its backup name or removed. The staging directory is discarded if the destination
+    /// can't be replaced.
+    pub async fn publish(self, overwrite: bool, backup: Option<&copy::Backup>) -> Result<()> {
+        let dst_exists = match tokio::fs::symlink_metadata(&self.dst).await {
+            Ok(_) => true,
+            Err(error) if error.kind() == std::io::ErrorKind::NotFound => false,
+            Err(error) => {
+                let error = anyhow::Error::new(error)
+                    .context(format!("failed reading metadata from {:?}", &self.dst));
+                return self.fail(error).await;
+            }
+        };
+        if !dst_exists {
+            // the destination may appear while copying, it's never replaced without --overwrite
+            return match self.rename(nix::fcntl::RenameFlags::RENAME_NOREPLACE) {
+                Ok(()) => Ok(()),
+                Err(error) => {
+                    let error = error.context(format!(
+                        "failed renaming {:?} to {:?}{}",
+                        &self.path,
+                        &self.dst,
+                        if overwrite {
+                            ""
+                        } else {
+                            ", use --overwrite to replace it"
+                        }
+                    ));
+                    self.fail(error).await
+                }
+            };
+        }
+        if !overwrite {
+            let error = anyhow!(
+                "destination {:?} already exists, use --overwrite to replace it",
+                &self.dst
+            );
+            return self.fail(error).await;
+        }
+        match self.rename(nix::fcntl::RenameFlags::RENAME_EXCHANGE) {
+            Ok(()) => {}
+            Err(error)
+                if matches!(
+                    error.downcast_ref::<nix::errno::Errno>(),
+                    Some(nix::errno::Errno::EINVAL | nix::errno::Errno::ENOSYS)
+                ) =>
+            {
+                // not all filesystems support exchanging entries, the destination goes missing for a moment
+                event!(
+                    Level::WARN,
+                    "{:?} can't be exchanged atomically with {:?}, renaming it aside first",
+                    &self.dst,
+                    &self.path
+                );
+                if let Err(error) = self.rename_aside().await {
+                    return self.fail(error).await;
+                }
+            }
+            Err(error) => {
+                let error = error.context(format!(
+                    "failed exchanging {:?} with {:?}",
+                    &self.path, &self.dst
+                ));
+                return self.fail(error).await;
+            }
+        }
+        // the staging path now holds the previous destination
+        if let Some(backup) = backup {
+            let backup_path = copy::free_backup_path(&self.dst, backup).await?;
+            tokio::fs::rename(&self.path, &backup_path)
+                .await
+                .with_context(|| format!("cannot back up {:?} as {:?}", &self.dst, &backup_path))?;
+            event!(
+                Level::DEBUG,
+                "backed up {:?} as {:?}",
+                &self.dst,
+                &backup_path
+            );
+            return Ok(());
+        }
+        remove_entry(&self.path)
+            .await
+            .with_context(|| format!("failed removing the replaced destination {:?}", &self.path))
+    }
+
+    /// Replaces the destination with the staging directory in three renames, leaving the previous destination at the
+    /// staging path like RENAME_EXCHANGE does. The previous destination is put back if any of the renames fails.
+    async fn rename_aside(&self) -> Result<()> {
+        let mut aside = self.path.clone().into_os_string();
+        aside.push(".old");
+        let aside = std::path::PathBuf::from(aside);
+        tokio::fs::rename(&self.dst, &aside)
+            .await
+            .with_context(|| format!("failed renaming {:?} to {:?}", &self.dst, &aside))?;
+        let error = match tokio::fs::rename(&self.path, &self.dst).await {
+            Ok(()) => match tokio::fs::rename(&aside, &self.path).await {
+                Ok(()) => return Ok(()),
+                Err(error) => {
+                    let error = anyhow::Error::new(error)
+                        .context(format!("failed renaming {:?} to {:?}", &aside, &self.path));
+                    // the staging directory goes back to its place first, so that the destination is free again
+                    if let Err(undo_error) = tokio::fs::rename(&self.dst, &self.path).await {
+                        return Err(error.context(format!(
+                            "failed moving {:?} back to {:?} ({}), the previous destination was left at {:?}",
+                            &self.dst, &self.path, &undo_error, &aside
+                        )));
+                    }
+                    error
+                }
+            },
+            Err(error) => anyhow::Error::new(error).context(format!(
+                "failed renaming {:?} to {:?}",
+                &self.path, &self.dst
+            )),
+        };
+        match tokio::fs::rename(&aside, &self.dst).await {
+            Ok(()) => Err(error),
+            Err(undo_error) => Err(error.context(format!(
+                "failed restoring the previous destination {:?} from {:?} ({})",
+                &self.dst, &aside, &undo_error
+            ))),
+        }
+    }
+
+    /// Removes the staging directory after a failed copy, unless it's kept
+    pub async fn discard(self) -> Result<()> {
+        if self.keep {
+            event!(
+                Level::INFO,
+                "keeping the staging directory {:?} of the failed copy",
+                &self.path
+            );
+            return Ok(());
+        }
+        match remove_entry(&self.path).await {
+            Err(error) if error.kind() != std::io::ErrorKind::NotFound => Err(error)
+                .with_context(|| format!("failed removing the staging directory {:?}", &self.path)),
+            _ => Ok(()),
+        }
+    }
+
+    async fn fail(self, error: anyhow::Error) -> Result<()> {
+        if let Err(discard_error) = self.discard().await {
+            event!(Level::ERROR, "{:#}", &discard_error);
+        }
+        Err(error)
+    }
+
+    fn rename(&self, flags: nix::fcntl::RenameFlags) -> Result<()> {
+        nix::fcntl::renameat2(None, &self.path, None, &self.dst, flags)?;
+        Ok(())
+    }
+}
+
+async fn remove_entry(path: &std::path::Path) -> std::io::Result<()> {
+    if tokio::fs::symlink_metadata(path).await?.is_dir() {
+        tokio::fs::remove_dir_all(path).await
+    } else {
+        tokio::fs::remove_file(path).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutils;
+
+    #[tokio::test]
+    async fn check_staging_publish() -> Result<()> {
+        let tmp_dir = testutils::create_temp_dir().await?;
+        let dst = tmp_dir.join("data");
+        let stage = |contents: &str| -> Result<Staging> {
+            let staging = Staging::new(&dst, false)?;
+            assert_eq!(staging.path().parent(), Some(tmp_dir.as_path()));
+            std::fs::create_dir(staging.path())?;
+            std::fs::write(staging.path().join("file"), contents)?;
+            Ok(staging)
+        };
+        stage("1")?.publish(false, None).await?;
+        assert_eq!(std::fs::read_to_string(dst.join("file"))?, "1");
+        assert!(stage("2")?.publish(false, None).await.is_err());
+        stage("2")?.publish(true, None).await?;
+        assert_eq!(std::fs::read_to_string(dst.join("file"))?, "2");
+        let backup = copy::Backup {
+            mode: copy::BackupMode::Simple,
+            suffix: "~".to_string(),
+        };
+        stage("3")?.publish(true, Some(&backup)).await?;
+        assert_eq!(std::fs::read_to_string(dst.join("file"))?, "3");
+        assert_eq!(
+            std::fs::read_to_string(tmp_dir.join("data~").join("file"))?,
+            "2"
+        );
+        let staging = stage("4")?;
+        let staging_path = staging.path().to_owned();
+        staging.discard().await?;
+        assert!(!staging_path.exists());
+        // only the destination and its backup are left
+        assert_eq!(std::fs::read_dir(&tmp_dir)?.count(), 2);
+        assert_eq!(std::fs::read_to_string(dst.join("file"))?, "3");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn check_staging_rename_aside_rollback() -> Result<()> {
+        let tmp_dir = testutils::create_temp_dir().await?;
+        let dst = tmp_dir.join("data");
+        std::fs::create_dir(&dst)?;
+        std::fs::write(dst.join("file"), "1")?;
+        // the staging directory is missing so the second rename fails
+        let staging = Staging::new(&dst, false)?;
+        let error = staging.rename_aside().await.unwrap_err();
+        assert!(format!("{:#}", &error).contains(&format!("{:?}", staging.path())));
+        assert_eq!(std::fs::read_to_string(dst.join("file"))?, "1");
+        // the previous destination didn't stay aside
+        assert_eq!(std::fs::read_dir(&tmp_dir)?.count(), 1);
+        Ok(())
+    }
+}
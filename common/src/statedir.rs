@@ -0,0 +1,234 @@
+use anyhow::{Context, Result};
+use std::os::unix::fs::MetadataExt;
+use tracing::{event, Level};
+
+/// Name of the directory holding rcp bookkeeping files in the destination
+pub const STATE_DIR_NAME: &str = ".rcp-state";
+
+/// Written by rcp into the state directories it creates, holds the device and inode of the directory so that a copy of
+/// it (e.g. found in a source) is not mistaken for one created by rcp
+const OWNER_FILE_NAME: &str = ".owner";
+
+/// File or directory found in the state directory
+#[derive(Clone, Debug)]
+pub struct StateEntry {
+    pub path: std::path::PathBuf,
+    pub modified: std::time::SystemTime,
+}
+
+/// Directory where temporary bookkeeping files of a destination live, so that interrupted runs don't leave them
+/// scattered in the destination tree. Only the entries of a state directory created by rcp are ever pruned.
+#[derive(Clone, Debug)]
+pub struct StateDir {
+    path: std::path::PathBuf,
+}
+
+impl StateDir {
+    /// State directory of the given destination: `<dst>/.rcp-state`
+    pub fn for_destination(dst: &std::path::Path) -> Self {
+        StateDir {
+            path: dst.join(STATE_DIR_NAME),
+        }
+    }
+
+    /// State directory at the given path, e.g. outside of the destination
+    pub fn new(path: &std::path::Path) -> Self {
+        StateDir {
+            path: path.to_owned(),
+        }
+    }
+
+    pub fn path(&self) -> &std::path::Path {
+        &self.path
+    }
+
+    /// Returns true if 'path' is this state directory, not just a directory with the same name
+    pub fn is_same(&self, path: &std::path::Path) -> bool {
+        if path.file_name() != self.path.file_name() {
+            return false;
+        }
+        match (
+            std::fs::symlink_metadata(path),
+            std::fs::metadata(&self.path),
+        ) {
+            (Ok(metadata), Ok(state_metadata)) => {
+                metadata.is_dir()
+                    && metadata.dev() == state_metadata.dev()
+                    && metadata.ino() == state_metadata.ino()
+            }
+            _ => false,
+        }
+    }
+
+    /// Creates the state directory if it doesn't exist yet and returns the path of a state file with the given name.
+    /// The directory containing the state directory must exist.
+    pub fn create_file(&self, name: &str) -> Result<std::path::PathBuf> {
+        match std::fs::create_dir(&self.path) {
+            Ok(()) => self.write_owner()?,
+            Err(error) if error.kind() == std::io::ErrorKind::AlreadyExists => {}
+            Err(error) => {
+                return Err(anyhow::Error::new(error)
+                    .context(format!("failed creating state directory {:?}", &self.path)))
+            }
+        }
+        Ok(self.path.join(name))
+    }
+
+    /// Marks the state directory as created by rcp
+    fn write_owner(&self) -> Result<()> {
+        let owner_file = self.path.join(OWNER_FILE_NAME);
+        let metadata = std::fs::symlink_metadata(&self.path)
+            .with_context(|| format!("failed reading metadata from {:?}", &self.path))?;
+        std::fs::write(
+            &owner_file,
+            format!("{} {}\n", metadata.dev(), metadata.ino()),
+        )
+        .with_context(|| format!("failed writing {:?}", &owner_file))
+    }
+
+    /// Returns true if rcp created the state directory, false also for a copy of a state directory created by rcp
+    pub fn is_owned(&self) -> bool {
+        let (Ok(owner), Ok(metadata)) = (
+            std::fs::read_to_string(self.path.join(OWNER_FILE_NAME)),
+            std::fs::symlink_metadata(&self.path),
+        ) else {
+            return false;
+        };
+        metadata.is_dir() && owner.trim_end() == format!("{} {}", metadata.dev(), metadata.ino())
+    }
+
+    /// Lists the entries of the state directory (other than the owner marker), a missing directory has no entries
+    pub fn list(&self) -> Result<Vec<StateEntry>> {
+        let dir = match std::fs::read_dir(&self.path) {
+            Ok(dir) => dir,
+            Err(error) if error.kind() == std::io::ErrorKind::NotFound => return Ok(vec![]),
+            Err(error) => {
+                return Err(anyhow::Error::new(error)
+                    .context(format!("cannot open state directory {:?}", &self.path)))
+            }
+        };
+        let mut entries = vec![];
+        for entry in dir {
+            let entry = entry
+                .with_context(|| format!("failed traversing state directory {:?}", &self.path))?;
+            if entry.file_name() == OWNER_FILE_NAME {
+                continue;
+            }
+            let path = entry.path();
+            let modified = std::fs::symlink_metadata(&path)
+                .and_then(|metadata| metadata.modified())
+                .with_context(|| format!("failed reading metadata from {:?}", &path))?;
+            entries.push(StateEntry { path, modified });
+        }
+        Ok(entries)
+    }
+
+    /// Removes the entries not modified within 'max_age' and then the state directory itself if it ended up empty,
+    /// returns the number of removed entries. A state directory not created by rcp (see [`StateDir::is_owned`]) is
+    /// left as it is.
+    pub fn prune(&self, max_age: std::time::Duration) -> Result<usize> {
+        if !self.is_owned() {
+            if self.path.exists() {
+                event!(
+                    Level::INFO,
+                    "{:?} was not created by rcp, leaving it as it is",
+                    &self.path
+                );
+            }
+            return Ok(0);
+        }
+        let now = std::time::SystemTime::now();
+        let mut removed = 0;
+        let mut remaining = 0;
+        for entry in self.list()? {
+            // entries modified "in the future" are kept
+            if now
+                .duration_since(entry.modified)
+                .is_ok_and(|age| age >= max_age)
+            {
+                event!(Level::INFO, "removing stale state {:?}", &entry.path);
+                if entry.path.is_dir() && !entry.path.is_symlink() {
+                    std::fs::remove_dir_all(&entry.path)
+                } else {
+                    std::fs::remove_file(&entry.path)
+                }
+                .with_context(|| format!("failed removing stale state {:?}", &entry.path))?;
+                removed += 1;
+            } else {
+                remaining += 1;
+            }
+        }
+        if remaining == 0 {
+            let owner_file = self.path.join(OWNER_FILE_NAME);
+            std::fs::remove_file(&owner_file)
+                .with_context(|| format!("failed removing {:?}", &owner_file))?;
+            match std::fs::remove_dir(&self.path) {
+                Ok(()) => {}
+                Err(error) if error.kind() == std::io::ErrorKind::NotFound => {}
+                Err(error) => {
+                    return Err(anyhow::Error::new(error)
+                        .context(format!("failed removing state directory {:?}", &self.path)))
+                }
+            }
+        }
+        Ok(removed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutils;
+
+    #[tokio::test]
+    async fn check_create_list_prune() -> Result<()> {
+        let tmp_dir = testutils::create_temp_dir().await?;
+        let state_dir = StateDir::for_destination(&tmp_dir);
+        assert!(state_dir.list()?.is_empty());
+        assert_eq!(state_dir.prune(std::time::Duration::ZERO)?, 0);
+        assert!(!state_dir.is_owned());
+        let fresh = state_dir.create_file("fresh")?;
+        let stale = state_dir.create_file("stale")?;
+        std::fs::write(&fresh, "fresh")?;
+        std::fs::create_dir(&stale)?;
+        std::fs::write(stale.join("data"), "stale")?;
+        std::fs::File::open(&stale)?
+            .set_times(std::fs::FileTimes::new().set_modified(
+                std::time::SystemTime::now() - std::time::Duration::from_secs(7200),
+            ))?;
+        assert_eq!(state_dir.list()?.len(), 2);
+        assert_eq!(state_dir.prune(std::time::Duration::from_secs(3600))?, 1);
+        assert!(fresh.exists());
+        assert!(!stale.exists());
+        assert_eq!(state_dir.prune(std::time::Duration::ZERO)?, 1);
+        assert!(!state_dir.path().exists());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn check_prune_only_owned() -> Result<()> {
+        let tmp_dir = testutils::create_temp_dir().await?;
+        // e.g. copied from a source, with the owner marker of another directory
+        let copied = StateDir::for_destination(&tmp_dir.join("copied"));
+        std::fs::create_dir_all(copied.path())?;
+        std::fs::write(copied.path().join("data"), "data")?;
+        let owned = StateDir::for_destination(&tmp_dir);
+        owned.create_file("data")?;
+        std::fs::copy(
+            owned.path().join(OWNER_FILE_NAME),
+            copied.path().join(OWNER_FILE_NAME),
+        )?;
+        assert!(owned.is_owned());
+        assert!(!copied.is_owned());
+        assert_eq!(copied.prune(std::time::Duration::ZERO)?, 0);
+        assert!(copied.path().join("data").exists());
+        assert!(!owned.is_same(copied.path()));
+        assert!(owned.is_same(owned.path()));
+        // the marker is created only together with the directory
+        let user = StateDir::for_destination(&tmp_dir.join("copied").join("user"));
+        std::fs::create_dir_all(user.path())?;
+        user.create_file("data")?;
+        assert!(!user.is_owned());
+        Ok(())
+    }
+}
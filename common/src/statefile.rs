@@ -0,0 +1,109 @@
+//! Helpers for the versioned and checksummed bookkeeping files, see [`crate::CompareCache`] and [`crate::Checkpoint`]
+
+use anyhow::{anyhow, Result};
+use std::os::unix::ffi::OsStrExt;
+
+use crate::checksum;
+
+const CHECKSUM_LEN: usize = 16;
+
+pub(crate) fn put_bytes(data: &mut Vec<u8>, bytes: &[u8]) {
+    data.extend_from_slice(&(bytes.len() as u32).to_le_bytes());
+    data.extend_from_slice(bytes);
+}
+
+/// Starts a state file with the given magic and version, see [`seal`]
+pub(crate) fn header(magic: &[u8], version: u8) -> Vec<u8> {
+    let mut data = magic.to_vec();
+    data.push(version);
+    data
+}
+
+/// Appends the checksum of the state file data
+pub(crate) fn seal(mut data: Vec<u8>) -> Vec<u8> {
+    let mut hasher = checksum::Hasher::new(checksum::ChecksumAlgo::Xxh3);
+    hasher.update(&data);
+    data.extend_from_slice(&hasher.finalize().bytes);
+    data
+}
+
+/// Checks the magic, version and checksum of a state file, returns the data following the header
+pub(crate) fn unseal<'a>(
+    magic: &[u8],
+    version: u8,
+    kind: &str,
+    data: &'a [u8],
+) -> Result<&'a [u8]> {
+    if data.len() < magic.len() + 1 + CHECKSUM_LEN || !data.starts_with(magic) {
+        return Err(anyhow!("not a {} file", kind));
+    }
+    let file_version = data[magic.len()];
+    if file_version != version {
+        return Err(anyhow!(
+            "unsupported {} version {}, expected {}",
+            kind,
+            file_version,
+            version
+        ));
+    }
+    let (body, expected_checksum) = data.split_at(data.len() - CHECKSUM_LEN);
+    let mut hasher = checksum::Hasher::new(checksum::ChecksumAlgo::Xxh3);
+    hasher.update(body);
+    if hasher.finalize().bytes != expected_checksum {
+        return Err(anyhow!("checksum mismatch"));
+    }
+    Ok(&body[magic.len() + 1..])
+}
+
+/// Writes the file atomically: to a temporary file first, renamed over 'path' once complete
+pub(crate) fn write_atomically(path: &std::path::Path, data: &[u8]) -> std::io::Result<()> {
+    let Some(name) = path.file_name() else {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            "path does not have a basename",
+        ));
+    };
+    // unique so that concurrent runs don't write into the same temporary file
+    let mut tmp_name = name.to_owned();
+    tmp_name.push(format!(
+        ".tmp.{:x}-{:08x}",
+        std::process::id(),
+        rand::random::<u32>()
+    ));
+    let tmp_path = path.with_file_name(tmp_name);
+    let result = std::fs::write(&tmp_path, data).and_then(|()| std::fs::rename(&tmp_path, path));
+    if result.is_err() {
+        std::fs::remove_file(&tmp_path).ok();
+    }
+    result
+}
+
+/// Reads the encoded state file entries, fails on any inconsistency
+pub(crate) struct Decoder<'a> {
+    pub data: &'a [u8],
+}
+
+impl<'a> Decoder<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8]> {
+        if self.data.len() < len {
+            return Err(anyhow!("unexpected end of data"));
+        }
+        let (bytes, rest) = self.data.split_at(len);
+        self.data = rest;
+        Ok(bytes)
+    }
+
+    pub fn u64(&mut self) -> Result<u64> {
+        Ok(u64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    pub fn i64(&mut self) -> Result<i64> {
+        Ok(i64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    pub fn path(&mut self) -> Result<std::path::PathBuf> {
+        let len = u32::from_le_bytes(self.take(4)?.try_into().unwrap());
+        let bytes = self.take(len as usize)?;
+        Ok(std::ffi::OsStr::from_bytes(bytes).into())
+    }
+}
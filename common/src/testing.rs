@@ -0,0 +1,85 @@
+//! Helpers shared by the integration tests of the tools
+
+/// Temporary directory of a test, removed when dropped - also when the test fails
+#[derive(Debug)]
+pub struct TempDir {
+    path: std::path::PathBuf,
+}
+
+impl TempDir {
+    /// Creates `<tmp>/<name>_test<pid>`, the name must be unique among the tests of a tool
+    pub fn new(name: &str) -> Self {
+        let path = std::env::temp_dir().join(format!("{}_test{}", name, std::process::id()));
+        std::fs::create_dir_all(&path)
+            .unwrap_or_else(|error| panic!("cannot create {:?}: {}", &path, error));
+        TempDir { path }
+    }
+
+    pub fn path(&self) -> &std::path::Path {
+        &self.path
+    }
+}
+
+impl std::ops::Deref for TempDir {
+    type Target = std::path::Path;
+
+    fn deref(&self) -> &std::path::Path {
+        &self.path
+    }
+}
+
+impl AsRef<std::path::Path> for TempDir {
+    fn as_ref(&self) -> &std::path::Path {
+        &self.path
+    }
+}
+
+impl AsRef<std::ffi::OsStr> for TempDir {
+    fn as_ref(&self) -> &std::ffi::OsStr {
+        self.path.as_os_str()
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        // e.g. a test may have removed it already
+        std::fs::remove_dir_all(&self.path).ok();
+    }
+}
+
+/// Checks the `--capabilities` document of the tool binary: its name and version, the lists of supported values and
+/// that every reported feature is enabled by an option listed in `--help`
+pub fn check_capabilities(bin: &std::path::Path, version: &str) {
+    let run = |arg: &str| {
+        let output = std::process::Command::new(bin).arg(arg).output().unwrap();
+        assert!(output.status.success(), "{:?} {} failed", bin, arg);
+        String::from_utf8(output.stdout).unwrap()
+    };
+    let doc: serde_json::Value = serde_json::from_str(&run("--capabilities")).unwrap();
+    assert_eq!(
+        doc["tool"].as_str(),
+        bin.file_name().and_then(std::ffi::OsStr::to_str)
+    );
+    assert_eq!(doc["version"], version);
+    for key in [
+        "preserve_attributes",
+        "compare_attributes",
+        "progress_types",
+    ] {
+        assert!(!doc[key].as_array().unwrap().is_empty(), "{}", key);
+    }
+    let help = run("--help");
+    let features = doc["features"].as_array().unwrap();
+    assert!(!features.is_empty());
+    for feature in features {
+        let flag = feature["flag"].as_str().unwrap();
+        assert!(
+            help.lines()
+                .any(|line| line.split_whitespace().any(|word| word == flag
+                    || word.starts_with(&format!("{}=", flag))
+                    || word.starts_with(&format!("{}[", flag)))),
+            "{} not found in --help",
+            flag
+        );
+    }
+}
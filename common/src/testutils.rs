@@ -4,7 +4,7 @@ use anyhow::{Context, Result};
 use async_recursion::async_recursion;
 use std::os::unix::fs::MetadataExt;
 
-async fn create_temp_dir() -> Result<std::path::PathBuf> {
+pub async fn create_temp_dir() -> Result<std::path::PathBuf> {
     let mut idx = 0;
     loop {
         let tmp_dir = std::env::temp_dir().join(format!("rcp_test{}", &idx));
@@ -107,8 +107,8 @@ pub async fn check_dirs_identical(
         // compare timestamps
         // NOTE: skip comparing "atime" - we read the file few times when comparing agaisnt "cp"
         assert_eq!(
-            src_md.mtime_nsec(),
-            dst_md.mtime_nsec(),
+            (src_md.mtime(), src_md.mtime_nsec()),
+            (dst_md.mtime(), dst_md.mtime_nsec()),
             "mtime doesn't match for {:?} {:?}",
             src_entry_path,
             dst_entry_path
@@ -1,6 +1,9 @@
-use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+
+use crate::broker;
 
 lazy_static! {
+    static ref MAX_OPEN_FILES: AtomicUsize = AtomicUsize::new(0);
     static ref ENABLE_OPEN_FILES_LIMIT: std::sync::Arc<AtomicBool> =
         std::sync::Arc::new(AtomicBool::new(false));
     static ref OPEN_FILES_SEM: tokio::sync::Semaphore =
@@ -9,8 +12,29 @@ lazy_static! {
         std::sync::Arc::new(AtomicBool::new(false));
     static ref THROTTLE_SEM: tokio::sync::Semaphore =
         tokio::sync::Semaphore::const_new(tokio::sync::Semaphore::MAX_PERMITS);
+    static ref OPS_REPLENISH: AtomicUsize = AtomicUsize::new(0);
+    static ref ENABLE_TPUT_THROTTLE: std::sync::Arc<AtomicBool> =
+        std::sync::Arc::new(AtomicBool::new(false));
+    // bytes replenished every TPUT_INTERVAL, 0 means no limit at the moment
+    static ref TPUT_REPLENISH: AtomicUsize = AtomicUsize::new(0);
+    static ref TPUT_SEM: tokio::sync::Semaphore = tokio::sync::Semaphore::const_new(0);
+    // total time spent waiting for ops and byte-rate tokens, in microseconds
+    static ref OPS_WAIT_US: AtomicU64 = AtomicU64::new(0);
+    static ref TPUT_WAIT_US: AtomicU64 = AtomicU64::new(0);
+    // total byte-rate tokens acquired, i.e. the bytes that went through the local byte-rate limit
+    static ref TPUT_TOKENS_CONSUMED: AtomicU64 = AtomicU64::new(0);
 }
 
+/// Returns the total time spent waiting for ops tokens and for byte-rate tokens
+pub fn wait_times() -> (std::time::Duration, std::time::Duration) {
+    (
+        std::time::Duration::from_micros(OPS_WAIT_US.load(Ordering::Relaxed)),
+        std::time::Duration::from_micros(TPUT_WAIT_US.load(Ordering::Relaxed)),
+    )
+}
+
+const TPUT_INTERVAL: std::time::Duration = std::time::Duration::from_millis(100);
+
 pub fn init_semaphore(
     value: usize,
     flag: &'static std::sync::Arc<AtomicBool>,
@@ -25,9 +49,22 @@ pub fn init_semaphore(
 }
 
 pub fn set_max_open_files(max_open_files: usize) {
+    MAX_OPEN_FILES.store(max_open_files, Ordering::Release);
     init_semaphore(max_open_files, &ENABLE_OPEN_FILES_LIMIT, &OPEN_FILES_SEM);
 }
 
+/// Returns the number of open file permits currently held and the limit, None if there's no limit
+pub fn open_files_in_use() -> Option<(usize, usize)> {
+    if !ENABLE_OPEN_FILES_LIMIT.load(Ordering::Acquire) {
+        return None;
+    }
+    let max_open_files = MAX_OPEN_FILES.load(Ordering::Acquire);
+    Some((
+        max_open_files.saturating_sub(OPEN_FILES_SEM.available_permits()),
+        max_open_files,
+    ))
+}
+
 pub struct OpeFileGuard<'a> {
     _permit: Option<tokio::sync::SemaphorePermit<'a>>,
 }
@@ -48,14 +85,20 @@ pub async fn open_file_permit() -> OpeFileGuard<'static> {
 }
 
 pub fn set_init_tokens(init_iops_tokens: usize) {
+    OPS_REPLENISH.store(init_iops_tokens, Ordering::Release);
     init_semaphore(init_iops_tokens, &ENABLE_THROTTLE, &THROTTLE_SEM);
 }
 
 pub async fn get_token() {
+    if broker::is_client() && broker::get_token().await {
+        return;
+    }
     if !ENABLE_THROTTLE.load(Ordering::Acquire) {
         return;
     }
+    let start = std::time::Instant::now();
     THROTTLE_SEM.acquire().await.unwrap().forget();
+    OPS_WAIT_US.fetch_add(start.elapsed().as_micros() as u64, Ordering::Relaxed);
 }
 
 pub async fn start_replenish_thread(replenish: usize, interval: std::time::Duration) {
@@ -71,3 +114,566 @@ pub async fn start_replenish_thread(replenish: usize, interval: std::time::Durat
         THROTTLE_SEM.add_permits(replenish - curr_permits);
     }
 }
+
+/// Takes at least one and at most `max` tokens, waiting only for the first one. Returns the number of tokens taken.
+async fn take_available(sem: &tokio::sync::Semaphore, max: u64) -> u64 {
+    sem.acquire().await.unwrap().forget();
+    let extra = (max - 1)
+        .min(sem.available_permits() as u64)
+        .min(u32::MAX as u64);
+    match sem.try_acquire_many(extra as u32) {
+        Ok(permit) => {
+            permit.forget();
+            1 + extra
+        }
+        // someone else took the tokens in the meantime
+        Err(_) => 1,
+    }
+}
+
+/// Takes ops tokens on behalf of a throttle broker client, see [`take_available`]
+pub(crate) async fn take_tokens(max: u64) -> u64 {
+    if max == 0 || !ENABLE_THROTTLE.load(Ordering::Acquire) {
+        return max;
+    }
+    take_available(&THROTTLE_SEM, max).await
+}
+
+/// Returns ops tokens a throttle broker client didn't use, up to the replenish amount
+pub(crate) fn return_tokens(tokens: u64) {
+    if !ENABLE_THROTTLE.load(Ordering::Acquire) {
+        return;
+    }
+    let room = OPS_REPLENISH
+        .load(Ordering::Acquire)
+        .saturating_sub(THROTTLE_SEM.available_permits());
+    THROTTLE_SEM.add_permits(room.min(tokens as usize));
+}
+
+/// Takes byte-rate tokens on behalf of a throttle broker client, see [`take_available`]
+pub(crate) async fn take_tput_tokens(max: u64) -> u64 {
+    if max == 0
+        || !ENABLE_TPUT_THROTTLE.load(Ordering::Acquire)
+        || TPUT_REPLENISH.load(Ordering::Acquire) == 0
+    {
+        return max;
+    }
+    take_available(&TPUT_SEM, max).await
+}
+
+/// Byte-rate limits depending on the time of day, e.g. "09:00-18:00=5MiB,else=unlimited".
+///
+/// Windows are checked in order and the first one containing the current (local) time wins, a window may wrap around
+/// midnight, e.g. "22:00-06:00". Time not covered by any window uses the "else" rate, or no limit if not specified.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BwSchedule {
+    windows: Vec<(chrono::NaiveTime, chrono::NaiveTime, Option<u64>)>,
+    default_rate: Option<u64>,
+}
+
+fn parse_rate(rate: &str) -> anyhow::Result<Option<u64>> {
+    if rate == "unlimited" {
+        return Ok(None);
+    }
+    let bytes = rate
+        .parse::<bytesize::ByteSize>()
+        .map_err(|err| anyhow::anyhow!("invalid rate {:?}: {}", rate, err))?
+        .as_u64();
+    if bytes == 0 {
+        return Err(anyhow::anyhow!(
+            "invalid rate {:?}, use \"unlimited\" to disable the limit",
+            rate
+        ));
+    }
+    Ok(Some(bytes))
+}
+
+fn parse_time(time: &str) -> anyhow::Result<chrono::NaiveTime> {
+    chrono::NaiveTime::parse_from_str(time, "%H:%M")
+        .map_err(|err| anyhow::anyhow!("invalid time {:?}, expected HH:MM: {}", time, err))
+}
+
+impl std::str::FromStr for BwSchedule {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut schedule = BwSchedule {
+            windows: vec![],
+            default_rate: None,
+        };
+        for entry in s.split(',') {
+            let (window, rate) = entry.split_once('=').ok_or_else(|| {
+                anyhow::anyhow!(
+                    "invalid schedule entry {:?}, expected <window>=<rate>",
+                    entry
+                )
+            })?;
+            let rate = parse_rate(rate.trim())?;
+            let window = window.trim();
+            if window == "else" {
+                schedule.default_rate = rate;
+                continue;
+            }
+            let (start, end) = window.split_once('-').ok_or_else(|| {
+                anyhow::anyhow!("invalid schedule window {:?}, expected HH:MM-HH:MM", window)
+            })?;
+            let (start, end) = (parse_time(start)?, parse_time(end)?);
+            if start == end {
+                return Err(anyhow::anyhow!("empty schedule window {:?}", window));
+            }
+            schedule.windows.push((start, end, rate));
+        }
+        Ok(schedule)
+    }
+}
+
+impl BwSchedule {
+    /// Returns the limit in bytes per second at the given time of day, None means no limit
+    pub fn rate_at(&self, time: chrono::NaiveTime) -> Option<u64> {
+        for &(start, end, rate) in &self.windows {
+            let in_window = if start < end {
+                start <= time && time < end
+            } else {
+                start <= time || time < end
+            };
+            if in_window {
+                return rate;
+            }
+        }
+        self.default_rate
+    }
+}
+
+fn set_tput_rate(rate: Option<u64>) {
+    // drop any tokens accumulated under the previous rate
+    TPUT_SEM.forget_permits(TPUT_SEM.available_permits());
+    match rate {
+        Some(rate) => {
+            let replenish = std::cmp::max(
+                1,
+                (rate as u128 * TPUT_INTERVAL.as_millis() / 1000) as usize,
+            );
+            TPUT_REPLENISH.store(replenish, Ordering::Release);
+            TPUT_SEM.add_permits(replenish);
+        }
+        None => {
+            TPUT_REPLENISH.store(0, Ordering::Release);
+            // wake up everyone waiting for tokens, they will notice there's no limit anymore
+            TPUT_SEM.add_permits(tokio::sync::Semaphore::MAX_PERMITS / 2);
+        }
+    }
+}
+
+/// Waits until copying the given number of bytes fits within the current byte-rate limit
+pub async fn get_tput_tokens(bytes: u64) {
+    // if we lose the connection to the broker the remaining bytes are throttled locally
+    let bytes = if broker::is_client() {
+        broker::get_tput_tokens(bytes).await
+    } else {
+        bytes
+    };
+    if !ENABLE_TPUT_THROTTLE.load(Ordering::Acquire) {
+        return;
+    }
+    let start = std::time::Instant::now();
+    let mut remaining = bytes;
+    while remaining > 0 {
+        let replenish = TPUT_REPLENISH.load(Ordering::Acquire) as u64;
+        if replenish == 0 {
+            break;
+        }
+        // never ask for more tokens than we ever replenish at once
+        let tokens = remaining.min(replenish).min(u32::MAX as u64);
+        TPUT_SEM.acquire_many(tokens as u32).await.unwrap().forget();
+        TPUT_TOKENS_CONSUMED.fetch_add(tokens, Ordering::Relaxed);
+        remaining -= tokens;
+    }
+    TPUT_WAIT_US.fetch_add(start.elapsed().as_micros() as u64, Ordering::Relaxed);
+}
+
+/// Returns the number of byte-rate tokens consumed so far, None if there's no byte-rate limit
+pub fn tput_tokens_consumed() -> Option<u64> {
+    if !ENABLE_TPUT_THROTTLE.load(Ordering::Acquire) {
+        return None;
+    }
+    Some(TPUT_TOKENS_CONSUMED.load(Ordering::Relaxed))
+}
+
+pub fn set_tput_schedule(schedule: &BwSchedule) {
+    ENABLE_TPUT_THROTTLE.store(true, Ordering::Release);
+    set_tput_rate(schedule.rate_at(chrono::Local::now().time()));
+}
+
+/// Replenishes the byte-rate tokens, adjusting the rate as the clock crosses the schedule window boundaries
+pub async fn start_tput_replenish_thread(schedule: BwSchedule) {
+    if !ENABLE_TPUT_THROTTLE.load(Ordering::Acquire) {
+        return;
+    }
+    let mut curr_rate = schedule.rate_at(chrono::Local::now().time());
+    loop {
+        tokio::time::sleep(TPUT_INTERVAL).await;
+        let rate = schedule.rate_at(chrono::Local::now().time());
+        if rate != curr_rate {
+            tracing::event!(
+                tracing::Level::INFO,
+                "changing the byte-rate limit from {:?} to {:?} bytes/s",
+                curr_rate,
+                rate
+            );
+            curr_rate = rate;
+            set_tput_rate(rate);
+            continue;
+        }
+        let replenish = TPUT_REPLENISH.load(Ordering::Acquire);
+        if replenish == 0 {
+            continue;
+        }
+        let curr_permits = TPUT_SEM.available_permits();
+        if curr_permits >= replenish {
+            continue;
+        }
+        TPUT_SEM.add_permits(replenish - curr_permits);
+    }
+}
+
+/// Splits an ops per second limit into the number of tokens to replenish and how often, so that the tokens don't all
+/// arrive at once
+pub fn ops_replenish(ops: usize) -> (usize, std::time::Duration) {
+    let mut replenish = ops;
+    let mut interval = std::time::Duration::from_secs(1);
+    while replenish > 100 && interval > std::time::Duration::from_millis(1) {
+        replenish /= 10;
+        interval /= 10;
+    }
+    (replenish, interval)
+}
+
+/// Limits for the paths matching a glob pattern, applied on top of the global ones, e.g. "/nfs/*:ops=200,bw=50MiB".
+///
+/// A path matches if it or any of its parent directories matches the pattern, relative paths are resolved against the
+/// current directory first.
+#[derive(Debug, Clone)]
+pub struct ThrottleRule {
+    pattern: glob::Pattern,
+    /// Operations per second
+    ops: Option<usize>,
+    /// Bytes per second
+    bytes: Option<u64>,
+}
+
+impl std::str::FromStr for ThrottleRule {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (pattern, limits) = s.rsplit_once(':').ok_or_else(|| {
+            anyhow::anyhow!(
+                "invalid throttle rule {:?}, expected <pattern>:<limit>=<value>,...",
+                s
+            )
+        })?;
+        let pattern = glob::Pattern::new(pattern)
+            .map_err(|err| anyhow::anyhow!("invalid pattern {:?}: {}", pattern, err))?;
+        let mut rule = ThrottleRule {
+            pattern,
+            ops: None,
+            bytes: None,
+        };
+        for limit in limits.split(',') {
+            match limit.split_once('=') {
+                Some(("ops", ops)) => {
+                    let ops = ops
+                        .parse::<usize>()
+                        .map_err(|err| anyhow::anyhow!("invalid ops limit {:?}: {}", ops, err))?;
+                    if ops == 0 {
+                        return Err(anyhow::anyhow!("invalid ops limit 0 in {:?}", s));
+                    }
+                    rule.ops = Some(ops);
+                }
+                Some(("bw", rate)) => {
+                    rule.bytes = parse_rate(rate)?;
+                }
+                _ => {
+                    return Err(anyhow::anyhow!(
+                        "invalid limit {:?}, expected ops=<n> or bw=<rate>",
+                        limit
+                    ))
+                }
+            }
+        }
+        Ok(rule)
+    }
+}
+
+impl ThrottleRule {
+    pub fn matches(&self, path: &std::path::Path) -> bool {
+        let Ok(path) = std::path::absolute(path) else {
+            return false;
+        };
+        path.ancestors().any(|path| self.pattern.matches_path(path))
+    }
+}
+
+/// Token buckets of a single throttle rule
+#[derive(Debug)]
+struct RuleBucket {
+    rule: ThrottleRule,
+    ops_replenish: usize,
+    ops_interval: std::time::Duration,
+    ops_sem: tokio::sync::Semaphore,
+    // bytes replenished every TPUT_INTERVAL
+    tput_replenish: usize,
+    tput_sem: tokio::sync::Semaphore,
+}
+
+/// Tops up the semaphore to the replenish amount, unused tokens don't accumulate beyond it
+fn top_up(sem: &tokio::sync::Semaphore, replenish: usize) {
+    let curr_permits = sem.available_permits();
+    if curr_permits < replenish {
+        sem.add_permits(replenish - curr_permits);
+    }
+}
+
+impl RuleBucket {
+    fn new(rule: ThrottleRule) -> Self {
+        let (ops_replenish, ops_interval) = ops_replenish(rule.ops.unwrap_or(0));
+        let tput_replenish = rule.bytes.map_or(0, |rate| {
+            std::cmp::max(
+                1,
+                (rate as u128 * TPUT_INTERVAL.as_millis() / 1000) as usize,
+            )
+        });
+        RuleBucket {
+            rule,
+            ops_replenish,
+            ops_interval,
+            ops_sem: tokio::sync::Semaphore::new(ops_replenish),
+            tput_replenish,
+            tput_sem: tokio::sync::Semaphore::new(tput_replenish),
+        }
+    }
+
+    async fn replenish_ops(&self) {
+        loop {
+            tokio::time::sleep(self.ops_interval).await;
+            top_up(&self.ops_sem, self.ops_replenish);
+        }
+    }
+
+    async fn replenish_tput(&self) {
+        loop {
+            tokio::time::sleep(TPUT_INTERVAL).await;
+            top_up(&self.tput_sem, self.tput_replenish);
+        }
+    }
+}
+
+/// Throttle rules in the order they were specified, the first rule matching a path applies to it
+#[derive(Debug)]
+struct ThrottleRules {
+    buckets: Vec<RuleBucket>,
+}
+
+impl ThrottleRules {
+    fn new(rules: Vec<ThrottleRule>) -> Self {
+        ThrottleRules {
+            buckets: rules.into_iter().map(RuleBucket::new).collect(),
+        }
+    }
+
+    /// Returns the buckets of the rules matching any of the paths, each bucket at most once
+    fn matching(&self, paths: &[&std::path::Path]) -> Vec<&RuleBucket> {
+        let mut buckets: Vec<&RuleBucket> = vec![];
+        for path in paths {
+            if let Some(bucket) = self.buckets.iter().find(|bucket| bucket.rule.matches(path)) {
+                if !buckets.iter().any(|other| std::ptr::eq(*other, bucket)) {
+                    buckets.push(bucket);
+                }
+            }
+        }
+        buckets
+    }
+
+    async fn get_token(&self, paths: &[&std::path::Path]) {
+        for bucket in self.matching(paths) {
+            if bucket.ops_replenish > 0 {
+                bucket.ops_sem.acquire().await.unwrap().forget();
+            }
+        }
+    }
+
+    async fn get_tput_tokens(&self, paths: &[&std::path::Path], bytes: u64) {
+        for bucket in self.matching(paths) {
+            let replenish = bucket.tput_replenish as u64;
+            let mut remaining = if replenish > 0 { bytes } else { 0 };
+            while remaining > 0 {
+                // never ask for more tokens than we ever replenish at once
+                let tokens = remaining.min(replenish).min(u32::MAX as u64);
+                bucket
+                    .tput_sem
+                    .acquire_many(tokens as u32)
+                    .await
+                    .unwrap()
+                    .forget();
+                remaining -= tokens;
+            }
+        }
+    }
+}
+
+static THROTTLE_RULES: std::sync::OnceLock<ThrottleRules> = std::sync::OnceLock::new();
+
+pub fn set_throttle_rules(rules: Vec<ThrottleRule>) {
+    if rules.is_empty() {
+        return;
+    }
+    THROTTLE_RULES
+        .set(ThrottleRules::new(rules))
+        .expect("throttle rules can only be set once");
+}
+
+/// Replenishes the tokens of every throttle rule, each rule at its own rate
+pub async fn start_throttle_rules_replenish_threads() {
+    let Some(rules) = THROTTLE_RULES.get() else {
+        return;
+    };
+    for bucket in &rules.buckets {
+        if bucket.ops_replenish > 0 {
+            tokio::spawn(bucket.replenish_ops());
+        }
+        if bucket.tput_replenish > 0 {
+            tokio::spawn(bucket.replenish_tput());
+        }
+    }
+}
+
+/// Waits for an ops token from the global throttle and from the throttle rules matching any of the paths
+pub async fn get_token_for(paths: &[&std::path::Path]) {
+    get_token().await;
+    if let Some(rules) = THROTTLE_RULES.get() {
+        let start = std::time::Instant::now();
+        rules.get_token(paths).await;
+        OPS_WAIT_US.fetch_add(start.elapsed().as_micros() as u64, Ordering::Relaxed);
+    }
+}
+
+/// Waits until copying the given number of bytes fits within the global byte-rate limit and the limits of the throttle
+/// rules matching any of the paths
+pub async fn get_tput_tokens_for(paths: &[&std::path::Path], bytes: u64) {
+    get_tput_tokens(bytes).await;
+    if let Some(rules) = THROTTLE_RULES.get() {
+        let start = std::time::Instant::now();
+        rules.get_tput_tokens(paths, bytes).await;
+        TPUT_WAIT_US.fetch_add(start.elapsed().as_micros() as u64, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod throttle_tests {
+    use super::*;
+
+    fn time(time: &str) -> chrono::NaiveTime {
+        parse_time(time).unwrap()
+    }
+
+    #[test]
+    fn check_schedule_parsing() {
+        let schedule: BwSchedule = "09:00-18:00=5MiB,else=unlimited".parse().unwrap();
+        assert_eq!(schedule.rate_at(time("08:59")), None);
+        assert_eq!(schedule.rate_at(time("09:00")), Some(5 * 1024 * 1024));
+        assert_eq!(schedule.rate_at(time("17:59")), Some(5 * 1024 * 1024));
+        assert_eq!(schedule.rate_at(time("18:00")), None);
+    }
+
+    #[test]
+    fn check_schedule_wraps_midnight() {
+        let schedule: BwSchedule = "22:00-06:00=unlimited,12:00-13:00=10MB,else=1MB"
+            .parse()
+            .unwrap();
+        assert_eq!(schedule.rate_at(time("23:30")), None);
+        assert_eq!(schedule.rate_at(time("05:59")), None);
+        assert_eq!(schedule.rate_at(time("06:00")), Some(1000 * 1000));
+        assert_eq!(schedule.rate_at(time("12:30")), Some(10 * 1000 * 1000));
+    }
+
+    #[test]
+    fn check_schedule_first_window_wins() {
+        let schedule: BwSchedule = "09:00-18:00=1MB,12:00-13:00=2MB".parse().unwrap();
+        assert_eq!(schedule.rate_at(time("12:30")), Some(1000 * 1000));
+        assert_eq!(schedule.rate_at(time("20:00")), None);
+    }
+
+    #[test]
+    fn check_invalid_schedules() {
+        for schedule in [
+            "",
+            "09:00-18:00",
+            "09:00=5M",
+            "09:00-09:00=5M",
+            "9am-5pm=5M",
+            "09:00-18:00=fast",
+            "else=0",
+        ] {
+            assert!(
+                schedule.parse::<BwSchedule>().is_err(),
+                "{:?} should not parse",
+                schedule
+            );
+        }
+    }
+
+    #[test]
+    fn check_throttle_rule_parsing() {
+        let rule: ThrottleRule = "/nfs/*:ops=200,bw=10MB".parse().unwrap();
+        assert_eq!(rule.ops, Some(200));
+        assert_eq!(rule.bytes, Some(10 * 1000 * 1000));
+        assert!(rule.matches(std::path::Path::new("/nfs/data")));
+        assert!(rule.matches(std::path::Path::new("/nfs/data/dir/file")));
+        assert!(!rule.matches(std::path::Path::new("/nfs")));
+        assert!(!rule.matches(std::path::Path::new("/local/nfs/data")));
+        for rule in [
+            "/nfs/*",
+            "/nfs/*:",
+            "/nfs/*:ops=0",
+            "/nfs/*:iops=5",
+            "[:ops=5",
+        ] {
+            assert!(
+                rule.parse::<ThrottleRule>().is_err(),
+                "{:?} should not parse",
+                rule
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn check_throttle_rules_independent() {
+        let rules = ThrottleRules::new(vec![
+            "/slow/*:ops=2".parse().unwrap(),
+            "/fast/*:ops=1000".parse().unwrap(),
+        ]);
+        let slow = std::path::Path::new("/slow/file");
+        let fast = std::path::Path::new("/fast/file");
+        let other = std::path::Path::new("/other/file");
+        let wait = std::time::Duration::from_millis(50);
+        rules.get_token(&[slow]).await;
+        rules.get_token(&[slow]).await;
+        // the slow rule is exhausted...
+        assert!(tokio::time::timeout(wait, rules.get_token(&[slow]))
+            .await
+            .is_err());
+        // ... which doesn't affect the other paths
+        for _ in 0..10 {
+            tokio::time::timeout(wait, rules.get_token(&[fast]))
+                .await
+                .unwrap();
+            tokio::time::timeout(wait, rules.get_token(&[other]))
+                .await
+                .unwrap();
+        }
+        assert!(tokio::time::timeout(wait, rules.get_token(&[fast, slow]))
+            .await
+            .is_err());
+        top_up(&rules.buckets[0].ops_sem, rules.buckets[0].ops_replenish);
+        tokio::time::timeout(wait, rules.get_token(&[slow]))
+            .await
+            .unwrap();
+    }
+}
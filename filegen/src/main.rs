@@ -1,8 +1,5 @@
 use anyhow::{Context, Result};
-use async_recursion::async_recursion;
-use rand::Rng;
 use structopt::StructOpt;
-use tokio::io::AsyncWriteExt;
 
 #[derive(Debug)]
 struct Dirwidth {
@@ -53,63 +50,6 @@ struct Args {
     bufsize: String,
 }
 
-async fn write_file(path: std::path::PathBuf, mut filesize: usize, bufsize: usize) -> Result<()> {
-    let mut bytes = vec![0u8; bufsize];
-    let mut file = tokio::fs::OpenOptions::new()
-        .write(true)
-        .create(true)
-        .truncate(false)
-        .open(&path)
-        .await
-        .context(format!("Error opening {:?}", &path))?;
-    while filesize > 0 {
-        {
-            // make sure rng falls out of scope before await
-            let mut rng = rand::thread_rng();
-            rng.fill(&mut bytes[..]);
-        }
-        let writesize = std::cmp::min(filesize, bufsize) as usize;
-        file.write_all(&bytes[..writesize])
-            .await
-            .context(format!("Error writing to {:?}", &path))?;
-        filesize -= writesize;
-    }
-    Ok(())
-}
-
-#[async_recursion]
-async fn filegen(
-    root: &std::path::Path,
-    dirwidth: &[usize],
-    numfiles: usize,
-    filesize: usize,
-    writebuf: usize,
-) -> Result<()> {
-    let numdirs = *dirwidth.first().unwrap_or(&0);
-    let mut join_set = tokio::task::JoinSet::new();
-    // generate directories and recurse into them
-    for i in 0..numdirs {
-        let path = root.join(format!("dir{}", i));
-        let dirwidth = dirwidth[1..].to_owned();
-        let recurse = || async move {
-            tokio::fs::create_dir(&path)
-                .await
-                .map_err(anyhow::Error::msg)?;
-            filegen(&path, &dirwidth, numfiles, filesize, writebuf).await
-        };
-        join_set.spawn(recurse());
-    }
-    // generate files
-    for i in 0..numfiles {
-        let path = root.join(format!("file{}", i));
-        join_set.spawn(write_file(path.clone(), filesize, writebuf));
-    }
-    while let Some(res) = join_set.join_next().await {
-        res??
-    }
-    Ok(())
-}
-
 #[tokio::main]
 async fn main() -> Result<()> {
     tracing_subscriber::fmt::init();
@@ -124,7 +64,7 @@ async fn main() -> Result<()> {
     tokio::fs::create_dir(&root)
         .await
         .context(format!("Error creating {:?}", &root))?;
-    filegen(
+    common::filegen(
         &root,
         &args.dirwidth.value,
         args.numfiles,
@@ -7,21 +7,28 @@ use structopt::StructOpt;
     name = "rcmp",
     about = "`rcmp` is a tool for comparing large filesets.
 
-Currently, it only supports comparing metadata (no content checking).
+It compares metadata and, if `hash` is part of `--metadata-compare`, file contents.
 
 Returns error code 1 if there are differences, 2 if there were errors."
 )]
 struct Args {
-    /// Attributes to compare when when deciding if objects are "identical". Options are: uid, gid, mode, size, mtime, ctime
+    /// Attributes to compare when when deciding if objects are "identical". Options are: uid, gid, mode, size, mtime, mtime_ns (mtime including the nanoseconds), ctime, hash
     ///
     /// The format is: "<type1>:<attributes1> <type2>:<attributes2> ..."
     /// Where <type> is one of: "f" (file), "d" (directory), "l" (symlink)
-    /// And <attributes> is a comma separated list of: uid, gid, size, mtime, ctime
+    /// And <attributes> is a comma separated list of: uid, gid, size, mtime, mtime_ns (mtime including the nanoseconds), ctime, hash (files only, contents hash
+    /// computed only if the other attributes match)
     ///
     /// Example: "f:mtime,ctime,mode,size d:mtime,ctime,mode l:mtime,ctime,mode"
     #[structopt(long, default_value = "f:mtime,size d:mtime l:mtime")]
     metadata_compare: String,
 
+    /// Algorithm used to hash file contents (see the "hash" attribute above).
+    ///
+    /// Options are: xxh3 (fast, non-cryptographic), blake3 (default, fast, cryptographic), sha256
+    #[structopt(long, default_value = "blake3")]
+    checksum_algo: common::ChecksumAlgo,
+
     /// Exit on first error
     #[structopt(short = "-e", long = "fail-early")]
     fail_early: bool,
@@ -66,14 +73,45 @@ struct Args {
     #[structopt(long)]
     summary: bool,
 
+    /// Print the version and the supported features, attributes and progress types as a JSON document and exit
+    #[structopt(long)]
+    #[allow(dead_code)]
+    // handled before parsing the other arguments, see common::capabilities_requested
+    capabilities: bool,
+
+    /// Units used to show byte counts in the progress and the summary.
+    ///
+    /// Options are: iec (default, powers of 1024: KiB, MiB, ...), si (powers of 1000: KB, MB, ...)
+    #[structopt(long, default_value = "iec")]
+    units: common::ByteUnits,
+
     /// Quiet mode, don't report errors
     #[structopt(short = "q", long = "quiet")]
     quiet: bool,
 
+    /// Write log messages to a file instead of stdout
+    #[structopt(long)]
+    log_file: Option<std::path::PathBuf>,
+
+    /// Rotate the log file once it grows beyond the specified size, e.g. "100MiB". Used with --log-file
+    #[structopt(long)]
+    log_file_max_size: Option<String>,
+
+    /// Number of rotated log files to keep. Used with --log-file-max-size
+    #[structopt(long, default_value = "5")]
+    log_file_keep: usize,
+
     /// File name where to store comparison mismatch output
     #[structopt(long)]
     log: Option<std::path::PathBuf>,
 
+    /// Format of the comparison mismatch output, options are: text, csv, json-lines
+    ///
+    /// The csv and json-lines formats produce one record per mismatch with the path relative to the compared roots,
+    /// the object type, the result (Different, SrcMissing, DstMissing) and the list of attributes that differed.
+    #[structopt(long, default_value = "text")]
+    log_format: common::LogFormat,
+
     /// File or directory to compare
     #[structopt()]
     src: std::path::PathBuf,
@@ -97,10 +135,38 @@ struct Args {
     /// Throttle the number of opearations per second, 0 means no throttle
     #[structopt(long, default_value = "0")]
     ops_throttle: usize,
+
+    /// Throttle the operations on paths matching a glob pattern, separately from the global limit. Can be repeated.
+    ///
+    /// The format is: "<pattern>:ops=<n>" where ops is the number of operations per second. A path matches if it or any
+    /// of its parent directories matches the pattern, the first matching rule applies. The limits apply on top of
+    /// --ops-throttle and are not shared with --throttle-broker.
+    ///
+    /// Example: "/nfs/*:ops=200"
+    #[structopt(long = "throttle-rule", number_of_values = 1)]
+    throttle_rules: Vec<String>,
+
+    /// Share the throttles with other processes using the same unix socket path.
+    ///
+    /// The first process to use the path becomes the broker: its --ops-throttle and --bwlimit-schedule limits apply to
+    /// all processes connected to it. If the broker exits another process takes over, without a broker throttling is
+    /// done locally.
+    #[structopt(long)]
+    throttle_broker: Option<String>,
+
+    /// Serve Prometheus metrics (progress, throughput, open files) over HTTP on this address, e.g. 127.0.0.1:9090
+    #[structopt(long)]
+    metrics_addr: Option<String>,
+
+    /// Cancel the operation if it doesn't finish within the given time, e.g. "30min" or "2h".
+    ///
+    /// A partial summary is printed (with --summary) and the exit code is 3
+    #[structopt(long)]
+    timeout: Option<String>,
 }
 
 async fn async_main(args: Args) -> Result<common::CmpSummary> {
-    let log_handle = common::LogWriter::new(args.log.as_deref()).await?;
+    let log_handle = common::LogWriter::new(args.log.as_deref(), args.log_format).await?;
     let summary = common::cmp(
         &args.src,
         &args.dst,
@@ -108,7 +174,7 @@ async fn async_main(args: Args) -> Result<common::CmpSummary> {
         &common::CmpSettings {
             fail_early: args.fail_early,
             exit_early: args.exit_early,
-            compare: common::parse_compare_settings(&args.metadata_compare)?,
+            compare: common::parse_compare_settings(&args.metadata_compare, args.checksum_algo)?,
         },
     )
     .await?;
@@ -117,7 +183,22 @@ async fn async_main(args: Args) -> Result<common::CmpSummary> {
 }
 
 fn main() -> Result<()> {
-    let args = Args::from_args();
+    if common::capabilities_requested() {
+        println!(
+            "{:#}",
+            common::capabilities("rcmp", env!("CARGO_PKG_VERSION"))
+        );
+        common::ExitCode::Success.exit();
+    }
+    let args = Args::from_iter_safe(std::env::args_os()).unwrap_or_else(|err| {
+        if !err.use_stderr() {
+            // --help or --version
+            err.exit();
+        }
+        eprintln!("{}", err.message);
+        // usage errors are reported the same way as any other errors
+        std::process::exit(2);
+    });
     let func = {
         let args = args.clone();
         || async_main(args)
@@ -138,6 +219,19 @@ fn main() -> Result<()> {
         args.max_blocking_threads,
         args.max_open_files,
         args.ops_throttle,
+        common::RunSettings {
+            log_file: args.log_file.map(|path| common::LogFileSettings {
+                path,
+                max_size: args.log_file_max_size,
+                max_files: args.log_file_keep,
+            }),
+            units: args.units,
+            throttle_rules: args.throttle_rules,
+            throttle_broker: args.throttle_broker,
+            metrics_addr: args.metrics_addr,
+            timeout: args.timeout,
+            ..Default::default()
+        },
         func,
     );
     match res {
@@ -157,6 +251,9 @@ fn main() -> Result<()> {
                 std::process::exit(0);
             }
         },
+        Err(error) if common::ExitCode::from_error(&error).is_cancelled() => {
+            common::ExitCode::from_error(&error).exit()
+        }
         Err(_) => std::process::exit(2),
     }
 }
@@ -3,3 +3,32 @@ fn check_rcp_help() {
     let mut cmd = assert_cmd::Command::cargo_bin("rcmp").unwrap();
     cmd.arg("--help").assert();
 }
+
+#[test]
+fn check_rcmp_exit_codes() {
+    let tmp_dir = common::testing::TempDir::new("rcmp_exit_codes");
+    let src = tmp_dir.join("src");
+    std::fs::create_dir_all(&src).unwrap();
+    std::fs::write(src.join("file.txt"), "x").unwrap();
+    let rcmp = || assert_cmd::Command::cargo_bin("rcmp").unwrap();
+    rcmp().arg(&src).arg(&src).assert().code(0);
+    rcmp()
+        .arg(&src)
+        .arg(tmp_dir.join("missing"))
+        .assert()
+        .code(1);
+    rcmp()
+        .arg(tmp_dir.join("missing"))
+        .arg(&src)
+        .assert()
+        .code(2);
+    rcmp().arg(&src).assert().code(2);
+}
+
+#[test]
+fn check_rcmp_capabilities() {
+    common::testing::check_capabilities(
+        &assert_cmd::cargo::cargo_bin("rcmp"),
+        env!("CARGO_PKG_VERSION"),
+    );
+}
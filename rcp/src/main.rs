@@ -1,5 +1,6 @@
 use anyhow::{anyhow, Context, Result};
 use common::ProgressType;
+use std::os::unix::fs::PermissionsExt;
 use structopt::StructOpt;
 use tracing::{event, instrument, Level};
 
@@ -19,16 +20,217 @@ struct Args {
     #[structopt(short, long)]
     overwrite: bool,
 
+    /// When overwriting a file, truncate and rewrite the existing destination file instead of removing it first.
+    ///
+    /// This keeps the inode of the destination file and any hard-links to it. Note that if the copy is interrupted the
+    /// destination file is left partially written.
+    #[structopt(long, requires = "overwrite")]
+    inplace: bool,
+
+    /// Skip files for which the destination is at least as new as the source (based on mtime), used with --overwrite
+    /// flag.
+    #[structopt(short, long, requires = "overwrite")]
+    update: bool,
+
+    /// Write each file to a temporary name and rename it into place once all data and metadata is written, so that an
+    /// interrupted copy never leaves partially written files under their final names.
+    ///
+    /// The temporary files are created in the state directory (see --state-dir) if it's on the same filesystem, next to
+    /// the destination files otherwise. With --overwrite the existing file is replaced atomically and stale temporary
+    /// files left behind by interrupted copies are removed.
+    #[structopt(long, conflicts_with = "inplace")]
+    atomic_writes: bool,
+
+    /// Copy each source to a temporary sibling of its destination ("<dst>.rcp-staging.<random>") and rename it into
+    /// place once the whole copy succeeded, so that the destination is never observed partially copied.
+    ///
+    /// With --overwrite an existing destination is exchanged atomically with the staged copy and then removed, or kept
+    /// under its backup name with --backup. The staged copy is removed if the copy fails, see --keep-staging.
+    #[structopt(
+        long,
+        conflicts_with_all = &["inplace", "update", "metadata-only", "checkpoint", "compare-cache"]
+    )]
+    whole_dir_atomic: bool,
+
+    /// Keep the staging directory of a failed --whole-dir-atomic copy, e.g. for debugging
+    #[structopt(long, requires = "whole-dir-atomic")]
+    keep_staging: bool,
+
+    /// Copy whole files, the default and currently the only supported mode
+    #[structopt(long, overrides_with = "no-whole-file")]
+    whole_file: bool,
+
+    /// Reserved for delta transfers: sending only the changed blocks of files existing on the destination, using the
+    /// checksums of their blocks. Not supported yet, files are copied whole with a warning.
+    #[structopt(long, overrides_with = "whole-file")]
+    no_whole_file: bool,
+
+    /// Create the missing parent directories of the destination before copying.
+    ///
+    /// The directories are created with the default mode (subject to umask) and --chmod applied. Without this flag a
+    /// missing destination parent directory is an error.
+    #[structopt(long)]
+    mkpath: bool,
+
+    /// Keep the destination entries replaced with --overwrite under a backup name instead of removing them.
+    ///
+    /// Options are: simple (default, "<name><suffix>", see --backup-suffix, replacing the previous backup), numbered
+    /// ("<name>.~<n>~"), e.g. --backup=numbered. Directories are backed up as a whole and symlinks are backed up
+    /// themselves, not their targets. With --atomic-writes the backup of a file is a hard-link to it, so the
+    /// destination never goes missing.
+    #[structopt(
+        long,
+        require_equals = true,
+        requires = "overwrite",
+        conflicts_with = "inplace"
+    )]
+    backup: Option<Option<common::BackupMode>>,
+
+    /// Suffix of the simple backups, see --backup
+    #[structopt(long, default_value = "~")]
+    backup_suffix: String,
+
+    /// Only update the metadata (owner, group, mode, times) of existing destination files and symlinks, never copy
+    /// their data.
+    ///
+    /// Destination files must match the source size and mtime, e.g. after a prior copy with --preserve, and symlinks
+    /// must point to the same path. Missing destination entries are errors unless "soft" is specified, in which case
+    /// they are copied, e.g. --metadata-only=soft
+    #[structopt(long, require_equals = true, conflicts_with = "overwrite")]
+    metadata_only: Option<Option<common::MetadataOnly>>,
+
+    /// Hard-link files identical to the corresponding files in this reference directory instead of copying them, e.g.
+    /// a previous snapshot of the same source.
+    ///
+    /// The reference directory mirrors the destination and files are compared using the --overwrite-compare
+    /// attributes. Hard-linked files share the metadata of the reference files.
+    #[structopt(long)]
+    link_dest: Option<std::path::PathBuf>,
+
+    /// Skip files identical to the corresponding files in this reference directory, e.g. the currently deployed
+    /// version of the same source, so that the destination only contains the files that differ.
+    ///
+    /// The reference directory mirrors the destination and files are compared using the --overwrite-compare
+    /// attributes. Skipped files are counted as unchanged in the summary.
+    #[structopt(long, conflicts_with = "link-dest")]
+    compare_dest: Option<std::path::PathBuf>,
+
+    /// Hard-link copied files with identical contents to the first such file instead of copying them again, to save
+    /// space in the destination.
+    ///
+    /// Source files are hashed (see --checksum-algo) before they are copied. Unlike preserving hard-links of the source,
+    /// this creates new hard-links between files that are independent in the source. Deduplicated files share the
+    /// metadata of the first file.
+    #[structopt(long)]
+    dedup: bool,
+
+    /// Handling of absolute symlink targets pointing inside the copied source tree.
+    ///
+    /// Options are: preserve (default, copy the targets verbatim), rewrite-relative (replace them with equivalent
+    /// relative targets), rewrite-prefix (replace the source path prefix with the destination path). Relative targets
+    /// and targets outside of the source tree are always copied verbatim.
+    #[structopt(long, default_value = "preserve")]
+    links: common::LinksMode,
+
+    /// Remember the files copied by this run in the state directory (see --state-dir), and don't compare the
+    /// destination of source files that didn't change since the previous run. Used with --overwrite.
+    ///
+    /// A source file is unchanged if its size, mtime, ctime and inode are the same, the destination must not be
+    /// modified between the runs. Only the destination side is skipped: the source is still fully traversed and every
+    /// source file is still checked. A corrupted or incompatible cache file is ignored.
+    #[structopt(long, requires = "overwrite")]
+    compare_cache: bool,
+
+    /// Periodically record the sources and directories that were copied completely in the state directory (see
+    /// --state-dir), so that a restarted copy can skip them. Used with --overwrite.
+    ///
+    /// The checkpoint is written atomically every --checkpoint-interval and at the end of the copy, and removed once the
+    /// copy finishes without errors. A corrupted or incompatible checkpoint is an error.
+    #[structopt(long, requires = "overwrite")]
+    checkpoint: bool,
+
+    /// How often to write the --checkpoint file, e.g. "1min"
+    #[structopt(long, default_value = "1min")]
+    checkpoint_interval: String,
+
+    /// Only print the entries that would be copied from the sources (after applying the filters) and exit, the
+    /// destination is not accessed.
+    ///
+    /// Each line contains the type (f, d or l), the size ("-" for directories) and the path relative to the directory
+    /// the source is copied into, separated by tabs.
+    #[structopt(long, conflicts_with_all = &["summary", "cleanup-state"])]
+    list: bool,
+
+    /// Only remove stale bookkeeping files from the state directories (.rcp-state) of the given destination paths, or
+    /// from --state-dir, nothing is copied.
+    ///
+    /// Entries not modified within --state-max-age are removed, and so is the state directory once it's empty. This
+    /// is also done automatically at the end of a successful copy. Only state directories created by rcp are cleaned
+    /// up, e.g. a .rcp-state directory copied from a source is left as it is.
+    #[structopt(long)]
+    cleanup_state: bool,
+
+    /// Directory for the bookkeeping files of --checkpoint, --compare-cache and --atomic-writes, created if it doesn't
+    /// exist. By default it's the .rcp-state directory of the destination.
+    #[structopt(long)]
+    state_dir: Option<std::path::PathBuf>,
+
+    /// Age after which entries in the state directory are considered stale, e.g. "7d"
+    #[structopt(long, default_value = "7d")]
+    state_max_age: String,
+
     /// Comma separated list of file attributes to compare when when deciding if files are "identical", used with
     /// --overwrite flag.
-    /// Options are: uid, gid, mode, size, mtime, ctime
+    /// Options are: uid, gid, mode, size, mtime, mtime_ns (mtime including the nanoseconds), ctime, hash (contents hash, computed only if the other attributes match)
     #[structopt(long, default_value = "size,mtime")]
     overwrite_compare: String,
 
+    /// Consider files "identical" if they have the same size, regardless of their mtime. Same as
+    /// --overwrite-compare=size, useful on filesystems with unreliable mtimes.
+    #[structopt(long, requires = "overwrite", conflicts_with_all = &["ignore-times", "overwrite-compare"])]
+    size_only: bool,
+
+    /// Always overwrite destination files, even if they are "identical" (see --overwrite-compare). Files whose
+    /// destination is newer are still skipped with --update.
+    #[structopt(long, requires = "overwrite")]
+    ignore_times: bool,
+
+    /// Algorithm used to hash file contents (see the "hash" attribute above).
+    ///
+    /// Options are: xxh3 (fast, non-cryptographic), blake3 (default, fast, cryptographic), sha256
+    #[structopt(long, default_value = "blake3")]
+    checksum_algo: common::ChecksumAlgo,
+
     /// Exit on first error
     #[structopt(short = "-e", long = "fail-early")]
     fail_early: bool,
 
+    /// Abort the copy once copying another file would exceed this number of copied files.
+    ///
+    /// Files left unchanged, hard-linked or deduplicated don't count. A guardrail against copying far more than
+    /// intended, e.g. because of a wrong source path.
+    #[structopt(long)]
+    max_files: Option<u64>,
+
+    /// Abort the copy once copying another file would exceed this number of copied bytes, e.g. "100GiB".
+    ///
+    /// Counted like --max-files.
+    #[structopt(long)]
+    max_bytes: Option<String>,
+
+    /// Skip source files and directories we don't have permission to read instead of reporting them as errors
+    ///
+    /// Skipped entries are counted as "entries unreadable" in the summary and do not affect the exit code.
+    #[structopt(long)]
+    skip_unreadable: bool,
+
+    /// Recreate special files (sockets, named pipes and devices) in the destination instead of skipping them.
+    ///
+    /// Without this flag special files are counted as "special files skipped" in the summary. Only root can create
+    /// devices.
+    #[structopt(long)]
+    copy_special: bool,
+
     /// Show progress
     #[structopt(long)]
     progress: bool,
@@ -63,17 +265,157 @@ struct Args {
     ///
     /// The format is: "<type1>:<attributes1> <type2>:<attributes2> ..."
     /// Where <type> is one of: f (file), d (directory), l (symlink)
-    /// And <attributes> is a comma separated list of: uid, gid, time, <mode mask>
+    /// And <attributes> is a comma separated list of: uid, gid, atime, mtime, time (same as "atime,mtime"), btime
+    /// (birth time, accepted but ignored as it cannot be set on Linux), <mode mask>
     /// Where <mode mask> is a 4 digit octal number
     ///
     /// Example: "f:uid,gid,time,0777 d:uid,gid,time,0777 l:uid,gid,time"
     #[structopt(long)]
     preserve_settings: Option<String>,
 
+    /// Preserve uid and gid as numbers, without any mapping (default)
+    #[structopt(long, conflicts_with_all = &["usermap", "groupmap"])]
+    numeric_ids: bool,
+
+    /// Map source uids to destination uids when preserving the file owner.
+    ///
+    /// The format is: "<src>:<dst>,<src>:<dst>,..." where <src> and <dst> are either numeric ids or user names.
+    ///
+    /// Example: "1000:1001,alice:bob"
+    #[structopt(long)]
+    usermap: Option<String>,
+
+    /// Map source gids to destination gids when preserving the file group, same format as --usermap
+    #[structopt(long)]
+    groupmap: Option<String>,
+
+    /// What to do with uids/gids missing in --usermap/--groupmap.
+    ///
+    /// Options are: keep-numeric (default, use the source id), skip-chown (leave the owner/group unchanged), error
+    #[structopt(long, default_value = "keep-numeric")]
+    unmapped_ids: common::UnmappedIds,
+
+    /// Set the owner and group of every copied file, directory and symlink, regardless of the source and of
+    /// --preserve.
+    ///
+    /// The format is: "<user>:<group>", "<user>:" or ":<group>" where <user> and <group> are either numeric ids or
+    /// names, only the specified parts are changed.
+    ///
+    /// Example: "alice:staff"
+    #[structopt(long)]
+    chown: Option<String>,
+
+    /// Change the mode of copied files and directories, applied after --preserve and --preserve-settings.
+    ///
+    /// A comma separated list of octal modes (e.g. "0644") or symbolic changes (e.g. "go-w", "u=rwX"), each optionally
+    /// prefixed with "F" (files only) or "D" (directories only). Symbolic changes without a user part apply to
+    /// everyone, regardless of the umask.
+    ///
+    /// Example: "Dg+s,Fo-rwx" or "F644,D755"
+    #[structopt(long)]
+    chmod: Option<common::Chmod>,
+
+    /// Reapply the immutable and append-only flags (see lsattr/chattr) of files and directories on the destination,
+    /// after their data was written. Setting these flags usually requires root.
+    #[structopt(long)]
+    preserve_flags: bool,
+
+    /// Use reflinks (copy-on-write clones) to copy file data.
+    ///
+    /// Options are: auto (default, reflink if supported, otherwise fall back to copy_file_range and then to regular
+    /// read/write), always (fail copying files that cannot be reflinked), never
+    #[structopt(long, default_value = "auto")]
+    reflink: common::Reflink,
+
+    /// Allocate the space of destination files before copying their data, reducing fragmentation and failing early
+    /// when the destination runs out of space.
+    ///
+    /// Options are: auto (default, files larger than 64MiB on filesystems supporting fallocate), always (every file,
+    /// emulated with posix_fallocate if needed), never
+    #[structopt(long, default_value = "auto")]
+    preallocate: common::Preallocate,
+
+    /// Only copy files at least this large, e.g. "1MiB"
+    #[structopt(long)]
+    min_size: Option<String>,
+
+    /// Only copy files at most this large, e.g. "10GiB"
+    #[structopt(long)]
+    max_size: Option<String>,
+
+    /// Only copy entries of the given types, comma separated list of: f (file), l (symlink), d (directory).
+    ///
+    /// Directories are always traversed and created as needed to hold the copied entries.
+    #[structopt(long = "type")]
+    types: Option<String>,
+
+    /// Only copy files and symlinks modified at or after this time.
+    ///
+    /// Accepts an RFC3339 timestamp (e.g. "2024-01-01T00:00:00Z"), seconds since the epoch (e.g. "@1704067200") or a
+    /// duration relative to the current time (e.g. "30d"). Unlike --update the comparison is against this time, not
+    /// the destination.
+    #[structopt(long)]
+    newer_than: Option<String>,
+
+    /// Only copy files and symlinks modified before this time, same format as --newer-than
+    #[structopt(long)]
+    older_than: Option<String>,
+
+    /// Skip entries with names matching a glob pattern, e.g. "*.tmp". Can be repeated.
+    ///
+    /// The --exclude, --include, --exclude-from and --include-from rules are checked in the order given and the first
+    /// one matching the entry name decides. A pattern ending with a slash only matches directories, excluded
+    /// directories are skipped with all their contents.
+    #[structopt(long, number_of_values = 1)]
+    exclude: Vec<String>,
+
+    /// Don't skip entries with names matching a glob pattern, even if a later --exclude rule matches them. Can be
+    /// repeated.
+    #[structopt(long, number_of_values = 1)]
+    include: Vec<String>,
+
+    /// Read --exclude patterns from a file, one per line. Empty lines and lines starting with '#' are ignored.
+    #[structopt(long, number_of_values = 1)]
+    exclude_from: Vec<std::path::PathBuf>,
+
+    /// Read --include patterns from a file, one per line. Empty lines and lines starting with '#' are ignored.
+    #[structopt(long, number_of_values = 1)]
+    include_from: Vec<std::path::PathBuf>,
+
+    /// Filter mode, currently only "gitignore": skip the entries ignored by the .gitignore and .rcpignore files found
+    /// in the source directories.
+    ///
+    /// Like in git, the rules of an ignore file apply to its directory and everything below it, the rules of deeper
+    /// files take precedence. Entries must also pass the --exclude/--include rules.
+    #[structopt(long, possible_values = &["gitignore"])]
+    filter: Option<String>,
+
+    /// The order of the --exclude, --include, --exclude-from and --include-from rules: the option and the position of
+    /// the value among the values of that option
+    #[structopt(skip)]
+    rule_order: Vec<(&'static str, usize)>,
+
+    /// Remove directories that ended up empty because all their entries were filtered out by --min-size, --max-size,
+    /// --type, --newer-than, --older-than, --exclude, --include, --exclude-from, --include-from or --filter
+    #[structopt(long)]
+    prune_empty_dirs: bool,
+
+    /// Allow copying a directory into a destination inside of it.
+    ///
+    /// By default this is an error as the copy would never finish. With this flag the destination directory is skipped
+    /// when found while traversing the source.
+    #[structopt(long)]
+    allow_recursive: bool,
+
     /// Always follow symbolic links in source
     #[structopt(short = "-L", long)]
     dereference: bool,
 
+    /// Follow the source paths given on the command line if they are symbolic links, symbolic links inside the
+    /// copied tree are preserved (unless --dereference)
+    #[structopt(long, conflicts_with = "list")]
+    dereference_root: bool,
+
     /// Verbose level (implies "summary"): -v INFO / -vv DEBUG / -vvv TRACE (default: ERROR))
     #[structopt(short = "v", long = "verbose", parse(from_occurrences))]
     verbose: u8,
@@ -82,10 +424,41 @@ struct Args {
     #[structopt(long)]
     summary: bool,
 
+    /// Print the version and the supported features, attributes and progress types as a JSON document and exit
+    #[structopt(long)]
+    #[allow(dead_code)]
+    // handled before parsing the other arguments, see common::capabilities_requested
+    capabilities: bool,
+
+    /// Print a line for every destination entry: the action taken, the type (f, d or l) and the path.
+    ///
+    /// Actions are: created, overwritten (followed by the compared attributes that differed, if any), unchanged,
+    /// metadata (only the metadata was updated) and hardlinked.
+    #[structopt(short = "i", long, conflicts_with = "list")]
+    itemize_changes: bool,
+
+    /// Units used to show byte counts in the progress and the summary.
+    ///
+    /// Options are: iec (default, powers of 1024: KiB, MiB, ...), si (powers of 1000: KB, MB, ...)
+    #[structopt(long, default_value = "iec")]
+    units: common::ByteUnits,
+
     /// Quiet mode, don't report errors
     #[structopt(short = "q", long = "quiet")]
     quiet: bool,
 
+    /// Write log messages to a file instead of stdout
+    #[structopt(long)]
+    log_file: Option<std::path::PathBuf>,
+
+    /// Rotate the log file once it grows beyond the specified size, e.g. "100MiB". Used with --log-file
+    #[structopt(long)]
+    log_file_max_size: Option<String>,
+
+    /// Number of rotated log files to keep. Used with --log-file-max-size
+    #[structopt(long, default_value = "5")]
+    log_file_keep: usize,
+
     /// Source path(s) and destination path
     #[structopt()]
     paths: Vec<String>,
@@ -106,24 +479,368 @@ struct Args {
     /// Throttle the number of opearations per second, 0 means no throttle
     #[structopt(long, default_value = "0")]
     ops_throttle: usize,
+
+    /// Share the throttles with other processes using the same unix socket path.
+    ///
+    /// The first process to use the path becomes the broker: its --ops-throttle and --bwlimit-schedule limits apply to
+    /// all processes connected to it. If the broker exits another process takes over, without a broker throttling is
+    /// done locally.
+    #[structopt(long)]
+    throttle_broker: Option<String>,
+
+    /// Limit the copy throughput depending on the time of day (local time)
+    ///
+    /// The format is: "<HH:MM>-<HH:MM>=<rate>,...,else=<rate>" where <rate> is the number of bytes per second, e.g.
+    /// "5MiB", or "unlimited". The first window containing the current time applies, windows may wrap around midnight
+    /// and the time outside of all windows uses the "else" rate (no limit if not specified). The limit is adjusted
+    /// while the copy is running.
+    ///
+    /// Example: "09:00-18:00=5MiB,else=unlimited"
+    #[structopt(long)]
+    bwlimit_schedule: Option<String>,
+
+    /// Throttle the operations on paths matching a glob pattern, separately from the global limits. Can be repeated.
+    ///
+    /// The format is: "<pattern>:ops=<n>,bw=<rate>" where ops is the number of operations per second and bw the number
+    /// of bytes per second, e.g. "50MiB". A path matches if it or any of its parent directories matches the pattern,
+    /// the first matching rule applies. The limits apply on top of --ops-throttle and --bwlimit-schedule and are not
+    /// shared with --throttle-broker.
+    ///
+    /// Example: "/nfs/*:ops=200,bw=50MiB"
+    #[structopt(long = "throttle-rule", number_of_values = 1)]
+    throttle_rules: Vec<String>,
+
+    /// Serve Prometheus metrics (progress, throughput, open files) over HTTP on this address, e.g. 127.0.0.1:9090
+    #[structopt(long)]
+    metrics_addr: Option<String>,
+
+    /// Cancel the operation if it doesn't finish within the given time, e.g. "30min" or "2h".
+    ///
+    /// A partial summary is printed (with --summary) and the exit code is 3
+    #[structopt(long)]
+    timeout: Option<String>,
+
+    /// Record the progress (every second), the summary and the exit code of the copy in this directory, see --status
+    /// and --wait
+    #[structopt(long)]
+    job_dir: Option<std::path::PathBuf>,
+
+    /// Run the copy in the background, detached from the terminal, and exit once it started. Used with --job-dir.
+    ///
+    /// The output goes to output.log in the job directory. The copy can be cancelled by sending SIGTERM to the
+    /// recorded pid, the job then finishes with exit code 3 (interrupted).
+    #[structopt(long, requires = "job-dir")]
+    background: bool,
+
+    /// Print the progress of the job recorded in the given job directory, or its summary if it finished, and exit
+    #[structopt(long, value_name = "JOB_DIR", conflicts_with_all = &["wait", "background", "job-dir"])]
+    status: Option<std::path::PathBuf>,
+
+    /// Wait until the job recorded in the given job directory finishes, print its summary and exit with its exit code
+    #[structopt(long, value_name = "JOB_DIR", conflicts_with_all = &["background", "job-dir"])]
+    wait: Option<std::path::PathBuf>,
+}
+
+fn usage_error(error: anyhow::Error) -> common::ExitError {
+    common::ExitError::new(error, common::ExitCode::UsageError)
+}
+
+/// Builds the filter settings out of the --min-size, --max-size, --type, --newer-than, --older-than and the
+/// include/exclude options
+fn filter_settings(args: &Args) -> Result<common::FilterSettings, common::ExitError> {
+    let mut filter = common::parse_filter_settings(
+        args.min_size.as_deref(),
+        args.max_size.as_deref(),
+        args.types.as_deref(),
+    )
+    .map_err(usage_error)?;
+    if let Some(newer_than) = args.newer_than.as_deref() {
+        filter.newer_than = Some(
+            common::parse_age_filter(newer_than, common::TimeAttr::Mtime)
+                .context("invalid --newer-than")
+                .map_err(usage_error)?,
+        );
+    }
+    if let Some(older_than) = args.older_than.as_deref() {
+        filter.older_than = Some(
+            common::parse_age_filter(older_than, common::TimeAttr::Mtime)
+                .context("invalid --older-than")
+                .map_err(usage_error)?,
+        );
+    }
+    filter.ignore_files = args.filter.is_some();
+    for &(flag, idx) in &args.rule_order {
+        let rules = match flag {
+            "exclude" => common::NameRule::new(&args.exclude[idx], false).map(|rule| vec![rule]),
+            "include" => common::NameRule::new(&args.include[idx], true).map(|rule| vec![rule]),
+            "exclude-from" => common::read_name_rules(&args.exclude_from[idx], false),
+            _ => common::read_name_rules(&args.include_from[idx], true),
+        };
+        filter.rules.extend(
+            rules
+                .with_context(|| format!("invalid --{}", flag))
+                .map_err(usage_error)?,
+        );
+    }
+    Ok(filter)
+}
+
+/// Name of the --checkpoint file in the state directory
+const CHECKPOINT_FILE_NAME: &str = "checkpoint";
+
+/// Name of the --compare-cache file in the state directory
+const COMPARE_CACHE_FILE_NAME: &str = "compare-cache";
+
+/// State directory given with --state-dir, or the one of the destination
+fn state_dir(args: &Args, dst: &std::path::Path) -> common::StateDir {
+    match &args.state_dir {
+        Some(path) => common::StateDir::new(path),
+        None => common::StateDir::for_destination(dst),
+    }
+}
+
+/// Removes the stale entries from the state directory, if it was created by rcp
+fn prune_state_dir(state_dir: &common::StateDir, max_age: std::time::Duration) -> Result<()> {
+    let removed = state_dir.prune(max_age)?;
+    if removed > 0 {
+        event!(
+            Level::INFO,
+            "removed {} stale entries from {:?}",
+            removed,
+            state_dir.path()
+        );
+    }
+    Ok(())
+}
+
+/// Returns the directory the destination will be created in
+fn dst_parent(dst: &std::path::Path) -> &std::path::Path {
+    match dst.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => std::path::Path::new("."),
+    }
+}
+
+/// Creates the missing directories leading to the destination (--mkpath), returns the number of directories created
+fn create_dst_parents(dst: &std::path::Path, chmod: &common::Chmod) -> Result<usize> {
+    let mut missing = vec![];
+    for dir in dst_parent(dst).ancestors() {
+        match std::fs::metadata(dir) {
+            Ok(metadata) if metadata.is_dir() => break,
+            Ok(_) => {
+                return Err(anyhow!(
+                    "cannot create the destination parent directories, {:?} is not a directory",
+                    dir
+                ))
+            }
+            Err(error) if error.kind() == std::io::ErrorKind::NotFound => missing.push(dir),
+            Err(error) => {
+                return Err(error)
+                    .with_context(|| format!("failed reading metadata from {:?}", dir))
+            }
+        }
+    }
+    for dir in missing.iter().rev() {
+        std::fs::create_dir(dir).with_context(|| format!("cannot create directory {:?}", dir))?;
+        let permissions = std::fs::metadata(dir)
+            .with_context(|| format!("failed reading metadata from {:?}", dir))?
+            .permissions();
+        let mode = chmod.apply(permissions.mode(), true);
+        if mode != permissions.mode() {
+            std::fs::set_permissions(dir, std::fs::Permissions::from_mode(mode))
+                .with_context(|| format!("cannot set permissions of {:?}", dir))?;
+        }
+        event!(
+            Level::INFO,
+            "created destination parent directory {:?}",
+            dir
+        );
+    }
+    Ok(missing.len())
+}
+
+/// Writes a line of the --itemize-changes output
+fn write_change(
+    out: &mut impl std::io::Write,
+    change: &common::ItemizedChange,
+) -> std::io::Result<()> {
+    let obj_type = match change.obj_type {
+        common::ObjType::File => "f",
+        common::ObjType::Dir => "d",
+        common::ObjType::Symlink => "l",
+        common::ObjType::Other => "s",
+    };
+    let action = match &change.action {
+        common::ItemAction::Created => "created",
+        common::ItemAction::Overwritten(_) => "overwritten",
+        common::ItemAction::Unchanged => "unchanged",
+        common::ItemAction::MetadataUpdated => "metadata",
+        common::ItemAction::HardLinked => "hardlinked",
+    };
+    write!(out, "{:<11} {} {}", action, obj_type, change.path.display())?;
+    if let common::ItemAction::Overwritten(attrs) = &change.action {
+        for (idx, attr) in attrs.iter().enumerate() {
+            write!(out, "{}{}", if idx == 0 { " (" } else { "," }, attr)?;
+        }
+        if !attrs.is_empty() {
+            write!(out, ")")?;
+        }
+    }
+    writeln!(out)
+}
+
+/// Outcome of copying a single source, reported separately when copying multiple sources
+#[derive(Debug)]
+struct SourceSummary {
+    src: std::path::PathBuf,
+    error: Option<String>,
+    summary: common::CopySummary,
+}
+
+impl std::fmt::Display for SourceSummary {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "{:?}: {} | bytes copied: {} | files copied: {} | symlinks created: {} | directories created: {} | unchanged: {}",
+            self.src,
+            if self.error.is_some() { "failed" } else { "ok" },
+            common::format_bytes(self.summary.bytes_copied),
+            self.summary.files_copied,
+            self.summary.symlinks_created,
+            self.summary.directories_created,
+            self.summary.files_unchanged
+                + self.summary.symlinks_unchanged
+                + self.summary.directories_unchanged,
+        )?;
+        if let Some(error) = &self.error {
+            write!(f, "\n    error: {}", error)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Default)]
+struct RcpSummary {
+    /// Per-source results in the order the sources were given
+    sources: Vec<SourceSummary>,
+    total: common::CopySummary,
+}
+
+impl std::fmt::Display for RcpSummary {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        if self.sources.len() > 1 {
+            writeln!(f, "per-source summary:")?;
+            for source in &self.sources {
+                writeln!(f, "{}", source)?;
+            }
+            writeln!(
+                f,
+                "sources failed: {} of {}\n\ntotal:",
+                self.sources
+                    .iter()
+                    .filter(|source| source.error.is_some())
+                    .count(),
+                self.sources.len()
+            )?;
+        }
+        write!(f, "{}", &self.total)
+    }
+}
+
+impl RcpSummary {
+    fn add(
+        &mut self,
+        src: std::path::PathBuf,
+        error: Option<String>,
+        summary: common::CopySummary,
+    ) {
+        self.total = self.total + summary;
+        self.sources.push(SourceSummary {
+            src,
+            error,
+            summary,
+        });
+    }
 }
 
 #[instrument]
-async fn async_main(args: Args) -> Result<common::CopySummary> {
+async fn async_main(args: Args) -> Result<RcpSummary, common::ExitError> {
+    let state_max_age = humantime::parse_duration(&args.state_max_age)
+        .with_context(|| format!("invalid --state-max-age: {:?}", &args.state_max_age))
+        .map_err(usage_error)?;
+    if args.cleanup_state {
+        let state_dirs: Vec<common::StateDir> = match &args.state_dir {
+            Some(path) => vec![common::StateDir::new(path)],
+            None => args
+                .paths
+                .iter()
+                .map(|path| common::StateDir::for_destination(std::path::Path::new(path)))
+                .collect(),
+        };
+        if state_dirs.is_empty() {
+            return Err(usage_error(anyhow!(
+                "You must specify at least one destination path to clean up!"
+            )));
+        }
+        let mut success = true;
+        for state_dir in &state_dirs {
+            if let Err(error) = prune_state_dir(state_dir, state_max_age) {
+                event!(Level::ERROR, "{:#}", &error);
+                success = false;
+            }
+        }
+        if !success {
+            return Err(common::ExitError::new(
+                anyhow!("rcp encountered errors"),
+                common::ExitCode::Failure,
+            ));
+        }
+        return Ok(Default::default());
+    }
     if args.paths.len() < 2 {
-        return Err(anyhow!(
+        return Err(usage_error(anyhow!(
             "You must specify at least one source and destination path!"
-        ));
+        )));
     }
     let src_strings = &args.paths[0..args.paths.len() - 1];
     for src in src_strings {
         if src == "." || src.ends_with("/.") {
-            return Err(anyhow!(
+            return Err(usage_error(anyhow!(
                 "expanding source directory ({:?}) using dot operator ('.') is not supported, please use absolute \
                 path or '*' instead",
                 std::path::PathBuf::from(src))
-            );
+            ));
+        }
+    }
+    if args.list {
+        let settings = common::ListSettings {
+            dereference: args.dereference,
+            filter: filter_settings(&args)?,
+            state_dir: Some(state_dir(
+                &args,
+                std::path::Path::new(args.paths.last().unwrap()),
+            )),
+        };
+        let mut failed = 0;
+        for src in src_strings {
+            if let Err(error) = common::list(std::path::Path::new(src), &settings).await {
+                event!(Level::ERROR, "{:#}", &error);
+                failed += 1;
+            }
+        }
+        if failed > 0 {
+            return Err(common::ExitError::new(
+                anyhow!("rcp encountered errors"),
+                common::ExitCode::from_errors(failed < src_strings.len()),
+            ));
         }
+        return Ok(Default::default());
+    }
+    if args.no_whole_file && !args.whole_file {
+        event!(
+            Level::WARN,
+            "delta transfers (--no-whole-file) are not supported yet, files are copied whole"
+        );
     }
     let dst_string = args.paths.last().unwrap();
     let src_dst: Vec<(std::path::PathBuf, std::path::PathBuf)> = if dst_string.ends_with('/') {
@@ -136,24 +853,24 @@ async fn async_main(args: Args) -> Result<common::CopySummary> {
                 let src_file = src_path
                     .file_name()
                     .context(format!("source {:?} does not have a basename", &src_path))
-                    .unwrap();
-                (src_path.to_owned(), dst_dir.join(src_file))
+                    .map_err(usage_error)?;
+                Ok((src_path.to_owned(), dst_dir.join(src_file)))
             })
-            .collect::<Vec<(std::path::PathBuf, std::path::PathBuf)>>()
+            .collect::<Result<Vec<(std::path::PathBuf, std::path::PathBuf)>, common::ExitError>>()?
     } else {
         if src_strings.len() > 1 {
-            return Err(anyhow!(
+            return Err(usage_error(anyhow!(
                 "Multiple sources can only be copied INTO to a directory; if this is your intent follow the \
                 destination path with a trailing slash"
-            ));
+            )));
         }
         let dst_path = std::path::PathBuf::from(dst_string);
-        if dst_path.exists() && !args.overwrite {
-            return Err(anyhow!(
+        if dst_path.exists() && !args.overwrite && args.metadata_only.is_none() {
+            return Err(usage_error(anyhow!(
                 "Destination path {dst_path:?} already exists! \n\
                 If you want to copy INTO it then follow the destination path with a trailing slash (/) or use \
                 --overwrite if you want to overwrite it"
-            ));
+            )));
         }
         assert_eq!(src_strings.len(), 1);
         vec![(
@@ -161,13 +878,171 @@ async fn async_main(args: Args) -> Result<common::CopySummary> {
             std::path::PathBuf::from(dst_string),
         )]
     };
+    let mut exclude_dst_dirs = false;
+    // only the source paths themselves are checked
+    let dereference_root = args.dereference || args.dereference_root;
+    for (src_path, dst_path) in &src_dst {
+        let dst_parent = dst_parent(dst_path);
+        if !args.mkpath && !dst_parent.is_dir() {
+            return Err(usage_error(anyhow!(
+                "Destination directory {:?} does not exist or is not a directory! \n\
+                Use --mkpath if you want to create the missing parent directories",
+                dst_parent
+            )));
+        }
+        if common::is_same_root(src_path, dst_path, dereference_root).map_err(usage_error)? {
+            return Err(usage_error(anyhow!(
+                "Source {:?} and destination {:?} are the same file or directory, nothing to copy!",
+                src_path,
+                dst_path
+            )));
+        }
+        if common::is_dst_inside_src(src_path, dst_path, dereference_root).map_err(usage_error)? {
+            if !args.allow_recursive {
+                return Err(usage_error(anyhow!(
+                    "Destination {:?} is inside the source {:?}, the copy would never finish! \n\
+                    Use --allow-recursive to copy it anyway, skipping the destination",
+                    dst_path,
+                    src_path
+                )));
+            }
+            event!(
+                Level::INFO,
+                "destination {:?} is inside the source {:?}, it will be skipped",
+                dst_path,
+                src_path
+            );
+            exclude_dst_dirs = true;
+        }
+    }
+    for (flag, reference) in [
+        ("--link-dest", &args.link_dest),
+        ("--compare-dest", &args.compare_dest),
+    ] {
+        if let Some(reference) = reference {
+            if !reference.is_dir() {
+                return Err(usage_error(anyhow!(
+                    "{} {:?} is not a directory",
+                    flag,
+                    reference
+                )));
+            }
+        }
+    }
+    let filter = filter_settings(&args)?;
+    if args.prune_empty_dirs && !filter.is_active() {
+        event!(
+            Level::WARN,
+            "--prune-empty-dirs has no effect without --min-size, --max-size, --type, --newer-than, --older-than, \
+            --exclude, --include, --exclude-from, --include-from or --filter"
+        );
+    }
+    // the state directory lives in the directory we copied into, or in the copied directory itself
+    let state_dir = state_dir(&args, std::path::Path::new(dst_string));
+    let compare_cache = if args.compare_cache {
+        let path = state_dir.path().join(COMPARE_CACHE_FILE_NAME);
+        Some(std::sync::Arc::new(
+            common::CompareCache::load(&path).unwrap_or_else(|error| {
+                event!(Level::WARN, "{:#}, checking all destination files", &error);
+                Default::default()
+            }),
+        ))
+    } else {
+        None
+    };
+    let checkpoint_interval = humantime::parse_duration(&args.checkpoint_interval)
+        .with_context(|| {
+            format!(
+                "invalid --checkpoint-interval: {:?}",
+                &args.checkpoint_interval
+            )
+        })
+        .map_err(usage_error)?;
+    let checkpoint = if args.checkpoint {
+        let path = state_dir.path().join(CHECKPOINT_FILE_NAME);
+        Some(std::sync::Arc::new(
+            common::Checkpoint::load(&path).map_err(usage_error)?,
+        ))
+    } else {
+        None
+    };
+    let (itemize_sender, itemize_writer) = if args.itemize_changes {
+        let (sender, mut receiver) = tokio::sync::mpsc::unbounded_channel();
+        // stdout is not locked for longer than a write, log messages may go there too
+        let writer = std::thread::spawn(move || -> std::io::Result<()> {
+            let mut out = std::io::BufWriter::new(std::io::stdout());
+            while let Some(change) = receiver.blocking_recv() {
+                write_change(&mut out, &change)?;
+            }
+            std::io::Write::flush(&mut out)
+        });
+        (Some(sender), Some(writer))
+    } else {
+        (None, None)
+    };
+    let max_bytes = args
+        .max_bytes
+        .as_ref()
+        .map(|max_bytes| {
+            max_bytes
+                .parse::<bytesize::ByteSize>()
+                .map(|size| size.as_u64())
+                .map_err(|error| anyhow!("invalid --max-bytes: {:?}: {}", max_bytes, error))
+                .map_err(usage_error)
+        })
+        .transpose()?;
+    let limits = if args.max_files.is_some() || max_bytes.is_some() {
+        Some(std::sync::Arc::new(common::CopyLimits::new(
+            args.max_files,
+            max_bytes,
+        )))
+    } else {
+        None
+    };
     let mut join_set = tokio::task::JoinSet::new();
     let settings = common::CopySettings {
         dereference: args.dereference,
+        dereference_root: args.dereference_root,
         fail_early: args.fail_early,
         overwrite: args.overwrite,
-        overwrite_compare: common::parse_metadata_cmp_settings(&args.overwrite_compare)
-            .map_err(|err| common::CopyError::new(err, Default::default()))?,
+        overwrite_compare: common::parse_metadata_cmp_settings(
+            if args.size_only {
+                "size"
+            } else {
+                &args.overwrite_compare
+            },
+            args.checksum_algo,
+        )
+        .map_err(usage_error)?,
+        reflink: args.reflink,
+        inplace: args.inplace,
+        filter,
+        prune_empty_dirs: args.prune_empty_dirs,
+        update_only: args.update,
+        exclude_dst_dirs,
+        atomic_writes: args.atomic_writes,
+        metadata_only: args
+            .metadata_only
+            .map(|mode| mode.unwrap_or(common::MetadataOnly::Strict)),
+        link_dest: None,
+        compare_dest: None,
+        dedup: args.dedup,
+        compare_cache: compare_cache.clone(),
+        symlink_rewrite: None,
+        skip_unreadable: args.skip_unreadable,
+        ignore_times: args.ignore_times,
+        checkpoint: checkpoint.clone(),
+        itemize: itemize_sender,
+        preallocate: args.preallocate,
+        backup: args.backup.map(|mode| common::Backup {
+            mode: mode.unwrap_or_default(),
+            suffix: args.backup_suffix.clone(),
+        }),
+        copy_special: args.copy_special,
+        limits,
+        state_dir: Some(state_dir.clone()),
+        // shared by all the sources
+        copy_state: Default::default(),
     };
     event!(Level::DEBUG, "copy settings: {:?}", &settings);
     if args.preserve_settings.is_some() && args.preserve {
@@ -176,59 +1051,356 @@ async fn async_main(args: Args) -> Result<common::CopySummary> {
             "The --preserve flag is ignored when --preserve-settings is specified!"
         );
     }
-    let preserve = if let Some(preserve_settings) = args.preserve_settings {
-        common::parse_preserve_settings(&preserve_settings)
-            .map_err(|err| common::CopyError::new(err, Default::default()))?
+    let mut preserve = if let Some(preserve_settings) = args.preserve_settings {
+        common::parse_preserve_settings(&preserve_settings).map_err(usage_error)?
     } else if args.preserve {
         common::preserve_all()
     } else {
         common::preserve_default()
     };
+    let mut id_mapping = common::IdMapping::default();
+    if !args.numeric_ids {
+        if let Some(usermap) = &args.usermap {
+            id_mapping.users = common::parse_user_map(usermap).map_err(usage_error)?;
+        }
+        if let Some(groupmap) = &args.groupmap {
+            id_mapping.groups = common::parse_group_map(groupmap).map_err(usage_error)?;
+        }
+        id_mapping.unmapped = args.unmapped_ids;
+    }
+    if let Some(chown) = &args.chown {
+        (id_mapping.owner, id_mapping.group) = common::parse_chown(chown)
+            .context("invalid --chown")
+            .map_err(usage_error)?;
+    }
+    preserve.id_mapping = std::sync::Arc::new(id_mapping);
+    if let Some(chmod) = &args.chmod {
+        preserve.chmod = std::sync::Arc::new(chmod.clone());
+    }
+    preserve.flags = args.preserve_flags;
     event!(Level::DEBUG, "preserve settings: {:?}", &preserve);
+    let mut parents_created = 0;
+    if args.mkpath {
+        for (_, dst_path) in &src_dst {
+            parents_created += create_dst_parents(dst_path, &preserve.chmod)
+                .map_err(|error| common::ExitError::new(error, common::ExitCode::Failure))?;
+        }
+    }
+    let mut src_order = std::collections::HashMap::new();
     for (src_path, dst_path) in src_dst {
-        let do_copy =
-            || async move { common::copy(&src_path, &dst_path, &settings, &preserve).await };
+        src_order.insert(src_path.clone(), src_order.len());
+        if checkpoint
+            .as_ref()
+            .is_some_and(|checkpoint| checkpoint.is_completed(&src_path, &dst_path))
+        {
+            event!(
+                Level::INFO,
+                "{:?} was copied completely by a previous run, skipping",
+                &src_path
+            );
+            continue;
+        }
+        let mut settings = settings.clone();
+        // the reference directories mirror the destination: copying INTO a directory uses the same source names
+        let reference_path = |reference: &std::path::PathBuf| {
+            if dst_string.ends_with('/') {
+                reference.join(dst_path.file_name().unwrap())
+            } else {
+                reference.clone()
+            }
+        };
+        settings.link_dest = args.link_dest.as_ref().map(reference_path);
+        settings.compare_dest = args.compare_dest.as_ref().map(reference_path);
+        if args.links != common::LinksMode::Preserve {
+            let absolute = |path: &std::path::Path| {
+                std::path::absolute(path)
+                    .with_context(|| format!("failed resolving absolute path of {:?}", path))
+                    .map_err(usage_error)
+            };
+            settings.symlink_rewrite = Some(common::SymlinkRewrite {
+                mode: args.links,
+                src_root: absolute(&src_path)?,
+                dst_root: absolute(&dst_path)?,
+            });
+        }
+        let staging = if args.whole_dir_atomic {
+            Some(common::Staging::new(&dst_path, args.keep_staging).map_err(usage_error)?)
+        } else {
+            None
+        };
+        // the backup is made when the staged copy replaces the destination
+        let backup = if staging.is_some() {
+            settings.backup.take()
+        } else {
+            None
+        };
+        let preserve = preserve.clone();
+        let do_copy = || async move {
+            let result = match staging {
+                Some(staging) => {
+                    match common::copy(&src_path, staging.path(), &settings, &preserve).await {
+                        Ok(summary) => staging
+                            .publish(settings.overwrite, backup.as_ref())
+                            .await
+                            .map(|()| summary)
+                            .map_err(|error| common::CopyError::new(error, summary)),
+                        Err(error) => {
+                            if let Err(discard_error) = staging.discard().await {
+                                event!(Level::ERROR, "{:#}", &discard_error);
+                            }
+                            Err(error)
+                        }
+                    }
+                }
+                None => common::copy(&src_path, &dst_path, &settings, &preserve).await,
+            };
+            if result.is_ok() {
+                if let Some(checkpoint) = &settings.checkpoint {
+                    checkpoint.record(&src_path, &dst_path);
+                }
+            }
+            (src_path, result)
+        };
         join_set.spawn(do_copy());
     }
+    let checkpoint_saver = if let Some(checkpoint) = &checkpoint {
+        let checkpoint = checkpoint.clone();
+        let state_dir = state_dir.clone();
+        Some(tokio::spawn(async move {
+            let mut interval = tokio::time::interval(checkpoint_interval);
+            // the first tick completes immediately
+            interval.tick().await;
+            loop {
+                interval.tick().await;
+                // e.g. the destination directory wasn't created yet
+                if let Err(error) = state_dir
+                    .create_file(CHECKPOINT_FILE_NAME)
+                    .and_then(|path| checkpoint.save(&path))
+                {
+                    event!(Level::WARN, "{:#}", &error);
+                }
+            }
+        }))
+    } else {
+        None
+    };
     let mut success = true;
-    let mut copy_summary = common::CopySummary::default();
+    // reported instead of the generic error once a copy limit was reached
+    let mut limit_error = None;
+    let mut copy_summary = RcpSummary::default();
+    copy_summary.total.directories_created = parents_created;
+    let error_exit_code = |copy_summary: &RcpSummary| {
+        let copy_summary = &copy_summary.total;
+        common::ExitCode::from_errors(
+            copy_summary.files_copied
+                + copy_summary.files_linked
+                + copy_summary.files_deduplicated
+                + copy_summary.symlinks_created
+                + copy_summary.directories_created
+                + copy_summary.files_unchanged
+                + copy_summary.symlinks_unchanged
+                + copy_summary.directories_unchanged
+                + copy_summary.files_same
+                + copy_summary.special_created
+                > 0,
+        )
+    };
+    let sort_sources = |copy_summary: &mut RcpSummary| {
+        copy_summary
+            .sources
+            .sort_by_key(|source| src_order.get(&source.src).copied());
+    };
     while let Some(res) = join_set.join_next().await {
         match res {
-            Ok(result) => match result {
-                Ok(summary) => copy_summary = copy_summary + summary,
+            Ok((src_path, result)) => match result {
+                Ok(summary) => copy_summary.add(src_path, None, summary),
                 Err(error) => {
                     event!(Level::ERROR, "{}", &error);
-                    copy_summary = copy_summary + error.summary;
+                    copy_summary.add(src_path, Some(format!("{}", &error)), error.summary);
+                    if common::is_limit_exceeded(&error.source) {
+                        // the copies of the other sources stop too, they share the limits
+                        limit_error.get_or_insert_with(|| format!("{}", &error));
+                    }
                     if args.fail_early {
+                        sort_sources(&mut copy_summary);
+                        // aborted
+                        let exit_code = common::ExitCode::Failure;
                         if args.summary {
-                            return Err(anyhow!("{}\n\n{}", error, &copy_summary));
+                            return Err(common::ExitError::new(
+                                anyhow!("{}\n\n{}", error, &copy_summary),
+                                exit_code,
+                            ));
                         }
-                        return Err(anyhow!("{}", error));
+                        return Err(common::ExitError::new(anyhow!("{}", error), exit_code));
                     }
                     success = false;
                 }
             },
             Err(error) => {
                 if settings.fail_early {
+                    sort_sources(&mut copy_summary);
+                    let exit_code = common::ExitCode::Failure;
                     if args.summary {
-                        return Err(anyhow!("{}\n\n{}", error, &copy_summary));
+                        return Err(common::ExitError::new(
+                            anyhow!("{}\n\n{}", error, &copy_summary),
+                            exit_code,
+                        ));
                     }
-                    return Err(anyhow!("{}", error));
+                    return Err(common::ExitError::new(anyhow!("{}", error), exit_code));
                 }
             }
         }
     }
+    sort_sources(&mut copy_summary);
+    // the itemize output is complete once all the senders are gone
+    drop(settings);
+    if let Some(itemize_writer) = itemize_writer {
+        if let Err(error) = itemize_writer.join().unwrap() {
+            event!(Level::ERROR, "failed writing itemized changes: {}", &error);
+            success = false;
+        }
+    }
+    if let Some(checkpoint_saver) = checkpoint_saver {
+        checkpoint_saver.abort();
+    }
+    if let Some(checkpoint) = &checkpoint {
+        let result = if success {
+            let path = state_dir.path().join(CHECKPOINT_FILE_NAME);
+            std::fs::remove_file(&path)
+                .or_else(|error| {
+                    if error.kind() == std::io::ErrorKind::NotFound {
+                        Ok(())
+                    } else {
+                        Err(error)
+                    }
+                })
+                .with_context(|| format!("failed removing checkpoint {:?}", path))
+        } else {
+            state_dir
+                .create_file(CHECKPOINT_FILE_NAME)
+                .and_then(|path| checkpoint.save(&path))
+        };
+        if let Err(error) = result {
+            event!(Level::ERROR, "{:#}", &error);
+            success = false;
+        }
+    }
+    if let Some(compare_cache) = &compare_cache {
+        event!(
+            Level::INFO,
+            "skipped checking {} unchanged files using the compare cache",
+            compare_cache.hits()
+        );
+        if let Err(error) = state_dir
+            .create_file(COMPARE_CACHE_FILE_NAME)
+            .and_then(|path| compare_cache.save(&path))
+        {
+            event!(Level::ERROR, "{:#}", &error);
+            success = false;
+        }
+    }
+    if success {
+        if let Err(error) = prune_state_dir(&state_dir, state_max_age) {
+            event!(Level::WARN, "{:#}", &error);
+        }
+    }
     if !success {
+        // reaching a copy limit aborts the copy
+        let exit_code = if limit_error.is_some() {
+            common::ExitCode::Failure
+        } else {
+            error_exit_code(&copy_summary)
+        };
+        let error = limit_error.unwrap_or_else(|| "rcp encountered errors".to_string());
         if args.summary {
-            return Err(anyhow!("rcp encountered errors\n\n{}", &copy_summary));
+            return Err(common::ExitError::new(
+                anyhow!("{}\n\n{}", error, &copy_summary),
+                exit_code,
+            ));
         }
-        return Err(anyhow!("rcp encountered errors"));
+        return Err(common::ExitError::new(anyhow!("{}", error), exit_code));
     }
     Ok(copy_summary)
 }
 
 fn main() -> Result<(), anyhow::Error> {
-    let args = Args::from_args();
+    if common::capabilities_requested() {
+        println!(
+            "{:#}",
+            common::capabilities("rcp", env!("CARGO_PKG_VERSION"))
+        );
+        common::ExitCode::Success.exit();
+    }
+    let matches = Args::clap()
+        .get_matches_from_safe(std::env::args_os())
+        .unwrap_or_else(|err| {
+            if !err.use_stderr() {
+                // --help or --version
+                err.exit();
+            }
+            eprintln!("{}", err.message);
+            common::ExitCode::UsageError.exit();
+        });
+    let mut args = Args::from_clap(&matches);
+    // the rules are checked in order, including across the different options
+    let mut rule_order = vec![];
+    for flag in ["exclude", "include", "exclude-from", "include-from"] {
+        if let Some(indices) = matches.indices_of(flag) {
+            rule_order.extend(
+                indices
+                    .enumerate()
+                    .map(|(idx, arg_index)| (arg_index, flag, idx)),
+            );
+        }
+    }
+    rule_order.sort_by_key(|&(arg_index, _, _)| arg_index);
+    args.rule_order = rule_order
+        .into_iter()
+        .map(|(_, flag, idx)| (flag, idx))
+        .collect();
+    if let Some(job_dir) = args.status.as_deref() {
+        match common::JobDir::new(job_dir).read_state() {
+            Ok(state) => println!("{}", state),
+            Err(error) => {
+                eprintln!("{:#}", error);
+                common::ExitCode::Failure.exit();
+            }
+        }
+        common::ExitCode::Success.exit();
+    }
+    if let Some(job_dir) = args.wait.as_deref() {
+        match common::JobDir::new(job_dir).wait() {
+            Ok(state) => {
+                println!("{}", state);
+                std::process::exit(state.exit_code.unwrap());
+            }
+            Err(error) => {
+                eprintln!("{:#}", error);
+                common::ExitCode::Failure.exit();
+            }
+        }
+    }
+    if args.background {
+        let job_dir = common::JobDir::new(args.job_dir.as_deref().unwrap());
+        let job_args: Vec<_> = std::env::args_os()
+            .skip(1)
+            .filter(|arg| arg != "--background")
+            .collect();
+        match job_dir.spawn_detached(&job_args) {
+            Ok(pid) => {
+                println!(
+                    "started job {} in the background, see: rcp --status {:?}",
+                    pid,
+                    job_dir.path()
+                );
+                common::ExitCode::Success.exit();
+            }
+            Err(error) => {
+                eprintln!("{:#}", error);
+                common::ExitCode::Failure.exit();
+            }
+        }
+    }
     let func = {
         let args = args.clone();
         || async_main(args)
@@ -242,7 +1414,6 @@ fn main() -> Result<(), anyhow::Error> {
         } else {
             None
         },
-        // args.progress_delay,
         args.quiet,
         args.verbose,
         args.summary,
@@ -250,10 +1421,24 @@ fn main() -> Result<(), anyhow::Error> {
         args.max_blocking_threads,
         args.max_open_files,
         args.ops_throttle,
+        common::RunSettings {
+            log_file: args.log_file.map(|path| common::LogFileSettings {
+                path,
+                max_size: args.log_file_max_size,
+                max_files: args.log_file_keep,
+            }),
+            units: args.units,
+            bwlimit_schedule: args.bwlimit_schedule,
+            throttle_rules: args.throttle_rules,
+            throttle_broker: args.throttle_broker,
+            metrics_addr: args.metrics_addr,
+            timeout: args.timeout,
+            job_dir: args.job_dir,
+        },
         func,
     );
     match res {
-        Ok(_) => std::process::exit(0),
-        Err(_) => std::process::exit(1),
+        Ok(_) => common::ExitCode::Success.exit(),
+        Err(error) => common::ExitCode::from_error(&error).exit(),
     }
 }
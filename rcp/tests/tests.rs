@@ -3,3 +3,742 @@ fn check_rcp_help() {
     let mut cmd = assert_cmd::Command::cargo_bin("rcp").unwrap();
     cmd.arg("--help").assert();
 }
+
+#[test]
+fn check_rcp_usage_error() {
+    let mut cmd = assert_cmd::Command::cargo_bin("rcp").unwrap();
+    cmd.arg("--no-such-flag").assert().code(2);
+    let mut cmd = assert_cmd::Command::cargo_bin("rcp").unwrap();
+    cmd.arg("only-one-path").assert().code(2);
+}
+
+#[test]
+fn check_rcp_no_whole_file() {
+    let tmp_dir = common::testing::TempDir::new("rcp_no_whole_file");
+    std::fs::write(tmp_dir.join("src"), "data").unwrap();
+    // delta transfers are reserved, the file is copied whole
+    let output = assert_cmd::Command::cargo_bin("rcp")
+        .unwrap()
+        .args(["-v", "--no-whole-file"])
+        .arg(tmp_dir.join("src"))
+        .arg(tmp_dir.join("dst"))
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    assert!(String::from_utf8_lossy(&output.stdout).contains("--no-whole-file"));
+    assert_eq!(
+        std::fs::read_to_string(tmp_dir.join("dst")).unwrap(),
+        "data"
+    );
+}
+
+/// Polls the condition until it holds, fails the test if that takes too long
+fn wait_until(what: &str, mut condition: impl FnMut() -> bool) {
+    let deadline = std::time::Instant::now() + std::time::Duration::from_secs(30);
+    while !condition() {
+        assert!(
+            std::time::Instant::now() < deadline,
+            "timed out waiting for {}",
+            what
+        );
+        std::thread::sleep(std::time::Duration::from_millis(10));
+    }
+}
+
+#[test]
+fn check_rcp_sigterm_partial_summary() {
+    let tmp_dir = common::testing::TempDir::new("rcp_sigterm");
+    let src = tmp_dir.join("src");
+    let dst = tmp_dir.join("dst");
+    std::fs::create_dir_all(&src).unwrap();
+    for i in 0..20 {
+        std::fs::write(src.join(format!("{}.txt", i)), "x").unwrap();
+    }
+    // throttle the copy so that it's still running when the signal arrives
+    let mut child = std::process::Command::new(assert_cmd::cargo::cargo_bin("rcp"))
+        .args(["--ops-throttle=1", "--atomic-writes", "--summary"])
+        .arg(&src)
+        .arg(&dst)
+        .stdout(std::process::Stdio::piped())
+        .spawn()
+        .unwrap();
+    // the copy started
+    wait_until("the destination directory", || dst.exists());
+    assert!(child.try_wait().unwrap().is_none());
+    let status = std::process::Command::new("kill")
+        .args(["-TERM", &child.id().to_string()])
+        .status()
+        .unwrap();
+    assert!(status.success());
+    let output = child.wait_with_output().unwrap();
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert_eq!(output.status.code(), Some(3), "{}", stdout);
+    assert!(stdout.contains("interrupted by SIGTERM"), "{}", stdout);
+    assert!(stdout.contains("partial summary:"), "{}", stdout);
+    // no temporary files are left behind, in the state directory or next to the destination files
+    for dir in [dst.join(".rcp-state").join("tmp"), dst] {
+        for entry in std::fs::read_dir(&dir).into_iter().flatten() {
+            let name = entry.unwrap().file_name();
+            assert!(!name.to_string_lossy().contains(".rcp-tmp."), "{:?}", name);
+        }
+    }
+}
+
+#[test]
+fn check_rcp_sigusr1_in_flight_dump() {
+    let tmp_dir = common::testing::TempDir::new("rcp_sigusr1");
+    let src = tmp_dir.join("src");
+    let dst = tmp_dir.join("dst");
+    std::fs::create_dir_all(&src).unwrap();
+    for i in 0..20 {
+        std::fs::write(src.join(format!("{}.txt", i)), "x").unwrap();
+    }
+    let mut child = std::process::Command::new(assert_cmd::cargo::cargo_bin("rcp"))
+        .args(["--ops-throttle=1"])
+        .arg(&src)
+        .arg(&dst)
+        .stdout(std::process::Stdio::null())
+        .stderr(std::process::Stdio::piped())
+        .spawn()
+        .unwrap();
+    let stderr = std::sync::Arc::new(std::sync::Mutex::new(String::new()));
+    let reader = {
+        let mut pipe = child.stderr.take().unwrap();
+        let stderr = stderr.clone();
+        std::thread::spawn(move || {
+            let mut buf = [0; 4096];
+            loop {
+                match std::io::Read::read(&mut pipe, &mut buf) {
+                    Ok(0) | Err(_) => break,
+                    Ok(len) => stderr
+                        .lock()
+                        .unwrap()
+                        .push_str(&String::from_utf8_lossy(&buf[..len])),
+                }
+            }
+        })
+    };
+    let pid = child.id().to_string();
+    let kill = |signal: &str| {
+        let status = std::process::Command::new("kill")
+            .args([signal, &pid])
+            .status()
+            .unwrap();
+        assert!(status.success());
+    };
+    wait_until("the destination directory", || dst.exists());
+    kill("-USR1");
+    wait_until("the in-flight dump", || {
+        stderr.lock().unwrap().contains(&format!("s {:?}", &src))
+    });
+    // the dump doesn't interrupt the copy, SIGTERM does
+    assert!(child.try_wait().unwrap().is_none());
+    kill("-TERM");
+    let status = child.wait().unwrap();
+    reader.join().unwrap();
+    let stderr = stderr.lock().unwrap();
+    assert_eq!(status.code(), Some(3), "{}", stderr);
+    assert!(stderr.contains("in flight: 1 directories"), "{}", stderr);
+}
+
+#[test]
+fn check_rcp_bwlimit() {
+    let tmp_dir = common::testing::TempDir::new("rcp_bwlimit");
+    let src = tmp_dir.join("src");
+    let dst = tmp_dir.join("dst");
+    std::fs::create_dir_all(&src).unwrap();
+    const FILE_SIZE: usize = 512 * 1024;
+    const FILES: usize = 4;
+    const RATE: f64 = 1024.0 * 1024.0;
+    for i in 0..FILES {
+        std::fs::write(src.join(format!("{}.bin", i)), vec![i as u8; FILE_SIZE]).unwrap();
+    }
+    let start = std::time::Instant::now();
+    let output = std::process::Command::new(assert_cmd::cargo::cargo_bin("rcp"))
+        .args(["--bwlimit-schedule=else=1MiB", "--summary"])
+        .arg(&src)
+        .arg(&dst)
+        .output()
+        .unwrap();
+    let elapsed = start.elapsed().as_secs_f64();
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(output.status.success(), "{}", stdout);
+    // every copied byte went through the throttle
+    assert!(
+        stdout.contains("bwlimit  : 2.0 MiB throttled"),
+        "{}",
+        stdout
+    );
+    // the first 100ms worth of tokens are available right away, only a lower bound is checked as a loaded machine may
+    // slow the copy down further
+    let expected = (FILES * FILE_SIZE) as f64 / RATE - 0.1;
+    assert!(
+        elapsed >= expected * 0.5,
+        "copy took {:.2}s, expected at least {:.2}s",
+        elapsed,
+        expected * 0.5
+    );
+}
+
+#[test]
+fn check_rcp_state_dir() {
+    let tmp_dir = common::testing::TempDir::new("rcp_state_dir");
+    let src = tmp_dir.join("src");
+    let dst = tmp_dir.join("dst");
+    std::fs::create_dir_all(src.join(".rcp-state")).unwrap();
+    std::fs::write(src.join(".rcp-state").join("cache"), "x").unwrap();
+    std::fs::write(src.join("file.txt"), "x").unwrap();
+    std::fs::create_dir_all(src.join("backup").join(".rcp-state")).unwrap();
+    std::fs::write(src.join("backup").join(".rcp-state").join("cache"), "x").unwrap();
+    let mut cmd = assert_cmd::Command::cargo_bin("rcp").unwrap();
+    cmd.arg(&src).arg(&dst).assert().success();
+    // not the state directory of this destination, it's copied like any other directory
+    assert!(dst.join(".rcp-state").join("cache").exists());
+    assert!(dst.join("backup").join(".rcp-state").join("cache").exists());
+    // the state directory of the destination found in the source is not copied
+    let backup = src.join("backup");
+    let mut cmd = assert_cmd::Command::cargo_bin("rcp").unwrap();
+    cmd.arg("--allow-recursive")
+        .arg(&src)
+        .arg(format!("{}/", backup.display()))
+        .assert()
+        .success();
+    assert!(backup.join("src").join("file.txt").exists());
+    assert!(backup.join("src").join(".rcp-state").join("cache").exists());
+    assert!(!backup
+        .join("src")
+        .join("backup")
+        .join(".rcp-state")
+        .exists());
+    // copied from the source, it's never cleaned up
+    let mut cmd = assert_cmd::Command::cargo_bin("rcp").unwrap();
+    cmd.args(["--cleanup-state", "--state-max-age=0s"])
+        .arg(&dst)
+        .assert()
+        .success();
+    assert!(dst.join(".rcp-state").join("cache").exists());
+    // created by rcp for its bookkeeping files
+    let plain_src = tmp_dir.join("plain_src");
+    let owned_dst = tmp_dir.join("owned_dst");
+    std::fs::create_dir_all(plain_src.join("dir")).unwrap();
+    std::fs::write(plain_src.join("dir").join("file.txt"), "x").unwrap();
+    let mut cmd = assert_cmd::Command::cargo_bin("rcp").unwrap();
+    cmd.args(["--overwrite", "--compare-cache", "--atomic-writes"])
+        .arg(&plain_src)
+        .arg(&owned_dst)
+        .assert()
+        .success();
+    let state_dir = owned_dst.join(".rcp-state");
+    assert!(state_dir.join("compare-cache").exists());
+    // the temporary files were renamed into place
+    assert_eq!(std::fs::read_dir(state_dir.join("tmp")).unwrap().count(), 0);
+    let mut cmd = assert_cmd::Command::cargo_bin("rcp").unwrap();
+    cmd.args(["--cleanup-state", "--state-max-age=0s"])
+        .arg(&owned_dst)
+        .assert()
+        .success();
+    assert!(!state_dir.exists());
+    assert!(owned_dst.join("dir").join("file.txt").exists());
+}
+
+#[test]
+fn check_rcp_checkpoint() {
+    let tmp_dir = common::testing::TempDir::new("rcp_checkpoint");
+    let src = tmp_dir.join("src");
+    let dst = tmp_dir.join("dst");
+    let state_dir = tmp_dir.join("state");
+    let checkpoint = state_dir.join("checkpoint");
+    std::fs::create_dir_all(src.join("dir")).unwrap();
+    std::fs::write(src.join("dir").join("file.txt"), "x").unwrap();
+    std::fs::create_dir_all(&state_dir).unwrap();
+    std::fs::write(&checkpoint, "garbage").unwrap();
+    let mut cmd = assert_cmd::Command::cargo_bin("rcp").unwrap();
+    cmd.args(["--overwrite", "--checkpoint", "--state-dir"])
+        .arg(&state_dir)
+        .arg(&src)
+        .arg(&dst)
+        .assert()
+        .code(2);
+    assert!(!dst.exists());
+    std::fs::remove_file(&checkpoint).unwrap();
+    let mut cmd = assert_cmd::Command::cargo_bin("rcp").unwrap();
+    cmd.args(["--overwrite", "--checkpoint", "--state-dir"])
+        .arg(&state_dir)
+        .arg(&src)
+        .arg(&dst)
+        .assert()
+        .success();
+    assert!(dst.join("dir").join("file.txt").exists());
+    // the checkpoint is removed once the copy succeeds
+    assert!(!checkpoint.exists());
+}
+
+#[test]
+fn check_rcp_itemize_changes() {
+    let tmp_dir = common::testing::TempDir::new("rcp_itemize");
+    let src = tmp_dir.join("src");
+    let dst = tmp_dir.join("dst");
+    std::fs::create_dir_all(&src).unwrap();
+    std::fs::write(src.join("file.txt"), "x").unwrap();
+    let mut cmd = assert_cmd::Command::cargo_bin("rcp").unwrap();
+    cmd.arg("--itemize-changes")
+        .arg(&src)
+        .arg(&dst)
+        .assert()
+        .success()
+        .stdout(format!(
+            "created     d {}\ncreated     f {}\n",
+            dst.display(),
+            dst.join("file.txt").display()
+        ));
+    std::fs::write(src.join("file.txt"), "xx").unwrap();
+    let mut cmd = assert_cmd::Command::cargo_bin("rcp").unwrap();
+    cmd.args(["-i", "--overwrite", "--overwrite-compare=size"])
+        .arg(&src)
+        .arg(&dst)
+        .assert()
+        .success()
+        .stdout(format!(
+            "unchanged   d {}\noverwritten f {} (size)\n",
+            dst.display(),
+            dst.join("file.txt").display()
+        ));
+}
+
+#[test]
+fn check_rcp_list() {
+    let tmp_dir = common::testing::TempDir::new("rcp_list");
+    let src = tmp_dir.join("src");
+    std::fs::create_dir_all(src.join("dir")).unwrap();
+    std::fs::write(src.join("dir").join("small.txt"), "x").unwrap();
+    std::fs::write(src.join("large.txt"), "xxxxxxxxxx").unwrap();
+    let mut cmd = assert_cmd::Command::cargo_bin("rcp").unwrap();
+    cmd.args(["--list", "--min-size=2"])
+        .arg(&src)
+        .arg(tmp_dir.join("dst"))
+        .assert()
+        .success()
+        .stdout("d\t-\tsrc\nd\t-\tsrc/dir\nf\t10\tsrc/large.txt\n");
+    // nothing is copied
+    assert!(!tmp_dir.join("dst").exists());
+}
+
+#[test]
+fn check_rcp_exit_codes() {
+    let tmp_dir = common::testing::TempDir::new("rcp_exit_codes");
+    let src = tmp_dir.join("src");
+    let dst = tmp_dir.join("dst");
+    std::fs::create_dir_all(&src).unwrap();
+    std::fs::create_dir_all(&dst).unwrap();
+    std::fs::write(src.join("file.txt"), "x").unwrap();
+    let rcp = || assert_cmd::Command::cargo_bin("rcp").unwrap();
+    rcp().arg(&src).arg(dst.join("copy")).assert().code(0);
+    // one of the sources is missing
+    rcp()
+        .arg(&src)
+        .arg(tmp_dir.join("missing"))
+        .arg(format!("{}/", dst.display()))
+        .assert()
+        .code(1);
+    // aborted on the first error
+    rcp()
+        .arg("--fail-early")
+        .arg(tmp_dir.join("missing"))
+        .arg(&src)
+        .arg(format!("{}/", dst.display()))
+        .assert()
+        .code(2);
+    // all of the sources are missing
+    rcp()
+        .arg(tmp_dir.join("missing"))
+        .arg(dst.join("other"))
+        .assert()
+        .code(2);
+    // the destination already exists
+    rcp().arg(&src).arg(dst.join("copy")).assert().code(2);
+    // the source and destination are the same
+    rcp()
+        .arg("--overwrite")
+        .arg(&src)
+        .arg(src.join("..").join("src"))
+        .assert()
+        .code(2);
+    assert_eq!(std::fs::read_to_string(src.join("file.txt")).unwrap(), "x");
+}
+
+#[test]
+fn check_rcp_per_source_summary() {
+    let tmp_dir = common::testing::TempDir::new("rcp_per_source");
+    let dst = tmp_dir.join("dst");
+    std::fs::create_dir_all(&dst).unwrap();
+    for name in ["a", "c"] {
+        std::fs::create_dir_all(tmp_dir.join(name)).unwrap();
+        std::fs::write(tmp_dir.join(name).join("file.txt"), name).unwrap();
+    }
+    let output = std::process::Command::new(assert_cmd::cargo::cargo_bin("rcp"))
+        .arg("--summary")
+        .arg(tmp_dir.join("a"))
+        .arg(tmp_dir.join("b"))
+        .arg(tmp_dir.join("c"))
+        .arg(format!("{}/", dst.display()))
+        .output()
+        .unwrap();
+    // some of the sources were copied
+    assert_eq!(output.status.code(), Some(1));
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let lines: Vec<&str> = stdout
+        .lines()
+        .filter(|line| line.starts_with(&format!("\"{}", tmp_dir.display())))
+        .collect();
+    assert_eq!(lines.len(), 3, "{}", stdout);
+    assert!(
+        lines[0].contains("/a\": ok | bytes copied: 1 B | files copied: 1"),
+        "{}",
+        stdout
+    );
+    assert!(
+        lines[1].contains("/b\": failed | bytes copied: 0 B | files copied: 0"),
+        "{}",
+        stdout
+    );
+    assert!(
+        lines[2].contains("/c\": ok | bytes copied: 1 B | files copied: 1"),
+        "{}",
+        stdout
+    );
+    assert!(stdout.contains("sources failed: 1 of 3"), "{}", stdout);
+    // the total still aggregates all of the sources
+    assert!(stdout.contains("files copied: 2\n"), "{}", stdout);
+}
+
+#[test]
+fn check_rcp_mkpath() {
+    let tmp_dir = common::testing::TempDir::new("rcp_mkpath");
+    let src = tmp_dir.join("src.txt");
+    std::fs::create_dir_all(tmp_dir.join("dst").join("a")).unwrap();
+    std::fs::write(&src, "x").unwrap();
+    let rcp = || assert_cmd::Command::cargo_bin("rcp").unwrap();
+    // without --mkpath a missing parent directory is an error
+    let deep = tmp_dir.join("deep").join("b").join("c").join("file.txt");
+    rcp().arg(&src).arg(&deep).assert().code(2);
+    assert!(!tmp_dir.join("deep").exists());
+    // a deep missing chain
+    let output = rcp()
+        .args(["--mkpath", "--summary"])
+        .arg(&src)
+        .arg(&deep)
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    assert!(String::from_utf8_lossy(&output.stdout).contains("directories created: 3\n"));
+    assert_eq!(std::fs::read_to_string(&deep).unwrap(), "x");
+    // a partially existing chain, copying into a directory
+    let partial = tmp_dir.join("dst").join("a").join("b").join("c");
+    rcp()
+        .arg("--mkpath")
+        .arg(&src)
+        .arg(format!("{}/", partial.display()))
+        .assert()
+        .code(0);
+    assert_eq!(
+        std::fs::read_to_string(partial.join("src.txt")).unwrap(),
+        "x"
+    );
+    // a file blocking the path
+    rcp()
+        .arg("--mkpath")
+        .arg(&src)
+        .arg(tmp_dir.join("src.txt").join("b").join("file.txt"))
+        .assert()
+        .code(2);
+}
+
+#[test]
+fn check_rcp_whole_dir_atomic() {
+    let tmp_dir = common::testing::TempDir::new("rcp_atomic_dir");
+    let src = tmp_dir.join("src");
+    let dst = tmp_dir.join("dst");
+    std::fs::create_dir_all(src.join("sub")).unwrap();
+    std::fs::write(src.join("sub").join("a.txt"), "new").unwrap();
+    std::fs::create_dir_all(&dst).unwrap();
+    std::fs::write(dst.join("old.txt"), "old").unwrap();
+    let rcp = || assert_cmd::Command::cargo_bin("rcp").unwrap();
+    rcp()
+        .args(["--whole-dir-atomic", "--overwrite", "--backup"])
+        .arg(&src)
+        .arg(&dst)
+        .assert()
+        .code(0);
+    // the destination is replaced as a whole, the previous one is kept as a backup
+    assert_eq!(
+        std::fs::read_to_string(dst.join("sub").join("a.txt")).unwrap(),
+        "new"
+    );
+    assert!(!dst.join("old.txt").exists());
+    assert_eq!(
+        std::fs::read_to_string(tmp_dir.join("dst~").join("old.txt")).unwrap(),
+        "old"
+    );
+    rcp()
+        .arg("--whole-dir-atomic")
+        .arg(&src)
+        .arg(format!("{}/", dst.display()))
+        .assert()
+        .code(0);
+    assert_eq!(
+        std::fs::read_to_string(dst.join("src").join("sub").join("a.txt")).unwrap(),
+        "new"
+    );
+    // no staging directories are left behind
+    let mut names: Vec<_> = std::fs::read_dir(&tmp_dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name())
+        .collect();
+    names.sort();
+    assert_eq!(names, ["dst", "dst~", "src"]);
+    assert_eq!(std::fs::read_dir(&dst).unwrap().count(), 2);
+    rcp()
+        .args(["--whole-dir-atomic", "--inplace", "--overwrite"])
+        .arg(&src)
+        .arg(&dst)
+        .assert()
+        .code(2);
+}
+
+#[test]
+fn check_rcp_exclude_include() {
+    let tmp_dir = common::testing::TempDir::new("rcp_exclude");
+    let src = tmp_dir.join("src");
+    std::fs::create_dir_all(src.join("cache")).unwrap();
+    std::fs::create_dir_all(src.join("logs")).unwrap();
+    for file in [
+        "a.txt",
+        "b.tmp",
+        "keep.tmp",
+        "cache/c.txt",
+        "logs/d.log",
+        "logs/e.tmp",
+    ] {
+        std::fs::write(src.join(file), file).unwrap();
+    }
+    let rules = tmp_dir.join("rules");
+    std::fs::write(&rules, "# temporary files\n*.tmp\n\ncache/\n").unwrap();
+    let rcp = || assert_cmd::Command::cargo_bin("rcp").unwrap();
+    // the include given before the rules file takes precedence over it
+    rcp()
+        .arg("--include=keep.tmp")
+        .arg("--exclude-from")
+        .arg(&rules)
+        .arg("--exclude=*.log")
+        .arg(&src)
+        .arg(tmp_dir.join("dst"))
+        .assert()
+        .code(0);
+    let copied = |dst: &str, file: &str| tmp_dir.join(dst).join(file).exists();
+    assert!(copied("dst", "a.txt"));
+    assert!(copied("dst", "keep.tmp"));
+    assert!(!copied("dst", "b.tmp"));
+    assert!(!copied("dst", "cache"));
+    assert!(copied("dst", "logs"));
+    assert!(!copied("dst", "logs/d.log"));
+    assert!(!copied("dst", "logs/e.tmp"));
+    // the same include given after the rules file has no effect
+    rcp()
+        .arg("--exclude-from")
+        .arg(&rules)
+        .arg("--include=keep.tmp")
+        .arg(&src)
+        .arg(tmp_dir.join("dst2"))
+        .assert()
+        .code(0);
+    assert!(!copied("dst2", "keep.tmp"));
+    assert!(copied("dst2", "logs/d.log"));
+    // invalid patterns are usage errors
+    rcp()
+        .arg("--exclude=[a-")
+        .arg(&src)
+        .arg(tmp_dir.join("dst3"))
+        .assert()
+        .code(2);
+}
+
+#[test]
+fn check_rcp_capabilities() {
+    common::testing::check_capabilities(
+        &assert_cmd::cargo::cargo_bin("rcp"),
+        env!("CARGO_PKG_VERSION"),
+    );
+}
+
+#[test]
+fn check_rcp_filter_gitignore() {
+    let tmp_dir = common::testing::TempDir::new("rcp_gitignore");
+    let src = tmp_dir.join("project");
+    std::fs::create_dir_all(src.join("target").join("debug")).unwrap();
+    std::fs::create_dir_all(src.join("src").join("gen")).unwrap();
+    std::fs::write(src.join(".gitignore"), "/target/\n*.log\n").unwrap();
+    std::fs::write(src.join("src").join(".rcpignore"), "gen/\n!keep.log\n").unwrap();
+    for file in [
+        "Cargo.toml",
+        "build.log",
+        "target/debug/rcp",
+        "src/main.rs",
+        "src/keep.log",
+        "src/other.log",
+        "src/gen/out.rs",
+    ] {
+        std::fs::write(src.join(file), file).unwrap();
+    }
+    let dst = tmp_dir.join("dst");
+    assert_cmd::Command::cargo_bin("rcp")
+        .unwrap()
+        .args(["--filter=gitignore", "--exclude=Cargo.toml"])
+        .arg(&src)
+        .arg(&dst)
+        .assert()
+        .code(0);
+    let mut copied = vec![];
+    let mut dirs = vec![dst.clone()];
+    while let Some(dir) = dirs.pop() {
+        for entry in std::fs::read_dir(dir).unwrap() {
+            let path = entry.unwrap().path();
+            if path.is_dir() {
+                dirs.push(path);
+            } else {
+                copied.push(
+                    path.strip_prefix(&dst)
+                        .unwrap()
+                        .to_str()
+                        .unwrap()
+                        .to_string(),
+                );
+            }
+        }
+    }
+    copied.sort();
+    assert_eq!(
+        copied,
+        [
+            ".gitignore",
+            "src/.rcpignore",
+            "src/keep.log",
+            "src/main.rs"
+        ]
+    );
+    assert!(!dst.join("target").exists());
+}
+
+#[test]
+fn check_rcp_background_job() {
+    let tmp_dir = common::testing::TempDir::new("rcp_background");
+    let src = tmp_dir.join("src");
+    let dst = tmp_dir.join("dst");
+    let job_dir = tmp_dir.join("job");
+    std::fs::create_dir_all(&src).unwrap();
+    std::fs::write(src.join("a.txt"), "a").unwrap();
+    let rcp = || assert_cmd::Command::cargo_bin("rcp").unwrap();
+    rcp().arg("--status").arg(&job_dir).assert().code(2);
+    rcp()
+        .args(["--background", "--summary", "--job-dir"])
+        .arg(&job_dir)
+        .arg(&src)
+        .arg(&dst)
+        .assert()
+        .code(0);
+    let output = rcp().arg("--wait").arg(&job_dir).output().unwrap();
+    assert_eq!(output.status.code(), Some(0));
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains("finished with exit code 0"), "{}", stdout);
+    assert!(stdout.contains("files copied: 1"), "{}", stdout);
+    assert_eq!(std::fs::read_to_string(dst.join("a.txt")).unwrap(), "a");
+    // the result stays available after the job finished
+    let output = rcp().arg("--status").arg(&job_dir).output().unwrap();
+    assert_eq!(output.status.code(), Some(0));
+    assert!(String::from_utf8_lossy(&output.stdout).contains("finished with exit code 0"));
+    // the exit code of a failed job is reported too: the destination exists and --overwrite is not given
+    rcp()
+        .args(["--background", "--job-dir"])
+        .arg(&job_dir)
+        .arg(&src)
+        .arg(&dst)
+        .assert()
+        .code(0);
+    let output = rcp().arg("--wait").arg(&job_dir).output().unwrap();
+    assert_eq!(output.status.code(), Some(2));
+    assert!(String::from_utf8_lossy(&output.stdout).contains("finished with exit code 2"));
+}
+
+#[test]
+fn check_rcp_copy_limits() {
+    let tmp_dir = common::testing::TempDir::new("rcp_limits");
+    let src = tmp_dir.join("src");
+    std::fs::create_dir_all(&src).unwrap();
+    for name in ["a.txt", "b.txt", "c.txt"] {
+        std::fs::write(src.join(name), "data").unwrap();
+    }
+    let rcp = || assert_cmd::Command::cargo_bin("rcp").unwrap();
+    let output = rcp()
+        .args(["--max-files", "2"])
+        .arg(&src)
+        .arg(tmp_dir.join("dst1"))
+        .output()
+        .unwrap();
+    // reaching a limit aborts the copy
+    assert_eq!(output.status.code(), Some(2));
+    assert!(String::from_utf8_lossy(&output.stdout).contains("--max-files"));
+    let output = rcp()
+        .args(["--max-bytes", "10B"])
+        .arg(&src)
+        .arg(tmp_dir.join("dst2"))
+        .output()
+        .unwrap();
+    assert_eq!(output.status.code(), Some(2));
+    assert!(String::from_utf8_lossy(&output.stdout).contains("--max-bytes"));
+    rcp()
+        .args(["--max-files", "3", "--max-bytes", "12B"])
+        .arg(&src)
+        .arg(tmp_dir.join("dst3"))
+        .assert()
+        .code(0);
+    rcp()
+        .args(["--max-bytes", "lots"])
+        .arg(&src)
+        .arg(tmp_dir.join("dst4"))
+        .assert()
+        .code(2);
+    // an existing destination isn't removed before the limit is checked
+    let dst = tmp_dir.join("dst5");
+    std::fs::write(&dst, "old").unwrap();
+    rcp()
+        .args(["--overwrite", "--max-files", "0"])
+        .arg(src.join("a.txt"))
+        .arg(&dst)
+        .assert()
+        .code(2);
+    assert_eq!(std::fs::read_to_string(&dst).unwrap(), "old");
+}
+
+#[test]
+fn check_rcp_dereference_root() {
+    let tmp_dir = common::testing::TempDir::new("rcp_dereference_root");
+    let v2 = tmp_dir.join("v2");
+    std::fs::create_dir_all(v2.join("sub")).unwrap();
+    std::fs::write(v2.join("a.txt"), "a").unwrap();
+    std::os::unix::fs::symlink("../a.txt", v2.join("sub").join("link")).unwrap();
+    // the relative target is resolved relative to the directory of the symlink, not the working directory
+    std::os::unix::fs::symlink("v2", tmp_dir.join("current")).unwrap();
+    let rcp = || assert_cmd::Command::cargo_bin("rcp").unwrap();
+    rcp()
+        .arg("--dereference-root")
+        .arg(tmp_dir.join("current"))
+        .arg(tmp_dir.join("dst"))
+        .assert()
+        .code(0);
+    let dst = tmp_dir.join("dst");
+    assert!(std::fs::symlink_metadata(&dst).unwrap().is_dir());
+    assert_eq!(std::fs::read_to_string(dst.join("a.txt")).unwrap(), "a");
+    assert_eq!(
+        std::fs::read_link(dst.join("sub").join("link")).unwrap(),
+        std::path::PathBuf::from("../a.txt")
+    );
+}
@@ -15,10 +15,16 @@ struct Args {
     #[structopt(short, long)]
     overwrite: bool,
 
-    /// Comma separated list of file attributes to compare when when deciding if files are "identical", used with --overwrite flag. Options are: uid, gid, mode, size, mtime, ctime
+    /// Comma separated list of file attributes to compare when when deciding if files are "identical", used with --overwrite flag. Options are: uid, gid, mode, size, mtime, mtime_ns (mtime including the nanoseconds), ctime, hash (contents hash, computed only if the other attributes match)
     #[structopt(long, default_value = "size,mtime")]
     overwrite_compare: String,
 
+    /// Algorithm used to hash file contents (see the "hash" attribute above).
+    ///
+    /// Options are: xxh3 (fast, non-cryptographic), blake3 (default, fast, cryptographic), sha256
+    #[structopt(long, default_value = "blake3")]
+    checksum_algo: common::ChecksumAlgo,
+
     /// Exit on first error
     #[structopt(short = "-e", long = "fail-early")]
     fail_early: bool,
@@ -55,10 +61,34 @@ struct Args {
     #[structopt(long)]
     summary: bool,
 
+    /// Print the version and the supported features, attributes and progress types as a JSON document and exit
+    #[structopt(long)]
+    #[allow(dead_code)]
+    // handled before parsing the other arguments, see common::capabilities_requested
+    capabilities: bool,
+
+    /// Units used to show byte counts in the progress and the summary.
+    ///
+    /// Options are: iec (default, powers of 1024: KiB, MiB, ...), si (powers of 1000: KB, MB, ...)
+    #[structopt(long, default_value = "iec")]
+    units: common::ByteUnits,
+
     /// Quiet mode, don't report errors
     #[structopt(short = "q", long = "quiet")]
     quiet: bool,
 
+    /// Write log messages to a file instead of stdout
+    #[structopt(long)]
+    log_file: Option<std::path::PathBuf>,
+
+    /// Rotate the log file once it grows beyond the specified size, e.g. "100MiB". Used with --log-file
+    #[structopt(long)]
+    log_file_max_size: Option<String>,
+
+    /// Number of rotated log files to keep. Used with --log-file-max-size
+    #[structopt(long, default_value = "5")]
+    log_file_keep: usize,
+
     /// Directory with contents we want to update into `dst`
     #[structopt()]
     src: std::path::PathBuf,
@@ -79,6 +109,11 @@ struct Args {
     #[structopt(long, default_value = "size,mtime")]
     update_compare: String,
 
+    /// Also compare the contents of files the --update-compare attributes find unchanged before hard-linking them,
+    /// files with different contents are copied from the update directory. Same as adding "hash" to --update-compare
+    #[structopt(long, requires = "update")]
+    update_checksum: bool,
+
     /// Number of worker threads, 0 means number of cores
     #[structopt(long, default_value = "0")]
     max_workers: usize,
@@ -94,9 +129,53 @@ struct Args {
     /// Throttle the number of opearations per second, 0 means no throttle
     #[structopt(long, default_value = "0")]
     ops_throttle: usize,
+
+    /// Share the throttles with other processes using the same unix socket path.
+    ///
+    /// The first process to use the path becomes the broker: its --ops-throttle and --bwlimit-schedule limits apply to
+    /// all processes connected to it. If the broker exits another process takes over, without a broker throttling is
+    /// done locally.
+    #[structopt(long)]
+    throttle_broker: Option<String>,
+
+    /// Limit the copy throughput depending on the time of day (local time)
+    ///
+    /// The format is: "<HH:MM>-<HH:MM>=<rate>,...,else=<rate>" where <rate> is the number of bytes per second, e.g.
+    /// "5MiB", or "unlimited". The first window containing the current time applies, windows may wrap around midnight
+    /// and the time outside of all windows uses the "else" rate (no limit if not specified). The limit is adjusted
+    /// while the copy is running.
+    ///
+    /// Example: "09:00-18:00=5MiB,else=unlimited"
+    #[structopt(long)]
+    bwlimit_schedule: Option<String>,
+
+    /// Throttle the operations on paths matching a glob pattern, separately from the global limits. Can be repeated.
+    ///
+    /// The format is: "<pattern>:ops=<n>,bw=<rate>" where ops is the number of operations per second and bw the number
+    /// of bytes per second, e.g. "50MiB". A path matches if it or any of its parent directories matches the pattern,
+    /// the first matching rule applies. The limits apply on top of --ops-throttle and --bwlimit-schedule and are not
+    /// shared with --throttle-broker.
+    ///
+    /// Example: "/nfs/*:ops=200,bw=50MiB"
+    #[structopt(long = "throttle-rule", number_of_values = 1)]
+    throttle_rules: Vec<String>,
+
+    /// Serve Prometheus metrics (progress, throughput, open files) over HTTP on this address, e.g. 127.0.0.1:9090
+    #[structopt(long)]
+    metrics_addr: Option<String>,
+
+    /// Cancel the operation if it doesn't finish within the given time, e.g. "30min" or "2h".
+    ///
+    /// A partial summary is printed (with --summary) and the exit code is 3
+    #[structopt(long)]
+    timeout: Option<String>,
+}
+
+fn usage_error(error: anyhow::Error) -> common::ExitError {
+    common::ExitError::new(error, common::ExitCode::UsageError)
 }
 
-async fn async_main(args: Args) -> Result<common::LinkSummary> {
+async fn async_main(args: Args) -> Result<common::LinkSummary, common::ExitError> {
     for src in &args.src {
         if src == "."
             || src
@@ -104,10 +183,10 @@ async fn async_main(args: Args) -> Result<common::LinkSummary> {
                 .expect("input path cannot be converted to string?!")
                 .ends_with("/.")
         {
-            return Err(anyhow!(
+            return Err(usage_error(anyhow!(
                 "expanding source directory ({:?}) using dot operator ('.') is not supported, please use absolute path or '*' instead",
                 std::path::PathBuf::from(src)
-            ));
+            )));
         }
     }
     let dst = if args.dst.ends_with('/') {
@@ -115,20 +194,24 @@ async fn async_main(args: Args) -> Result<common::LinkSummary> {
             .src
             .file_name()
             .context(format!("source {:?} does not have a basename", &args.src))
-            .unwrap();
+            .map_err(usage_error)?;
         let dst_dir = std::path::PathBuf::from(args.dst);
         dst_dir.join(src_file)
     } else {
         let dst_path = std::path::PathBuf::from(args.dst);
         if dst_path.exists() && !args.overwrite {
-            return Err(anyhow!(
+            return Err(usage_error(anyhow!(
                 "Destination path {dst_path:?} already exists! \n\
                 If you want to copy INTO it then follow the destination path with a trailing slash (/) or use \
                 --overwrite if you want to overwrite it"
-            ));
+            )));
         }
         dst_path
     };
+    let mut update_compare =
+        common::parse_metadata_cmp_settings(&args.update_compare, args.checksum_algo)
+            .map_err(usage_error)?;
+    update_compare.hash |= args.update_checksum;
     let result = common::link(
         &args.src,
         &dst,
@@ -136,11 +219,39 @@ async fn async_main(args: Args) -> Result<common::LinkSummary> {
         &common::LinkSettings {
             copy_settings: common::CopySettings {
                 dereference: false, // currently not supported
+                dereference_root: false,
                 fail_early: args.fail_early,
                 overwrite: args.overwrite,
-                overwrite_compare: common::parse_metadata_cmp_settings(&args.overwrite_compare)?,
+                overwrite_compare: common::parse_metadata_cmp_settings(
+                    &args.overwrite_compare,
+                    args.checksum_algo,
+                )
+                .map_err(usage_error)?,
+                reflink: Default::default(),
+                inplace: false,
+                filter: Default::default(),
+                prune_empty_dirs: false,
+                update_only: false,
+                exclude_dst_dirs: false,
+                atomic_writes: false,
+                metadata_only: None,
+                link_dest: None,
+                compare_dest: None,
+                dedup: false,
+                compare_cache: None,
+                symlink_rewrite: None,
+                skip_unreadable: false,
+                ignore_times: false,
+                checkpoint: None,
+                itemize: None,
+                preallocate: Default::default(),
+                backup: None,
+                copy_special: false,
+                limits: None,
+                state_dir: None,
+                copy_state: Default::default(),
             },
-            update_compare: common::parse_metadata_cmp_settings(&args.update_compare)?,
+            update_compare,
             update_exclusive: args.update_exclusive,
         },
     )
@@ -149,16 +260,53 @@ async fn async_main(args: Args) -> Result<common::LinkSummary> {
         Ok(summary) => Ok(summary),
         Err(error) => {
             event!(Level::ERROR, "{}", &error);
+            let summary = &error.summary;
+            let exit_code = if args.fail_early {
+                // aborted
+                common::ExitCode::Failure
+            } else {
+                common::ExitCode::from_errors(
+                    summary.hard_links_created
+                        + summary.hard_links_unchanged
+                        + summary.copy_summary.files_copied
+                        + summary.copy_summary.symlinks_created
+                        + summary.copy_summary.directories_created
+                        + summary.copy_summary.files_unchanged
+                        + summary.copy_summary.symlinks_unchanged
+                        + summary.copy_summary.directories_unchanged
+                        > 0,
+                )
+            };
             if args.summary {
-                return Err(anyhow!("rlink encountered errors\n\n{}", &error.summary));
+                return Err(common::ExitError::new(
+                    anyhow!("rlink encountered errors\n\n{}", &error.summary),
+                    exit_code,
+                ));
             }
-            Err(anyhow!("rlink encountered errors"))
+            Err(common::ExitError::new(
+                anyhow!("rlink encountered errors"),
+                exit_code,
+            ))
         }
     }
 }
 
 fn main() -> Result<()> {
-    let args = Args::from_args();
+    if common::capabilities_requested() {
+        println!(
+            "{:#}",
+            common::capabilities("rlink", env!("CARGO_PKG_VERSION"))
+        );
+        common::ExitCode::Success.exit();
+    }
+    let args = Args::from_iter_safe(std::env::args_os()).unwrap_or_else(|err| {
+        if !err.use_stderr() {
+            // --help or --version
+            err.exit();
+        }
+        eprintln!("{}", err.message);
+        common::ExitCode::UsageError.exit();
+    });
     let func = {
         let args = args.clone();
         || async_main(args)
@@ -179,10 +327,24 @@ fn main() -> Result<()> {
         args.max_blocking_threads,
         args.max_open_files,
         args.ops_throttle,
+        common::RunSettings {
+            log_file: args.log_file.map(|path| common::LogFileSettings {
+                path,
+                max_size: args.log_file_max_size,
+                max_files: args.log_file_keep,
+            }),
+            units: args.units,
+            bwlimit_schedule: args.bwlimit_schedule,
+            throttle_rules: args.throttle_rules,
+            throttle_broker: args.throttle_broker,
+            metrics_addr: args.metrics_addr,
+            timeout: args.timeout,
+            ..Default::default()
+        },
         func,
     );
     match res {
-        Ok(_) => std::process::exit(0),
-        Err(_) => std::process::exit(1),
+        Ok(_) => common::ExitCode::Success.exit(),
+        Err(error) => common::ExitCode::from_error(&error).exit(),
     }
 }
@@ -3,3 +3,79 @@ fn check_rlink_help() {
     let mut cmd = assert_cmd::Command::cargo_bin("rlink").unwrap();
     cmd.arg("--help").assert();
 }
+
+#[test]
+fn check_rlink_exit_codes() {
+    let tmp_dir = common::testing::TempDir::new("rlink_exit_codes");
+    let src = tmp_dir.join("src");
+    std::fs::create_dir_all(&src).unwrap();
+    std::fs::write(src.join("file.txt"), "x").unwrap();
+    let rlink = || assert_cmd::Command::cargo_bin("rlink").unwrap();
+    rlink().arg(&src).assert().code(2);
+    rlink().arg(&src).arg(tmp_dir.join("dst")).assert().code(0);
+    rlink()
+        .arg(tmp_dir.join("missing"))
+        .arg(tmp_dir.join("other"))
+        .assert()
+        .code(2);
+}
+
+#[test]
+fn check_rlink_capabilities() {
+    common::testing::check_capabilities(
+        &assert_cmd::cargo::cargo_bin("rlink"),
+        env!("CARGO_PKG_VERSION"),
+    );
+}
+
+#[test]
+fn check_rlink_update_checksum() {
+    let tmp_dir = common::testing::TempDir::new("rlink_update_checksum");
+    let src = tmp_dir.join("src");
+    let update = tmp_dir.join("update");
+    std::fs::create_dir_all(&src).unwrap();
+    std::fs::create_dir_all(&update).unwrap();
+    std::fs::write(src.join("file.txt"), "old").unwrap();
+    // same size and mtime, different contents
+    std::fs::write(update.join("file.txt"), "new").unwrap();
+    let mtime = std::fs::metadata(src.join("file.txt"))
+        .unwrap()
+        .modified()
+        .unwrap();
+    std::fs::File::options()
+        .write(true)
+        .open(update.join("file.txt"))
+        .unwrap()
+        .set_times(std::fs::FileTimes::new().set_modified(mtime))
+        .unwrap();
+    let rlink = || assert_cmd::Command::cargo_bin("rlink").unwrap();
+    rlink()
+        .arg("--update-checksum")
+        .arg(&src)
+        .arg(tmp_dir.join("dst1"))
+        .assert()
+        .code(2);
+    rlink()
+        .arg("--update")
+        .arg(&update)
+        .arg(&src)
+        .arg(tmp_dir.join("dst2"))
+        .assert()
+        .code(0);
+    assert_eq!(
+        std::fs::read_to_string(tmp_dir.join("dst2").join("file.txt")).unwrap(),
+        "old"
+    );
+    rlink()
+        .arg("--update-checksum")
+        .arg("--update")
+        .arg(&update)
+        .arg(&src)
+        .arg(tmp_dir.join("dst3"))
+        .assert()
+        .code(0);
+    assert_eq!(
+        std::fs::read_to_string(tmp_dir.join("dst3").join("file.txt")).unwrap(),
+        "new"
+    );
+}
@@ -1,4 +1,4 @@
-use anyhow::{anyhow, Result};
+use anyhow::{anyhow, Context, Result};
 use common::ProgressType;
 use structopt::StructOpt;
 use tracing::{event, instrument, Level};
@@ -15,6 +15,43 @@ struct Args {
     #[structopt(short = "-e", long = "fail-early")]
     fail_early: bool,
 
+    /// Only remove files at least this large, e.g. "1GiB"
+    #[structopt(long)]
+    min_size: Option<String>,
+
+    /// Only remove files at most this large, e.g. "10GiB"
+    #[structopt(long)]
+    max_size: Option<String>,
+
+    /// Only remove entries of the given types, comma separated list of: f (file), l (symlink), d (directory).
+    ///
+    /// Directories are always traversed, they are removed only if "d" is specified and all their entries were removed.
+    #[structopt(long = "type")]
+    types: Option<String>,
+
+    /// Only remove files and symlinks older than this, e.g. "30d", "2024-01-01T00:00:00Z" or "@1704067200". Uses mtime
+    /// unless --atime or --ctime is set
+    #[structopt(long)]
+    older_than: Option<String>,
+
+    /// Compare access time against --older-than instead of modification time
+    #[structopt(long, requires = "older-than", conflicts_with = "ctime")]
+    atime: bool,
+
+    /// Compare status change time against --older-than instead of modification time
+    #[structopt(long, requires = "older-than")]
+    ctime: bool,
+
+    /// Only remove files and symlinks whose name matches the glob pattern, e.g. "*.log"
+    #[structopt(long)]
+    matching: Option<String>,
+
+    /// Remove directories left empty after their entries were removed.
+    ///
+    /// With --older-than or --matching directories are kept by default, even if all their entries were removed
+    #[structopt(long)]
+    prune_empty_dirs: bool,
+
     /// Show progress
     #[structopt(long)]
     progress: bool,
@@ -47,10 +84,34 @@ struct Args {
     #[structopt(long)]
     summary: bool,
 
+    /// Print the version and the supported features, attributes and progress types as a JSON document and exit
+    #[structopt(long)]
+    #[allow(dead_code)]
+    // handled before parsing the other arguments, see common::capabilities_requested
+    capabilities: bool,
+
+    /// Units used to show byte counts in the progress and the summary.
+    ///
+    /// Options are: iec (default, powers of 1024: KiB, MiB, ...), si (powers of 1000: KB, MB, ...)
+    #[structopt(long, default_value = "iec")]
+    units: common::ByteUnits,
+
     /// Quiet mode, don't report errors
     #[structopt(short = "q", long = "quiet")]
     quiet: bool,
 
+    /// Write log messages to a file instead of stdout
+    #[structopt(long)]
+    log_file: Option<std::path::PathBuf>,
+
+    /// Rotate the log file once it grows beyond the specified size, e.g. "100MiB". Used with --log-file
+    #[structopt(long)]
+    log_file_max_size: Option<String>,
+
+    /// Number of rotated log files to keep. Used with --log-file-max-size
+    #[structopt(long, default_value = "5")]
+    log_file_keep: usize,
+
     /// Source path(s) and destination path
     #[structopt()]
     paths: Vec<std::path::PathBuf>,
@@ -70,47 +131,140 @@ struct Args {
     /// Throttle the number of opearations per second, 0 means no throttle
     #[structopt(long, default_value = "0")]
     ops_throttle: usize,
+
+    /// Throttle the operations on paths matching a glob pattern, separately from the global limit. Can be repeated.
+    ///
+    /// The format is: "<pattern>:ops=<n>" where ops is the number of operations per second. A path matches if it or any
+    /// of its parent directories matches the pattern, the first matching rule applies. The limits apply on top of
+    /// --ops-throttle and are not shared with --throttle-broker.
+    ///
+    /// Example: "/nfs/*:ops=200"
+    #[structopt(long = "throttle-rule", number_of_values = 1)]
+    throttle_rules: Vec<String>,
+
+    /// Share the throttles with other processes using the same unix socket path.
+    ///
+    /// The first process to use the path becomes the broker: its --ops-throttle and --bwlimit-schedule limits apply to
+    /// all processes connected to it. If the broker exits another process takes over, without a broker throttling is
+    /// done locally.
+    #[structopt(long)]
+    throttle_broker: Option<String>,
+
+    /// Serve Prometheus metrics (progress, throughput, open files) over HTTP on this address, e.g. 127.0.0.1:9090
+    #[structopt(long)]
+    metrics_addr: Option<String>,
+
+    /// Cancel the operation if it doesn't finish within the given time, e.g. "30min" or "2h".
+    ///
+    /// A partial summary is printed (with --summary) and the exit code is 3
+    #[structopt(long)]
+    timeout: Option<String>,
 }
 
 #[instrument]
-async fn async_main(args: Args) -> Result<common::RmSummary> {
+async fn async_main(args: Args) -> Result<common::RmSummary, common::ExitError> {
+    let usage_error = |err| common::ExitError::new(err, common::ExitCode::UsageError);
+    if args.paths.is_empty() {
+        return Err(usage_error(anyhow!(
+            "You must specify at least one path to remove!"
+        )));
+    }
+    let mut filter = common::parse_filter_settings(
+        args.min_size.as_deref(),
+        args.max_size.as_deref(),
+        args.types.as_deref(),
+    )
+    .map_err(usage_error)?;
+    if let Some(older_than) = args.older_than.as_deref() {
+        let attr = if args.atime {
+            common::TimeAttr::Atime
+        } else if args.ctime {
+            common::TimeAttr::Ctime
+        } else {
+            common::TimeAttr::Mtime
+        };
+        filter.older_than = Some(
+            common::parse_age_filter(older_than, attr)
+                .context("invalid --older-than")
+                .map_err(usage_error)?,
+        );
+    }
+    if let Some(pattern) = args.matching.as_deref() {
+        filter.matching = Some(common::parse_name_pattern(pattern).map_err(usage_error)?);
+    }
     let mut join_set = tokio::task::JoinSet::new();
     for path in args.paths {
         let settings = common::RmSettings {
             fail_early: args.fail_early,
+            filter: filter.clone(),
+            prune_empty_dirs: args.prune_empty_dirs,
         };
         let do_rm = || async move { common::rm(&path, &settings).await };
         join_set.spawn(do_rm());
     }
     let mut success = true;
     let mut rm_summary = common::RmSummary::default();
+    let error_exit_code = |rm_summary: &common::RmSummary| {
+        common::ExitCode::from_errors(
+            rm_summary.files_removed + rm_summary.symlinks_removed + rm_summary.directories_removed
+                > 0,
+        )
+    };
     while let Some(res) = join_set.join_next().await {
-        match res? {
+        match res.map_err(|err| {
+            common::ExitError::new(anyhow::Error::msg(err), common::ExitCode::Failure)
+        })? {
             Ok(summary) => rm_summary = rm_summary + summary,
             Err(error) => {
                 event!(Level::ERROR, "{}", &error);
                 rm_summary = rm_summary + error.summary;
                 if args.fail_early {
+                    // aborted
+                    let exit_code = common::ExitCode::Failure;
                     if args.summary {
-                        return Err(anyhow!("{}\n\n{}", error, &rm_summary));
+                        return Err(common::ExitError::new(
+                            anyhow!("{}\n\n{}", error, &rm_summary),
+                            exit_code,
+                        ));
                     }
-                    return Err(anyhow!("{}", error));
+                    return Err(common::ExitError::new(anyhow!("{}", error), exit_code));
                 }
                 success = false;
             }
         }
     }
     if !success {
+        let exit_code = error_exit_code(&rm_summary);
         if args.summary {
-            return Err(anyhow!("rrm encountered errors\n\n{}", &rm_summary));
+            return Err(common::ExitError::new(
+                anyhow!("rrm encountered errors\n\n{}", &rm_summary),
+                exit_code,
+            ));
         }
-        return Err(anyhow!("rrm encountered errors"));
+        return Err(common::ExitError::new(
+            anyhow!("rrm encountered errors"),
+            exit_code,
+        ));
     }
     Ok(rm_summary)
 }
 
 fn main() -> Result<()> {
-    let args = Args::from_args();
+    if common::capabilities_requested() {
+        println!(
+            "{:#}",
+            common::capabilities("rrm", env!("CARGO_PKG_VERSION"))
+        );
+        common::ExitCode::Success.exit();
+    }
+    let args = Args::from_iter_safe(std::env::args_os()).unwrap_or_else(|err| {
+        if !err.use_stderr() {
+            // --help or --version
+            err.exit();
+        }
+        eprintln!("{}", err.message);
+        common::ExitCode::UsageError.exit();
+    });
     let func = {
         let args = args.clone();
         || async_main(args)
@@ -131,10 +285,23 @@ fn main() -> Result<()> {
         args.max_blocking_threads,
         args.max_open_files,
         args.ops_throttle,
+        common::RunSettings {
+            log_file: args.log_file.map(|path| common::LogFileSettings {
+                path,
+                max_size: args.log_file_max_size,
+                max_files: args.log_file_keep,
+            }),
+            units: args.units,
+            throttle_rules: args.throttle_rules,
+            throttle_broker: args.throttle_broker,
+            metrics_addr: args.metrics_addr,
+            timeout: args.timeout,
+            ..Default::default()
+        },
         func,
     );
     match res {
-        Ok(_) => std::process::exit(0),
-        Err(_) => std::process::exit(1),
+        Ok(_) => common::ExitCode::Success.exit(),
+        Err(error) => common::ExitCode::from_error(&error).exit(),
     }
 }
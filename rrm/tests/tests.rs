@@ -3,3 +3,41 @@ fn check_rrm_help() {
     let mut cmd = assert_cmd::Command::cargo_bin("rrm").unwrap();
     cmd.arg("--help").assert();
 }
+
+#[test]
+fn check_rrm_exit_codes() {
+    let tmp_dir = common::testing::TempDir::new("rrm_exit_codes");
+    std::fs::create_dir_all(tmp_dir.join("dir")).unwrap();
+    std::fs::write(tmp_dir.join("dir").join("file.txt"), "x").unwrap();
+    std::fs::write(tmp_dir.join("file.txt"), "x").unwrap();
+    let rrm = || assert_cmd::Command::cargo_bin("rrm").unwrap();
+    rrm().assert().code(2);
+    rrm().arg("--no-such-flag").assert().code(2);
+    rrm().arg(tmp_dir.join("dir")).assert().code(0);
+    assert!(!tmp_dir.join("dir").exists());
+    // one of the paths is missing
+    rrm()
+        .arg(tmp_dir.join("file.txt"))
+        .arg(tmp_dir.join("missing"))
+        .assert()
+        .code(1);
+    assert!(!tmp_dir.join("file.txt").exists());
+    // aborted on the first error
+    std::fs::write(tmp_dir.join("file.txt"), "x").unwrap();
+    rrm()
+        .arg("--fail-early")
+        .arg(tmp_dir.join("missing"))
+        .arg(tmp_dir.join("file.txt"))
+        .assert()
+        .code(2);
+    // all of the paths are missing
+    rrm().arg(tmp_dir.join("missing")).assert().code(2);
+}
+
+#[test]
+fn check_rrm_capabilities() {
+    common::testing::check_capabilities(
+        &assert_cmd::cargo::cargo_bin("rrm"),
+        env!("CARGO_PKG_VERSION"),
+    );
+}